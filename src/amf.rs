@@ -1,6 +1,7 @@
-use std::collections::HashMap;
-use std::io::Cursor;
+use std::io::{self, Cursor};
+use std::iter::FromIterator;
 
+use crate::amf3::{Amf3Decoder, Amf3Encoder, Amf3Value};
 use crate::error::{Error, Result};
 use crate::utils::*;
 
@@ -16,49 +17,248 @@ const ECMA_ARRAY_MARKER: u8 = 0x8;
 const OBJECT_END_MARKER: u8 = 0x9;
 const STRICT_ARRAY_MARKER: u8 = 0xA;
 const DATE_MARKER: u8 = 0xB;
-// const LONG_STRING_MARKER: u8 = 0xC;
-// const UNSUPPORTED_MARKER: u8 = 0xD;
-// const RECORDSET_MARKER: u8 = 0xE;
-// const XML_DOCUMENT_MARKER: u8 = 0xF;
-// const TYPED_OBJECT_MARKER: u8 = 0x10;
+const LONG_STRING_MARKER: u8 = 0xC;
+const UNSUPPORTED_MARKER: u8 = 0xD;
+const RECORDSET_MARKER: u8 = 0xE;
+const XML_DOCUMENT_MARKER: u8 = 0xF;
+const TYPED_OBJECT_MARKER: u8 = 0x10;
+const AVMPLUS_OBJECT_MARKER: u8 = 0x11;
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum AmfObject {
     Number(f64),
     Boolean(bool),
     String(String),
-    Object(HashMap<String, AmfObject>),
+    Object(AmfObjectMap),
     Null,
     Undefined,
     Reference(u16),
     EcmaArray(Vec<(String, AmfObject)>),
     StrictArray(Vec<AmfObject>),
     Date((f64, i16)),
+    LongString(String),
+    Unsupported,
+    /// The recordset type is reserved by the specification and carries no payload.
+    Recordset,
+    XmlDocument(String),
+    TypedObject(String, AmfObjectMap),
+    AvmPlus(Amf3Value),
+}
+
+/// Properties of an AMF object, kept in insertion order so that objects are encoded
+/// deterministically and decoded objects are re-encoded byte for byte.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct AmfObjectMap(Vec<(String, AmfObject)>);
+
+impl AmfObjectMap {
+    pub fn new() -> Self {
+        Self(Vec::new())
+    }
+
+    /// Returns the value of `key`. As in ActionScript, the last occurrence of a duplicated key
+    /// wins.
+    pub fn get(&self, key: &str) -> Option<&AmfObject> {
+        self.0.iter().rev().find(|(k, _)| k == key).map(|(_, v)| v)
+    }
+
+    /// Sets `key` to `value`, keeping the position of `key` if it is already present.
+    pub fn insert<K: Into<String>>(&mut self, key: K, value: AmfObject) -> Option<AmfObject> {
+        let key = key.into();
+        match self.0.iter_mut().rev().find(|(k, _)| *k == key) {
+            Some((_, v)) => Some(std::mem::replace(v, value)),
+            None => {
+                self.0.push((key, value));
+                None
+            }
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &AmfObject)> {
+        self.0.iter().map(|(k, v)| (k, v))
+    }
+}
+
+impl FromIterator<(String, AmfObject)> for AmfObjectMap {
+    fn from_iter<I: IntoIterator<Item = (String, AmfObject)>>(iter: I) -> Self {
+        let mut map = Self::new();
+        iter.into_iter().for_each(|(k, v)| {
+            map.insert(k, v);
+        });
+        map
+    }
+}
+
+impl IntoIterator for AmfObjectMap {
+    type Item = (String, AmfObject);
+    type IntoIter = std::vec::IntoIter<(String, AmfObject)>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.into_iter()
+    }
+}
+
+/// Builder for AMF objects such as command objects and `onStatus` information objects.
+///
+/// ```
+/// # use rtmp::amf::AmfObjectBuilder;
+/// let information = AmfObjectBuilder::new()
+///     .property("level", "status")
+///     .property("code", "NetStream.Play.Start")
+///     .build();
+/// ```
+#[derive(Debug, Default)]
+pub struct AmfObjectBuilder {
+    properties: AmfObjectMap,
+}
+
+impl AmfObjectBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn property<K: Into<String>, V: Into<AmfObject>>(mut self, key: K, value: V) -> Self {
+        self.properties.insert(key, value.into());
+        self
+    }
+
+    pub fn build(self) -> AmfObject {
+        AmfObject::Object(self.properties)
+    }
+}
+
+impl From<f64> for AmfObject {
+    fn from(x: f64) -> Self {
+        AmfObject::Number(x)
+    }
+}
+
+impl From<bool> for AmfObject {
+    fn from(b: bool) -> Self {
+        AmfObject::Boolean(b)
+    }
+}
+
+impl From<&str> for AmfObject {
+    fn from(s: &str) -> Self {
+        AmfObject::String(s.to_string())
+    }
+}
+
+impl From<String> for AmfObject {
+    fn from(s: String) -> Self {
+        AmfObject::String(s)
+    }
+}
+
+impl From<AmfObjectMap> for AmfObject {
+    fn from(properties: AmfObjectMap) -> Self {
+        AmfObject::Object(properties)
+    }
+}
+
+impl From<Amf3Value> for AmfObject {
+    /// Converts an AMF-3 value into its AMF-0 counterpart, keeping values without one (byte
+    /// arrays, vectors and dictionaries) wrapped in `AmfObject::AvmPlus`.
+    fn from(value: Amf3Value) -> Self {
+        match value {
+            Amf3Value::Undefined => AmfObject::Undefined,
+            Amf3Value::Null => AmfObject::Null,
+            Amf3Value::Boolean(b) => AmfObject::Boolean(b),
+            Amf3Value::Integer(x) => AmfObject::Number(x as f64),
+            Amf3Value::Double(x) => AmfObject::Number(x),
+            Amf3Value::String(s) => AmfObject::String(s),
+            Amf3Value::XmlDocument(s) | Amf3Value::Xml(s) => AmfObject::XmlDocument(s),
+            Amf3Value::Date(d) => AmfObject::Date((d, 0)),
+            Amf3Value::Array { associative, dense } if associative.is_empty() => {
+                AmfObject::StrictArray(dense.into_iter().map(AmfObject::from).collect())
+            }
+            Amf3Value::Array { associative, dense } => AmfObject::EcmaArray(
+                dense
+                    .into_iter()
+                    .enumerate()
+                    .map(|(i, v)| (i.to_string(), v))
+                    .chain(associative)
+                    .map(|(key, v)| (key, AmfObject::from(v)))
+                    .collect(),
+            ),
+            Amf3Value::Object {
+                traits,
+                sealed,
+                dynamic,
+            } => {
+                let properties = traits
+                    .sealed
+                    .into_iter()
+                    .zip(sealed)
+                    .chain(dynamic)
+                    .map(|(key, v)| (key, AmfObject::from(v)))
+                    .collect();
+                if traits.class_name.is_empty() {
+                    AmfObject::Object(properties)
+                } else {
+                    AmfObject::TypedObject(traits.class_name, properties)
+                }
+            }
+            value => AmfObject::AvmPlus(value),
+        }
+    }
+}
+
+/// Bounds applied while decoding untrusted AMF input.
+#[derive(Debug, Clone, Copy)]
+pub struct AmfDecodeLimits {
+    /// Maximum nesting depth of objects and arrays.
+    pub max_depth: usize,
+    /// Maximum number of bytes materialized by the decoder, including values duplicated through
+    /// AMF-3 references.
+    pub max_size: usize,
+}
+
+impl Default for AmfDecodeLimits {
+    fn default() -> Self {
+        Self {
+            max_depth: 64,
+            max_size: 16 << 20,
+        }
+    }
+}
+
+pub(crate) fn map_io_error(e: io::Error) -> Error {
+    if e.kind() == io::ErrorKind::UnexpectedEof {
+        Error::AmfTruncated
+    } else {
+        Error::Io(e)
+    }
+}
+
+pub(crate) fn remaining<T: AsRef<[u8]>>(reader: &Cursor<T>) -> usize {
+    (reader.get_ref().as_ref().len() as u64).saturating_sub(reader.position()) as usize
+}
+
+/// Reads `size` bytes, failing before allocating anything if the input is shorter than that.
+pub(crate) fn read_bytes<T: AsRef<[u8]>>(reader: &mut Cursor<T>, size: usize) -> Result<Vec<u8>> {
+    if size > remaining(reader) {
+        return Err(Error::AmfTruncated);
+    }
+    read_buffer(reader, size).map_err(map_io_error)
+}
+
+pub(crate) fn read_utf8<T: AsRef<[u8]>>(reader: &mut Cursor<T>, size: usize) -> Result<String> {
+    let offset = reader.position();
+    String::from_utf8(read_bytes(reader, size)?)
+        .map_err(|e| Error::AmfInvalidUtf8(offset + e.utf8_error().valid_up_to() as u64))
 }
 
 fn verify_type_marker<T: AsRef<[u8]>>(
     reader: &mut Cursor<T>,
     expected_type_marker: u8,
 ) -> Result<()> {
-    if read_u8(reader).map_err(Error::Io)? == expected_type_marker {
+    let type_marker = read_u8(reader).map_err(map_io_error)?;
+    if type_marker == expected_type_marker {
         Ok(())
     } else {
-        Err(Error::AmfIncorrectTypeMarker)
-    }
-}
-
-fn decode_amf_object_property<T: AsRef<[u8]>>(
-    reader: &mut Cursor<T>,
-) -> Result<Option<(String, AmfObject)>> {
-    let str_size = read_u16(reader).map_err(Error::Io)?;
-    if str_size == 0 {
-        return Ok(None);
+        Err(Error::AmfIncorrectTypeMarker(type_marker))
     }
-    Ok(Some((
-        String::from_utf8(read_buffer(reader, str_size as usize).map_err(Error::Io)?)
-            .expect("Invalid UTF-8 string"),
-        decode_amf_message(reader)?,
-    )))
 }
 
 pub fn decode_amf_number<T: AsRef<[u8]>>(
@@ -68,29 +268,7 @@ pub fn decode_amf_number<T: AsRef<[u8]>>(
     if verify_marker {
         verify_type_marker(reader, NUMBER_MARKER)?;
     }
-    read_f64(reader).map_err(Error::Io)
-}
-
-pub fn decode_amf_object<T: AsRef<[u8]>>(
-    reader: &mut Cursor<T>,
-    verify_marker: bool,
-) -> Result<HashMap<String, AmfObject>> {
-    if verify_marker {
-        verify_type_marker(reader, OBJECT_MARKER)?;
-    }
-    let mut map: HashMap<String, AmfObject> = HashMap::new();
-    loop {
-        match decode_amf_object_property(reader)? {
-            Some((key, value)) => {
-                map.insert(key, value);
-            }
-            None => {
-                verify_type_marker(reader, OBJECT_END_MARKER)?;
-                break;
-            }
-        }
-    }
-    Ok(map)
+    read_f64(reader).map_err(map_io_error)
 }
 
 pub fn decode_amf_null<T: AsRef<[u8]>>(reader: &mut Cursor<T>, verify_marker: bool) -> Result<()> {
@@ -107,11 +285,8 @@ pub fn decode_amf_string<T: AsRef<[u8]>>(
     if verify_marker {
         verify_type_marker(reader, STRING_MARKER)?;
     }
-    let size = read_u16(reader).map_err(Error::Io)?;
-    Ok(
-        String::from_utf8(read_buffer(reader, size as usize).map_err(Error::Io)?)
-            .expect("Invalid UTF-8 string"),
-    )
+    let size = read_u16(reader).map_err(map_io_error)?;
+    read_utf8(reader, size as usize)
 }
 
 pub fn decode_amf_boolean<T: AsRef<[u8]>>(
@@ -121,93 +296,312 @@ pub fn decode_amf_boolean<T: AsRef<[u8]>>(
     if verify_marker {
         verify_type_marker(reader, BOOLEAN_MARKER)?;
     }
-    Ok(read_u8(reader).map_err(Error::Io)? != 0)
+    Ok(read_u8(reader).map_err(map_io_error)? != 0)
 }
 
-pub fn decode_amf_ecma_array<T: AsRef<[u8]>>(
+pub fn decode_amf_reference<T: AsRef<[u8]>>(
     reader: &mut Cursor<T>,
     verify_marker: bool,
-) -> Result<Vec<(String, AmfObject)>> {
+) -> Result<u16> {
     if verify_marker {
-        verify_type_marker(reader, ECMA_ARRAY_MARKER)?;
-    }
-    let mut result = Vec::new();
-    for _ in 0..read_u32(reader).map_err(Error::Io)? {
-        if let Some((key, value)) = decode_amf_object_property(reader)? {
-            result.push((key, value));
-        }
-    }
-    if decode_amf_object_property(reader)?.is_some() {
-        return Err(Error::AmfIncorrectEndOfEcmaArray);
+        verify_type_marker(reader, REFERENCE_MARKER)?;
     }
-    verify_type_marker(reader, OBJECT_END_MARKER)?;
-    Ok(result)
+    read_u16(reader).map_err(map_io_error)
 }
 
-pub fn decode_amf_reference<T: AsRef<[u8]>>(
+pub fn decode_amf_date<T: AsRef<[u8]>>(
     reader: &mut Cursor<T>,
     verify_marker: bool,
-) -> Result<u16> {
+) -> Result<(f64, i16)> {
     if verify_marker {
-        verify_type_marker(reader, REFERENCE_MARKER)?;
+        verify_type_marker(reader, DATE_MARKER)?;
     }
-    read_u16(reader).map_err(Error::Io)
+    Ok((
+        read_f64(reader).map_err(map_io_error)?,
+        read_i16(reader).map_err(map_io_error)?,
+    ))
 }
 
-pub fn decode_amf_strict_array<T: AsRef<[u8]>>(
+pub fn decode_amf_long_string<T: AsRef<[u8]>>(
     reader: &mut Cursor<T>,
     verify_marker: bool,
-) -> Result<Vec<AmfObject>> {
+) -> Result<String> {
     if verify_marker {
-        verify_type_marker(reader, STRICT_ARRAY_MARKER)?;
+        verify_type_marker(reader, LONG_STRING_MARKER)?;
     }
-    (0..read_u32(reader).map_err(Error::Io)?)
-        .map(|_| decode_amf_message(reader))
-        .collect::<Result<Vec<_>>>()
+    let size = read_u32(reader).map_err(map_io_error)?;
+    read_utf8(reader, size as usize)
 }
 
-pub fn decode_amf_date<T: AsRef<[u8]>>(
+pub fn decode_amf_xml_document<T: AsRef<[u8]>>(
     reader: &mut Cursor<T>,
     verify_marker: bool,
-) -> Result<(f64, i16)> {
+) -> Result<String> {
     if verify_marker {
-        verify_type_marker(reader, DATE_MARKER)?;
+        verify_type_marker(reader, XML_DOCUMENT_MARKER)?;
     }
-    Ok((
-        read_f64(reader).map_err(Error::Io)?,
-        read_i16(reader).map_err(Error::Io)?,
-    ))
+    decode_amf_long_string(reader, false)
+}
+
+/// Decoder for nested AMF-0 values, enforcing an `AmfDecodeLimits` on the input.
+#[derive(Debug)]
+pub struct AmfDecoder {
+    limits: AmfDecodeLimits,
+    depth: usize,
+    size: usize,
+}
+
+impl Default for AmfDecoder {
+    fn default() -> Self {
+        Self::new(AmfDecodeLimits::default())
+    }
+}
+
+impl AmfDecoder {
+    pub fn new(limits: AmfDecodeLimits) -> Self {
+        Self {
+            limits,
+            depth: 0,
+            size: 0,
+        }
+    }
+
+    fn charge(&mut self, size: usize) -> Result<()> {
+        self.size = self.size.saturating_add(size);
+        if self.size > self.limits.max_size {
+            Err(Error::AmfSizeLimitExceeded(self.limits.max_size))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Runs `decode` one nesting level deeper.
+    fn nested<T, R, F>(&mut self, reader: &mut Cursor<T>, decode: F) -> Result<R>
+    where
+        T: AsRef<[u8]>,
+        F: FnOnce(&mut Self, &mut Cursor<T>) -> Result<R>,
+    {
+        if self.depth >= self.limits.max_depth {
+            return Err(Error::AmfNestingTooDeep(self.limits.max_depth));
+        }
+        self.depth += 1;
+        let result = decode(self, reader);
+        self.depth -= 1;
+        result
+    }
+
+    fn decode_property<T: AsRef<[u8]>>(
+        &mut self,
+        reader: &mut Cursor<T>,
+    ) -> Result<Option<(String, AmfObject)>> {
+        let str_size = read_u16(reader).map_err(map_io_error)?;
+        if str_size == 0 {
+            return Ok(None);
+        }
+        let key = read_utf8(reader, str_size as usize)?;
+        self.charge(key.len())?;
+        Ok(Some((key, self.decode(reader)?)))
+    }
+
+    pub fn decode_object<T: AsRef<[u8]>>(
+        &mut self,
+        reader: &mut Cursor<T>,
+        verify_marker: bool,
+    ) -> Result<AmfObjectMap> {
+        if verify_marker {
+            verify_type_marker(reader, OBJECT_MARKER)?;
+        }
+        self.nested(reader, |decoder, reader| {
+            let mut map = AmfObjectMap::new();
+            loop {
+                match decoder.decode_property(reader)? {
+                    Some((key, value)) => {
+                        // Duplicated keys are kept so that the object is re-encoded as is.
+                        map.0.push((key, value));
+                    }
+                    None => {
+                        verify_type_marker(reader, OBJECT_END_MARKER)?;
+                        break;
+                    }
+                }
+            }
+            Ok(map)
+        })
+    }
+
+    pub fn decode_ecma_array<T: AsRef<[u8]>>(
+        &mut self,
+        reader: &mut Cursor<T>,
+        verify_marker: bool,
+    ) -> Result<Vec<(String, AmfObject)>> {
+        if verify_marker {
+            verify_type_marker(reader, ECMA_ARRAY_MARKER)?;
+        }
+        let count = read_u32(reader).map_err(map_io_error)?;
+        self.nested(reader, |decoder, reader| {
+            let mut result = Vec::new();
+            for _ in 0..count {
+                if let Some((key, value)) = decoder.decode_property(reader)? {
+                    result.push((key, value));
+                }
+            }
+            if decoder.decode_property(reader)?.is_some() {
+                return Err(Error::AmfIncorrectEndOfEcmaArray);
+            }
+            verify_type_marker(reader, OBJECT_END_MARKER)?;
+            Ok(result)
+        })
+    }
+
+    pub fn decode_strict_array<T: AsRef<[u8]>>(
+        &mut self,
+        reader: &mut Cursor<T>,
+        verify_marker: bool,
+    ) -> Result<Vec<AmfObject>> {
+        if verify_marker {
+            verify_type_marker(reader, STRICT_ARRAY_MARKER)?;
+        }
+        let count = read_u32(reader).map_err(map_io_error)? as usize;
+        // Every element takes at least one byte.
+        if count > remaining(reader) {
+            return Err(Error::AmfTruncated);
+        }
+        self.nested(reader, |decoder, reader| {
+            (0..count)
+                .map(|_| decoder.decode(reader))
+                .collect::<Result<Vec<_>>>()
+        })
+    }
+
+    pub fn decode<T: AsRef<[u8]>>(&mut self, reader: &mut Cursor<T>) -> Result<AmfObject> {
+        let type_marker = read_u8(reader).map_err(map_io_error)?;
+        self.charge(1)?;
+        let value = match type_marker {
+            NUMBER_MARKER => AmfObject::Number(decode_amf_number(reader, false)?),
+            BOOLEAN_MARKER => AmfObject::Boolean(decode_amf_boolean(reader, false)?),
+            STRING_MARKER => AmfObject::String(decode_amf_string(reader, false)?),
+            OBJECT_MARKER => AmfObject::Object(self.decode_object(reader, false)?),
+            NULL_MARKER => AmfObject::Null,
+            UNDEFINED_MARKER => AmfObject::Undefined,
+            REFERENCE_MARKER => AmfObject::Reference(decode_amf_reference(reader, false)?),
+            ECMA_ARRAY_MARKER => AmfObject::EcmaArray(self.decode_ecma_array(reader, false)?),
+            STRICT_ARRAY_MARKER => AmfObject::StrictArray(self.decode_strict_array(reader, false)?),
+            DATE_MARKER => AmfObject::Date(decode_amf_date(reader, false)?),
+            LONG_STRING_MARKER => AmfObject::LongString(decode_amf_long_string(reader, false)?),
+            UNSUPPORTED_MARKER => AmfObject::Unsupported,
+            RECORDSET_MARKER => AmfObject::Recordset,
+            XML_DOCUMENT_MARKER => AmfObject::XmlDocument(decode_amf_xml_document(reader, false)?),
+            TYPED_OBJECT_MARKER => {
+                let class_name = decode_amf_string(reader, false)?;
+                self.charge(class_name.len())?;
+                AmfObject::TypedObject(class_name, self.decode_object(reader, false)?)
+            }
+            AVMPLUS_OBJECT_MARKER => {
+                let mut decoder = Amf3Decoder::new(AmfDecodeLimits {
+                    max_depth: self.limits.max_depth - self.depth,
+                    max_size: self.limits.max_size - self.size,
+                });
+                let value = decoder.decode(reader)?;
+                self.charge(decoder.size())?;
+                AmfObject::AvmPlus(value)
+            }
+            // The movie clip marker is reserved and the object end marker cannot appear on its
+            // own.
+            MOVIECLIP_MARKER | OBJECT_END_MARKER => {
+                return Err(Error::AmfIncorrectTypeMarker(type_marker))
+            }
+            _ => return Err(Error::AmfIncorrectTypeMarker(type_marker)),
+        };
+        if let AmfObject::String(ref s)
+        | AmfObject::LongString(ref s)
+        | AmfObject::XmlDocument(ref s) = value
+        {
+            self.charge(s.len())?;
+        }
+        Ok(value)
+    }
+
+    /// Decodes all remaining values in `reader`, e.g. the name and arguments of a command
+    /// message.
+    pub fn decode_all<T: AsRef<[u8]>>(&mut self, reader: &mut Cursor<T>) -> Result<Vec<AmfObject>> {
+        let mut values = Vec::new();
+        while remaining(reader) > 0 {
+            values.push(self.decode(reader)?);
+        }
+        Ok(values)
+    }
+}
+
+pub fn decode_amf_object<T: AsRef<[u8]>>(
+    reader: &mut Cursor<T>,
+    verify_marker: bool,
+) -> Result<AmfObjectMap> {
+    AmfDecoder::default().decode_object(reader, verify_marker)
+}
+
+pub fn decode_amf_ecma_array<T: AsRef<[u8]>>(
+    reader: &mut Cursor<T>,
+    verify_marker: bool,
+) -> Result<Vec<(String, AmfObject)>> {
+    AmfDecoder::default().decode_ecma_array(reader, verify_marker)
+}
+
+pub fn decode_amf_strict_array<T: AsRef<[u8]>>(
+    reader: &mut Cursor<T>,
+    verify_marker: bool,
+) -> Result<Vec<AmfObject>> {
+    AmfDecoder::default().decode_strict_array(reader, verify_marker)
 }
 
 pub fn decode_amf_message<T: AsRef<[u8]>>(reader: &mut Cursor<T>) -> Result<AmfObject> {
-    let type_marker = read_u8(reader).map_err(Error::Io)?;
-    match type_marker {
-        NUMBER_MARKER => Ok(AmfObject::Number(decode_amf_number(reader, false)?)),
-        BOOLEAN_MARKER => Ok(AmfObject::Boolean(decode_amf_boolean(reader, false)?)),
-        STRING_MARKER => Ok(AmfObject::String(decode_amf_string(reader, false)?)),
-        MOVIECLIP_MARKER => unreachable!("Movie clip marker is reserved"),
-        OBJECT_MARKER => Ok(AmfObject::Object(decode_amf_object(reader, false)?)),
-        NULL_MARKER => Ok(AmfObject::Null),
-        UNDEFINED_MARKER => Ok(AmfObject::Undefined),
-        REFERENCE_MARKER => Ok(AmfObject::Reference(decode_amf_reference(reader, false)?)),
-        ECMA_ARRAY_MARKER => Ok(AmfObject::EcmaArray(decode_amf_ecma_array(reader, false)?)),
-        OBJECT_END_MARKER => unreachable!("Object end marker should not appear on its own"),
-        STRICT_ARRAY_MARKER => Ok(AmfObject::StrictArray(decode_amf_strict_array(
-            reader, false,
-        )?)),
-        DATE_MARKER => Ok(AmfObject::Date(decode_amf_date(reader, false)?)),
-        _ => Err(Error::AmfIncorrectTypeMarker),
-    }
-}
-
-pub fn encode_amf_messages(src: &[AmfObject]) -> Vec<u8> {
+    AmfDecoder::default().decode(reader)
+}
+
+/// Decodes all remaining values in `reader`, e.g. the name and arguments of a command message.
+pub fn decode_amf_messages<T: AsRef<[u8]>>(reader: &mut Cursor<T>) -> Result<Vec<AmfObject>> {
+    AmfDecoder::default().decode_all(reader)
+}
+
+/// Encodes `src` back to back. Fails if a property key or class name is longer than 65535 bytes.
+pub fn encode_amf_messages(src: &[AmfObject]) -> Result<Vec<u8>> {
     let mut buffer = Vec::new();
-    src.iter()
-        .for_each(|obj| encode_amf_message_impl(obj, &mut buffer));
-    buffer
+    for obj in src {
+        encode_amf_message_impl(obj, &mut buffer)?;
+    }
+    Ok(buffer)
 }
 
-fn encode_amf_message_impl(src: &AmfObject, message: &mut Vec<u8>) {
+fn encode_amf_short_string(s: &str, message: &mut Vec<u8>) -> Result<()> {
+    if s.len() > u16::MAX as usize {
+        return Err(Error::AmfStringTooLong(s.len()));
+    }
+    message.extend_from_slice(&(s.len() as u16).to_be_bytes());
+    message.extend_from_slice(s.as_bytes());
+    Ok(())
+}
+
+fn encode_amf_property(key: &str, value: &AmfObject, message: &mut Vec<u8>) -> Result<()> {
+    encode_amf_short_string(key, message)?;
+    encode_amf_message_impl(value, message)
+}
+
+fn encode_amf_properties<'a, I>(properties: I, message: &mut Vec<u8>) -> Result<()>
+where
+    I: IntoIterator<Item = (&'a String, &'a AmfObject)>,
+{
+    for (key, val) in properties {
+        encode_amf_property(key, val, message)?;
+    }
+    message.extend_from_slice(&[0x0, 0x0, OBJECT_END_MARKER]);
+    Ok(())
+}
+
+fn encode_amf_long_string(s: &str, message: &mut Vec<u8>) {
+    message.extend_from_slice(&(s.len() as u32).to_be_bytes());
+    message.extend_from_slice(s.as_bytes());
+}
+
+fn encode_amf_message_impl(src: &AmfObject, message: &mut Vec<u8>) -> Result<()> {
     match *src {
         AmfObject::Number(ref x) => {
             message.push(NUMBER_MARKER);
@@ -218,19 +612,17 @@ fn encode_amf_message_impl(src: &AmfObject, message: &mut Vec<u8>) {
             let byte = if *b { 1 } else { 0 };
             message.push(byte);
         }
+        AmfObject::String(ref s) if s.len() > u16::MAX as usize => {
+            message.push(LONG_STRING_MARKER);
+            encode_amf_long_string(s, message);
+        }
         AmfObject::String(ref s) => {
             message.push(STRING_MARKER);
-            message.extend_from_slice(&(s.len() as u16).to_be_bytes());
-            message.extend_from_slice(s.as_bytes());
+            encode_amf_short_string(s, message)?;
         }
         AmfObject::Object(ref obj) => {
             message.push(OBJECT_MARKER);
-            obj.iter().for_each(|(key, val)| {
-                message.extend_from_slice(&(key.len() as u16).to_be_bytes());
-                message.extend_from_slice(key.as_bytes());
-                encode_amf_message_impl(val, message);
-            });
-            message.extend_from_slice(&[0x0, 0x0, OBJECT_END_MARKER]);
+            encode_amf_properties(obj.iter(), message)?;
         }
         AmfObject::Null => {
             message.push(NULL_MARKER);
@@ -245,24 +637,45 @@ fn encode_amf_message_impl(src: &AmfObject, message: &mut Vec<u8>) {
         AmfObject::EcmaArray(ref v) => {
             message.push(ECMA_ARRAY_MARKER);
             message.extend_from_slice(&(v.len() as u32).to_be_bytes());
-            v.iter().for_each(|(key, val)| {
-                message.extend_from_slice(&(key.len() as u16).to_be_bytes());
-                message.extend_from_slice(key.as_bytes());
-                encode_amf_message_impl(val, message);
-            });
-            message.extend_from_slice(&[0x0, 0x0, OBJECT_END_MARKER]);
+            encode_amf_properties(v.iter().map(|(key, val)| (key, val)), message)?;
         }
         AmfObject::StrictArray(ref v) => {
             message.push(STRICT_ARRAY_MARKER);
             message.extend_from_slice(&(v.len() as u32).to_be_bytes());
-            v.iter().for_each(|t| encode_amf_message_impl(t, message));
+            for t in v {
+                encode_amf_message_impl(t, message)?;
+            }
         }
         AmfObject::Date((ref d, ref t)) => {
             message.push(DATE_MARKER);
             message.extend_from_slice(&d.to_be_bytes());
             message.extend_from_slice(&t.to_be_bytes());
         }
+        AmfObject::LongString(ref s) => {
+            message.push(LONG_STRING_MARKER);
+            encode_amf_long_string(s, message);
+        }
+        AmfObject::Unsupported => {
+            message.push(UNSUPPORTED_MARKER);
+        }
+        AmfObject::Recordset => {
+            message.push(RECORDSET_MARKER);
+        }
+        AmfObject::XmlDocument(ref s) => {
+            message.push(XML_DOCUMENT_MARKER);
+            encode_amf_long_string(s, message);
+        }
+        AmfObject::TypedObject(ref class_name, ref obj) => {
+            message.push(TYPED_OBJECT_MARKER);
+            encode_amf_short_string(class_name, message)?;
+            encode_amf_properties(obj.iter(), message)?;
+        }
+        AmfObject::AvmPlus(ref v) => {
+            message.push(AVMPLUS_OBJECT_MARKER);
+            Amf3Encoder::default().encode(message, v);
+        }
     }
+    Ok(())
 }
 
 #[cfg(test)]
@@ -286,7 +699,7 @@ mod tests {
     fn amf_parse_bool_false() {
         let mut reader = Cursor::new([BOOLEAN_MARKER, 0x0]);
         if let AmfObject::Boolean(x) = decode_amf_message(&mut reader).unwrap() {
-            assert_eq!(x, false);
+            assert!(!x);
             assert!(reader.bytes().next().is_none());
         } else {
             panic!("Test failed");
@@ -297,7 +710,7 @@ mod tests {
     fn amf_parse_bool_true() {
         let mut reader = Cursor::new([BOOLEAN_MARKER, 0xA]);
         if let AmfObject::Boolean(x) = decode_amf_message(&mut reader).unwrap() {
-            assert_eq!(x, true);
+            assert!(x);
             assert!(reader.bytes().next().is_none());
         } else {
             panic!("Test failed");
@@ -373,7 +786,7 @@ mod tests {
     #[allow(clippy::float_cmp)]
     #[test]
     fn amf_encode_number() {
-        let buffer = encode_amf_messages(&[AmfObject::Number(7122.123_f64)]);
+        let buffer = encode_amf_messages(&[AmfObject::Number(7122.123_f64)]).unwrap();
         if let AmfObject::Number(x) = decode_amf_message(&mut Cursor::new(buffer)).unwrap() {
             assert_eq!(x, 7122.123_f64);
         } else {
@@ -383,7 +796,7 @@ mod tests {
 
     #[test]
     fn amf_encode_object() {
-        let object: HashMap<String, AmfObject> = [
+        let object: AmfObjectMap = [
             (
                 String::from("field1"),
                 AmfObject::String(String::from("value1")),
@@ -395,9 +808,9 @@ mod tests {
         .iter()
         .cloned()
         .collect();
-        let buffer = encode_amf_messages(&[AmfObject::Object(object.clone())]);
+        let buffer = encode_amf_messages(&[AmfObject::Object(object.clone())]).unwrap();
         if let AmfObject::Object(amf) = decode_amf_message(&mut Cursor::new(buffer)).unwrap() {
-            assert_eq!(amf.len(), object.len());
+            assert_eq!(amf, object);
             for i in 1..5 {
                 let key = format!("field{}", i);
                 assert_eq!(object.get(&key), amf.get(&key));
@@ -418,7 +831,7 @@ mod tests {
             (String::from("key3"), AmfObject::Number(71.22_f64)),
             (String::from("key4"), AmfObject::Null),
         ];
-        let buffer = encode_amf_messages(&[AmfObject::EcmaArray(array.clone())]);
+        let buffer = encode_amf_messages(&[AmfObject::EcmaArray(array.clone())]).unwrap();
         if let AmfObject::EcmaArray(v) = decode_amf_message(&mut Cursor::new(buffer)).unwrap() {
             eprintln!("array = {:?}, v = {:?}", array, v);
             assert_eq!(array, v);
@@ -426,4 +839,245 @@ mod tests {
             panic!("Test failed");
         }
     }
+
+    #[test]
+    fn amf_avmplus() {
+        let value = AmfObject::AvmPlus(Amf3Value::ByteArray(vec![0x7, 0x1, 0x2, 0x2]));
+        let buffer = encode_amf_messages(&[value.clone(), AmfObject::Null]).unwrap();
+        let mut reader = Cursor::new(buffer);
+        assert_eq!(decode_amf_message(&mut reader).unwrap(), value);
+        assert_eq!(decode_amf_message(&mut reader).unwrap(), AmfObject::Null);
+        assert!(reader.bytes().next().is_none());
+    }
+
+    #[test]
+    fn amf_from_amf3() {
+        assert_eq!(
+            AmfObject::from(Amf3Value::Integer(7122)),
+            AmfObject::Number(7122_f64)
+        );
+        assert_eq!(
+            AmfObject::from(Amf3Value::Array {
+                associative: vec![(String::from("key"), Amf3Value::Null)],
+                dense: vec![Amf3Value::Boolean(true)],
+            }),
+            AmfObject::EcmaArray(vec![
+                (String::from("0"), AmfObject::Boolean(true)),
+                (String::from("key"), AmfObject::Null),
+            ])
+        );
+    }
+
+    #[test]
+    fn amf_encode_long_string() {
+        let s = "lyb".repeat(30000);
+        let buffer = encode_amf_messages(&[AmfObject::String(s.clone())]).unwrap();
+        assert_eq!(buffer[0], LONG_STRING_MARKER);
+        assert_eq!(buffer[1..5], (s.len() as u32).to_be_bytes());
+        assert_eq!(
+            decode_amf_message(&mut Cursor::new(buffer)).unwrap(),
+            AmfObject::LongString(s)
+        );
+    }
+
+    #[test]
+    fn amf_encode_remaining_types() {
+        let object: AmfObjectMap = [(String::from("x"), AmfObject::Number(71.22_f64))]
+            .iter()
+            .cloned()
+            .collect();
+        let values = [
+            AmfObject::LongString(String::from("jizz")),
+            AmfObject::Unsupported,
+            AmfObject::Recordset,
+            AmfObject::XmlDocument(String::from("<lyb/>")),
+            AmfObject::TypedObject(String::from("Point"), object),
+        ];
+        let mut reader = Cursor::new(encode_amf_messages(&values).unwrap());
+        for value in values.iter() {
+            assert_eq!(&decode_amf_message(&mut reader).unwrap(), value);
+        }
+        assert!(reader.bytes().next().is_none());
+    }
+
+    #[test]
+    fn amf_encode_long_key() {
+        let key = "k".repeat(u16::MAX as usize);
+        let object: AmfObjectMap = [(key.clone(), AmfObject::Null)].iter().cloned().collect();
+        let buffer = encode_amf_messages(&[AmfObject::Object(object.clone())]).unwrap();
+        assert_eq!(
+            decode_amf_message(&mut Cursor::new(buffer)).unwrap(),
+            AmfObject::Object(object)
+        );
+
+        let array = vec![(key + "k", AmfObject::Null)];
+        match encode_amf_messages(&[AmfObject::EcmaArray(array)]) {
+            Err(Error::AmfStringTooLong(len)) => assert_eq!(len, u16::MAX as usize + 1),
+            _ => panic!("Test failed"),
+        }
+    }
+
+    #[test]
+    fn amf_encode_long_class_name() {
+        let class_name = "c".repeat(u16::MAX as usize);
+        let value = AmfObject::TypedObject(class_name.clone(), AmfObjectMap::new());
+        let buffer = encode_amf_messages(std::slice::from_ref(&value)).unwrap();
+        assert_eq!(decode_amf_message(&mut Cursor::new(buffer)).unwrap(), value);
+
+        let value = AmfObject::TypedObject(class_name + "c", AmfObjectMap::new());
+        match encode_amf_messages(&[value]) {
+            Err(Error::AmfStringTooLong(len)) => assert_eq!(len, u16::MAX as usize + 1),
+            _ => panic!("Test failed"),
+        }
+    }
+
+    #[test]
+    fn amf_parse_typed_object() {
+        let mut reader = Cursor::new([
+            TYPED_OBJECT_MARKER,
+            0x0,
+            0x1,
+            0x41,
+            0x0,
+            0x1,
+            0x62,
+            NULL_MARKER,
+            0x0,
+            0x0,
+            OBJECT_END_MARKER,
+        ]);
+        if let AmfObject::TypedObject(class_name, properties) =
+            decode_amf_message(&mut reader).unwrap()
+        {
+            assert_eq!(class_name, "A");
+            assert_eq!(properties.get("b"), Some(&AmfObject::Null));
+            assert!(reader.bytes().next().is_none());
+        } else {
+            panic!("Test failed");
+        }
+    }
+
+    #[test]
+    fn amf_parse_invalid_utf8() {
+        let mut reader = Cursor::new([STRING_MARKER, 0x0, 0x3, 0x6A, 0xFF, 0x7A]);
+        assert!(matches!(
+            decode_amf_message(&mut reader),
+            Err(Error::AmfInvalidUtf8(4))
+        ));
+    }
+
+    #[test]
+    fn amf_parse_unexpected_marker() {
+        for &marker in &[MOVIECLIP_MARKER, OBJECT_END_MARKER, 0x12] {
+            assert!(matches!(
+                decode_amf_message(&mut Cursor::new([marker])),
+                Err(Error::AmfIncorrectTypeMarker(m)) if m == marker
+            ));
+        }
+    }
+
+    #[test]
+    fn amf_parse_truncated() {
+        let inputs: [&[u8]; 4] = [
+            &[NUMBER_MARKER, 0x0, 0x0],
+            &[LONG_STRING_MARKER, 0xFF, 0xFF, 0xFF, 0xFF, 0x0],
+            &[STRICT_ARRAY_MARKER, 0xFF, 0xFF, 0xFF, 0xFF, NULL_MARKER],
+            &[OBJECT_MARKER, 0x0, 0x1, 0x6A],
+        ];
+        for input in inputs.iter() {
+            assert!(matches!(
+                decode_amf_message(&mut Cursor::new(input)),
+                Err(Error::AmfTruncated)
+            ));
+        }
+    }
+
+    #[test]
+    fn amf_parse_nesting_too_deep() {
+        let mut buffer = [STRICT_ARRAY_MARKER, 0x0, 0x0, 0x0, 0x1].repeat(100);
+        buffer.push(NULL_MARKER);
+        assert!(matches!(
+            decode_amf_message(&mut Cursor::new(&buffer)),
+            Err(Error::AmfNestingTooDeep(64))
+        ));
+        let mut decoder = AmfDecoder::new(AmfDecodeLimits {
+            max_depth: 100,
+            ..AmfDecodeLimits::default()
+        });
+        assert!(decoder.decode(&mut Cursor::new(&buffer)).is_ok());
+    }
+
+    #[test]
+    fn amf_encode_object_order() {
+        let information = AmfObjectBuilder::new()
+            .property("level", "status")
+            .property("code", "A")
+            .property("description", "")
+            .build();
+        let buffer = encode_amf_messages(std::slice::from_ref(&information)).unwrap();
+        assert_eq!(
+            buffer,
+            [
+                &[OBJECT_MARKER, 0x0, 0x5][..],
+                b"level",
+                &[STRING_MARKER, 0x0, 0x6],
+                b"status",
+                &[0x0, 0x4],
+                b"code",
+                &[STRING_MARKER, 0x0, 0x1, 0x41, 0x0, 0xB],
+                b"description",
+                &[STRING_MARKER, 0x0, 0x0, 0x0, 0x0, OBJECT_END_MARKER],
+            ]
+            .concat()
+        );
+        assert_eq!(
+            decode_amf_message(&mut Cursor::new(&buffer)).unwrap(),
+            information
+        );
+    }
+
+    #[test]
+    fn amf_object_round_trip() {
+        // An object with a duplicated key is re-encoded as is.
+        let buffer = [
+            OBJECT_MARKER,
+            0x0,
+            0x1,
+            0x62,
+            NULL_MARKER,
+            0x0,
+            0x1,
+            0x61,
+            UNDEFINED_MARKER,
+            0x0,
+            0x1,
+            0x62,
+            BOOLEAN_MARKER,
+            0x1,
+            0x0,
+            0x0,
+            OBJECT_END_MARKER,
+        ];
+        let object = decode_amf_message(&mut Cursor::new(buffer)).unwrap();
+        if let AmfObject::Object(ref properties) = object {
+            assert_eq!(properties.get("b"), Some(&AmfObject::Boolean(true)));
+        } else {
+            panic!("Test failed");
+        }
+        assert_eq!(encode_amf_messages(&[object]).unwrap(), buffer);
+    }
+
+    #[test]
+    fn amf_object_map_insert() {
+        let mut map: AmfObjectMap = vec![
+            (String::from("a"), AmfObject::Null),
+            (String::from("b"), AmfObject::Null),
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(map.insert("a", AmfObject::Undefined), Some(AmfObject::Null));
+        assert_eq!(map.insert("c", AmfObject::Undefined), None);
+        let keys: Vec<_> = map.iter().map(|(k, _)| k.as_str()).collect();
+        assert_eq!(keys, ["a", "b", "c"]);
+    }
 }
@@ -0,0 +1,766 @@
+use std::collections::HashMap;
+use std::io::Cursor;
+
+use crate::amf::{map_io_error, read_bytes, read_utf8, remaining, AmfDecodeLimits};
+use crate::error::{Error, Result};
+use crate::utils::*;
+
+const UNDEFINED_MARKER: u8 = 0x0;
+const NULL_MARKER: u8 = 0x1;
+const FALSE_MARKER: u8 = 0x2;
+const TRUE_MARKER: u8 = 0x3;
+const INTEGER_MARKER: u8 = 0x4;
+const DOUBLE_MARKER: u8 = 0x5;
+const STRING_MARKER: u8 = 0x6;
+const XML_DOCUMENT_MARKER: u8 = 0x7;
+const DATE_MARKER: u8 = 0x8;
+const ARRAY_MARKER: u8 = 0x9;
+const OBJECT_MARKER: u8 = 0xA;
+const XML_MARKER: u8 = 0xB;
+const BYTE_ARRAY_MARKER: u8 = 0xC;
+const VECTOR_INT_MARKER: u8 = 0xD;
+const VECTOR_UINT_MARKER: u8 = 0xE;
+const VECTOR_DOUBLE_MARKER: u8 = 0xF;
+const VECTOR_OBJECT_MARKER: u8 = 0x10;
+const DICTIONARY_MARKER: u8 = 0x11;
+
+// Integers outside of the U29 range are encoded as doubles.
+const INTEGER_MIN: i32 = -(1 << 28);
+const INTEGER_MAX: i32 = (1 << 28) - 1;
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Amf3Traits {
+    pub class_name: String,
+    pub dynamic: bool,
+    pub sealed: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Amf3Value {
+    Undefined,
+    Null,
+    Boolean(bool),
+    Integer(i32),
+    Double(f64),
+    String(String),
+    XmlDocument(String),
+    Date(f64),
+    Array {
+        associative: Vec<(String, Amf3Value)>,
+        dense: Vec<Amf3Value>,
+    },
+    Object {
+        traits: Amf3Traits,
+        sealed: Vec<Amf3Value>,
+        dynamic: Vec<(String, Amf3Value)>,
+    },
+    Xml(String),
+    ByteArray(Vec<u8>),
+    VectorInt {
+        fixed: bool,
+        items: Vec<i32>,
+    },
+    VectorUInt {
+        fixed: bool,
+        items: Vec<u32>,
+    },
+    VectorDouble {
+        fixed: bool,
+        items: Vec<f64>,
+    },
+    VectorObject {
+        fixed: bool,
+        type_name: String,
+        items: Vec<Amf3Value>,
+    },
+    Dictionary {
+        weak_keys: bool,
+        entries: Vec<(Amf3Value, Amf3Value)>,
+    },
+}
+
+/// Either an index into one of the reference tables or the length/count of an inline value.
+enum U29Header {
+    Reference(usize),
+    Inline(u32),
+}
+
+fn read_u29<T: AsRef<[u8]>>(reader: &mut Cursor<T>) -> Result<u32> {
+    let mut value = 0_u32;
+    for i in 0..4 {
+        let byte = read_u8(reader).map_err(map_io_error)?;
+        if i == 3 {
+            return Ok((value << 8) | byte as u32);
+        }
+        value = (value << 7) | (byte & 0x7F) as u32;
+        if byte & 0x80 == 0 {
+            break;
+        }
+    }
+    Ok(value)
+}
+
+fn read_u29_header<T: AsRef<[u8]>>(reader: &mut Cursor<T>) -> Result<U29Header> {
+    let value = read_u29(reader)?;
+    if value & 1 == 0 {
+        Ok(U29Header::Reference((value >> 1) as usize))
+    } else {
+        Ok(U29Header::Inline(value >> 1))
+    }
+}
+
+fn write_u29(buffer: &mut Vec<u8>, value: u32) {
+    let value = value & 0x1FFFFFFF;
+    if value < 0x80 {
+        buffer.push(value as u8);
+    } else if value < 0x4000 {
+        buffer.extend_from_slice(&[(value >> 7) as u8 | 0x80, (value & 0x7F) as u8]);
+    } else if value < 0x200000 {
+        buffer.extend_from_slice(&[
+            (value >> 14) as u8 | 0x80,
+            ((value >> 7) & 0x7F) as u8 | 0x80,
+            (value & 0x7F) as u8,
+        ]);
+    } else {
+        buffer.extend_from_slice(&[
+            (value >> 22) as u8 | 0x80,
+            ((value >> 15) & 0x7F) as u8 | 0x80,
+            ((value >> 8) & 0x7F) as u8 | 0x80,
+            (value & 0xFF) as u8,
+        ]);
+    }
+}
+
+/// Writes the header of an inline value, i.e. `length << 1 | 1`.
+fn write_inline_header(buffer: &mut Vec<u8>, length: usize) {
+    write_u29(buffer, ((length as u32) << 1) | 1);
+}
+
+/// Decoder state for a single AMF-3 context, holding the string, object and traits reference
+/// tables.
+#[derive(Debug)]
+pub struct Amf3Decoder {
+    limits: AmfDecodeLimits,
+    depth: usize,
+    size: usize,
+    strings: Vec<String>,
+    // Objects are stored with the size charged while decoding them, so that references are
+    // accounted for as well. Objects currently being decoded are `None` so that cyclic
+    // references are rejected.
+    objects: Vec<Option<(Amf3Value, usize)>>,
+    traits: Vec<Amf3Traits>,
+}
+
+impl Default for Amf3Decoder {
+    fn default() -> Self {
+        Self::new(AmfDecodeLimits::default())
+    }
+}
+
+/// Fails if fewer than `count` elements of `element_size` bytes are left in `reader`.
+fn ensure_remaining<T: AsRef<[u8]>>(
+    reader: &Cursor<T>,
+    count: u32,
+    element_size: usize,
+) -> Result<()> {
+    if count as u64 * element_size as u64 > remaining(reader) as u64 {
+        Err(Error::AmfTruncated)
+    } else {
+        Ok(())
+    }
+}
+
+impl Amf3Decoder {
+    pub fn new(limits: AmfDecodeLimits) -> Self {
+        Self {
+            limits,
+            depth: 0,
+            size: 0,
+            strings: Vec::new(),
+            objects: Vec::new(),
+            traits: Vec::new(),
+        }
+    }
+
+    /// Number of bytes materialized so far.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    fn charge(&mut self, size: usize) -> Result<()> {
+        self.size = self.size.saturating_add(size);
+        if self.size > self.limits.max_size {
+            Err(Error::AmfSizeLimitExceeded(self.limits.max_size))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn read_string<T: AsRef<[u8]>>(&mut self, reader: &mut Cursor<T>) -> Result<String> {
+        let s = match read_u29_header(reader)? {
+            U29Header::Reference(index) => self
+                .strings
+                .get(index)
+                .cloned()
+                .ok_or(Error::Amf3InvalidReference(index))?,
+            U29Header::Inline(length) => {
+                let s = read_utf8(reader, length as usize)?;
+                if !s.is_empty() {
+                    self.strings.push(s.clone());
+                }
+                s
+            }
+        };
+        self.charge(s.len())?;
+        Ok(s)
+    }
+
+    /// Decodes a value stored in the object reference table. `decode_inline` receives the
+    /// length/count carried by the U29 header.
+    fn read_complex<T, F>(&mut self, reader: &mut Cursor<T>, decode_inline: F) -> Result<Amf3Value>
+    where
+        T: AsRef<[u8]>,
+        F: FnOnce(&mut Self, &mut Cursor<T>, u32) -> Result<Amf3Value>,
+    {
+        match read_u29_header(reader)? {
+            U29Header::Reference(index) => {
+                let (value, size) = self
+                    .objects
+                    .get(index)
+                    .cloned()
+                    .flatten()
+                    .ok_or(Error::Amf3InvalidReference(index))?;
+                self.charge(size)?;
+                Ok(value)
+            }
+            U29Header::Inline(length) => {
+                if self.depth >= self.limits.max_depth {
+                    return Err(Error::AmfNestingTooDeep(self.limits.max_depth));
+                }
+                let index = self.objects.len();
+                self.objects.push(None);
+                let size = self.size;
+                self.depth += 1;
+                let value = decode_inline(self, reader, length);
+                self.depth -= 1;
+                let value = value?;
+                self.objects[index] = Some((value.clone(), self.size - size));
+                Ok(value)
+            }
+        }
+    }
+
+    fn read_traits<T: AsRef<[u8]>>(
+        &mut self,
+        reader: &mut Cursor<T>,
+        header: u32,
+    ) -> Result<Amf3Traits> {
+        // The lowest bit of `header` (already shifted once) tells whether the traits are inline.
+        if header & 1 == 0 {
+            let index = (header >> 1) as usize;
+            return self
+                .traits
+                .get(index)
+                .cloned()
+                .ok_or(Error::Amf3InvalidReference(index));
+        }
+        let externalizable = (header >> 1) & 1 == 1;
+        let dynamic = (header >> 2) & 1 == 1;
+        let sealed_count = header >> 3;
+        let class_name = self.read_string(reader)?;
+        if externalizable {
+            return Err(Error::Amf3ExternalizableNotSupported(class_name));
+        }
+        ensure_remaining(reader, sealed_count, 1)?;
+        let sealed = (0..sealed_count)
+            .map(|_| self.read_string(reader))
+            .collect::<Result<Vec<_>>>()?;
+        let traits = Amf3Traits {
+            class_name,
+            dynamic,
+            sealed,
+        };
+        self.traits.push(traits.clone());
+        Ok(traits)
+    }
+
+    fn read_dynamic_properties<T: AsRef<[u8]>>(
+        &mut self,
+        reader: &mut Cursor<T>,
+    ) -> Result<Vec<(String, Amf3Value)>> {
+        let mut properties = Vec::new();
+        loop {
+            let key = self.read_string(reader)?;
+            if key.is_empty() {
+                return Ok(properties);
+            }
+            properties.push((key, self.decode(reader)?));
+        }
+    }
+
+    pub fn decode<T: AsRef<[u8]>>(&mut self, reader: &mut Cursor<T>) -> Result<Amf3Value> {
+        let type_marker = read_u8(reader).map_err(map_io_error)?;
+        self.charge(1)?;
+        match type_marker {
+            UNDEFINED_MARKER => Ok(Amf3Value::Undefined),
+            NULL_MARKER => Ok(Amf3Value::Null),
+            FALSE_MARKER => Ok(Amf3Value::Boolean(false)),
+            TRUE_MARKER => Ok(Amf3Value::Boolean(true)),
+            INTEGER_MARKER => {
+                let value = read_u29(reader)?;
+                // Sign-extend the 29-bit integer.
+                Ok(Amf3Value::Integer(((value << 3) as i32) >> 3))
+            }
+            DOUBLE_MARKER => Ok(Amf3Value::Double(read_f64(reader).map_err(map_io_error)?)),
+            STRING_MARKER => Ok(Amf3Value::String(self.read_string(reader)?)),
+            XML_DOCUMENT_MARKER | XML_MARKER => {
+                self.read_complex(reader, |decoder, reader, length| {
+                    let s = read_utf8(reader, length as usize)?;
+                    decoder.charge(s.len())?;
+                    Ok(if type_marker == XML_MARKER {
+                        Amf3Value::Xml(s)
+                    } else {
+                        Amf3Value::XmlDocument(s)
+                    })
+                })
+            }
+            DATE_MARKER => self.read_complex(reader, |_, reader, _| {
+                Ok(Amf3Value::Date(read_f64(reader).map_err(map_io_error)?))
+            }),
+            ARRAY_MARKER => self.read_complex(reader, |decoder, reader, count| {
+                let associative = decoder.read_dynamic_properties(reader)?;
+                ensure_remaining(reader, count, 1)?;
+                let dense = (0..count)
+                    .map(|_| decoder.decode(reader))
+                    .collect::<Result<Vec<_>>>()?;
+                Ok(Amf3Value::Array { associative, dense })
+            }),
+            OBJECT_MARKER => self.read_complex(reader, |decoder, reader, header| {
+                let traits = decoder.read_traits(reader, header)?;
+                let sealed = (0..traits.sealed.len())
+                    .map(|_| decoder.decode(reader))
+                    .collect::<Result<Vec<_>>>()?;
+                let dynamic = if traits.dynamic {
+                    decoder.read_dynamic_properties(reader)?
+                } else {
+                    Vec::new()
+                };
+                Ok(Amf3Value::Object {
+                    traits,
+                    sealed,
+                    dynamic,
+                })
+            }),
+            BYTE_ARRAY_MARKER => self.read_complex(reader, |decoder, reader, length| {
+                let bytes = read_bytes(reader, length as usize)?;
+                decoder.charge(bytes.len())?;
+                Ok(Amf3Value::ByteArray(bytes))
+            }),
+            VECTOR_INT_MARKER => self.read_complex(reader, |decoder, reader, count| {
+                let fixed = read_u8(reader).map_err(map_io_error)? != 0;
+                ensure_remaining(reader, count, 4)?;
+                decoder.charge(count as usize * 4)?;
+                let items = (0..count)
+                    .map(|_| read_u32(reader).map(|x| x as i32).map_err(map_io_error))
+                    .collect::<Result<Vec<_>>>()?;
+                Ok(Amf3Value::VectorInt { fixed, items })
+            }),
+            VECTOR_UINT_MARKER => self.read_complex(reader, |decoder, reader, count| {
+                let fixed = read_u8(reader).map_err(map_io_error)? != 0;
+                ensure_remaining(reader, count, 4)?;
+                decoder.charge(count as usize * 4)?;
+                let items = (0..count)
+                    .map(|_| read_u32(reader).map_err(map_io_error))
+                    .collect::<Result<Vec<_>>>()?;
+                Ok(Amf3Value::VectorUInt { fixed, items })
+            }),
+            VECTOR_DOUBLE_MARKER => self.read_complex(reader, |decoder, reader, count| {
+                let fixed = read_u8(reader).map_err(map_io_error)? != 0;
+                ensure_remaining(reader, count, 8)?;
+                decoder.charge(count as usize * 8)?;
+                let items = (0..count)
+                    .map(|_| read_f64(reader).map_err(map_io_error))
+                    .collect::<Result<Vec<_>>>()?;
+                Ok(Amf3Value::VectorDouble { fixed, items })
+            }),
+            VECTOR_OBJECT_MARKER => self.read_complex(reader, |decoder, reader, count| {
+                let fixed = read_u8(reader).map_err(map_io_error)? != 0;
+                let type_name = decoder.read_string(reader)?;
+                ensure_remaining(reader, count, 1)?;
+                let items = (0..count)
+                    .map(|_| decoder.decode(reader))
+                    .collect::<Result<Vec<_>>>()?;
+                Ok(Amf3Value::VectorObject {
+                    fixed,
+                    type_name,
+                    items,
+                })
+            }),
+            DICTIONARY_MARKER => self.read_complex(reader, |decoder, reader, count| {
+                let weak_keys = read_u8(reader).map_err(map_io_error)? != 0;
+                ensure_remaining(reader, count, 2)?;
+                let entries = (0..count)
+                    .map(|_| Ok((decoder.decode(reader)?, decoder.decode(reader)?)))
+                    .collect::<Result<Vec<_>>>()?;
+                Ok(Amf3Value::Dictionary { weak_keys, entries })
+            }),
+            _ => Err(Error::AmfIncorrectTypeMarker(type_marker)),
+        }
+    }
+}
+
+/// Encoder state for a single AMF-3 context. Strings and traits are sent by reference once they
+/// have been seen; objects are always sent inline.
+#[derive(Debug, Default)]
+pub struct Amf3Encoder {
+    strings: HashMap<String, usize>,
+    traits: Vec<Amf3Traits>,
+}
+
+impl Amf3Encoder {
+    fn write_string(&mut self, buffer: &mut Vec<u8>, s: &str) {
+        if let Some(&index) = self.strings.get(s) {
+            write_u29(buffer, (index as u32) << 1);
+            return;
+        }
+        if !s.is_empty() {
+            self.strings.insert(s.to_string(), self.strings.len());
+        }
+        write_inline_header(buffer, s.len());
+        buffer.extend_from_slice(s.as_bytes());
+    }
+
+    fn write_traits(&mut self, buffer: &mut Vec<u8>, traits: &Amf3Traits) {
+        if let Some(index) = self.traits.iter().position(|t| t == traits) {
+            write_u29(buffer, ((index as u32) << 2) | 0b01);
+            return;
+        }
+        self.traits.push(traits.clone());
+        let dynamic = if traits.dynamic { 0b1000 } else { 0 };
+        write_u29(
+            buffer,
+            ((traits.sealed.len() as u32) << 4) | dynamic | 0b0011,
+        );
+        self.write_string(buffer, &traits.class_name);
+        traits
+            .sealed
+            .iter()
+            .for_each(|name| self.write_string(buffer, name));
+    }
+
+    fn write_dynamic_properties(
+        &mut self,
+        buffer: &mut Vec<u8>,
+        properties: &[(String, Amf3Value)],
+    ) {
+        properties.iter().for_each(|(key, value)| {
+            self.write_string(buffer, key);
+            self.encode(buffer, value);
+        });
+        self.write_string(buffer, "");
+    }
+
+    pub fn encode(&mut self, buffer: &mut Vec<u8>, src: &Amf3Value) {
+        match *src {
+            Amf3Value::Undefined => buffer.push(UNDEFINED_MARKER),
+            Amf3Value::Null => buffer.push(NULL_MARKER),
+            Amf3Value::Boolean(b) => buffer.push(if b { TRUE_MARKER } else { FALSE_MARKER }),
+            Amf3Value::Integer(x) if (INTEGER_MIN..=INTEGER_MAX).contains(&x) => {
+                buffer.push(INTEGER_MARKER);
+                write_u29(buffer, x as u32);
+            }
+            Amf3Value::Integer(x) => {
+                buffer.push(DOUBLE_MARKER);
+                buffer.extend_from_slice(&(x as f64).to_be_bytes());
+            }
+            Amf3Value::Double(x) => {
+                buffer.push(DOUBLE_MARKER);
+                buffer.extend_from_slice(&x.to_be_bytes());
+            }
+            Amf3Value::String(ref s) => {
+                buffer.push(STRING_MARKER);
+                self.write_string(buffer, s);
+            }
+            Amf3Value::XmlDocument(ref s) | Amf3Value::Xml(ref s) => {
+                buffer.push(if let Amf3Value::Xml(_) = *src {
+                    XML_MARKER
+                } else {
+                    XML_DOCUMENT_MARKER
+                });
+                write_inline_header(buffer, s.len());
+                buffer.extend_from_slice(s.as_bytes());
+            }
+            Amf3Value::Date(ref d) => {
+                buffer.push(DATE_MARKER);
+                write_inline_header(buffer, 0);
+                buffer.extend_from_slice(&d.to_be_bytes());
+            }
+            Amf3Value::Array {
+                ref associative,
+                ref dense,
+            } => {
+                buffer.push(ARRAY_MARKER);
+                write_inline_header(buffer, dense.len());
+                self.write_dynamic_properties(buffer, associative);
+                dense.iter().for_each(|v| self.encode(buffer, v));
+            }
+            Amf3Value::Object {
+                ref traits,
+                ref sealed,
+                ref dynamic,
+            } => {
+                buffer.push(OBJECT_MARKER);
+                self.write_traits(buffer, traits);
+                sealed.iter().for_each(|v| self.encode(buffer, v));
+                if traits.dynamic {
+                    self.write_dynamic_properties(buffer, dynamic);
+                }
+            }
+            Amf3Value::ByteArray(ref bytes) => {
+                buffer.push(BYTE_ARRAY_MARKER);
+                write_inline_header(buffer, bytes.len());
+                buffer.extend_from_slice(bytes);
+            }
+            Amf3Value::VectorInt { fixed, ref items } => {
+                buffer.push(VECTOR_INT_MARKER);
+                write_inline_header(buffer, items.len());
+                buffer.push(fixed as u8);
+                items
+                    .iter()
+                    .for_each(|x| buffer.extend_from_slice(&x.to_be_bytes()));
+            }
+            Amf3Value::VectorUInt { fixed, ref items } => {
+                buffer.push(VECTOR_UINT_MARKER);
+                write_inline_header(buffer, items.len());
+                buffer.push(fixed as u8);
+                items
+                    .iter()
+                    .for_each(|x| buffer.extend_from_slice(&x.to_be_bytes()));
+            }
+            Amf3Value::VectorDouble { fixed, ref items } => {
+                buffer.push(VECTOR_DOUBLE_MARKER);
+                write_inline_header(buffer, items.len());
+                buffer.push(fixed as u8);
+                items
+                    .iter()
+                    .for_each(|x| buffer.extend_from_slice(&x.to_be_bytes()));
+            }
+            Amf3Value::VectorObject {
+                fixed,
+                ref type_name,
+                ref items,
+            } => {
+                buffer.push(VECTOR_OBJECT_MARKER);
+                write_inline_header(buffer, items.len());
+                buffer.push(fixed as u8);
+                self.write_string(buffer, type_name);
+                items.iter().for_each(|v| self.encode(buffer, v));
+            }
+            Amf3Value::Dictionary {
+                weak_keys,
+                ref entries,
+            } => {
+                buffer.push(DICTIONARY_MARKER);
+                write_inline_header(buffer, entries.len());
+                buffer.push(weak_keys as u8);
+                entries.iter().for_each(|(key, value)| {
+                    self.encode(buffer, key);
+                    self.encode(buffer, value);
+                });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    fn decode_amf3_message<T: AsRef<[u8]>>(reader: &mut Cursor<T>) -> Result<Amf3Value> {
+        Amf3Decoder::default().decode(reader)
+    }
+
+    fn encode_amf3_messages(src: &[Amf3Value]) -> Vec<u8> {
+        let mut encoder = Amf3Encoder::default();
+        let mut buffer = Vec::new();
+        src.iter().for_each(|v| encoder.encode(&mut buffer, v));
+        buffer
+    }
+
+    fn round_trip(value: Amf3Value) -> Amf3Value {
+        let buffer = encode_amf3_messages(&[value]);
+        let mut reader = Cursor::new(buffer);
+        let result = decode_amf3_message(&mut reader).unwrap();
+        assert!(reader.bytes().next().is_none());
+        result
+    }
+
+    #[test]
+    fn amf3_u29() {
+        for &(value, ref bytes) in &[
+            (0x7F_u32, vec![0x7F]),
+            (0x80, vec![0x81, 0x00]),
+            (0x3FFF, vec![0xFF, 0x7F]),
+            (0x4000, vec![0x81, 0x80, 0x00]),
+            (0x1FFFFF, vec![0xFF, 0xFF, 0x7F]),
+            (0x200000, vec![0x80, 0xC0, 0x80, 0x00]),
+            (0x1FFFFFFF, vec![0xFF, 0xFF, 0xFF, 0xFF]),
+        ] {
+            let mut buffer = Vec::new();
+            write_u29(&mut buffer, value);
+            assert_eq!(&buffer, bytes);
+            assert_eq!(read_u29(&mut Cursor::new(buffer)).unwrap(), value);
+        }
+    }
+
+    #[test]
+    fn amf3_integer() {
+        for &x in &[0, 1, -1, INTEGER_MIN, INTEGER_MAX] {
+            assert_eq!(round_trip(Amf3Value::Integer(x)), Amf3Value::Integer(x));
+        }
+        assert_eq!(
+            round_trip(Amf3Value::Integer(INTEGER_MAX + 1)),
+            Amf3Value::Double((INTEGER_MAX + 1) as f64)
+        );
+    }
+
+    #[test]
+    fn amf3_string_reference() {
+        let buffer = encode_amf3_messages(&[
+            Amf3Value::String(String::from("jizz")),
+            Amf3Value::String(String::from("jizz")),
+        ]);
+        assert_eq!(
+            buffer,
+            [
+                STRING_MARKER,
+                0x9,
+                0x6A,
+                0x69,
+                0x7A,
+                0x7A,
+                STRING_MARKER,
+                0x0
+            ]
+        );
+        let mut decoder = Amf3Decoder::default();
+        let mut reader = Cursor::new(buffer);
+        for _ in 0..2 {
+            assert_eq!(
+                decoder.decode(&mut reader).unwrap(),
+                Amf3Value::String(String::from("jizz"))
+            );
+        }
+    }
+
+    #[test]
+    fn amf3_object_traits_reference() {
+        let object = Amf3Value::Object {
+            traits: Amf3Traits {
+                class_name: String::from("Point"),
+                dynamic: true,
+                sealed: vec![String::from("x"), String::from("y")],
+            },
+            sealed: vec![Amf3Value::Integer(7), Amf3Value::Double(1.22)],
+            dynamic: vec![(String::from("z"), Amf3Value::Null)],
+        };
+        let array = Amf3Value::Array {
+            associative: vec![(String::from("key"), Amf3Value::Boolean(true))],
+            dense: vec![object.clone(), object],
+        };
+        assert_eq!(round_trip(array.clone()), array);
+    }
+
+    #[test]
+    fn amf3_object_reference() {
+        // [ByteArray(1, 2), reference to object 1]
+        let mut reader = Cursor::new([
+            ARRAY_MARKER,
+            0x5,
+            0x1,
+            BYTE_ARRAY_MARKER,
+            0x5,
+            0x1,
+            0x2,
+            BYTE_ARRAY_MARKER,
+            0x2,
+        ]);
+        let bytes = Amf3Value::ByteArray(vec![0x1, 0x2]);
+        assert_eq!(
+            decode_amf3_message(&mut reader).unwrap(),
+            Amf3Value::Array {
+                associative: vec![],
+                dense: vec![bytes.clone(), bytes],
+            }
+        );
+    }
+
+    #[test]
+    fn amf3_cyclic_reference() {
+        // An array containing a reference to itself.
+        let mut reader = Cursor::new([ARRAY_MARKER, 0x3, 0x1, ARRAY_MARKER, 0x0]);
+        assert!(matches!(
+            decode_amf3_message(&mut reader),
+            Err(Error::Amf3InvalidReference(0))
+        ));
+    }
+
+    #[test]
+    fn amf3_vectors_dictionary() {
+        for value in &[
+            Amf3Value::VectorInt {
+                fixed: true,
+                items: vec![-1, 0, 7122],
+            },
+            Amf3Value::VectorUInt {
+                fixed: false,
+                items: vec![0xFFFFFFFF, 0],
+            },
+            Amf3Value::VectorDouble {
+                fixed: false,
+                items: vec![71.22],
+            },
+            Amf3Value::VectorObject {
+                fixed: false,
+                type_name: String::from("String"),
+                items: vec![Amf3Value::String(String::from("lyb"))],
+            },
+            Amf3Value::Dictionary {
+                weak_keys: false,
+                entries: vec![(Amf3Value::Integer(1), Amf3Value::XmlDocument(String::new()))],
+            },
+            Amf3Value::Date(1_615_000_000_000.0),
+            Amf3Value::Xml(String::from("<a/>")),
+        ] {
+            assert_eq!(&round_trip(value.clone()), value);
+        }
+    }
+
+    #[test]
+    fn amf3_reference_expansion() {
+        // Each array holds two references to the previous one, doubling the decoded size.
+        let mut buffer = vec![BYTE_ARRAY_MARKER, 0x3, 0x0];
+        for i in 0..40 {
+            buffer.extend_from_slice(&[ARRAY_MARKER, 0x5, 0x1]);
+            buffer.extend_from_slice(&[ARRAY_MARKER, (i as u8) << 1, ARRAY_MARKER, (i as u8) << 1]);
+        }
+        let mut decoder = Amf3Decoder::new(AmfDecodeLimits {
+            max_size: 1 << 16,
+            ..AmfDecodeLimits::default()
+        });
+        let mut reader = Cursor::new(buffer);
+        assert!(decoder.decode(&mut reader).is_ok());
+        let result = (0..40).try_for_each(|_| decoder.decode(&mut reader).map(|_| ()));
+        assert!(matches!(result, Err(Error::AmfSizeLimitExceeded(_))));
+    }
+
+    #[test]
+    fn amf3_truncated_vector() {
+        let mut reader = Cursor::new([VECTOR_DOUBLE_MARKER, 0xFF, 0xFF, 0xFF, 0xFF, 0x0]);
+        assert!(matches!(
+            decode_amf3_message(&mut reader),
+            Err(Error::AmfTruncated)
+        ));
+    }
+}
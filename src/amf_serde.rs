@@ -0,0 +1,659 @@
+use std::fmt;
+
+use serde::de::value::{MapDeserializer, SeqDeserializer};
+use serde::de::{
+    self, DeserializeOwned, DeserializeSeed, EnumAccess, IntoDeserializer, Unexpected,
+    VariantAccess, Visitor,
+};
+use serde::ser::{self, Serialize};
+
+use crate::amf::{AmfObject, AmfObjectMap};
+use crate::amf3::Amf3Value;
+use crate::error::{Error, Result};
+
+impl ser::Error for Error {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        Error::AmfSerde(msg.to_string())
+    }
+}
+
+impl de::Error for Error {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        Error::AmfSerde(msg.to_string())
+    }
+}
+
+/// Serializes `value` into an AMF-0 value.
+///
+/// Structs become anonymous objects, maps become ECMA arrays, sequences become strict arrays,
+/// numbers become doubles and `None` becomes null.
+pub fn to_amf_object<T: Serialize + ?Sized>(value: &T) -> Result<AmfObject> {
+    value.serialize(Serializer)
+}
+
+/// Deserializes an AMF-0 value into `T`.
+pub fn from_amf_object<T: DeserializeOwned>(value: AmfObject) -> Result<T> {
+    T::deserialize(Deserializer::new(value))
+}
+
+/// Deserializes an optional field, treating a value of another type as absent so that one
+/// mistyped property does not reject the whole object. Used with
+/// `#[serde(deserialize_with = "lenient")]`.
+pub fn lenient<'de, D, T>(deserializer: D) -> std::result::Result<Option<T>, D::Error>
+where
+    D: de::Deserializer<'de>,
+    T: de::Deserialize<'de>,
+{
+    Ok(<Option<T> as de::Deserialize>::deserialize(deserializer).unwrap_or(None))
+}
+
+pub struct Serializer;
+
+impl ser::Serializer for Serializer {
+    type Ok = AmfObject;
+    type Error = Error;
+
+    type SerializeSeq = SerializeVec;
+    type SerializeTuple = SerializeVec;
+    type SerializeTupleStruct = SerializeVec;
+    type SerializeTupleVariant = SerializeTupleVariant;
+    type SerializeMap = SerializeMap;
+    type SerializeStruct = SerializeStruct;
+    type SerializeStructVariant = SerializeStructVariant;
+
+    fn serialize_bool(self, v: bool) -> Result<AmfObject> {
+        Ok(AmfObject::Boolean(v))
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<AmfObject> {
+        self.serialize_f64(v as f64)
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<AmfObject> {
+        self.serialize_f64(v as f64)
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<AmfObject> {
+        self.serialize_f64(v as f64)
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<AmfObject> {
+        self.serialize_f64(v as f64)
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<AmfObject> {
+        self.serialize_f64(v as f64)
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<AmfObject> {
+        self.serialize_f64(v as f64)
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<AmfObject> {
+        self.serialize_f64(v as f64)
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<AmfObject> {
+        self.serialize_f64(v as f64)
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<AmfObject> {
+        self.serialize_f64(v as f64)
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<AmfObject> {
+        Ok(AmfObject::Number(v))
+    }
+
+    fn serialize_char(self, v: char) -> Result<AmfObject> {
+        Ok(AmfObject::String(v.to_string()))
+    }
+
+    fn serialize_str(self, v: &str) -> Result<AmfObject> {
+        Ok(AmfObject::String(v.to_string()))
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<AmfObject> {
+        Ok(AmfObject::StrictArray(
+            v.iter().map(|&b| AmfObject::Number(b as f64)).collect(),
+        ))
+    }
+
+    fn serialize_none(self) -> Result<AmfObject> {
+        Ok(AmfObject::Null)
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<AmfObject> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<AmfObject> {
+        Ok(AmfObject::Null)
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<AmfObject> {
+        Ok(AmfObject::Null)
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+    ) -> Result<AmfObject> {
+        Ok(AmfObject::String(variant.to_string()))
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<AmfObject> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result<AmfObject> {
+        let mut map = AmfObjectMap::new();
+        map.insert(variant, to_amf_object(value)?);
+        Ok(AmfObject::Object(map))
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> Result<SerializeVec> {
+        Ok(SerializeVec {
+            vec: Vec::with_capacity(len.unwrap_or(0)),
+        })
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<SerializeVec> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_struct(self, _name: &'static str, len: usize) -> Result<SerializeVec> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<SerializeTupleVariant> {
+        Ok(SerializeTupleVariant {
+            variant,
+            vec: Vec::with_capacity(len),
+        })
+    }
+
+    fn serialize_map(self, len: Option<usize>) -> Result<SerializeMap> {
+        Ok(SerializeMap {
+            entries: Vec::with_capacity(len.unwrap_or(0)),
+            next_key: None,
+        })
+    }
+
+    fn serialize_struct(self, _name: &'static str, _len: usize) -> Result<SerializeStruct> {
+        Ok(SerializeStruct {
+            map: AmfObjectMap::new(),
+        })
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        _len: usize,
+    ) -> Result<SerializeStructVariant> {
+        Ok(SerializeStructVariant {
+            variant,
+            map: AmfObjectMap::new(),
+        })
+    }
+}
+
+pub struct SerializeVec {
+    vec: Vec<AmfObject>,
+}
+
+impl ser::SerializeSeq for SerializeVec {
+    type Ok = AmfObject;
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        self.vec.push(to_amf_object(value)?);
+        Ok(())
+    }
+
+    fn end(self) -> Result<AmfObject> {
+        Ok(AmfObject::StrictArray(self.vec))
+    }
+}
+
+impl ser::SerializeTuple for SerializeVec {
+    type Ok = AmfObject;
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        ser::SerializeSeq::serialize_element(self, value)
+    }
+
+    fn end(self) -> Result<AmfObject> {
+        ser::SerializeSeq::end(self)
+    }
+}
+
+impl ser::SerializeTupleStruct for SerializeVec {
+    type Ok = AmfObject;
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        ser::SerializeSeq::serialize_element(self, value)
+    }
+
+    fn end(self) -> Result<AmfObject> {
+        ser::SerializeSeq::end(self)
+    }
+}
+
+pub struct SerializeTupleVariant {
+    variant: &'static str,
+    vec: Vec<AmfObject>,
+}
+
+impl ser::SerializeTupleVariant for SerializeTupleVariant {
+    type Ok = AmfObject;
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        self.vec.push(to_amf_object(value)?);
+        Ok(())
+    }
+
+    fn end(self) -> Result<AmfObject> {
+        let mut map = AmfObjectMap::new();
+        map.insert(self.variant, AmfObject::StrictArray(self.vec));
+        Ok(AmfObject::Object(map))
+    }
+}
+
+pub struct SerializeMap {
+    entries: Vec<(String, AmfObject)>,
+    next_key: Option<String>,
+}
+
+impl ser::SerializeMap for SerializeMap {
+    type Ok = AmfObject;
+    type Error = Error;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<()> {
+        self.next_key = Some(match to_amf_object(key)? {
+            AmfObject::String(s) => s,
+            AmfObject::Number(x) => x.to_string(),
+            _ => return Err(ser::Error::custom("AMF keys must be strings or numbers")),
+        });
+        Ok(())
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        let key = self
+            .next_key
+            .take()
+            .ok_or_else(|| <Error as ser::Error>::custom("serialize_value called before key"))?;
+        self.entries.push((key, to_amf_object(value)?));
+        Ok(())
+    }
+
+    fn end(self) -> Result<AmfObject> {
+        Ok(AmfObject::EcmaArray(self.entries))
+    }
+}
+
+pub struct SerializeStruct {
+    map: AmfObjectMap,
+}
+
+impl ser::SerializeStruct for SerializeStruct {
+    type Ok = AmfObject;
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<()> {
+        self.map.insert(key, to_amf_object(value)?);
+        Ok(())
+    }
+
+    fn end(self) -> Result<AmfObject> {
+        Ok(AmfObject::Object(self.map))
+    }
+}
+
+pub struct SerializeStructVariant {
+    variant: &'static str,
+    map: AmfObjectMap,
+}
+
+impl ser::SerializeStructVariant for SerializeStructVariant {
+    type Ok = AmfObject;
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<()> {
+        self.map.insert(key, to_amf_object(value)?);
+        Ok(())
+    }
+
+    fn end(self) -> Result<AmfObject> {
+        let mut map = AmfObjectMap::new();
+        map.insert(self.variant, AmfObject::Object(self.map));
+        Ok(AmfObject::Object(map))
+    }
+}
+
+pub struct Deserializer {
+    value: AmfObject,
+}
+
+impl Deserializer {
+    pub fn new(value: AmfObject) -> Self {
+        Self { value }
+    }
+
+    fn unexpected(&self) -> Unexpected<'_> {
+        match self.value {
+            AmfObject::Number(x) => Unexpected::Float(x),
+            AmfObject::Boolean(b) => Unexpected::Bool(b),
+            AmfObject::String(ref s)
+            | AmfObject::LongString(ref s)
+            | AmfObject::XmlDocument(ref s) => Unexpected::Str(s),
+            AmfObject::Null | AmfObject::Undefined | AmfObject::Unsupported => Unexpected::Unit,
+            AmfObject::Object(_) | AmfObject::TypedObject(_, _) | AmfObject::EcmaArray(_) => {
+                Unexpected::Map
+            }
+            AmfObject::StrictArray(_) => Unexpected::Seq,
+            AmfObject::Date(_) => Unexpected::Other("AMF date"),
+            AmfObject::Reference(_) => Unexpected::Other("AMF reference"),
+            AmfObject::Recordset => Unexpected::Other("AMF recordset"),
+            AmfObject::AvmPlus(_) => Unexpected::Other("AMF-3 value"),
+        }
+    }
+
+    fn deserialize_integer<'de, V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        match self.value {
+            AmfObject::Number(x) if x.fract() == 0.0 && x >= 0.0 && x <= u64::MAX as f64 => {
+                visitor.visit_u64(x as u64)
+            }
+            AmfObject::Number(x) if x.fract() == 0.0 && x >= i64::MIN as f64 && x < 0.0 => {
+                visitor.visit_i64(x as i64)
+            }
+            _ => de::Deserializer::deserialize_any(self, visitor),
+        }
+    }
+}
+
+impl<'de> IntoDeserializer<'de, Error> for AmfObject {
+    type Deserializer = Deserializer;
+
+    fn into_deserializer(self) -> Deserializer {
+        Deserializer::new(self)
+    }
+}
+
+fn visit_map<'de, V, I>(visitor: V, entries: I) -> Result<V::Value>
+where
+    V: Visitor<'de>,
+    I: Iterator<Item = (String, AmfObject)>,
+{
+    let mut map = MapDeserializer::new(entries);
+    let value = visitor.visit_map(&mut map)?;
+    map.end()?;
+    Ok(value)
+}
+
+macro_rules! deserialize_integer {
+    ($($method:ident)*) => {
+        $(
+            fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+                self.deserialize_integer(visitor)
+            }
+        )*
+    };
+}
+
+impl<'de> de::Deserializer<'de> for Deserializer {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        match self.value {
+            AmfObject::Number(x) => visitor.visit_f64(x),
+            AmfObject::Boolean(b) => visitor.visit_bool(b),
+            AmfObject::String(s) | AmfObject::LongString(s) | AmfObject::XmlDocument(s) => {
+                visitor.visit_string(s)
+            }
+            AmfObject::Null | AmfObject::Undefined | AmfObject::Unsupported => visitor.visit_unit(),
+            AmfObject::Object(map) | AmfObject::TypedObject(_, map) => {
+                visit_map(visitor, map.into_iter())
+            }
+            AmfObject::EcmaArray(entries) => visit_map(visitor, entries.into_iter()),
+            AmfObject::StrictArray(values) => {
+                let mut seq = SeqDeserializer::new(values.into_iter());
+                let value = visitor.visit_seq(&mut seq)?;
+                seq.end()?;
+                Ok(value)
+            }
+            // Dates are exposed as milliseconds since the Unix epoch.
+            AmfObject::Date((milliseconds, _)) => visitor.visit_f64(milliseconds),
+            AmfObject::AvmPlus(Amf3Value::ByteArray(bytes)) => visitor.visit_byte_buf(bytes),
+            AmfObject::AvmPlus(value) => match AmfObject::from(value) {
+                AmfObject::AvmPlus(value) => Err(de::Error::invalid_type(
+                    Deserializer::new(AmfObject::AvmPlus(value)).unexpected(),
+                    &visitor,
+                )),
+                value => Deserializer::new(value).deserialize_any(visitor),
+            },
+            AmfObject::Reference(_) | AmfObject::Recordset => {
+                Err(de::Error::invalid_type(self.unexpected(), &visitor))
+            }
+        }
+    }
+
+    deserialize_integer! {
+        deserialize_i8 deserialize_i16 deserialize_i32 deserialize_i64
+        deserialize_u8 deserialize_u16 deserialize_u32 deserialize_u64
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        match self.value {
+            AmfObject::Null | AmfObject::Undefined => visitor.visit_none(),
+            _ => visitor.visit_some(self),
+        }
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value> {
+        match self.value {
+            AmfObject::String(variant) => visitor.visit_enum(variant.into_deserializer()),
+            AmfObject::Object(map) => {
+                let mut entries = map.into_iter();
+                match (entries.next(), entries.next()) {
+                    (Some((variant, value)), None) => {
+                        visitor.visit_enum(EnumDeserializer { variant, value })
+                    }
+                    _ => Err(de::Error::invalid_value(
+                        Unexpected::Map,
+                        &"an object with a single property",
+                    )),
+                }
+            }
+            _ => Err(de::Error::invalid_type(self.unexpected(), &"enum")),
+        }
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool f32 f64 char str string bytes byte_buf unit unit_struct seq tuple tuple_struct map
+        struct identifier ignored_any
+    }
+}
+
+struct EnumDeserializer {
+    variant: String,
+    value: AmfObject,
+}
+
+impl<'de> EnumAccess<'de> for EnumDeserializer {
+    type Error = Error;
+    type Variant = Deserializer;
+
+    fn variant_seed<V: DeserializeSeed<'de>>(self, seed: V) -> Result<(V::Value, Deserializer)> {
+        let variant = seed.deserialize(self.variant.into_deserializer())?;
+        Ok((variant, Deserializer::new(self.value)))
+    }
+}
+
+impl<'de> VariantAccess<'de> for Deserializer {
+    type Error = Error;
+
+    fn unit_variant(self) -> Result<()> {
+        de::Deserialize::deserialize(self)
+    }
+
+    fn newtype_variant_seed<T: DeserializeSeed<'de>>(self, seed: T) -> Result<T::Value> {
+        seed.deserialize(self)
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(self, _len: usize, visitor: V) -> Result<V::Value> {
+        de::Deserializer::deserialize_seq(self, visitor)
+    }
+
+    fn struct_variant<V: Visitor<'de>>(
+        self,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value> {
+        de::Deserializer::deserialize_map(self, visitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::amf::AmfObjectBuilder;
+    use serde::{Deserialize, Serialize};
+    use std::collections::BTreeMap;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    struct Info {
+        level: String,
+        code: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        description: Option<String>,
+        object_encoding: u8,
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    enum Kind {
+        Live,
+        Sized(u32),
+        Point { x: i32, y: i32 },
+    }
+
+    #[test]
+    fn serde_struct() {
+        let info = Info {
+            level: String::from("status"),
+            code: String::from("NetConnection.Connect.Success"),
+            description: None,
+            object_encoding: 3,
+        };
+        let object = to_amf_object(&info).unwrap();
+        assert_eq!(
+            object,
+            AmfObjectBuilder::new()
+                .property("level", "status")
+                .property("code", "NetConnection.Connect.Success")
+                .property("objectEncoding", 3.0)
+                .build()
+        );
+        assert_eq!(from_amf_object::<Info>(object).unwrap(), info);
+    }
+
+    #[test]
+    fn serde_struct_from_ecma_array() {
+        let object = AmfObject::EcmaArray(vec![
+            (String::from("code"), AmfObject::from("A")),
+            (String::from("level"), AmfObject::from("error")),
+            (String::from("description"), AmfObject::Null),
+            (String::from("objectEncoding"), AmfObject::Number(0.0)),
+            (String::from("ignored"), AmfObject::Boolean(true)),
+        ]);
+        let info: Info = from_amf_object(object).unwrap();
+        assert_eq!(info.level, "error");
+        assert_eq!(info.description, None);
+    }
+
+    #[test]
+    fn serde_type_mismatch() {
+        let object = AmfObjectBuilder::new()
+            .property("level", "status")
+            .property("code", "A")
+            .property("objectEncoding", 1.5)
+            .build();
+        let e = from_amf_object::<Info>(object).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "invalid type: floating point `1.5`, expected u8"
+        );
+        let e = from_amf_object::<Info>(AmfObject::from("jizz")).unwrap_err();
+        assert!(e.to_string().starts_with("invalid type: string \"jizz\""));
+    }
+
+    #[test]
+    fn serde_enum_seq_map() {
+        for kind in [Kind::Live, Kind::Sized(7122), Kind::Point { x: -1, y: 2 }] {
+            let object = to_amf_object(&kind).unwrap();
+            assert_eq!(from_amf_object::<Kind>(object).unwrap(), kind);
+        }
+        let values = vec![Some(1.5), None];
+        let object = to_amf_object(&values).unwrap();
+        assert_eq!(
+            object,
+            AmfObject::StrictArray(vec![AmfObject::Number(1.5), AmfObject::Null])
+        );
+        assert_eq!(from_amf_object::<Vec<Option<f64>>>(object).unwrap(), values);
+        let map: BTreeMap<String, bool> = [(String::from("a"), true)].iter().cloned().collect();
+        let object = to_amf_object(&map).unwrap();
+        assert_eq!(
+            object,
+            AmfObject::EcmaArray(vec![(String::from("a"), AmfObject::Boolean(true))])
+        );
+        assert_eq!(
+            from_amf_object::<BTreeMap<String, bool>>(object).unwrap(),
+            map
+        );
+    }
+}
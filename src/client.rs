@@ -0,0 +1,650 @@
+//! RTMP client publishing or playing a stream on a server.
+
+use std::collections::VecDeque;
+use std::io::{self, Cursor};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+use crate::amf::{decode_amf_messages, encode_amf_messages, AmfObject};
+use crate::amf_serde::from_amf_object;
+use crate::command::{Command, CommandMessage};
+use crate::constant::*;
+use crate::error::{Error, Result};
+use crate::flv::FlvTagHeader;
+use crate::object::{ConnectObject, StatusInfo};
+use crate::stream::{chunk_stream_id, Message, RtmpMessageStream};
+use crate::utils::{read_buffer, read_u16, read_u32, read_u8};
+
+/// A message received from the server.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ClientMessage {
+    Audio {
+        timestamp: u32,
+        payload: Vec<u8>,
+    },
+    Video {
+        timestamp: u32,
+        payload: Vec<u8>,
+    },
+    Data {
+        timestamp: u32,
+        values: Vec<AmfObject>,
+    },
+    /// A command which is not the response to a pending call, such as `onStatus`.
+    Command(Box<CommandMessage>),
+}
+
+/// A client connection to an application of an RTMP server.
+#[derive(Debug)]
+pub struct RtmpConnection {
+    message_stream: RtmpMessageStream,
+    transaction_id: f64,
+    /// Message stream created for publishing or playing.
+    stream_id: u32,
+    /// Messages of an aggregate message which have not been read yet.
+    pending: VecDeque<ClientMessage>,
+}
+
+/// Audio, video or data message of the given type, or `None` for other types.
+fn media_message(
+    message_type_id: u8,
+    timestamp: u32,
+    payload: Vec<u8>,
+) -> Result<Option<ClientMessage>> {
+    let message = match message_type_id {
+        RTMP_AUDIO_MESSAGE => ClientMessage::Audio { timestamp, payload },
+        RTMP_VIDEO_MESSAGE => ClientMessage::Video { timestamp, payload },
+        RTMP_DATA_MESSAGE_AMF0 | RTMP_DATA_MESSAGE_AMF3 => {
+            let mut cursor = Cursor::new(payload);
+            // AMF-3 data messages start with a format selector byte.
+            if message_type_id == RTMP_DATA_MESSAGE_AMF3 {
+                cursor.set_position(1);
+            }
+            let values = decode_amf_messages(&mut cursor)?;
+            ClientMessage::Data { timestamp, values }
+        }
+        _ => return Ok(None),
+    };
+    Ok(Some(message))
+}
+
+/// Splits an aggregate message into its audio, video and data messages. Its body is a sequence
+/// of FLV tags, whose timestamps are shifted so that the first one is the timestamp of the
+/// aggregate message.
+fn split_aggregate(timestamp: u32, payload: &[u8]) -> Result<Vec<ClientMessage>> {
+    let mut cursor = Cursor::new(payload);
+    let mut messages = vec![];
+    let mut offset = None;
+    while (cursor.position() as usize) < payload.len() {
+        let header = FlvTagHeader::read(&mut cursor)?;
+        let data = read_buffer(&mut cursor, header.data_size).map_err(Error::Io)?;
+        // Each tag is followed by its size, as PreviousTagSize.
+        read_u32(&mut cursor).map_err(Error::Io)?;
+        let offset = *offset.get_or_insert(timestamp.wrapping_sub(header.timestamp));
+        let timestamp = header.timestamp.wrapping_add(offset);
+        messages.extend(media_message(header.tag_type, timestamp, data)?);
+    }
+    Ok(messages)
+}
+
+/// Information object among the arguments of a response, if any.
+fn status_info(arguments: &[AmfObject]) -> Option<StatusInfo> {
+    arguments
+        .iter()
+        .find_map(|argument| from_amf_object(argument.clone()).ok())
+}
+
+impl RtmpConnection {
+    /// Connects to the application `app` of the server at `address`, given as `host:port`.
+    pub fn connect(address: &str, app: &str) -> Result<Self> {
+        let stream = TcpStream::connect(address).map_err(Error::Io)?;
+        Self::open(stream, address, app)
+    }
+
+    /// Connects like `connect`, but fails with a timeout error if establishing the connection
+    /// or any read or write takes longer than `timeout`. The timeouts apply until changed with
+    /// `set_read_timeout` and `set_write_timeout`.
+    pub fn connect_timeout(address: &str, app: &str, timeout: Duration) -> Result<Self> {
+        let mut last_error = None;
+        for socket_address in address.to_socket_addrs().map_err(Error::Io)? {
+            match TcpStream::connect_timeout(&socket_address, timeout) {
+                Ok(stream) => {
+                    stream.set_read_timeout(Some(timeout)).map_err(Error::Io)?;
+                    stream.set_write_timeout(Some(timeout)).map_err(Error::Io)?;
+                    return Self::open(stream, address, app);
+                }
+                Err(e) => last_error = Some(e),
+            }
+        }
+        Err(Error::Io(last_error.unwrap_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, "Address resolves to nothing")
+        })))
+    }
+
+    fn open(stream: TcpStream, address: &str, app: &str) -> Result<Self> {
+        stream.set_nodelay(true).map_err(Error::Io)?;
+        let mut connection = Self {
+            message_stream: RtmpMessageStream::new(stream),
+            transaction_id: 0_f64,
+            stream_id: RTMP_NET_CONNECTION_STREAM_ID,
+            pending: VecDeque::new(),
+        };
+        connection.message_stream.handle_client_handshake()?;
+        connection
+            .message_stream
+            .set_chunk_size(RTMP_DEFAULT_CHUNK_SIZE)?;
+        connection.call(Command::Connect(ConnectObject {
+            app: String::from(app),
+            flash_ver: Some(String::from("FMLE/3.0 (compatible; rtmp)")),
+            tc_url: Some(format!("rtmp://{}/{}", address, app)),
+            object_encoding: Some(RTMP_OBJECT_ENCODING_AMF0),
+            // Media is handed over untouched, so every Enhanced RTMP codec is accepted.
+            four_cc_list: Some(vec![String::from("*")]),
+            ..ConnectObject::default()
+        }))?;
+        Ok(connection)
+    }
+
+    /// Creates a stream and publishes it live as `name`.
+    pub fn publish(&mut self, name: &str) -> Result<()> {
+        self.create_stream()?;
+        self.send_command(
+            self.stream_id,
+            0_f64,
+            Command::Publish {
+                name: String::from(name),
+                publishing_type: Some(String::from("live")),
+            },
+        )?;
+        self.wait_for_status("NetStream.Publish.Start")
+    }
+
+    /// Creates a stream and plays `name` from it. Media follows as messages read with `read`.
+    pub fn play(&mut self, name: &str) -> Result<()> {
+        self.create_stream()?;
+        self.send_command(
+            self.stream_id,
+            0_f64,
+            Command::Play {
+                name: String::from(name),
+                start: None,
+                duration: None,
+                reset: None,
+            },
+        )?;
+        self.wait_for_status("NetStream.Play.Start")
+    }
+
+    pub fn send_audio(&mut self, timestamp: u32, payload: &[u8]) -> Result<()> {
+        self.send_message(RTMP_AUDIO_MESSAGE, timestamp, payload)
+    }
+
+    pub fn send_video(&mut self, timestamp: u32, payload: &[u8]) -> Result<()> {
+        self.send_message(RTMP_VIDEO_MESSAGE, timestamp, payload)
+    }
+
+    /// Sends an AMF-0 data message, such as `@setDataFrame` with the metadata of the stream.
+    pub fn send_data(&mut self, timestamp: u32, values: &[AmfObject]) -> Result<()> {
+        self.send_message(
+            RTMP_DATA_MESSAGE_AMF0,
+            timestamp,
+            &encode_amf_messages(values)?,
+        )
+    }
+
+    /// Sends an audio, video or data message whose payload is already encoded.
+    pub fn send_message(
+        &mut self,
+        message_type_id: u8,
+        timestamp: u32,
+        payload: &[u8],
+    ) -> Result<()> {
+        self.message_stream.send_message(
+            chunk_stream_id(message_type_id),
+            self.stream_id,
+            timestamp,
+            message_type_id,
+            payload,
+        )
+    }
+
+    /// Sets the timeout of reads, shared with decoupled connections. `None` blocks indefinitely.
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<()> {
+        self.message_stream
+            .set_read_timeout(timeout)
+            .map_err(Error::Io)
+    }
+
+    /// Sets the timeout of writes, shared with decoupled connections. `None` blocks indefinitely.
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> Result<()> {
+        self.message_stream
+            .set_write_timeout(timeout)
+            .map_err(Error::Io)
+    }
+
+    /// Returns a connection sharing the socket with this one, so that one thread can send
+    /// messages while another one reads.
+    pub fn decouple(&self) -> Self {
+        Self {
+            message_stream: self.message_stream.decouple(),
+            transaction_id: self.transaction_id,
+            stream_id: self.stream_id,
+            pending: VecDeque::new(),
+        }
+    }
+
+    /// Deletes the stream, which unpublishes it if this connection published it, and closes the
+    /// connection.
+    pub fn close(mut self) -> Result<()> {
+        let result = self.send_command(
+            RTMP_NET_CONNECTION_STREAM_ID,
+            0_f64,
+            Command::DeleteStream {
+                stream_id: f64::from(self.stream_id),
+            },
+        );
+        // Shutting the socket down also ends reads blocked on decoupled connections.
+        self.message_stream
+            .shutdown()
+            .map_err(Error::Io)
+            .and(result)
+    }
+
+    /// Reads the next audio, video, data or command message, handling protocol control messages
+    /// in the meantime. Aggregate messages are split into the messages they carry, and messages
+    /// of other types are skipped.
+    pub fn read(&mut self) -> Result<ClientMessage> {
+        loop {
+            if let Some(message) = self.pending.pop_front() {
+                return Ok(message);
+            }
+            let Message { header, message } = match self.message_stream.read_message()? {
+                Some(message) => message,
+                None => continue,
+            };
+            let timestamp = header.timestamp;
+            let mut cursor = Cursor::new(message);
+            match header.message_type_id {
+                RTMP_AUDIO_MESSAGE
+                | RTMP_VIDEO_MESSAGE
+                | RTMP_DATA_MESSAGE_AMF0
+                | RTMP_DATA_MESSAGE_AMF3 => {
+                    if let Some(message) =
+                        media_message(header.message_type_id, timestamp, cursor.into_inner())?
+                    {
+                        return Ok(message);
+                    }
+                }
+                RTMP_AGGREGATE_MESSAGE => {
+                    self.pending = split_aggregate(timestamp, cursor.get_ref())?.into();
+                }
+                RTMP_COMMAND_MESSAGE_AMF0 | RTMP_COMMAND_MESSAGE_AMF3 => {
+                    if header.message_type_id == RTMP_COMMAND_MESSAGE_AMF3 {
+                        cursor.set_position(1);
+                    }
+                    let values = decode_amf_messages(&mut cursor)?;
+                    return Ok(ClientMessage::Command(Box::new(CommandMessage::parse(
+                        values,
+                    )?)));
+                }
+                RTMP_SET_CHUNK_SIZE => {
+                    let chunk_size = read_u32(&mut cursor).map_err(Error::Io)?;
+                    self.message_stream.max_chunk_size_read = (chunk_size & 0x7FFFFFFF) as usize;
+                }
+                RTMP_ABORT_MESSAGE => {
+                    let chunk_stream_id = read_u32(&mut cursor).map_err(Error::Io)?;
+                    self.message_stream
+                        .channels
+                        .remove(&(chunk_stream_id as u16));
+                }
+                RTMP_ACKNOWLEDGEMENT => {
+                    let ack = read_u32(&mut cursor).map_err(Error::Io)?;
+                    self.message_stream.handle_acknowledgement(ack);
+                }
+                RTMP_WINDOW_ACK_SIZE => {
+                    self.message_stream.window_ack_size_read =
+                        read_u32(&mut cursor).map_err(Error::Io)?;
+                }
+                RTMP_SET_PEER_BANDWIDTH => {
+                    let window_size = read_u32(&mut cursor).map_err(Error::Io)?;
+                    let limit_type = read_u8(&mut cursor).map_err(Error::Io)?;
+                    self.message_stream
+                        .handle_set_peer_bandwidth(window_size, limit_type)?;
+                }
+                RTMP_USER_CONTROL_MESSAGE => {
+                    let event_type = read_u16(&mut cursor).map_err(Error::Io)?;
+                    if event_type == RTMP_USER_CONTROL_PING_REQUEST {
+                        let mut buffer = Vec::from(RTMP_USER_CONTROL_PING_RESPONSE.to_be_bytes());
+                        buffer.extend_from_slice(&cursor.get_ref()[2..]);
+                        self.message_stream.send_message(
+                            RTMP_PROTOCOL_CONTROL_CHUNK_STREAM_ID,
+                            RTMP_PROTOCOL_CONTROL_MESSAGE_STREAM_ID,
+                            0,
+                            RTMP_USER_CONTROL_MESSAGE,
+                            &buffer,
+                        )?;
+                    }
+                }
+                // Messages of other types, such as shared objects, are not supported.
+                _ => {}
+            }
+        }
+    }
+
+    fn create_stream(&mut self) -> Result<()> {
+        self.stream_id = match self.call(Command::CreateStream)?.first() {
+            Some(&AmfObject::Number(stream_id)) => stream_id as u32,
+            _ => return Err(Error::UnexpectedAmfObjectType),
+        };
+        Ok(())
+    }
+
+    fn send_command(
+        &mut self,
+        message_stream_id: u32,
+        transaction_id: f64,
+        command: Command,
+    ) -> Result<()> {
+        let values = CommandMessage::new(transaction_id, command).serialize()?;
+        self.message_stream.send_message(
+            RTMP_COMMAND_CHUNK_STREAM_ID,
+            message_stream_id,
+            0,
+            RTMP_COMMAND_MESSAGE_AMF0,
+            &encode_amf_messages(&values)?,
+        )
+    }
+
+    /// Sends a command on the connection and returns the arguments of its `_result`. Messages
+    /// received before the response are dropped.
+    fn call(&mut self, command: Command) -> Result<Vec<AmfObject>> {
+        self.transaction_id += 1_f64;
+        let transaction_id = self.transaction_id;
+        self.send_command(RTMP_NET_CONNECTION_STREAM_ID, transaction_id, command)?;
+        loop {
+            let message = match self.read()? {
+                ClientMessage::Command(message) => *message,
+                _ => continue,
+            };
+            let (name, arguments) = match message {
+                CommandMessage {
+                    transaction_id: id,
+                    command:
+                        Command::Call {
+                            name, arguments, ..
+                        },
+                } if id == transaction_id => (name, arguments),
+                _ => continue,
+            };
+            match name.as_str() {
+                "_result" => return Ok(arguments),
+                "_error" => {
+                    let code = status_info(&arguments).map(|info| info.code);
+                    return Err(Error::CommandFailed(code.unwrap_or(name)));
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Waits for `onStatus` with `code`, failing on an error status or a denial.
+    fn wait_for_status(&mut self, code: &str) -> Result<()> {
+        loop {
+            let message = match self.read()? {
+                ClientMessage::Command(message) => *message,
+                _ => continue,
+            };
+            let arguments = match message.command {
+                Command::Call {
+                    name, arguments, ..
+                } if name == "onStatus" => arguments,
+                _ => continue,
+            };
+            let info = match status_info(&arguments) {
+                Some(info) => info,
+                None => continue,
+            };
+            if info.code == code {
+                return Ok(());
+            }
+            if info.level == "error" || info.code.ends_with(".Denied") {
+                return Err(Error::CommandFailed(info.code));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::flv::FlvReader;
+    use crate::server::{RtmpMediaStream, RtmpServer};
+    use std::collections::HashMap;
+    use std::fs::{self, File};
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    /// Starts a server on a free port and returns its address.
+    pub fn spawn_server(config: Config) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let media_streams = Arc::new(Mutex::new(HashMap::<String, RtmpMediaStream>::new()));
+        let config = Arc::new(config);
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let media_streams = Arc::clone(&media_streams);
+                let config = Arc::clone(&config);
+                thread::spawn(move || RtmpServer::new(stream, media_streams, config).serve());
+            }
+        });
+        address
+    }
+
+    pub fn read_video(connection: &mut RtmpConnection) -> (u32, Vec<u8>) {
+        loop {
+            if let ClientMessage::Video { timestamp, payload } = connection.read().unwrap() {
+                return (timestamp, payload);
+            }
+        }
+    }
+
+    #[test]
+    fn test_split_aggregate() {
+        let mut payload = vec![];
+        for &(tag_type, timestamp, ref data) in &[
+            (FLV_TAG_TYPE_AUDIO, 1000, vec![0xAF, 0x1]),
+            (FLV_TAG_TYPE_VIDEO, 1040, vec![0x27, 0x1]),
+            (0x7, 1040, vec![0x0]),
+        ] {
+            let header = FlvTagHeader {
+                tag_type,
+                data_size: data.len(),
+                timestamp,
+            };
+            payload.extend_from_slice(&header.encode());
+            payload.extend_from_slice(data);
+            payload.extend_from_slice(&(11 + data.len() as u32).to_be_bytes());
+        }
+        // Timestamps are relative to the aggregate message, and unknown tags are skipped.
+        assert_eq!(
+            split_aggregate(5000, &payload).unwrap(),
+            [
+                ClientMessage::Audio {
+                    timestamp: 5000,
+                    payload: vec![0xAF, 0x1]
+                },
+                ClientMessage::Video {
+                    timestamp: 5040,
+                    payload: vec![0x27, 0x1]
+                },
+            ]
+        );
+        assert!(split_aggregate(5000, &payload[..payload.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn test_invalid_commands() {
+        let address = spawn_server(Config::default());
+        let mut connection = RtmpConnection::connect(&address, "live").unwrap();
+        let call = |name: &str| Command::Call {
+            name: String::from(name),
+            command_object: AmfObject::Null,
+            arguments: vec![],
+        };
+        // Responses from the client get no `_error`, which would answer the next call.
+        let transaction_id = connection.transaction_id + 1_f64;
+        connection
+            .send_command(
+                RTMP_NET_CONNECTION_STREAM_ID,
+                transaction_id,
+                call("_result"),
+            )
+            .unwrap();
+        connection.create_stream().unwrap();
+        // Known commands with invalid arguments are answered with `_error`, or with an error
+        // `onStatus` without a transaction ID.
+        assert!(matches!(
+            connection.call(call("seek")),
+            Err(Error::CommandFailed(ref code)) if code == "NetConnection.Call.Failed"
+        ));
+        connection
+            .send_command(connection.stream_id, 0_f64, call("publish"))
+            .unwrap();
+        assert!(matches!(
+            connection.wait_for_status("NetStream.Publish.Start"),
+            Err(Error::CommandFailed(ref code)) if code == "NetStream.Failed"
+        ));
+    }
+
+    #[test]
+    fn test_connect_timeout() {
+        // The server accepts the connection but never answers the handshake.
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let timeout = Duration::from_millis(100);
+        assert!(RtmpConnection::connect_timeout(&address, "live", timeout).is_err());
+    }
+
+    #[test]
+    fn test_publish_and_play() {
+        let address = spawn_server(Config::default());
+        let mut publisher = RtmpConnection::connect(&address, "live").unwrap();
+        publisher.publish("test").unwrap();
+        // Sorenson H.263 keyframe, which is relayed without being parsed.
+        let keyframe = vec![0x12, 0x0, 0x0, 0x84];
+        publisher.send_video(0, &keyframe).unwrap();
+
+        let mut player = RtmpConnection::connect(&address, "live").unwrap();
+        player.play("test").unwrap();
+        assert_eq!(read_video(&mut player), (0, keyframe.clone()));
+
+        // Large enough to be split into several chunks.
+        let frame: Vec<_> = [0x22].iter().copied().chain(vec![0x7; 10000]).collect();
+        publisher.send_video(40, &frame).unwrap();
+        assert_eq!(read_video(&mut player), (40, frame));
+
+        let mut denied = RtmpConnection::connect(&address, "live").unwrap();
+        match denied.publish("test") {
+            Err(Error::CommandFailed(code)) => assert_eq!(code, "NetStream.Publish.Denied"),
+            result => panic!("unexpected result {:?}", result),
+        }
+
+        // Deleting the stream of a player leaves the stream published. The server closes the
+        // connection once `deleteStream` has been handled.
+        let delete_stream = |mut connection: RtmpConnection| {
+            let stream_id = f64::from(connection.stream_id);
+            connection
+                .send_command(
+                    RTMP_NET_CONNECTION_STREAM_ID,
+                    0_f64,
+                    Command::DeleteStream { stream_id },
+                )
+                .unwrap();
+            while connection.read().is_ok() {}
+        };
+        let mut other = RtmpConnection::connect(&address, "live").unwrap();
+        other.play("test").unwrap();
+        delete_stream(other);
+        player
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        publisher.send_video(80, &keyframe).unwrap();
+        assert_eq!(read_video(&mut player), (80, keyframe));
+
+        // Deleting the stream of the publisher unpublishes it.
+        delete_stream(publisher);
+        denied.publish("test").unwrap();
+    }
+
+    #[test]
+    fn test_record() {
+        let directory =
+            std::env::temp_dir().join(format!("rtmp-client-record-{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let address = spawn_server(Config {
+            record_directory: directory.clone(),
+            ..Config::default()
+        });
+        let mut publisher = RtmpConnection::connect(&address, "live").unwrap();
+        publisher.create_stream().unwrap();
+        publisher
+            .send_command(
+                publisher.stream_id,
+                0_f64,
+                Command::Publish {
+                    name: String::from("test"),
+                    publishing_type: Some(String::from("record")),
+                },
+            )
+            .unwrap();
+        publisher
+            .wait_for_status("NetStream.Publish.Start")
+            .unwrap();
+        publisher
+            .send_data(
+                0,
+                &[
+                    AmfObject::String(String::from("@setDataFrame")),
+                    AmfObject::String(String::from("onMetaData")),
+                    AmfObject::EcmaArray(vec![(String::from("width"), AmfObject::Number(640.0))]),
+                ],
+            )
+            .unwrap();
+        for timestamp in [0, 40, 80] {
+            publisher
+                .send_video(timestamp, &[0x12, 0x0, 0x0, 0x84])
+                .unwrap();
+        }
+        publisher.close().unwrap();
+
+        // The recording subscriber writes every queued message before finishing the file.
+        let path = directory.join("test.flv");
+        let mut timestamps = vec![];
+        for _ in 0..100 {
+            thread::sleep(Duration::from_millis(50));
+            let mut reader = match File::open(&path)
+                .map_err(Error::Io)
+                .and_then(FlvReader::open)
+            {
+                Ok(reader) => reader,
+                Err(_) => continue,
+            };
+            timestamps.clear();
+            while let Some(tag) = reader.read_tag().unwrap() {
+                if tag.header.tag_type == FLV_TAG_TYPE_VIDEO {
+                    timestamps.push(tag.header.timestamp);
+                }
+            }
+            if timestamps.len() == 3 {
+                let metadata = reader.read_headers().unwrap().0.unwrap();
+                assert_eq!(metadata.get("width"), Some(&AmfObject::Number(640.0)));
+                assert_eq!(metadata.get("duration"), Some(&AmfObject::Number(0.08)));
+                break;
+            }
+        }
+        fs::remove_dir_all(&directory).unwrap();
+        assert_eq!(timestamps, [0, 40, 80]);
+    }
+}
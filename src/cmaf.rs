@@ -0,0 +1,638 @@
+//! Low-latency HLS packaging of live streams as CMAF: fragmented MP4 segments made of partial
+//! segments, listed by a playlist whose reloads can wait for the next part.
+
+use std::collections::VecDeque;
+use std::convert::TryFrom;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::Duration;
+
+use crate::config::Config;
+use crate::constant::*;
+use crate::error::{Error, Result};
+use crate::media::{
+    AudioSpecificConfig, AudioTagHeader, AvcDecoderConfigurationRecord, VideoTagHeader,
+};
+use crate::mp4::{write_fragment, write_init_segment, Sample, SampleEntry, Track, TrackFragment};
+
+const VIDEO_TRACK_ID: u32 = 1;
+const AUDIO_TRACK_ID: u32 = 2;
+const VIDEO_TIMESCALE: u32 = 90000;
+/// Samples per AAC frame.
+pub const AAC_FRAME_LENGTH: u32 = 1024;
+
+/// A frame of a track, with its timestamp in milliseconds.
+#[derive(Debug)]
+pub struct Frame {
+    pub track_id: u32,
+    pub timestamp: u32,
+    pub composition_time: i32,
+    pub is_keyframe: bool,
+    pub data: Vec<u8>,
+}
+
+/// H.264 and AAC tracks of a stream, as described by its sequence headers.
+#[derive(Debug, Default)]
+pub struct CmafTracks {
+    video: Option<SampleEntry>,
+    audio: Option<SampleEntry>,
+}
+
+impl CmafTracks {
+    /// Updates the tracks from an audio or video message, or returns the frame it carries if its
+    /// track is known. Codecs other than H.264 and AAC are ignored.
+    pub fn parse(
+        &mut self,
+        message_type_id: u8,
+        timestamp: u32,
+        payload: &[u8],
+    ) -> Result<Option<Frame>> {
+        match message_type_id {
+            RTMP_VIDEO_MESSAGE => {
+                let (header, data) = VideoTagHeader::parse(payload)?;
+                if !header.codec.is_avc() {
+                    return Ok(None);
+                }
+                if header.is_sequence_header() {
+                    let sequence_parameter_set =
+                        AvcDecoderConfigurationRecord::parse(data)?.sequence_parameter_set()?;
+                    let dimension = |value: u32| {
+                        u16::try_from(value)
+                            .map_err(|_| Error::InvalidMediaData("Picture size is too large"))
+                    };
+                    self.video = Some(SampleEntry::Avc {
+                        width: dimension(sequence_parameter_set.width)?,
+                        height: dimension(sequence_parameter_set.height)?,
+                        config: data.to_vec(),
+                    });
+                } else if self.video.is_some()
+                    && (header.packet_type == Some(FLV_PACKET_TYPE_CODED_FRAMES)
+                        || header.packet_type == Some(FLV_VIDEO_PACKET_TYPE_CODED_FRAMES_X))
+                {
+                    return Ok(Some(Frame {
+                        track_id: VIDEO_TRACK_ID,
+                        timestamp,
+                        composition_time: header.composition_time,
+                        is_keyframe: header.is_keyframe(),
+                        data: data.to_vec(),
+                    }));
+                }
+            }
+            RTMP_AUDIO_MESSAGE => {
+                let (header, data) = AudioTagHeader::parse(payload)?;
+                if !header.codec.is_aac() {
+                    return Ok(None);
+                }
+                if header.is_sequence_header() {
+                    let config = AudioSpecificConfig::parse(data)?;
+                    self.audio = Some(SampleEntry::Aac {
+                        channel_count: u16::from(config.channel_configuration),
+                        sample_rate: config.sampling_frequency,
+                        config: data.to_vec(),
+                    });
+                } else if self.audio.is_some()
+                    && header.packet_type == Some(FLV_PACKET_TYPE_CODED_FRAMES)
+                {
+                    return Ok(Some(Frame {
+                        track_id: AUDIO_TRACK_ID,
+                        timestamp,
+                        composition_time: 0,
+                        is_keyframe: true,
+                        data: data.to_vec(),
+                    }));
+                }
+            }
+            _ => {}
+        }
+        Ok(None)
+    }
+
+    /// ID of the track which segments are cut on: video, or audio for streams without video.
+    pub fn main_track_id(&self) -> u32 {
+        if self.video.is_some() {
+            VIDEO_TRACK_ID
+        } else {
+            AUDIO_TRACK_ID
+        }
+    }
+
+    pub fn tracks(&self) -> Vec<Track> {
+        let mut tracks = vec![];
+        if let Some(ref sample_entry) = self.video {
+            tracks.push(Track {
+                id: VIDEO_TRACK_ID,
+                timescale: VIDEO_TIMESCALE,
+                sample_entry: sample_entry.clone(),
+            });
+        }
+        if let Some(ref sample_entry @ SampleEntry::Aac { sample_rate, .. }) = self.audio {
+            tracks.push(Track {
+                id: AUDIO_TRACK_ID,
+                timescale: sample_rate,
+                sample_entry: sample_entry.clone(),
+            });
+        }
+        tracks
+    }
+}
+
+/// Samples of consecutive frames of a track, the last of which lasts until `end`.
+pub fn samples(frames: Vec<Frame>, end: u32, track: &Track) -> Vec<Sample> {
+    let timescale = i64::from(track.timescale);
+    let next_timestamps: Vec<_> = frames
+        .iter()
+        .skip(1)
+        .map(|frame| frame.timestamp)
+        .chain(std::iter::once(end))
+        .collect();
+    frames
+        .into_iter()
+        .zip(next_timestamps)
+        .map(|(frame, next_timestamp)| {
+            let duration = match track.sample_entry {
+                SampleEntry::Avc { .. } => {
+                    i64::from(next_timestamp.wrapping_sub(frame.timestamp)) * timescale / 1000
+                }
+                SampleEntry::Aac { .. } => i64::from(AAC_FRAME_LENGTH),
+            };
+            Sample {
+                duration: duration as u32,
+                composition_time_offset: (i64::from(frame.composition_time) * timescale / 1000)
+                    as i32,
+                is_sync: frame.is_keyframe,
+                data: frame.data,
+            }
+        })
+        .collect()
+}
+
+/// A partial segment: one movie fragment with the frames of every track.
+#[derive(Debug, Clone)]
+pub struct Part {
+    /// Duration in milliseconds.
+    pub duration: u32,
+    /// Whether it starts with a keyframe.
+    pub independent: bool,
+    pub data: Arc<[u8]>,
+}
+
+#[derive(Debug)]
+struct Segment {
+    sequence: u64,
+    duration: u32,
+    parts: Vec<Part>,
+    /// Fragments of all the parts.
+    data: Arc<[u8]>,
+}
+
+/// Packages the H.264 and AAC messages of a stream into segments of about the segment duration,
+/// themselves made of parts of at most the part duration. Segments start with a keyframe, or
+/// with any audio frame for streams without video.
+#[derive(Debug)]
+pub struct CmafPackager {
+    /// Minimum duration of a segment, and maximum duration of a part, in milliseconds.
+    segment_duration: u32,
+    part_duration: u32,
+    /// Longest segment duration so far, which the target duration never drops below.
+    max_segment_duration: u32,
+    playlist_length: usize,
+    stream_tracks: CmafTracks,
+    /// Tracks described by the init segment.
+    tracks: Vec<Track>,
+    init_segment: Option<Arc<[u8]>>,
+    video: Vec<Frame>,
+    audio: Vec<Frame>,
+    fragment_sequence: u32,
+    segments: VecDeque<Segment>,
+    /// Sequence number of the segment being written and its finished parts.
+    sequence: u64,
+    parts: Vec<Part>,
+    /// Timestamps of the start of the current segment and part.
+    segment_start: Option<u32>,
+    part_start: u32,
+    /// Timestamp and duration of the last frame of the track which segments are cut on.
+    last_timestamp: u32,
+    frame_duration: u32,
+    ended: bool,
+}
+
+impl CmafPackager {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            segment_duration: config.hls_segment_duration.saturating_mul(1000),
+            part_duration: std::cmp::max(config.hls_part_duration, 1),
+            max_segment_duration: 0,
+            playlist_length: std::cmp::max(config.hls_playlist_length, 1),
+            stream_tracks: CmafTracks::default(),
+            tracks: vec![],
+            init_segment: None,
+            video: vec![],
+            audio: vec![],
+            fragment_sequence: 1,
+            segments: VecDeque::new(),
+            sequence: 0,
+            parts: vec![],
+            segment_start: None,
+            part_start: 0,
+            last_timestamp: 0,
+            frame_duration: 0,
+            ended: false,
+        }
+    }
+
+    /// Adds an audio or video message.
+    pub fn push(&mut self, message_type_id: u8, timestamp: u32, payload: &[u8]) -> Result<()> {
+        if let Some(frame) = self
+            .stream_tracks
+            .parse(message_type_id, timestamp, payload)?
+        {
+            self.push_frame(frame);
+        }
+        Ok(())
+    }
+
+    fn push_frame(&mut self, frame: Frame) {
+        let track_id = frame.track_id;
+        if track_id == self.stream_tracks.main_track_id() {
+            let timestamp = frame.timestamp;
+            if let Some(segment_start) = self.segment_start {
+                let frame_duration = timestamp.wrapping_sub(self.last_timestamp);
+                if frame.is_keyframe
+                    && timestamp.wrapping_sub(segment_start) >= self.segment_duration
+                {
+                    self.finish_part(timestamp);
+                    self.finish_segment(timestamp);
+                } else if timestamp.wrapping_sub(self.part_start) + frame_duration
+                    > self.part_duration
+                {
+                    // The next frame, if it comes as late as this one, would not fit.
+                    self.finish_part(timestamp);
+                }
+                self.frame_duration = frame_duration;
+            }
+            if self.segment_start.is_none() {
+                if !frame.is_keyframe {
+                    return;
+                }
+                self.start_segment(timestamp);
+            }
+            self.last_timestamp = timestamp;
+        } else if self.segment_start.is_none() {
+            return;
+        }
+        if track_id == VIDEO_TRACK_ID {
+            self.video.push(frame);
+        } else {
+            self.audio.push(frame);
+        }
+    }
+
+    fn start_segment(&mut self, timestamp: u32) {
+        let tracks = self.stream_tracks.tracks();
+        if self.init_segment.is_none() || tracks != self.tracks {
+            let mut init_segment = vec![];
+            write_init_segment(&mut init_segment, &tracks);
+            self.init_segment = Some(Arc::from(init_segment));
+            self.tracks = tracks;
+        }
+        self.segment_start = Some(timestamp);
+        self.part_start = timestamp;
+    }
+
+    /// Writes the pending frames, which last until `end`, as the next part.
+    fn finish_part(&mut self, end: u32) {
+        let mut video = std::mem::take(&mut self.video);
+        let mut audio = std::mem::take(&mut self.audio);
+        if video.is_empty() && audio.is_empty() {
+            return;
+        }
+        let independent = video.first().is_none_or(|frame| frame.is_keyframe);
+        // Frames of tracks which appeared after the init segment are dropped.
+        let mut track_samples = vec![];
+        for track in &self.tracks {
+            let frames = if track.id == VIDEO_TRACK_ID {
+                std::mem::take(&mut video)
+            } else {
+                std::mem::take(&mut audio)
+            };
+            let start = match frames.first() {
+                Some(frame) => frame.timestamp,
+                None => continue,
+            };
+            let base_media_decode_time = u64::from(start) * u64::from(track.timescale) / 1000;
+            track_samples.push((
+                track.id,
+                base_media_decode_time,
+                samples(frames, end, track),
+            ));
+        }
+        let fragments: Vec<_> = track_samples
+            .iter()
+            .map(
+                |(track_id, base_media_decode_time, samples)| TrackFragment {
+                    track_id: *track_id,
+                    base_media_decode_time: *base_media_decode_time,
+                    samples,
+                },
+            )
+            .collect();
+        let mut data = vec![];
+        write_fragment(&mut data, self.fragment_sequence, &fragments);
+        self.fragment_sequence = self.fragment_sequence.wrapping_add(1);
+        self.parts.push(Part {
+            duration: end.wrapping_sub(self.part_start),
+            independent,
+            data: Arc::from(data),
+        });
+        self.part_start = end;
+    }
+
+    fn finish_segment(&mut self, end: u32) {
+        let start = match self.segment_start.take() {
+            Some(start) => start,
+            None => return,
+        };
+        let parts = std::mem::take(&mut self.parts);
+        let data: Vec<u8> = parts
+            .iter()
+            .flat_map(|part| part.data.iter().copied())
+            .collect();
+        let duration = end.wrapping_sub(start);
+        self.max_segment_duration = std::cmp::max(self.max_segment_duration, duration);
+        self.segments.push_back(Segment {
+            sequence: self.sequence,
+            duration,
+            parts,
+            data: Arc::from(data),
+        });
+        self.sequence += 1;
+        while self.segments.len() > self.playlist_length {
+            self.segments.pop_front();
+        }
+    }
+
+    /// Writes the last part and segment, and ends the playlist.
+    pub fn finish(&mut self) {
+        let end = self.last_timestamp.wrapping_add(self.frame_duration);
+        if self.segment_start.is_some() {
+            self.finish_part(end);
+            self.finish_segment(end);
+        }
+        self.ended = true;
+    }
+
+    pub fn is_ended(&self) -> bool {
+        self.ended
+    }
+
+    /// Sequence number of the segment being written and index of its next part.
+    pub fn next_part(&self) -> (u64, usize) {
+        (self.sequence, self.parts.len())
+    }
+
+    /// Whether the playlist lists part `part` of segment `sequence`, or the whole segment if
+    /// `part` is `None`.
+    pub fn has_part(&self, sequence: u64, part: Option<usize>) -> bool {
+        sequence < self.sequence
+            || sequence == self.sequence && part.is_some_and(|part| part < self.parts.len())
+    }
+
+    /// Target duration of segments in milliseconds, raised to the longest segment so far when
+    /// keyframes are far apart. It never decreases, as players expect it to stay the same.
+    pub fn target_duration(&self) -> u32 {
+        std::cmp::max(self.segment_duration, self.max_segment_duration).div_ceil(1000) * 1000
+    }
+
+    /// Media playlist listing the latest segments, and the parts of those close to the live
+    /// edge, whose URIs are relative to the playlist.
+    pub fn playlist(&self) -> String {
+        let target_duration = self.target_duration();
+        let media_sequence = self
+            .segments
+            .front()
+            .map_or(self.sequence, |segment| segment.sequence);
+        let mut playlist = format!(
+            "#EXTM3U\n#EXT-X-VERSION:6\n#EXT-X-TARGETDURATION:{}\n\
+             #EXT-X-PART-INF:PART-TARGET={:.3}\n\
+             #EXT-X-SERVER-CONTROL:CAN-BLOCK-RELOAD=YES,PART-HOLD-BACK={:.3}\n\
+             #EXT-X-MEDIA-SEQUENCE:{}\n#EXT-X-MAP:URI=\"init.mp4\"\n",
+            target_duration / 1000,
+            f64::from(self.part_duration) / 1000.0,
+            f64::from(self.part_duration * 3) / 1000.0,
+            media_sequence
+        );
+        let write_parts = |playlist: &mut String, sequence: u64, parts: &[Part]| {
+            for (i, part) in parts.iter().enumerate() {
+                playlist.push_str(&format!(
+                    "#EXT-X-PART:DURATION={:.3},URI=\"{}.{}.m4s\"{}\n",
+                    f64::from(part.duration) / 1000.0,
+                    sequence,
+                    i,
+                    if part.independent {
+                        ",INDEPENDENT=YES"
+                    } else {
+                        ""
+                    }
+                ));
+            }
+        };
+        // Parts are listed for the segments of the last three target durations.
+        let current_duration: u32 = self.parts.iter().map(|part| part.duration).sum();
+        let mut age = self
+            .segments
+            .iter()
+            .map(|segment| segment.duration)
+            .sum::<u32>()
+            + current_duration;
+        for segment in &self.segments {
+            if age <= 3 * target_duration {
+                write_parts(&mut playlist, segment.sequence, &segment.parts);
+            }
+            age -= segment.duration;
+            playlist.push_str(&format!(
+                "#EXTINF:{:.3},\n{}.m4s\n",
+                f64::from(segment.duration) / 1000.0,
+                segment.sequence
+            ));
+        }
+        write_parts(&mut playlist, self.sequence, &self.parts);
+        if self.ended {
+            playlist.push_str("#EXT-X-ENDLIST\n");
+        } else {
+            playlist.push_str(&format!(
+                "#EXT-X-PRELOAD-HINT:TYPE=PART,URI=\"{}.{}.m4s\"\n",
+                self.sequence,
+                self.parts.len()
+            ));
+        }
+        playlist
+    }
+
+    pub fn init_segment(&self) -> Option<Arc<[u8]>> {
+        self.init_segment.clone()
+    }
+
+    pub fn segment(&self, sequence: u64) -> Option<Arc<[u8]>> {
+        self.segments
+            .iter()
+            .find(|segment| segment.sequence == sequence)
+            .map(|segment| Arc::clone(&segment.data))
+    }
+
+    pub fn part(&self, sequence: u64, index: usize) -> Option<Arc<[u8]>> {
+        let parts = if sequence == self.sequence {
+            &self.parts
+        } else {
+            &self
+                .segments
+                .iter()
+                .find(|segment| segment.sequence == sequence)?
+                .parts
+        };
+        parts.get(index).map(|part| Arc::clone(&part.data))
+    }
+}
+
+/// A packager shared by the subscriber feeding it and the HTTP requests waiting for its next
+/// parts, which are woken up whenever a part is finished.
+#[derive(Debug)]
+pub struct LlHlsStream {
+    packager: Mutex<CmafPackager>,
+    changed: Condvar,
+}
+
+impl LlHlsStream {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            packager: Mutex::new(CmafPackager::new(config)),
+            changed: Condvar::new(),
+        }
+    }
+
+    pub fn push(&self, message_type_id: u8, timestamp: u32, payload: &[u8]) -> Result<()> {
+        let mut packager = self.packager.lock().unwrap();
+        let next_part = packager.next_part();
+        let result = packager.push(message_type_id, timestamp, payload);
+        if packager.next_part() != next_part {
+            self.changed.notify_all();
+        }
+        result
+    }
+
+    pub fn finish(&self) {
+        self.packager.lock().unwrap().finish();
+        self.changed.notify_all();
+    }
+
+    pub fn lock(&self) -> MutexGuard<'_, CmafPackager> {
+        self.packager.lock().unwrap()
+    }
+
+    /// Waits for at most `timeout` until `ready` holds or the stream ends.
+    pub fn wait<F>(&self, timeout: Duration, mut ready: F) -> MutexGuard<'_, CmafPackager>
+    where
+        F: FnMut(&CmafPackager) -> bool,
+    {
+        let packager = self.packager.lock().unwrap();
+        self.changed
+            .wait_timeout_while(packager, timeout, |packager| {
+                !packager.is_ended() && !ready(packager)
+            })
+            .unwrap()
+            .0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::media::tests::AVC_SEQUENCE_HEADER;
+
+    #[test]
+    fn test_cmaf_packager() {
+        let config = Config {
+            hls_segment_duration: 1,
+            hls_part_duration: 300,
+            ..Config::default()
+        };
+        let mut packager = CmafPackager::new(&config);
+        packager
+            .push(RTMP_VIDEO_MESSAGE, 0, &AVC_SEQUENCE_HEADER)
+            .unwrap();
+        packager
+            .push(RTMP_AUDIO_MESSAGE, 0, &[0xAF, 0x0, 0x12, 0x10])
+            .unwrap();
+        // A keyframe every 1.2 seconds, at 10 frames per second.
+        for timestamp in (0..2000).step_by(100) {
+            let frame_type = if timestamp % 1200 == 0 { 0x17 } else { 0x27 };
+            packager
+                .push(
+                    RTMP_VIDEO_MESSAGE,
+                    timestamp,
+                    &[frame_type, 0x1, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x1, 0x65],
+                )
+                .unwrap();
+            packager
+                .push(RTMP_AUDIO_MESSAGE, timestamp, &[0xAF, 0x1, 0x21])
+                .unwrap();
+        }
+        assert!(packager.has_part(0, None));
+        assert!(packager.has_part(1, Some(1)));
+        assert!(!packager.has_part(1, Some(2)));
+        assert_eq!(packager.next_part(), (1, 2));
+        assert_eq!(
+            packager.playlist(),
+            "#EXTM3U\n#EXT-X-VERSION:6\n#EXT-X-TARGETDURATION:2\n\
+             #EXT-X-PART-INF:PART-TARGET=0.300\n\
+             #EXT-X-SERVER-CONTROL:CAN-BLOCK-RELOAD=YES,PART-HOLD-BACK=0.900\n\
+             #EXT-X-MEDIA-SEQUENCE:0\n#EXT-X-MAP:URI=\"init.mp4\"\n\
+             #EXT-X-PART:DURATION=0.300,URI=\"0.0.m4s\",INDEPENDENT=YES\n\
+             #EXT-X-PART:DURATION=0.300,URI=\"0.1.m4s\"\n\
+             #EXT-X-PART:DURATION=0.300,URI=\"0.2.m4s\"\n\
+             #EXT-X-PART:DURATION=0.300,URI=\"0.3.m4s\"\n\
+             #EXTINF:1.200,\n0.m4s\n\
+             #EXT-X-PART:DURATION=0.300,URI=\"1.0.m4s\",INDEPENDENT=YES\n\
+             #EXT-X-PART:DURATION=0.300,URI=\"1.1.m4s\"\n\
+             #EXT-X-PRELOAD-HINT:TYPE=PART,URI=\"1.2.m4s\"\n"
+        );
+        let part = packager.part(0, 1).unwrap();
+        assert_eq!(&part[4..8], b"moof");
+        let segment = packager.segment(0).unwrap();
+        assert_eq!(
+            segment.len(),
+            (0..4).map(|i| packager.part(0, i).unwrap().len()).sum()
+        );
+        assert_eq!(&packager.init_segment().unwrap()[4..8], b"ftyp");
+
+        packager.finish();
+        assert!(packager.has_part(1, None));
+        assert!(packager
+            .playlist()
+            .ends_with("#EXTINF:0.800,\n1.m4s\n#EXT-X-ENDLIST\n"));
+    }
+
+    #[test]
+    fn test_cmaf_target_duration() {
+        let config = Config {
+            hls_segment_duration: 1,
+            hls_playlist_length: 1,
+            ..Config::default()
+        };
+        let mut packager = CmafPackager::new(&config);
+        packager
+            .push(RTMP_VIDEO_MESSAGE, 0, &AVC_SEQUENCE_HEADER)
+            .unwrap();
+        for timestamp in [0, 2500, 3500, 4500] {
+            packager
+                .push(
+                    RTMP_VIDEO_MESSAGE,
+                    timestamp,
+                    &[0x17, 0x1, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x1, 0x65],
+                )
+                .unwrap();
+        }
+        // The 2.5 second segment has left the playlist, but still sets the target duration.
+        assert!(packager.playlist().contains("#EXTINF:1.000,\n2.m4s\n"));
+        assert!(!packager.playlist().contains("\n0.m4s"));
+        assert_eq!(packager.target_duration(), 3000);
+    }
+}
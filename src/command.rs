@@ -0,0 +1,512 @@
+//! Typed representation of NetConnection and NetStream command messages.
+
+use std::vec;
+
+use crate::amf::{encode_amf_messages, AmfObject};
+use crate::amf_serde::{from_amf_object, to_amf_object};
+use crate::constant::*;
+use crate::error::{Error, Result};
+use crate::object::{ConnectObject, StatusInfo};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    Connect(ConnectObject),
+    CreateStream,
+    Play {
+        name: String,
+        start: Option<f64>,
+        duration: Option<f64>,
+        reset: Option<bool>,
+    },
+    Publish {
+        name: String,
+        publishing_type: Option<String>,
+    },
+    Pause {
+        pause: bool,
+        milliseconds: f64,
+    },
+    Seek {
+        milliseconds: f64,
+    },
+    DeleteStream {
+        stream_id: f64,
+    },
+    CloseStream,
+    ReleaseStream {
+        name: String,
+    },
+    FCPublish {
+        name: String,
+    },
+    FCUnpublish {
+        name: String,
+    },
+    GetStreamLength {
+        name: String,
+    },
+    ReceiveAudio(bool),
+    ReceiveVideo(bool),
+    /// Any other command, including responses such as `_result`, `_error` and `onStatus`.
+    Call {
+        name: String,
+        command_object: AmfObject,
+        arguments: Vec<AmfObject>,
+    },
+}
+
+impl Command {
+    pub fn name(&self) -> &str {
+        match *self {
+            Command::Connect(_) => "connect",
+            Command::CreateStream => "createStream",
+            Command::Play { .. } => "play",
+            Command::Publish { .. } => "publish",
+            Command::Pause { .. } => "pause",
+            Command::Seek { .. } => "seek",
+            Command::DeleteStream { .. } => "deleteStream",
+            Command::CloseStream => "closeStream",
+            Command::ReleaseStream { .. } => "releaseStream",
+            Command::FCPublish { .. } => "FCPublish",
+            Command::FCUnpublish { .. } => "FCUnpublish",
+            Command::GetStreamLength { .. } => "getStreamLength",
+            Command::ReceiveAudio(_) => "receiveAudio",
+            Command::ReceiveVideo(_) => "receiveVideo",
+            Command::Call { ref name, .. } => name,
+        }
+    }
+}
+
+/// A command together with the transaction ID chosen by its sender.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CommandMessage {
+    pub transaction_id: f64,
+    pub command: Command,
+}
+
+/// Positional values of a command message. Missing values, `null` and `undefined` are all
+/// treated as absent so that optional trailing arguments may be left out.
+struct Arguments {
+    name: String,
+    index: usize,
+    values: vec::IntoIter<AmfObject>,
+}
+
+impl Arguments {
+    fn next(&mut self) -> Option<AmfObject> {
+        self.index += 1;
+        match self.values.next() {
+            Some(AmfObject::Null) | Some(AmfObject::Undefined) | None => None,
+            value => value,
+        }
+    }
+
+    fn invalid(&self) -> Error {
+        Error::InvalidCommandArgument(self.name.clone(), self.index)
+    }
+
+    fn optional_number(&mut self) -> Result<Option<f64>> {
+        match self.next() {
+            Some(AmfObject::Number(x)) => Ok(Some(x)),
+            None => Ok(None),
+            Some(_) => Err(self.invalid()),
+        }
+    }
+
+    fn optional_string(&mut self) -> Result<Option<String>> {
+        match self.next() {
+            Some(AmfObject::String(s)) | Some(AmfObject::LongString(s)) => Ok(Some(s)),
+            None => Ok(None),
+            Some(_) => Err(self.invalid()),
+        }
+    }
+
+    fn optional_boolean(&mut self) -> Result<Option<bool>> {
+        match self.next() {
+            Some(AmfObject::Boolean(b)) => Ok(Some(b)),
+            None => Ok(None),
+            Some(_) => Err(self.invalid()),
+        }
+    }
+
+    fn number(&mut self) -> Result<f64> {
+        self.optional_number()?.ok_or_else(|| self.invalid())
+    }
+
+    fn string(&mut self) -> Result<String> {
+        self.optional_string()?.ok_or_else(|| self.invalid())
+    }
+
+    fn boolean(&mut self) -> Result<bool> {
+        self.optional_boolean()?.ok_or_else(|| self.invalid())
+    }
+}
+
+impl CommandMessage {
+    pub fn new(transaction_id: f64, command: Command) -> Self {
+        Self {
+            transaction_id,
+            command,
+        }
+    }
+
+    /// Parses the values of an AMF-0 command message.
+    pub fn parse(values: Vec<AmfObject>) -> Result<Self> {
+        let mut values = values.into_iter();
+        let name = match values.next() {
+            Some(AmfObject::String(name)) => name,
+            _ => return Err(Error::NonStringCommand),
+        };
+        let mut args = Arguments {
+            name,
+            index: 0,
+            values,
+        };
+        let transaction_id = args.optional_number()?.unwrap_or(0.0);
+        // The command object is null for every command except `connect`.
+        let command_object = args.next();
+        let command = match args.name.as_str() {
+            "connect" => {
+                Command::Connect(from_amf_object(command_object.unwrap_or(AmfObject::Null))?)
+            }
+            "createStream" => Command::CreateStream,
+            "play" => Command::Play {
+                name: args.string()?,
+                start: args.optional_number()?,
+                duration: args.optional_number()?,
+                reset: args.optional_boolean()?,
+            },
+            "publish" => Command::Publish {
+                name: args.string()?,
+                publishing_type: args.optional_string()?,
+            },
+            "pause" => Command::Pause {
+                pause: args.boolean()?,
+                milliseconds: args.optional_number()?.unwrap_or(0.0),
+            },
+            "seek" => Command::Seek {
+                milliseconds: args.number()?,
+            },
+            "deleteStream" => Command::DeleteStream {
+                stream_id: args.optional_number()?.unwrap_or(0.0),
+            },
+            "closeStream" => Command::CloseStream,
+            "releaseStream" => Command::ReleaseStream {
+                name: args.optional_string()?.unwrap_or_default(),
+            },
+            "FCPublish" => Command::FCPublish {
+                name: args.optional_string()?.unwrap_or_default(),
+            },
+            "FCUnpublish" => Command::FCUnpublish {
+                name: args.optional_string()?.unwrap_or_default(),
+            },
+            "getStreamLength" => Command::GetStreamLength {
+                name: args.optional_string()?.unwrap_or_default(),
+            },
+            "receiveAudio" => Command::ReceiveAudio(args.boolean()?),
+            "receiveVideo" => Command::ReceiveVideo(args.boolean()?),
+            _ => Command::Call {
+                name: args.name,
+                command_object: command_object.unwrap_or(AmfObject::Null),
+                arguments: args.values.collect(),
+            },
+        };
+        Ok(Self {
+            transaction_id,
+            command,
+        })
+    }
+
+    /// Serializes the command into the values of an AMF-0 command message. Absent optional
+    /// arguments are written as `null`, or left out if nothing follows them.
+    pub fn serialize(&self) -> Result<Vec<AmfObject>> {
+        let mut values = vec![
+            AmfObject::String(String::from(self.command.name())),
+            AmfObject::Number(self.transaction_id),
+        ];
+        let optional = |value: Option<AmfObject>| value.unwrap_or(AmfObject::Null);
+        let (command_object, arguments) = match self.command {
+            Command::Connect(ref command_object) => (to_amf_object(command_object)?, vec![]),
+            Command::CreateStream | Command::CloseStream => (AmfObject::Null, vec![]),
+            Command::Play {
+                ref name,
+                start,
+                duration,
+                reset,
+            } => (
+                AmfObject::Null,
+                vec![
+                    AmfObject::from(name.as_str()),
+                    optional(start.map(AmfObject::Number)),
+                    optional(duration.map(AmfObject::Number)),
+                    optional(reset.map(AmfObject::Boolean)),
+                ],
+            ),
+            Command::Publish {
+                ref name,
+                ref publishing_type,
+            } => (
+                AmfObject::Null,
+                vec![
+                    AmfObject::from(name.as_str()),
+                    optional(publishing_type.as_deref().map(AmfObject::from)),
+                ],
+            ),
+            Command::Pause {
+                pause,
+                milliseconds,
+            } => (
+                AmfObject::Null,
+                vec![AmfObject::Boolean(pause), AmfObject::Number(milliseconds)],
+            ),
+            Command::Seek { milliseconds } => {
+                (AmfObject::Null, vec![AmfObject::Number(milliseconds)])
+            }
+            Command::DeleteStream { stream_id } => {
+                (AmfObject::Null, vec![AmfObject::Number(stream_id)])
+            }
+            Command::ReleaseStream { ref name }
+            | Command::FCPublish { ref name }
+            | Command::FCUnpublish { ref name }
+            | Command::GetStreamLength { ref name } => {
+                (AmfObject::Null, vec![AmfObject::from(name.as_str())])
+            }
+            Command::ReceiveAudio(flag) | Command::ReceiveVideo(flag) => {
+                (AmfObject::Null, vec![AmfObject::Boolean(flag)])
+            }
+            Command::Call {
+                ref command_object,
+                ref arguments,
+                ..
+            } => {
+                values.push(command_object.clone());
+                values.extend_from_slice(arguments);
+                return Ok(values);
+            }
+        };
+        values.push(command_object);
+        values.extend(arguments);
+        while values.len() > 3 && values.last() == Some(&AmfObject::Null) {
+            values.pop();
+        }
+        Ok(values)
+    }
+}
+
+/// Values of an `onStatus` command message.
+pub fn on_status(code: &str, success: bool) -> Result<Vec<AmfObject>> {
+    Ok(vec![
+        AmfObject::String(String::from("onStatus")),
+        AmfObject::Number(0_f64),
+        AmfObject::Null,
+        to_amf_object(&StatusInfo::new(code, success))?,
+    ])
+}
+
+/// Encodes the values of a command message, encapsulated in an AMF-3 command message if AMF-3
+/// has been negotiated in `connect`. Returns the message type ID with the payload.
+pub fn encode_command_message(object_encoding: u8, values: &[AmfObject]) -> Result<(u8, Vec<u8>)> {
+    let (message_type_id, mut buffer) = if object_encoding == RTMP_OBJECT_ENCODING_AMF3 {
+        // AMF-3 command messages start with a format selector byte.
+        (RTMP_COMMAND_MESSAGE_AMF3, vec![0x0])
+    } else {
+        (RTMP_COMMAND_MESSAGE_AMF0, Vec::new())
+    };
+    buffer.extend_from_slice(&encode_amf_messages(values)?);
+    Ok((message_type_id, buffer))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::amf::{decode_amf_messages, AmfObjectBuilder};
+    use crate::object::ServerProperties;
+    use std::io::Cursor;
+
+    fn round_trip(message: CommandMessage) {
+        let buffer = encode_amf_messages(&message.serialize().unwrap()).unwrap();
+        let values = decode_amf_messages(&mut Cursor::new(buffer)).unwrap();
+        assert_eq!(CommandMessage::parse(values).unwrap(), message);
+    }
+
+    #[test]
+    fn test_command_round_trip() {
+        round_trip(CommandMessage::new(
+            1.0,
+            Command::Connect(ConnectObject {
+                app: String::from("live"),
+                tc_url: Some(String::from("rtmp://localhost/live")),
+                object_encoding: Some(3),
+                ..ConnectObject::default()
+            }),
+        ));
+        round_trip(CommandMessage::new(4.0, Command::CreateStream));
+        round_trip(CommandMessage::new(
+            0.0,
+            Command::Play {
+                name: String::from("jizz"),
+                start: Some(-2.0),
+                duration: None,
+                reset: Some(true),
+            },
+        ));
+        round_trip(CommandMessage::new(
+            5.0,
+            Command::Publish {
+                name: String::from("jizz"),
+                publishing_type: Some(String::from("live")),
+            },
+        ));
+        round_trip(CommandMessage::new(
+            0.0,
+            Command::Pause {
+                pause: true,
+                milliseconds: 7122.0,
+            },
+        ));
+        round_trip(CommandMessage::new(0.0, Command::ReceiveVideo(false)));
+        round_trip(CommandMessage::new(
+            2.0,
+            Command::Call {
+                name: String::from("_result"),
+                command_object: AmfObject::Null,
+                arguments: vec![AmfObject::Number(1.0)],
+            },
+        ));
+    }
+
+    #[test]
+    fn test_command_optional_arguments() {
+        let values = vec![
+            AmfObject::from("play"),
+            AmfObject::Number(0.0),
+            AmfObject::Null,
+            AmfObject::from("jizz"),
+        ];
+        assert_eq!(
+            CommandMessage::parse(values).unwrap().command,
+            Command::Play {
+                name: String::from("jizz"),
+                start: None,
+                duration: None,
+                reset: None,
+            }
+        );
+        let play = CommandMessage::new(
+            0.0,
+            Command::Play {
+                name: String::from("jizz"),
+                start: None,
+                duration: Some(1.0),
+                reset: None,
+            },
+        );
+        assert_eq!(
+            play.serialize().unwrap()[3..],
+            [
+                AmfObject::from("jizz"),
+                AmfObject::Null,
+                AmfObject::Number(1.0)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_connect_four_cc_list() {
+        let values = vec![
+            AmfObject::from("connect"),
+            AmfObject::Number(1.0),
+            AmfObjectBuilder::new()
+                .property("app", "live")
+                .property(
+                    "fourCcList",
+                    AmfObject::StrictArray(vec![AmfObject::from("hvc1")]),
+                )
+                .property(
+                    "videoFourCcInfoMap",
+                    AmfObjectBuilder::new().property("av01", 1.0).build(),
+                )
+                .build(),
+        ];
+        let connect_object = match CommandMessage::parse(values).unwrap().command {
+            Command::Connect(connect_object) => connect_object,
+            command => panic!("unexpected command {:?}", command),
+        };
+        assert!(connect_object.supports_four_cc("hvc1"));
+        assert!(connect_object.supports_four_cc("av01"));
+        assert!(!connect_object.supports_four_cc("vp09"));
+        assert!(!ConnectObject::default().supports_four_cc("hvc1"));
+    }
+
+    #[test]
+    fn test_server_properties_four_cc_info_map_order() {
+        let info_map = ["vp09", "av01", "hvc1"]
+            .iter()
+            .map(|&four_cc| (String::from(four_cc), 2.0))
+            .collect();
+        let properties = ServerProperties {
+            fms_ver: String::from("FMS/3,0,1,123"),
+            capabilities: 31.0,
+            mode: 1.0,
+            four_cc_list: None,
+            video_four_cc_info_map: Some(info_map),
+            audio_four_cc_info_map: None,
+        };
+        let info_map = match to_amf_object(&properties).unwrap() {
+            AmfObject::Object(properties) => properties.get("videoFourCcInfoMap").cloned(),
+            object => panic!("unexpected object {:?}", object),
+        };
+        let keys: Vec<_> = match info_map {
+            Some(AmfObject::EcmaArray(info_map)) => info_map.into_iter().map(|(k, _)| k).collect(),
+            object => panic!("unexpected info map {:?}", object),
+        };
+        assert_eq!(keys, ["av01", "hvc1", "vp09"]);
+    }
+
+    #[test]
+    fn test_connect_mistyped_properties() {
+        let values = vec![
+            AmfObject::from("connect"),
+            AmfObject::Number(1.0),
+            AmfObjectBuilder::new()
+                .property("app", "live")
+                .property("objectEncoding", "3")
+                .property("fpad", 0.0)
+                .property("tcUrl", "rtmp://localhost/live")
+                .build(),
+        ];
+        let connect_object = match CommandMessage::parse(values).unwrap().command {
+            Command::Connect(connect_object) => connect_object,
+            command => panic!("unexpected command {:?}", command),
+        };
+        assert_eq!(connect_object.object_encoding, None);
+        assert_eq!(connect_object.fpad, None);
+        assert_eq!(
+            connect_object.tc_url.as_deref(),
+            Some("rtmp://localhost/live")
+        );
+    }
+
+    #[test]
+    fn test_command_invalid_arguments() {
+        let values = vec![
+            AmfObject::from("publish"),
+            AmfObject::Number(5.0),
+            AmfObject::Null,
+        ];
+        assert!(matches!(
+            CommandMessage::parse(values),
+            Err(Error::InvalidCommandArgument(ref name, 3)) if name == "publish"
+        ));
+        let values = vec![
+            AmfObject::from("connect"),
+            AmfObject::Number(1.0),
+            AmfObjectBuilder::new().property("app", 7122.0).build(),
+        ];
+        assert!(CommandMessage::parse(values).is_err());
+        assert!(matches!(
+            CommandMessage::parse(vec![AmfObject::Number(1.0)]),
+            Err(Error::NonStringCommand)
+        ));
+    }
+}
@@ -0,0 +1,157 @@
+use std::env;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use crate::amf::AmfDecodeLimits;
+use crate::error::{Error, Result};
+use crate::hls::HlsMode;
+use crate::queue::OverflowPolicy;
+use crate::relay::PushTargets;
+
+/// Server settings, read from environment variables.
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// `PORT`: port to listen on.
+    pub port: u16,
+    /// `HTTP_PORT`: port serving live streams over HTTP, or 0, the default, to disable HTTP.
+    pub http_port: u16,
+    /// `AMF_MAX_DEPTH`: maximum nesting depth of objects and arrays in AMF values received from
+    /// clients.
+    pub amf_max_depth: usize,
+    /// `AMF_MAX_SIZE`: maximum number of bytes decoded from the AMF values of a command or data
+    /// message.
+    pub amf_max_size: usize,
+    /// `SEND_QUEUE_SIZE`: maximum number of messages waiting to be sent to each subscriber.
+    pub send_queue_size: usize,
+    /// `OVERFLOW_POLICY`: `drop-non-keyframes`, `drop-oldest` or `disconnect`, applied when a
+    /// subscriber's send queue is full.
+    pub overflow_policy: OverflowPolicy,
+    /// `GOP_CACHE_SIZE`: maximum number of payload bytes of the latest group of pictures kept
+    /// for new subscribers, or 0 to disable the cache.
+    pub gop_cache_size: usize,
+    /// `RECORD_DIRECTORY`: directory of the recordings of streams published with type `record`
+    /// or `append`.
+    pub record_directory: PathBuf,
+    /// `RECORD_TEMPLATE`: file name of recordings, where `{name}` is replaced by the stream
+    /// name, `{time}` by the Unix time and `{index}` by the number of files recorded before.
+    pub record_template: String,
+    /// `RECORD_MAX_SIZE`: size in bytes after which a new recording file is started, or 0 for
+    /// no limit.
+    pub record_max_size: u64,
+    /// `RECORD_MAX_DURATION`: duration in seconds after which a new recording file is started,
+    /// or 0 for no limit.
+    pub record_max_duration: u32,
+    /// `VOD_DIRECTORY`: directory of the FLV files played on demand. A stream name is played
+    /// from the file with the same name as its recording if no live stream is published.
+    pub vod_directory: PathBuf,
+    /// `HLS`: `http` to serve published H.264 and AAC streams as HLS from memory, `disk` to
+    /// also write them to the HLS directory, or `off`, the default.
+    pub hls_mode: HlsMode,
+    /// `HLS_DIRECTORY`: directory of the playlists and segments in disk mode, one subdirectory
+    /// per stream.
+    pub hls_directory: PathBuf,
+    /// `HLS_SEGMENT_DURATION`: minimum duration of segments in seconds. Segments are cut on
+    /// the first keyframe after it.
+    pub hls_segment_duration: u32,
+    /// `HLS_PLAYLIST_LENGTH`: number of segments listed in playlists.
+    pub hls_playlist_length: usize,
+    /// `LL_HLS`: whether to also serve low-latency HLS, with fragmented MP4 segments, from
+    /// memory unless HLS is `off`. Off by default.
+    pub ll_hls: bool,
+    /// `HLS_PART_DURATION`: maximum duration of the partial segments of low-latency HLS in
+    /// milliseconds.
+    pub hls_part_duration: u32,
+    /// `DASH`: whether to serve published H.264 and AAC streams as MPEG-DASH. Off by default.
+    pub dash: bool,
+    /// `DASH_SEGMENT_DURATION`: minimum duration of DASH segments in seconds. Segments are cut
+    /// on the first keyframe after it.
+    pub dash_segment_duration: u32,
+    /// `DASH_WINDOW_SIZE`: number of segments of each track listed in MPDs.
+    pub dash_window_size: usize,
+    /// `PUSH`: upstream servers published streams are pushed to, as space-separated
+    /// `<stream>=rtmp://host[:port]/app/name` entries. The stream `*` matches every stream, and
+    /// `{name}` in a URL is replaced by the stream name.
+    pub push: PushTargets,
+    /// `PUSH_FROM_QUERY`: whether publishers may add push targets with `push` parameters after
+    /// the stream name, as in `name?push=rtmp://host/app/name`. Off by default, since it lets
+    /// any publisher make the server connect to arbitrary hosts.
+    pub push_from_query: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            port: 7122,
+            http_port: 0,
+            amf_max_depth: AmfDecodeLimits::default().max_depth,
+            amf_max_size: AmfDecodeLimits::default().max_size,
+            send_queue_size: 1024,
+            overflow_policy: OverflowPolicy::DropNonKeyframes,
+            gop_cache_size: 8 << 20,
+            record_directory: PathBuf::from("."),
+            record_template: String::from("{name}.flv"),
+            record_max_size: 0,
+            record_max_duration: 0,
+            vod_directory: PathBuf::from("."),
+            hls_mode: HlsMode::Disabled,
+            hls_directory: PathBuf::from("hls"),
+            hls_segment_duration: 4,
+            hls_playlist_length: 6,
+            ll_hls: false,
+            hls_part_duration: 500,
+            dash: false,
+            dash_segment_duration: 2,
+            dash_window_size: 5,
+            push: PushTargets::default(),
+            push_from_query: false,
+        }
+    }
+}
+
+fn var<T: FromStr>(name: &str, default: T) -> Result<T> {
+    match env::var(name) {
+        Ok(value) => value
+            .parse()
+            .map_err(|_| Error::InvalidConfig(format!("invalid {}: {}", name, value))),
+        Err(_) => Ok(default),
+    }
+}
+
+impl Config {
+    pub fn from_env() -> Result<Self> {
+        let default = Config::default();
+        Ok(Self {
+            port: var("PORT", default.port)?,
+            http_port: var("HTTP_PORT", default.http_port)?,
+            amf_max_depth: var("AMF_MAX_DEPTH", default.amf_max_depth)?,
+            amf_max_size: var("AMF_MAX_SIZE", default.amf_max_size)?,
+            send_queue_size: var("SEND_QUEUE_SIZE", default.send_queue_size)?,
+            overflow_policy: var("OVERFLOW_POLICY", default.overflow_policy)?,
+            gop_cache_size: var("GOP_CACHE_SIZE", default.gop_cache_size)?,
+            record_directory: var("RECORD_DIRECTORY", default.record_directory)?,
+            record_template: var("RECORD_TEMPLATE", default.record_template)?,
+            record_max_size: var("RECORD_MAX_SIZE", default.record_max_size)?,
+            record_max_duration: var("RECORD_MAX_DURATION", default.record_max_duration)?,
+            vod_directory: var("VOD_DIRECTORY", default.vod_directory)?,
+            hls_mode: var("HLS", default.hls_mode)?,
+            hls_directory: var("HLS_DIRECTORY", default.hls_directory)?,
+            hls_segment_duration: var("HLS_SEGMENT_DURATION", default.hls_segment_duration)?,
+            hls_playlist_length: var("HLS_PLAYLIST_LENGTH", default.hls_playlist_length)?,
+            ll_hls: var("LL_HLS", default.ll_hls)?,
+            hls_part_duration: var("HLS_PART_DURATION", default.hls_part_duration)?,
+            dash: var("DASH", default.dash)?,
+            dash_segment_duration: var("DASH_SEGMENT_DURATION", default.dash_segment_duration)?,
+            dash_window_size: var("DASH_WINDOW_SIZE", default.dash_window_size)?,
+            push: var("PUSH", default.push)?,
+            push_from_query: var("PUSH_FROM_QUERY", default.push_from_query)?,
+        })
+    }
+
+    /// Limits applied when decoding AMF values received from clients.
+    pub fn amf_decode_limits(&self) -> AmfDecodeLimits {
+        AmfDecodeLimits {
+            max_depth: self.amf_max_depth,
+            max_size: self.amf_max_size,
+        }
+    }
+}
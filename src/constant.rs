@@ -13,11 +13,71 @@ pub const RTMP_DATA_MESSAGE_AMF0: u8 = 18;
 pub const RTMP_DATA_MESSAGE_AMF3: u8 = 15;
 pub const RTMP_AUDIO_MESSAGE: u8 = 8;
 pub const RTMP_VIDEO_MESSAGE: u8 = 9;
+pub const RTMP_AGGREGATE_MESSAGE: u8 = 22;
+
+// Port of `rtmp://` URLs without one
+pub const RTMP_DEFAULT_PORT: u16 = 1935;
 
 pub const RTMP_NET_CONNECTION_STREAM_ID: u32 = 0;
 
 pub const RTMP_PROTOCOL_CONTROL_MESSAGE_STREAM_ID: u32 = 0;
 pub const RTMP_PROTOCOL_CONTROL_CHUNK_STREAM_ID: u16 = 0x2;
+pub const RTMP_COMMAND_CHUNK_STREAM_ID: u16 = 0x3;
+pub const RTMP_DATA_CHUNK_STREAM_ID: u16 = 0x5;
+pub const RTMP_AUDIO_CHUNK_STREAM_ID: u16 = 0x6;
+pub const RTMP_VIDEO_CHUNK_STREAM_ID: u16 = 0x7;
+
+// Chunk size of outgoing messages, small enough for audio to be interleaved with video.
+pub const RTMP_DEFAULT_CHUNK_SIZE: u32 = 4096;
+
+pub const RTMP_DEFAULT_WINDOW_ACK_SIZE: u32 = 1048576;
+
+// Limit types of Set Peer Bandwidth
+pub const RTMP_PEER_BANDWIDTH_HARD: u8 = 0;
+pub const RTMP_PEER_BANDWIDTH_SOFT: u8 = 1;
+pub const RTMP_PEER_BANDWIDTH_DYNAMIC: u8 = 2;
 
 // RTMP user control message events
+pub const RTMP_USER_CONTROL_STREAM_EOF: u16 = 0x1;
 pub const RTMP_USER_CONTROL_SET_BUFFER_LENGTH: u16 = 0x3;
+pub const RTMP_USER_CONTROL_PING_REQUEST: u16 = 0x6;
+pub const RTMP_USER_CONTROL_PING_RESPONSE: u16 = 0x7;
+
+// AMF object encodings negotiated in `connect`
+pub const RTMP_OBJECT_ENCODING_AMF0: u8 = 0;
+pub const RTMP_OBJECT_ENCODING_AMF3: u8 = 3;
+
+// FLV tag types
+pub const FLV_TAG_TYPE_AUDIO: u8 = 8;
+pub const FLV_TAG_TYPE_VIDEO: u8 = 9;
+pub const FLV_TAG_TYPE_SCRIPT: u8 = 18;
+
+// FLV audio and video tag fields
+pub const FLV_VIDEO_FRAME_TYPE_KEYFRAME: u8 = 1;
+pub const FLV_VIDEO_CODEC_AVC: u8 = 7;
+pub const FLV_VIDEO_CODEC_HEVC: u8 = 12;
+pub const FLV_AVC_PACKET_TYPE_SEQUENCE_HEADER: u8 = 0;
+pub const FLV_AUDIO_FORMAT_AAC: u8 = 10;
+pub const FLV_AAC_PACKET_TYPE_SEQUENCE_HEADER: u8 = 0;
+
+// Enhanced RTMP extended tag headers
+pub const FLV_VIDEO_EX_HEADER: u8 = 0x80;
+pub const FLV_AUDIO_FORMAT_EX_HEADER: u8 = 9;
+pub const FLV_PACKET_TYPE_SEQUENCE_START: u8 = 0;
+pub const FLV_PACKET_TYPE_CODED_FRAMES: u8 = 1;
+pub const FLV_PACKET_TYPE_SEQUENCE_END: u8 = 2;
+pub const FLV_VIDEO_PACKET_TYPE_CODED_FRAMES_X: u8 = 3;
+pub const FLV_VIDEO_PACKET_TYPE_MPEG2TS_SEQUENCE_START: u8 = 5;
+pub const FLV_VIDEO_PACKET_TYPE_MULTITRACK: u8 = 6;
+pub const FLV_AUDIO_PACKET_TYPE_MULTITRACK: u8 = 5;
+pub const FLV_PACKET_TYPE_MOD_EX: u8 = 7;
+
+// Flags of the FourCC info maps in `connect`
+pub const FOURCC_INFO_CAN_DECODE: u8 = 0x1;
+pub const FOURCC_INFO_CAN_ENCODE: u8 = 0x2;
+pub const FOURCC_INFO_CAN_FORWARD: u8 = 0x4;
+
+// Enhanced RTMP codecs advertised in the `_result` of `connect`. Media is relayed untouched, so
+// any codec can be forwarded.
+pub const ENHANCED_RTMP_VIDEO_FOURCCS: [&str; 5] = ["av01", "vp09", "vp08", "hvc1", "avc1"];
+pub const ENHANCED_RTMP_AUDIO_FOURCCS: [&str; 6] = ["Opus", "fLaC", "ac-3", "ec-3", "mp4a", ".mp3"];
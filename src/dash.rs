@@ -0,0 +1,431 @@
+//! MPEG-DASH packaging of live streams: fragmented MP4 segments of each track, listed by a
+//! dynamic MPD with a segment timeline.
+
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::cmaf::{samples, CmafTracks, Frame, AAC_FRAME_LENGTH};
+use crate::config::Config;
+use crate::error::Result;
+use crate::mp4::{write_fragment, write_init_segment, SampleEntry, Track, TrackFragment};
+
+#[derive(Debug)]
+struct DashSegment {
+    number: u64,
+    /// Decode time and duration, in units of the timescale of the track.
+    time: u64,
+    duration: u64,
+    data: Arc<[u8]>,
+}
+
+/// A track, served as a representation of an adaptation set of its own.
+#[derive(Debug)]
+struct Representation {
+    track: Track,
+    init_segment: Arc<[u8]>,
+    segments: VecDeque<DashSegment>,
+    /// Decode time of the next segment.
+    next_time: Option<u64>,
+}
+
+impl Representation {
+    fn name(&self) -> &'static str {
+        match self.track.sample_entry {
+            SampleEntry::Avc { .. } => "video",
+            SampleEntry::Aac { .. } => "audio",
+        }
+    }
+
+    /// Average bitrate of the segments, in bits per second.
+    fn bandwidth(&self) -> u64 {
+        let size: u64 = self
+            .segments
+            .iter()
+            .map(|segment| segment.data.len() as u64)
+            .sum();
+        let duration: u64 = self.segments.iter().map(|segment| segment.duration).sum();
+        std::cmp::max(
+            (size * 8 * u64::from(self.track.timescale))
+                .checked_div(duration)
+                .unwrap_or(0),
+            1,
+        )
+    }
+
+    fn write_adaptation_set(&self, manifest: &mut String, id: usize) {
+        let name = self.name();
+        manifest.push_str(&format!(
+            "    <AdaptationSet id=\"{}\" contentType=\"{}\" mimeType=\"{}/mp4\" \
+             segmentAlignment=\"true\" startWithSAP=\"1\">\n",
+            id, name, name
+        ));
+        let start_number = self.segments.front().map_or(0, |segment| segment.number);
+        manifest.push_str(&format!(
+            "      <SegmentTemplate timescale=\"{}\" initialization=\"{}-init.mp4\" \
+             media=\"{}-$Number$.m4s\" startNumber=\"{}\">\n        <SegmentTimeline>\n",
+            self.track.timescale, name, name, start_number
+        ));
+        for segment in &self.segments {
+            manifest.push_str(&format!(
+                "          <S t=\"{}\" d=\"{}\"/>\n",
+                segment.time, segment.duration
+            ));
+        }
+        manifest.push_str("        </SegmentTimeline>\n      </SegmentTemplate>\n");
+        match self.track.sample_entry {
+            SampleEntry::Avc {
+                width,
+                height,
+                ref config,
+            } => manifest.push_str(&format!(
+                "      <Representation id=\"video\" codecs=\"avc1.{}\" width=\"{}\" height=\"{}\" \
+                 bandwidth=\"{}\"/>\n",
+                config
+                    .get(1..4)
+                    .unwrap_or_default()
+                    .iter()
+                    .map(|byte| format!("{:02x}", byte))
+                    .collect::<String>(),
+                width,
+                height,
+                self.bandwidth()
+            )),
+            SampleEntry::Aac {
+                channel_count,
+                sample_rate,
+                ref config,
+            } => manifest.push_str(&format!(
+                "      <Representation id=\"audio\" codecs=\"mp4a.40.{}\" \
+                 audioSamplingRate=\"{}\" bandwidth=\"{}\">\n        \
+                 <AudioChannelConfiguration \
+                 schemeIdUri=\"urn:mpeg:dash:23003:3:audio_channel_configuration:2011\" \
+                 value=\"{}\"/>\n      </Representation>\n",
+                config.first().map_or(2, |byte| byte >> 3),
+                sample_rate,
+                self.bandwidth(),
+                channel_count
+            )),
+        }
+        manifest.push_str("    </AdaptationSet>\n");
+    }
+}
+
+/// Formats a time as an `xs:dateTime` in UTC.
+fn format_date_time(time: SystemTime) -> String {
+    let duration = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let seconds = duration.as_secs();
+    // Civil date of a day number, from http://howardhinnant.github.io/date_algorithms.html.
+    let days = (seconds / 86400) as i64 + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        seconds / 3600 % 24,
+        seconds / 60 % 60,
+        seconds % 60,
+        duration.subsec_millis()
+    )
+}
+
+fn format_duration(milliseconds: u64) -> String {
+    format!("PT{}.{:03}S", milliseconds / 1000, milliseconds % 1000)
+}
+
+/// Packages the H.264 and AAC messages of a stream into segments of about the segment duration,
+/// cut at the same time for every track. Segments start with a keyframe, or with any audio
+/// frame for streams without video.
+#[derive(Debug)]
+pub struct DashPackager {
+    /// Minimum duration of a segment in milliseconds.
+    segment_duration: u32,
+    window_size: usize,
+    stream_tracks: CmafTracks,
+    representations: Vec<Representation>,
+    frames: Vec<Frame>,
+    /// Number of the segment being written and the timestamp of its start.
+    number: u64,
+    segment_start: Option<u32>,
+    /// Wall-clock time of timestamp 0.
+    availability_start_time: Option<SystemTime>,
+}
+
+impl DashPackager {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            segment_duration: config.dash_segment_duration.saturating_mul(1000),
+            window_size: std::cmp::max(config.dash_window_size, 1),
+            stream_tracks: CmafTracks::default(),
+            representations: vec![],
+            frames: vec![],
+            number: 0,
+            segment_start: None,
+            availability_start_time: None,
+        }
+    }
+
+    /// Adds an audio or video message. Codecs other than H.264 and AAC are ignored.
+    pub fn push(&mut self, message_type_id: u8, timestamp: u32, payload: &[u8]) -> Result<()> {
+        let frame = match self
+            .stream_tracks
+            .parse(message_type_id, timestamp, payload)?
+        {
+            Some(frame) => frame,
+            None => return Ok(()),
+        };
+        if frame.track_id == self.stream_tracks.main_track_id() {
+            if let Some(segment_start) = self.segment_start {
+                if frame.is_keyframe
+                    && timestamp.wrapping_sub(segment_start) >= self.segment_duration
+                {
+                    self.finish_segment(timestamp);
+                }
+            }
+            if self.segment_start.is_none() {
+                if !frame.is_keyframe {
+                    return Ok(());
+                }
+                self.start_segment(timestamp);
+            }
+        } else if self.segment_start.is_none() {
+            return Ok(());
+        }
+        self.frames.push(frame);
+        Ok(())
+    }
+
+    fn start_segment(&mut self, timestamp: u32) {
+        let tracks = self.stream_tracks.tracks();
+        // The timeline starts again with new codecs.
+        if !tracks.iter().eq(self
+            .representations
+            .iter()
+            .map(|representation| &representation.track))
+        {
+            self.representations = tracks
+                .into_iter()
+                .map(|track| {
+                    let mut init_segment = vec![];
+                    write_init_segment(&mut init_segment, std::slice::from_ref(&track));
+                    Representation {
+                        track,
+                        init_segment: Arc::from(init_segment),
+                        segments: VecDeque::new(),
+                        next_time: None,
+                    }
+                })
+                .collect();
+        }
+        if self.availability_start_time.is_none() {
+            self.availability_start_time = SystemTime::now()
+                .checked_sub(Duration::from_millis(u64::from(timestamp)))
+                .or(Some(UNIX_EPOCH));
+        }
+        self.segment_start = Some(timestamp);
+    }
+
+    /// Writes the frames of each track, which last until `end`, as their next segment.
+    fn finish_segment(&mut self, end: u32) {
+        if self.segment_start.take().is_none() {
+            return;
+        }
+        let mut frames = std::mem::take(&mut self.frames);
+        for representation in &mut self.representations {
+            let track = &representation.track;
+            let (track_frames, other_frames) = frames
+                .into_iter()
+                .partition::<Vec<_>, _>(|frame| frame.track_id == track.id);
+            frames = other_frames;
+            let start = match track_frames.first() {
+                Some(frame) => frame.timestamp,
+                None => continue,
+            };
+            let timescale = u64::from(track.timescale);
+            // Decode times follow on from the previous segment, so that the timeline has no gaps,
+            // unless the timestamps drift away by more than a frame, as AAC frames are taken to
+            // last 1024 samples whatever their timestamps.
+            let max_drift = match track.sample_entry {
+                SampleEntry::Avc { .. } => 0,
+                SampleEntry::Aac { .. } => u64::from(AAC_FRAME_LENGTH),
+            };
+            let time = match representation.next_time {
+                Some(next_time) => {
+                    let drift = start.wrapping_sub((next_time * 1000 / timescale) as u32) as i32;
+                    if u64::from(drift.unsigned_abs()) * timescale > max_drift * 1000 {
+                        next_time.saturating_add_signed(i64::from(drift) * timescale as i64 / 1000)
+                    } else {
+                        next_time
+                    }
+                }
+                None => u64::from(start) * timescale / 1000,
+            };
+            let samples = samples(track_frames, end, track);
+            let duration = samples
+                .iter()
+                .map(|sample| u64::from(sample.duration))
+                .sum();
+            let mut data = vec![];
+            write_fragment(
+                &mut data,
+                self.number as u32,
+                &[TrackFragment {
+                    track_id: track.id,
+                    base_media_decode_time: time,
+                    samples: &samples,
+                }],
+            );
+            representation.segments.push_back(DashSegment {
+                number: self.number,
+                time,
+                duration,
+                data: Arc::from(data),
+            });
+            representation.next_time = Some(time + duration);
+            while representation.segments.len() > self.window_size {
+                representation.segments.pop_front();
+            }
+        }
+        self.number += 1;
+    }
+
+    /// Dynamic MPD listing the latest segments of each track, whose URLs are relative to it,
+    /// once the first segment has started.
+    pub fn manifest(&self, now: SystemTime) -> Option<String> {
+        let availability_start_time = self.availability_start_time?;
+        let segment_duration = u64::from(self.segment_duration);
+        let mut manifest = format!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+             <MPD xmlns=\"urn:mpeg:dash:schema:mpd:2011\" \
+             profiles=\"urn:mpeg:dash:profile:isoff-live:2011\" type=\"dynamic\" \
+             availabilityStartTime=\"{}\" publishTime=\"{}\" minimumUpdatePeriod=\"{}\" \
+             minBufferTime=\"{}\" timeShiftBufferDepth=\"{}\" \
+             suggestedPresentationDelay=\"{}\">\n  <Period id=\"0\" start=\"PT0S\">\n",
+            format_date_time(availability_start_time),
+            format_date_time(now),
+            format_duration(segment_duration),
+            format_duration(segment_duration),
+            format_duration(segment_duration * self.window_size as u64),
+            format_duration(segment_duration * 3)
+        );
+        for (id, representation) in self.representations.iter().enumerate() {
+            representation.write_adaptation_set(&mut manifest, id);
+        }
+        manifest.push_str(&format!(
+            "  </Period>\n  <UTCTiming schemeIdUri=\"urn:mpeg:dash:utc:direct:2014\" \
+             value=\"{}\"/>\n</MPD>\n",
+            format_date_time(now)
+        ));
+        Some(manifest)
+    }
+
+    /// Init segment of the `video` or `audio` track.
+    pub fn init_segment(&self, name: &str) -> Option<Arc<[u8]>> {
+        self.representations
+            .iter()
+            .find(|representation| representation.name() == name)
+            .map(|representation| Arc::clone(&representation.init_segment))
+    }
+
+    /// Media segment of the `video` or `audio` track.
+    pub fn segment(&self, name: &str, number: u64) -> Option<Arc<[u8]>> {
+        self.representations
+            .iter()
+            .find(|representation| representation.name() == name)?
+            .segments
+            .iter()
+            .find(|segment| segment.number == number)
+            .map(|segment| Arc::clone(&segment.data))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::constant::*;
+    use crate::media::tests::AVC_SEQUENCE_HEADER;
+
+    #[test]
+    fn test_format_date_time() {
+        assert_eq!(format_date_time(UNIX_EPOCH), "1970-01-01T00:00:00.000Z");
+        assert_eq!(
+            format_date_time(UNIX_EPOCH + Duration::from_millis(1_000_000_000_123)),
+            "2001-09-09T01:46:40.123Z"
+        );
+        assert_eq!(
+            format_date_time(UNIX_EPOCH + Duration::from_secs(951_782_400)),
+            "2000-02-29T00:00:00.000Z"
+        );
+    }
+
+    #[test]
+    fn test_dash_packager() {
+        let config = Config {
+            dash_segment_duration: 1,
+            dash_window_size: 2,
+            ..Config::default()
+        };
+        let mut packager = DashPackager::new(&config);
+        assert_eq!(packager.manifest(SystemTime::now()), None);
+        packager
+            .push(RTMP_VIDEO_MESSAGE, 0, &AVC_SEQUENCE_HEADER)
+            .unwrap();
+        packager
+            .push(RTMP_AUDIO_MESSAGE, 0, &[0xAF, 0x0, 0x12, 0x10])
+            .unwrap();
+        // A keyframe every second, at 10 frames per second, with AAC frames of about 23 ms.
+        let mut messages = vec![];
+        for timestamp in (0..=5000).step_by(100) {
+            let frame_type = if timestamp % 1000 == 0 { 0x17 } else { 0x27 };
+            let payload = vec![frame_type, 0x1, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x1, 0x65];
+            messages.push((timestamp, RTMP_VIDEO_MESSAGE, payload));
+        }
+        for timestamp in (0..=5000).step_by(23) {
+            messages.push((timestamp, RTMP_AUDIO_MESSAGE, vec![0xAF, 0x1, 0x21]));
+        }
+        messages.sort_by_key(|&(timestamp, _, _)| timestamp);
+        for (timestamp, message_type_id, payload) in messages {
+            packager.push(message_type_id, timestamp, &payload).unwrap();
+        }
+        assert!(packager.segment("video", 2).is_none());
+        assert_eq!(&packager.segment("video", 4).unwrap()[4..8], b"moof");
+        assert_eq!(&packager.init_segment("audio").unwrap()[4..8], b"ftyp");
+        let manifest = packager.manifest(UNIX_EPOCH).unwrap();
+        assert!(manifest.contains(
+            "<SegmentTemplate timescale=\"90000\" initialization=\"video-init.mp4\" \
+             media=\"video-$Number$.m4s\" startNumber=\"3\">\n        <SegmentTimeline>\n          \
+             <S t=\"270000\" d=\"90000\"/>\n          <S t=\"360000\" d=\"90000\"/>\n"
+        ));
+        // Audio segments start within a frame of video ones, although AAC frames are a little
+        // longer than the 23 ms between their timestamps.
+        let times = |name: &str| -> Vec<u64> {
+            let start = manifest.find(&format!("contentType=\"{}\"", name)).unwrap();
+            let end = start + manifest[start..].find("</AdaptationSet>").unwrap();
+            manifest[start..end]
+                .split("<S t=\"")
+                .skip(1)
+                .map(|s| s[..s.find('"').unwrap()].parse().unwrap())
+                .collect()
+        };
+        let (video_times, audio_times) = (times("video"), times("audio"));
+        assert_eq!(audio_times.len(), 2);
+        for (video_time, audio_time) in video_times.into_iter().zip(audio_times) {
+            assert!((audio_time * 90000).abs_diff(video_time * 44100) < 1024 * 90000);
+        }
+        assert!(manifest.contains("codecs=\"avc1.64001f\" width=\"1280\" height=\"720\""));
+        assert!(manifest.contains("codecs=\"mp4a.40.2\" audioSamplingRate=\"44100\""));
+    }
+}
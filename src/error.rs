@@ -7,9 +7,13 @@ pub enum Error {
     // IO errors
     Io(std::io::Error),
 
+    // Configuration errors
+    InvalidConfig(String),
+
     // RTMP chunk stream errors
     HandshakeCorrupted,
     InvalidTimestamp,
+    InvalidChunkSize(u32),
     UnknownMessageTypeId(u8),
 
     // RTMP message stream errors
@@ -17,23 +21,48 @@ pub enum Error {
     UnexpectedAmfObjectType,
     UnknownDataMessage,
     UnknownCommandMessage(String),
+    InvalidCommandArgument(String, usize),
     InconsistentMessageLength,
     MissingMediaStream,
 
+    // RTMP client errors
+    CommandFailed(String),
+    InvalidRtmpUrl(String),
+
     // AMF errors
-    Amf3NotSupported,
-    AmfIncorrectTypeMarker,
+    AmfIncorrectTypeMarker(u8),
     AmfIncorrectEndOfEcmaArray,
+    AmfStringTooLong(usize),
+    AmfInvalidUtf8(u64),
+    AmfTruncated,
+    AmfNestingTooDeep(usize),
+    AmfSizeLimitExceeded(usize),
+    Amf3InvalidReference(usize),
+    Amf3ExternalizableNotSupported(String),
+    AmfSerde(String),
+
+    // Media errors
+    MediaTruncated,
+    InvalidMediaData(&'static str),
+    InvalidFlv(&'static str),
+
+    // HTTP errors
+    InvalidHttpRequest(&'static str),
 }
 
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match *self {
             Error::Io(ref e) => fmt::Display::fmt(e, f),
+            Error::InvalidConfig(ref msg) => write!(f, "Invalid configuration: {}", msg),
             Error::HandshakeCorrupted => {
                 write!(f, "RTMP handshake failed with incorrect random digest")
             }
-            Error::InvalidTimestamp => write!(f, ""),
+            Error::InvalidTimestamp => write!(
+                f,
+                "Receive chunk with timestamp delta on a chunk stream without prior timestamp"
+            ),
+            Error::InvalidChunkSize(ref size) => write!(f, "Invalid chunk size: {}", size),
             Error::UnknownMessageTypeId(ref id) => write!(f, "Unknown message type ID: {}", id),
             Error::NonStringCommand => write!(
                 f,
@@ -43,13 +72,54 @@ impl fmt::Display for Error {
             Error::UnknownCommandMessage(ref msg) => {
                 write!(f, "Unknown AMF-0 command message: {}", msg)
             }
+            Error::InvalidCommandArgument(ref name, ref index) => write!(
+                f,
+                "Invalid or missing value #{} in {} command message",
+                index, name
+            ),
+            Error::CommandFailed(ref code) => write!(f, "Server rejected the command: {}", code),
+            Error::InvalidRtmpUrl(ref url) => write!(f, "Invalid RTMP URL: {}", url),
 
-            Error::Amf3NotSupported => write!(f, "AMF-3 encoded messages are not supported"),
-            Error::AmfIncorrectTypeMarker => write!(f, "Receive unexpected AMF type marker"),
+            Error::AmfIncorrectTypeMarker(ref marker) => {
+                write!(f, "Receive unexpected AMF type marker: {:#04x}", marker)
+            }
             Error::AmfIncorrectEndOfEcmaArray => {
                 write!(f, "Expect end-of-object marker at the end of ECMA array")
             }
+            Error::AmfStringTooLong(ref len) => {
+                write!(
+                    f,
+                    "AMF string of {} bytes exceeds the 16-bit length prefix",
+                    len
+                )
+            }
+            Error::AmfInvalidUtf8(ref offset) => {
+                write!(f, "Invalid UTF-8 string in AMF data at byte {}", offset)
+            }
+            Error::AmfTruncated => write!(f, "AMF data ends unexpectedly"),
+            Error::AmfNestingTooDeep(ref depth) => {
+                write!(f, "AMF values are nested deeper than {} levels", depth)
+            }
+            Error::AmfSizeLimitExceeded(ref size) => {
+                write!(f, "AMF values exceed the size limit of {} bytes", size)
+            }
+            Error::Amf3InvalidReference(ref index) => {
+                write!(f, "Invalid AMF-3 reference: {}", index)
+            }
+            Error::Amf3ExternalizableNotSupported(ref class_name) => write!(
+                f,
+                "AMF-3 externalizable objects are not supported: {}",
+                class_name
+            ),
+            Error::AmfSerde(ref msg) => write!(f, "{}", msg),
+
+            Error::MediaTruncated => write!(f, "Audio or video data ends unexpectedly"),
+            Error::InvalidMediaData(msg) => write!(f, "Invalid audio or video data: {}", msg),
+            Error::InvalidFlv(msg) => write!(f, "Invalid FLV file: {}", msg),
+            Error::InvalidHttpRequest(msg) => write!(f, "Invalid HTTP request: {}", msg),
             _ => Ok(()),
         }
     }
 }
+
+impl std::error::Error for Error {}
@@ -0,0 +1,598 @@
+//! FLV file format, used to record published streams and to play files on demand.
+
+use std::io::{Cursor, ErrorKind, Read, Seek, SeekFrom, Write};
+
+use crate::amf::{decode_amf_messages, encode_amf_messages, AmfObject, AmfObjectMap};
+use crate::constant::*;
+use crate::error::{Error, Result};
+use crate::media::{AudioTagHeader, VideoTagHeader};
+use crate::utils::{aggregate, read_buffer, read_buffer_sized};
+
+const FLV_HEADER_SIZE: u32 = 9;
+pub const FLV_TAG_HEADER_SIZE: usize = 11;
+
+/// Encodes the FLV header, followed by PreviousTagSize0.
+pub fn encode_header(has_audio: bool, has_video: bool) -> Vec<u8> {
+    let flags = if has_audio { 0x4 } else { 0x0 } | if has_video { 0x1 } else { 0x0 };
+    let mut header = vec![b'F', b'L', b'V', 0x1, flags];
+    header.extend_from_slice(&FLV_HEADER_SIZE.to_be_bytes());
+    // PreviousTagSize0 is always 0.
+    header.extend_from_slice(&[0x0; 4]);
+    header
+}
+
+/// Header of an FLV tag.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FlvTagHeader {
+    pub tag_type: u8,
+    pub data_size: usize,
+    pub timestamp: u32,
+}
+
+impl FlvTagHeader {
+    pub fn read<R: Read>(reader: &mut R) -> Result<Self> {
+        let buffer = read_buffer_sized::<_, FLV_TAG_HEADER_SIZE>(reader).map_err(Error::Io)?;
+        // The filter and reserved bits are ignored.
+        let tag_type = buffer[0] & 0x1F;
+        let data_size = aggregate::<usize>(&buffer[1..4], false);
+        // The extended byte holds the upper 8 bits of the timestamp.
+        let timestamp = aggregate::<u32>(&buffer[4..7], false) | u32::from(buffer[7]) << 24;
+        Ok(Self {
+            tag_type,
+            data_size,
+            timestamp,
+        })
+    }
+
+    pub fn encode(&self) -> [u8; FLV_TAG_HEADER_SIZE] {
+        let mut buffer = [0x0; FLV_TAG_HEADER_SIZE];
+        buffer[0] = self.tag_type;
+        buffer[1..4].copy_from_slice(&(self.data_size as u32).to_be_bytes()[1..]);
+        buffer[4..7].copy_from_slice(&self.timestamp.to_be_bytes()[1..]);
+        buffer[7] = (self.timestamp >> 24) as u8;
+        buffer
+    }
+}
+
+/// Encodes the values of an `onMetaData` script tag.
+fn encode_metadata(properties: &AmfObjectMap) -> Result<Vec<u8>> {
+    encode_amf_messages(&[
+        AmfObject::String(String::from("onMetaData")),
+        AmfObject::EcmaArray(
+            properties
+                .iter()
+                .map(|(key, value)| (key.clone(), value.clone()))
+                .collect(),
+        ),
+    ])
+}
+
+/// Properties of a script tag if it is `onMetaData`.
+fn decode_metadata(data: &[u8]) -> Result<Option<AmfObjectMap>> {
+    let values = decode_amf_messages(&mut Cursor::new(data))?;
+    match values[..] {
+        [AmfObject::String(ref name), ref properties] if name == "onMetaData" => {
+            Ok(Some(match *properties {
+                AmfObject::EcmaArray(ref entries) => entries.iter().cloned().collect(),
+                AmfObject::Object(ref properties) => properties.clone(),
+                _ => AmfObjectMap::new(),
+            }))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// The `onMetaData` tag at the beginning of a file, whose duration and file size are updated
+/// when the file is finished.
+#[derive(Debug)]
+struct MetadataTag {
+    position: u64,
+    properties: AmfObjectMap,
+    size: usize,
+}
+
+/// Writes audio, video and script tags to an FLV file.
+#[derive(Debug)]
+pub struct FlvWriter<W: Write + Seek> {
+    writer: W,
+    metadata: Option<MetadataTag>,
+    position: u64,
+    duration: u32,
+}
+
+impl<W: Write + Seek> FlvWriter<W> {
+    /// Writes the FLV header to a new file.
+    pub fn create(mut writer: W, has_audio: bool, has_video: bool) -> Result<Self> {
+        let header = encode_header(has_audio, has_video);
+        writer.write_all(&header).map_err(Error::Io)?;
+        Ok(Self {
+            writer,
+            metadata: None,
+            position: header.len() as u64,
+            duration: 0,
+        })
+    }
+
+    /// Writes a tag followed by its PreviousTagSize.
+    pub fn write_tag(&mut self, tag_type: u8, timestamp: u32, data: &[u8]) -> Result<()> {
+        let header = FlvTagHeader {
+            tag_type,
+            data_size: data.len(),
+            timestamp,
+        };
+        let tag_size = (FLV_TAG_HEADER_SIZE + data.len()) as u32;
+        self.writer.write_all(&header.encode()).map_err(Error::Io)?;
+        self.writer.write_all(data).map_err(Error::Io)?;
+        self.writer
+            .write_all(&tag_size.to_be_bytes())
+            .map_err(Error::Io)?;
+        self.position += u64::from(tag_size) + 4;
+        self.duration = std::cmp::max(self.duration, timestamp);
+        Ok(())
+    }
+
+    /// Writes an `onMetaData` script tag. The duration and file size of the first one written
+    /// are updated by `finish`.
+    pub fn write_metadata(&mut self, timestamp: u32, mut properties: AmfObjectMap) -> Result<()> {
+        if self.metadata.is_none() {
+            properties.insert("duration", AmfObject::Number(0.0));
+            properties.insert("filesize", AmfObject::Number(0.0));
+        }
+        let data = encode_metadata(&properties)?;
+        if self.metadata.is_none() {
+            self.metadata = Some(MetadataTag {
+                position: self.position + FLV_TAG_HEADER_SIZE as u64,
+                properties,
+                size: data.len(),
+            });
+        }
+        self.write_tag(FLV_TAG_TYPE_SCRIPT, timestamp, &data)
+    }
+
+    /// Largest timestamp written, in milliseconds.
+    pub fn duration(&self) -> u32 {
+        self.duration
+    }
+
+    /// Number of bytes of the file.
+    pub fn size(&self) -> u64 {
+        self.position
+    }
+
+    /// Updates the duration and file size in `onMetaData` and flushes the file.
+    pub fn finish(mut self) -> Result<W> {
+        if let Some(mut metadata) = self.metadata.take() {
+            let duration = f64::from(self.duration) / 1000.0;
+            metadata
+                .properties
+                .insert("duration", AmfObject::Number(duration));
+            metadata
+                .properties
+                .insert("filesize", AmfObject::Number(self.position as f64));
+            let data = encode_metadata(&metadata.properties)?;
+            // Only numbers change, so the tag keeps its size unless it was not written by us.
+            if data.len() == metadata.size {
+                self.writer
+                    .seek(SeekFrom::Start(metadata.position))
+                    .map_err(Error::Io)?;
+                self.writer.write_all(&data).map_err(Error::Io)?;
+                self.writer
+                    .seek(SeekFrom::Start(self.position))
+                    .map_err(Error::Io)?;
+            }
+        }
+        self.writer.flush().map_err(Error::Io)?;
+        Ok(self.writer)
+    }
+
+    /// Writes tags at the end of an existing file, which is inspected with `reader`. The
+    /// duration is that of the file, so that appended tags can follow its last timestamp.
+    pub fn append<R: Read + Seek>(mut writer: W, reader: &mut R) -> Result<Self> {
+        reader.seek(SeekFrom::Start(0)).map_err(Error::Io)?;
+        let header = read_buffer_sized::<_, 9>(reader).map_err(Error::Io)?;
+        if header[..3] != *b"FLV" {
+            return Err(Error::InvalidFlv("Missing FLV signature"));
+        }
+        let data_offset = aggregate::<u32>(&header[5..9], false);
+        let end = writer.seek(SeekFrom::End(0)).map_err(Error::Io)?;
+        if reader.seek(SeekFrom::End(0)).map_err(Error::Io)? != end {
+            return Err(Error::InvalidFlv("File changed while being opened"));
+        }
+        let mut flv = Self {
+            writer,
+            metadata: None,
+            position: end,
+            duration: 0,
+        };
+        let first_tag = u64::from(data_offset) + 4;
+        if end <= first_tag {
+            return Ok(flv);
+        }
+        reader.seek(SeekFrom::Start(first_tag)).map_err(Error::Io)?;
+        let header = FlvTagHeader::read(reader)?;
+        if header.tag_type == FLV_TAG_TYPE_SCRIPT {
+            let data = read_buffer(reader, header.data_size).map_err(Error::Io)?;
+            if let Some(properties) = decode_metadata(&data)? {
+                flv.metadata = Some(MetadataTag {
+                    position: first_tag + FLV_TAG_HEADER_SIZE as u64,
+                    properties,
+                    size: data.len(),
+                });
+            }
+        }
+        // The last PreviousTagSize leads to the last tag and its timestamp.
+        reader.seek(SeekFrom::End(-4)).map_err(Error::Io)?;
+        let last_tag_size = u64::from(aggregate::<u32>(
+            &read_buffer_sized::<_, 4>(reader).map_err(Error::Io)?,
+            false,
+        ));
+        if last_tag_size >= FLV_TAG_HEADER_SIZE as u64 && end >= first_tag + last_tag_size + 4 {
+            reader
+                .seek(SeekFrom::Start(end - 4 - last_tag_size))
+                .map_err(Error::Io)?;
+            flv.duration = FlvTagHeader::read(reader)?.timestamp;
+        }
+        // The reader may share its position with the writer, as a cloned file does.
+        flv.writer.seek(SeekFrom::Start(end)).map_err(Error::Io)?;
+        Ok(flv)
+    }
+}
+
+/// A tag read from an FLV file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FlvTag {
+    pub header: FlvTagHeader,
+    pub data: Vec<u8>,
+}
+
+/// A tag playback can start from, with the position of its header in the file.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Keyframe {
+    pub timestamp: u32,
+    pub position: u64,
+}
+
+fn is_end_of_file(e: &Error) -> bool {
+    matches!(*e, Error::Io(ref e) if e.kind() == ErrorKind::UnexpectedEof)
+}
+
+/// Keyframes listed by the `keyframes` property of `onMetaData`, as written by most muxers.
+fn metadata_keyframes(metadata: &AmfObjectMap) -> Option<Vec<Keyframe>> {
+    let keyframes = match *metadata.get("keyframes")? {
+        AmfObject::Object(ref properties) => properties.clone(),
+        AmfObject::EcmaArray(ref entries) => entries.iter().cloned().collect(),
+        _ => return None,
+    };
+    let numbers = |key| match keyframes.get(key) {
+        Some(AmfObject::StrictArray(ref values)) => values
+            .iter()
+            .map(|value| match *value {
+                AmfObject::Number(n) if n >= 0.0 => Some(n),
+                _ => None,
+            })
+            .collect::<Option<Vec<f64>>>(),
+        _ => None,
+    };
+    let times = numbers("times")?;
+    let positions = numbers("filepositions")?;
+    if times.is_empty() || times.len() != positions.len() {
+        return None;
+    }
+    Some(
+        times
+            .iter()
+            .zip(positions.iter())
+            .map(|(&time, &position)| Keyframe {
+                timestamp: (time * 1000.0) as u32,
+                position: position as u64,
+            })
+            .collect(),
+    )
+}
+
+/// Reads the tags of an FLV file.
+#[derive(Debug)]
+pub struct FlvReader<R: Read + Seek> {
+    reader: R,
+    /// Position of the first tag.
+    data_offset: u64,
+}
+
+impl<R: Read + Seek> FlvReader<R> {
+    /// Checks the FLV header and positions the reader at the first tag.
+    pub fn open(mut reader: R) -> Result<Self> {
+        let header = read_buffer_sized::<_, 9>(&mut reader).map_err(Error::Io)?;
+        if header[..3] != *b"FLV" {
+            return Err(Error::InvalidFlv("Missing FLV signature"));
+        }
+        // The data offset is followed by PreviousTagSize0.
+        let data_offset = u64::from(aggregate::<u32>(&header[5..9], false)) + 4;
+        reader
+            .seek(SeekFrom::Start(data_offset))
+            .map_err(Error::Io)?;
+        Ok(Self {
+            reader,
+            data_offset,
+        })
+    }
+
+    /// Reads the next tag, or returns `None` at the end of the file. A truncated last tag, as
+    /// left by a recording in progress, is treated as the end of the file.
+    pub fn read_tag(&mut self) -> Result<Option<FlvTag>> {
+        let header = match FlvTagHeader::read(&mut self.reader) {
+            Ok(header) => header,
+            Err(ref e) if is_end_of_file(e) => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let data = match read_buffer(&mut self.reader, header.data_size) {
+            Ok(data) => data,
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(Error::Io(e)),
+        };
+        // PreviousTagSize is not needed to read forward.
+        self.reader.seek(SeekFrom::Current(4)).map_err(Error::Io)?;
+        Ok(Some(FlvTag { header, data }))
+    }
+
+    pub fn position(&mut self) -> Result<u64> {
+        self.reader.stream_position().map_err(Error::Io)
+    }
+
+    /// Positions the reader at the tag starting at `position`.
+    pub fn seek(&mut self, position: u64) -> Result<()> {
+        let position = std::cmp::max(position, self.data_offset);
+        self.reader
+            .seek(SeekFrom::Start(position))
+            .map_err(Error::Io)?;
+        Ok(())
+    }
+
+    /// Tags playback can start from: those listed in `metadata` if any, otherwise the video
+    /// keyframes found by scanning the file, or every audio tag of files without video. The
+    /// reader is left at an unspecified position.
+    pub fn keyframes(&mut self, metadata: Option<&AmfObjectMap>) -> Result<Vec<Keyframe>> {
+        if let Some(keyframes) = metadata.and_then(metadata_keyframes) {
+            return Ok(keyframes);
+        }
+        let mut video_keyframes = vec![];
+        let mut audio_tags = vec![];
+        self.seek(self.data_offset)?;
+        loop {
+            let position = self.position()?;
+            let header = match FlvTagHeader::read(&mut self.reader) {
+                Ok(header) => header,
+                Err(ref e) if is_end_of_file(e) => break,
+                Err(e) => return Err(e),
+            };
+            // Only the beginning of the data is needed to tell keyframes apart.
+            let prefix_size = std::cmp::min(header.data_size, 16);
+            let prefix = match read_buffer(&mut self.reader, prefix_size) {
+                Ok(prefix) => prefix,
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(Error::Io(e)),
+            };
+            let keyframe = Keyframe {
+                timestamp: header.timestamp,
+                position,
+            };
+            match header.tag_type {
+                FLV_TAG_TYPE_VIDEO
+                    if VideoTagHeader::parse(&prefix).is_ok_and(|(header, _)| {
+                        header.is_keyframe() && !header.is_sequence_header()
+                    }) =>
+                {
+                    video_keyframes.push(keyframe)
+                }
+                FLV_TAG_TYPE_AUDIO
+                    if !AudioTagHeader::parse(&prefix)
+                        .is_ok_and(|(header, _)| header.is_sequence_header()) =>
+                {
+                    audio_tags.push(keyframe)
+                }
+                _ => {}
+            }
+            self.reader
+                .seek(SeekFrom::Current(
+                    (header.data_size - prefix_size) as i64 + 4,
+                ))
+                .map_err(Error::Io)?;
+        }
+        Ok(if video_keyframes.is_empty() {
+            audio_tags
+        } else {
+            video_keyframes
+        })
+    }
+
+    /// Reads the `onMetaData` properties and sequence headers at the beginning of the file,
+    /// which players need before any frame. Returns them with the position of the first other
+    /// tag.
+    pub fn read_headers(&mut self) -> Result<(Option<AmfObjectMap>, Vec<FlvTag>, u64)> {
+        let mut metadata = None;
+        let mut tags = vec![];
+        self.seek(self.data_offset)?;
+        loop {
+            let position = self.position()?;
+            let tag = match self.read_tag()? {
+                Some(tag) => tag,
+                None => return Ok((metadata, tags, position)),
+            };
+            let is_header = match tag.header.tag_type {
+                FLV_TAG_TYPE_SCRIPT => {
+                    if metadata.is_none() {
+                        metadata = decode_metadata(&tag.data)?;
+                    }
+                    true
+                }
+                FLV_TAG_TYPE_VIDEO => VideoTagHeader::parse(&tag.data)
+                    .is_ok_and(|(header, _)| header.is_sequence_header()),
+                FLV_TAG_TYPE_AUDIO => AudioTagHeader::parse(&tag.data)
+                    .is_ok_and(|(header, _)| header.is_sequence_header()),
+                _ => true,
+            };
+            if !is_header {
+                return Ok((metadata, tags, position));
+            }
+            tags.push(tag);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::amf::decode_amf_message;
+
+    fn metadata(buffer: &[u8]) -> AmfObject {
+        let mut cursor = Cursor::new(buffer);
+        cursor.set_position(13);
+        let header = FlvTagHeader::read(&mut cursor).unwrap();
+        assert_eq!(header.tag_type, FLV_TAG_TYPE_SCRIPT);
+        assert_eq!(
+            decode_amf_message(&mut cursor).unwrap(),
+            "onMetaData".into()
+        );
+        decode_amf_message(&mut cursor).unwrap()
+    }
+
+    #[test]
+    fn test_flv_writer() {
+        let mut flv = FlvWriter::create(Cursor::new(Vec::new()), true, true).unwrap();
+        let properties = [(String::from("width"), AmfObject::Number(1280.0))];
+        flv.write_metadata(0, properties.iter().cloned().collect())
+            .unwrap();
+        flv.write_tag(FLV_TAG_TYPE_VIDEO, 0x1000020, &[0x17, 0x1])
+            .unwrap();
+        let size = flv.size();
+        let buffer = flv.finish().unwrap().into_inner();
+        assert_eq!(buffer.len() as u64, size);
+        assert_eq!(
+            buffer[..13],
+            [b'F', b'L', b'V', 1, 5, 0, 0, 0, 9, 0, 0, 0, 0]
+        );
+        assert_eq!(
+            buffer[buffer.len() - 17..],
+            [9, 0, 0, 2, 0, 0, 0x20, 1, 0, 0, 0, 0x17, 0x1, 0, 0, 0, 13]
+        );
+        assert_eq!(
+            metadata(&buffer),
+            AmfObject::EcmaArray(vec![
+                (String::from("width"), AmfObject::Number(1280.0)),
+                (String::from("duration"), AmfObject::Number(16777.248)),
+                (String::from("filesize"), AmfObject::Number(size as f64)),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_flv_append() {
+        let mut flv = FlvWriter::create(Cursor::new(Vec::new()), true, false).unwrap();
+        flv.write_metadata(0, AmfObjectMap::new()).unwrap();
+        flv.write_tag(FLV_TAG_TYPE_AUDIO, 1000, &[0xAF, 0x1])
+            .unwrap();
+        let buffer = flv.finish().unwrap().into_inner();
+        let mut reader = Cursor::new(buffer.clone());
+        let mut flv = FlvWriter::append(Cursor::new(buffer), &mut reader).unwrap();
+        assert_eq!(flv.duration(), 1000);
+        flv.write_tag(FLV_TAG_TYPE_AUDIO, 3000, &[0xAF, 0x1])
+            .unwrap();
+        let size = flv.size();
+        let buffer = flv.finish().unwrap().into_inner();
+        assert_eq!(buffer.len() as u64, size);
+        assert_eq!(
+            metadata(&buffer),
+            AmfObject::EcmaArray(vec![
+                (String::from("duration"), AmfObject::Number(3.0)),
+                (String::from("filesize"), AmfObject::Number(size as f64)),
+            ])
+        );
+        let mut reader = Cursor::new(vec![0x0; 13]);
+        assert!(FlvWriter::append(Cursor::new(vec![0x0; 13]), &mut reader).is_err());
+    }
+
+    /// A file with metadata, an AVC sequence header and keyframes at 0 and 2000.
+    fn video_file(properties: AmfObjectMap) -> (Vec<u8>, Vec<u64>) {
+        let mut flv = FlvWriter::create(Cursor::new(Vec::new()), false, true).unwrap();
+        flv.write_metadata(0, properties).unwrap();
+        flv.write_tag(FLV_TAG_TYPE_VIDEO, 0, &[0x17, 0x0, 0x0, 0x0, 0x0])
+            .unwrap();
+        let mut positions = vec![];
+        for timestamp in [0, 1000, 2000, 3000] {
+            positions.push(flv.size());
+            let frame_type = if timestamp % 2000 == 0 { 0x17 } else { 0x27 };
+            flv.write_tag(
+                FLV_TAG_TYPE_VIDEO,
+                timestamp,
+                &[frame_type, 0x1, 0x0, 0x0, 0x0],
+            )
+            .unwrap();
+        }
+        (flv.finish().unwrap().into_inner(), positions)
+    }
+
+    #[test]
+    fn test_flv_reader() {
+        let (buffer, positions) = video_file(AmfObjectMap::new());
+        let mut reader = FlvReader::open(Cursor::new(&buffer)).unwrap();
+        let (metadata, headers, position) = reader.read_headers().unwrap();
+        assert!(metadata.unwrap().get("duration").is_some());
+        assert_eq!(headers.len(), 2);
+        assert_eq!(position, positions[0]);
+        assert_eq!(
+            reader.keyframes(None).unwrap(),
+            [
+                Keyframe {
+                    timestamp: 0,
+                    position: positions[0],
+                },
+                Keyframe {
+                    timestamp: 2000,
+                    position: positions[2],
+                },
+            ]
+        );
+        reader.seek(positions[3]).unwrap();
+        let tag = reader.read_tag().unwrap().unwrap();
+        assert_eq!(tag.header.timestamp, 3000);
+        assert_eq!(tag.data, [0x27, 0x1, 0x0, 0x0, 0x0]);
+        assert!(reader.read_tag().unwrap().is_none());
+        // A truncated tag ends the file.
+        let mut reader = FlvReader::open(Cursor::new(&buffer[..buffer.len() - 6])).unwrap();
+        reader.seek(positions[3]).unwrap();
+        assert!(reader.read_tag().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_flv_metadata_keyframes() {
+        let keyframes = [
+            (
+                String::from("times"),
+                AmfObject::StrictArray(vec![0.0.into(), 2.5.into()]),
+            ),
+            (
+                String::from("filepositions"),
+                AmfObject::StrictArray(vec![100.0.into(), 200.0.into()]),
+            ),
+        ];
+        let properties = [(
+            String::from("keyframes"),
+            AmfObject::Object(keyframes.iter().cloned().collect()),
+        )];
+        let (buffer, _) = video_file(properties.iter().cloned().collect());
+        let mut reader = FlvReader::open(Cursor::new(&buffer)).unwrap();
+        let (metadata, _, _) = reader.read_headers().unwrap();
+        assert_eq!(
+            reader.keyframes(metadata.as_ref()).unwrap(),
+            [
+                Keyframe {
+                    timestamp: 0,
+                    position: 100,
+                },
+                Keyframe {
+                    timestamp: 2500,
+                    position: 200,
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,132 @@
+//! Cache of the messages a new subscriber needs to start playing a live stream right away.
+
+use std::sync::Arc;
+
+use crate::constant::*;
+use crate::media::{AudioTagHeader, VideoTagHeader};
+use crate::queue::is_keyframe;
+use crate::stream::SharedMessage;
+
+/// The latest sequence headers and the audio and video messages since the last keyframe.
+#[derive(Debug, Default)]
+pub struct GopCache {
+    max_size: usize,
+    video_sequence_header: Option<Arc<SharedMessage>>,
+    audio_sequence_header: Option<Arc<SharedMessage>>,
+    /// Messages of the current group of pictures, starting with its keyframe.
+    messages: Vec<Arc<SharedMessage>>,
+    size: usize,
+}
+
+impl GopCache {
+    /// Creates a cache keeping up to `max_size` payload bytes of the current group of pictures.
+    /// Sequence headers are always kept.
+    pub fn new(max_size: usize) -> Self {
+        Self {
+            max_size,
+            ..Self::default()
+        }
+    }
+
+    pub fn push(&mut self, message: &Arc<SharedMessage>) {
+        let payload = &message.payload;
+        match message.header.message_type_id {
+            RTMP_VIDEO_MESSAGE
+                if VideoTagHeader::parse(payload)
+                    .is_ok_and(|(header, _)| header.is_sequence_header()) =>
+            {
+                self.video_sequence_header = Some(Arc::clone(message));
+                return;
+            }
+            RTMP_AUDIO_MESSAGE
+                if AudioTagHeader::parse(payload)
+                    .is_ok_and(|(header, _)| header.is_sequence_header()) =>
+            {
+                self.audio_sequence_header = Some(Arc::clone(message));
+                return;
+            }
+            RTMP_VIDEO_MESSAGE | RTMP_AUDIO_MESSAGE => {}
+            _ => return,
+        }
+        if is_keyframe(message) {
+            self.clear();
+        } else if self.messages.is_empty() {
+            // Nothing is cached until the next keyframe.
+            return;
+        }
+        // A group of pictures which does not fit is dropped as a whole.
+        if self.size + payload.len() > self.max_size {
+            self.clear();
+            return;
+        }
+        self.size += payload.len();
+        self.messages.push(Arc::clone(message));
+    }
+
+    fn clear(&mut self) {
+        self.messages.clear();
+        self.size = 0;
+    }
+
+    /// Messages to send to a new subscriber before live ones: the sequence headers followed by
+    /// the current group of pictures.
+    pub fn messages(&self) -> impl Iterator<Item = &Arc<SharedMessage>> {
+        self.video_sequence_header
+            .iter()
+            .chain(self.audio_sequence_header.iter())
+            .chain(self.messages.iter())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stream::ChunkMessageHeader;
+
+    /// An AVC or AAC message whose last byte identifies it.
+    fn message(message_type_id: u8, tag: u8, packet_type: u8, id: u8) -> Arc<SharedMessage> {
+        let payload = if message_type_id == RTMP_VIDEO_MESSAGE {
+            vec![tag, packet_type, 0x0, 0x0, 0x0, id]
+        } else {
+            vec![tag, packet_type, id]
+        };
+        let header = ChunkMessageHeader::new(0, payload.len(), message_type_id, 1);
+        Arc::new(SharedMessage::new(header, payload))
+    }
+
+    fn cached(cache: &GopCache) -> Vec<u8> {
+        cache
+            .messages()
+            .map(|m| *m.payload.last().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_gop_cache() {
+        let mut cache = GopCache::new(1024);
+        cache.push(&message(RTMP_VIDEO_MESSAGE, 0x27, 0x1, 0));
+        assert!(cached(&cache).is_empty());
+        cache.push(&message(RTMP_VIDEO_MESSAGE, 0x17, 0x0, 1));
+        cache.push(&message(RTMP_AUDIO_MESSAGE, 0xAF, 0x0, 2));
+        cache.push(&message(RTMP_VIDEO_MESSAGE, 0x17, 0x1, 3));
+        cache.push(&message(RTMP_AUDIO_MESSAGE, 0xAF, 0x1, 4));
+        cache.push(&message(RTMP_VIDEO_MESSAGE, 0x27, 0x1, 5));
+        assert_eq!(cached(&cache), [1, 2, 3, 4, 5]);
+        // A new keyframe starts a new group of pictures.
+        cache.push(&message(RTMP_VIDEO_MESSAGE, 0x17, 0x1, 6));
+        assert_eq!(cached(&cache), [1, 2, 6]);
+    }
+
+    #[test]
+    fn test_gop_cache_size() {
+        let mut cache = GopCache::new(12);
+        cache.push(&message(RTMP_VIDEO_MESSAGE, 0x17, 0x1, 0));
+        cache.push(&message(RTMP_VIDEO_MESSAGE, 0x27, 0x1, 1));
+        assert_eq!(cached(&cache), [0, 1]);
+        cache.push(&message(RTMP_VIDEO_MESSAGE, 0x27, 0x1, 2));
+        assert!(cached(&cache).is_empty());
+        // The rest of the group of pictures is not cached either.
+        cache.push(&message(RTMP_AUDIO_MESSAGE, 0xAF, 0x1, 3));
+        assert!(cached(&cache).is_empty());
+    }
+}
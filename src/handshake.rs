@@ -0,0 +1,175 @@
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+pub const HANDSHAKE_SIZE: usize = 1536;
+pub const DIGEST_SIZE: usize = 32;
+
+// Version advertised in S1 when responding to a digest-based handshake.
+const SERVER_VERSION: [u8; 4] = [0x04, 0x05, 0x00, 0x01];
+// Version advertised in C1, asking the server for a digest-based handshake.
+const CLIENT_VERSION: [u8; 4] = [0x80, 0x00, 0x07, 0x02];
+
+const GENUINE_KEY_SUFFIX: [u8; 32] = [
+    0xF0, 0xEE, 0xC2, 0x4A, 0x80, 0x68, 0xBE, 0xE8, 0x2E, 0x00, 0xD0, 0xD1, 0x02, 0x9E, 0x7E, 0x57,
+    0x6E, 0xEC, 0x5D, 0x2D, 0x29, 0x80, 0x6F, 0xAB, 0x93, 0xB8, 0xE6, 0x36, 0xCF, 0xEB, 0x31, 0xAE,
+];
+const GENUINE_FMS: &[u8] = b"Genuine Adobe Flash Media Server 001";
+const GENUINE_FP: &[u8] = b"Genuine Adobe Flash Player 001";
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Location of the digest block inside C1/S1.
+///
+/// In schema 0 the key block comes first and the digest block starts at byte 772, while in
+/// schema 1 the digest block directly follows the time and version fields.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DigestSchema {
+    Schema0,
+    Schema1,
+}
+
+impl DigestSchema {
+    fn block_offset(self) -> usize {
+        match self {
+            DigestSchema::Schema0 => 772,
+            DigestSchema::Schema1 => 8,
+        }
+    }
+
+    /// Offset of the 32-byte digest inside a handshake packet.
+    pub fn digest_offset(self, packet: &[u8]) -> usize {
+        let base = self.block_offset();
+        let sum = packet[base..base + 4]
+            .iter()
+            .fold(0_usize, |sum, &byte| sum + byte as usize);
+        sum % 728 + base + 4
+    }
+}
+
+fn genuine_key(prefix: &[u8], full: bool) -> Vec<u8> {
+    let mut key = Vec::from(prefix);
+    if full {
+        key.extend_from_slice(&GENUINE_KEY_SUFFIX);
+    }
+    key
+}
+
+/// Key used by servers to sign S1 (`full == false`) or to derive the S2 key (`full == true`).
+pub fn server_key(full: bool) -> Vec<u8> {
+    genuine_key(GENUINE_FMS, full)
+}
+
+/// Key used by clients to sign C1 (`full == false`) or to derive the C2 key (`full == true`).
+pub fn client_key(full: bool) -> Vec<u8> {
+    genuine_key(GENUINE_FP, full)
+}
+
+pub fn hmac_sha256(key: &[u8], parts: &[&[u8]]) -> [u8; DIGEST_SIZE] {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC accepts keys of any length");
+    parts.iter().for_each(|part| mac.update(part));
+    let mut digest = [0x0; DIGEST_SIZE];
+    digest.copy_from_slice(&mac.finalize().into_bytes());
+    digest
+}
+
+/// Computes the digest of `packet` with the 32 bytes at `offset` left out.
+fn packet_digest(packet: &[u8], offset: usize, key: &[u8]) -> [u8; DIGEST_SIZE] {
+    hmac_sha256(key, &[&packet[..offset], &packet[offset + DIGEST_SIZE..]])
+}
+
+/// Returns the schema and digest of a C1/S1 packet signed with `key`, or `None` if the packet
+/// carries no valid digest under either schema.
+pub fn find_digest(packet: &[u8], key: &[u8]) -> Option<(DigestSchema, [u8; DIGEST_SIZE])> {
+    [DigestSchema::Schema1, DigestSchema::Schema0]
+        .iter()
+        .find_map(|&schema| {
+            let offset = schema.digest_offset(packet);
+            let digest = packet_digest(packet, offset, key);
+            if packet[offset..offset + DIGEST_SIZE] == digest {
+                Some((schema, digest))
+            } else {
+                None
+            }
+        })
+}
+
+/// Generates a random C1/S1 packet carrying `version` and a digest signed with `key`.
+pub fn generate_digested_packet(schema: DigestSchema, version: [u8; 4], key: &[u8]) -> Vec<u8> {
+    let mut packet: Vec<_> = (0..HANDSHAKE_SIZE)
+        .map(|i| if i < 4 { 0 } else { rand::random::<u8>() })
+        .collect();
+    packet[4..8].copy_from_slice(&version);
+    let offset = schema.digest_offset(&packet);
+    let digest = packet_digest(&packet, offset, key);
+    packet[offset..offset + DIGEST_SIZE].copy_from_slice(&digest);
+    packet
+}
+
+/// Generates a random C2/S2 packet whose trailing 32 bytes sign the packet with a key derived
+/// from the peer's C1/S1 digest.
+pub fn generate_response_packet(peer_digest: &[u8], key: &[u8]) -> Vec<u8> {
+    let mut packet: Vec<_> = (0..HANDSHAKE_SIZE).map(|_| rand::random::<u8>()).collect();
+    let signature = response_signature(&packet, peer_digest, key);
+    packet[HANDSHAKE_SIZE - DIGEST_SIZE..].copy_from_slice(&signature);
+    packet
+}
+
+pub fn response_signature(packet: &[u8], peer_digest: &[u8], key: &[u8]) -> [u8; DIGEST_SIZE] {
+    let derived_key = hmac_sha256(key, &[peer_digest]);
+    hmac_sha256(&derived_key, &[&packet[..HANDSHAKE_SIZE - DIGEST_SIZE]])
+}
+
+/// Builds the S1 packet of a digest-based handshake, using the schema the client chose.
+pub fn generate_s1(schema: DigestSchema) -> Vec<u8> {
+    generate_digested_packet(schema, SERVER_VERSION, &server_key(false))
+}
+
+/// Builds the S2 packet of a digest-based handshake from the C1 digest.
+pub fn generate_s2(c1_digest: &[u8]) -> Vec<u8> {
+    generate_response_packet(c1_digest, &server_key(true))
+}
+
+/// Builds the C1 packet of a digest-based handshake.
+pub fn generate_c1() -> Vec<u8> {
+    generate_digested_packet(DigestSchema::Schema1, CLIENT_VERSION, &client_key(false))
+}
+
+/// Builds the C2 packet of a digest-based handshake from the S1 digest.
+pub fn generate_c2(s1_digest: &[u8]) -> Vec<u8> {
+    generate_response_packet(s1_digest, &client_key(true))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_digest() {
+        for &schema in &[DigestSchema::Schema0, DigestSchema::Schema1] {
+            let c1 = generate_digested_packet(schema, [0x80, 0x0, 0x7, 0x2], &client_key(false));
+            let (found, _) = find_digest(&c1, &client_key(false)).unwrap();
+            assert_eq!(found, schema);
+            assert!(find_digest(&c1, &server_key(false)).is_none());
+        }
+    }
+
+    #[test]
+    fn test_find_digest_simple() {
+        assert!(find_digest(&[0x0; HANDSHAKE_SIZE], &client_key(false)).is_none());
+    }
+
+    #[test]
+    fn test_response_signature() {
+        let c1 = generate_digested_packet(
+            DigestSchema::Schema1,
+            [0x80, 0x0, 0x7, 0x2],
+            &client_key(false),
+        );
+        let (_, digest) = find_digest(&c1, &client_key(false)).unwrap();
+        let s2 = generate_s2(&digest);
+        assert_eq!(
+            s2[HANDSHAKE_SIZE - DIGEST_SIZE..],
+            response_signature(&s2, &digest, &server_key(true))
+        );
+    }
+}
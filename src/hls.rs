@@ -0,0 +1,347 @@
+//! HLS packaging of live streams: MPEG-TS segments cut on keyframes, listed by a sliding-window
+//! playlist.
+
+use std::collections::VecDeque;
+use std::fs;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use crate::config::Config;
+use crate::constant::*;
+use crate::error::{Error, Result};
+use crate::media::{AudioTagHeader, MediaInfo, VideoTagHeader};
+use crate::record::sanitize;
+use crate::ts::{adts_header, avc_to_annex_b, TsMuxer};
+
+/// Where the playlists and segments of published streams go.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HlsMode {
+    Disabled,
+    /// Served over HTTP from memory.
+    Http,
+    /// Served over HTTP and written to disk.
+    Disk,
+}
+
+impl FromStr for HlsMode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(HlsMode::Disabled),
+            "http" => Ok(HlsMode::Http),
+            "disk" => Ok(HlsMode::Disk),
+            _ => Err(Error::InvalidConfig(format!("unknown HLS mode {}", s))),
+        }
+    }
+}
+
+/// A finished segment.
+#[derive(Debug, Clone)]
+pub struct Segment {
+    pub sequence: u64,
+    /// Duration in milliseconds.
+    pub duration: u32,
+    pub data: Arc<[u8]>,
+}
+
+/// Packages the H.264 and AAC messages of a stream into segments of about the target duration.
+#[derive(Debug)]
+pub struct HlsPackager {
+    /// Directory of the playlist and segment files, in disk mode.
+    directory: Option<PathBuf>,
+    /// Minimum duration of a segment in milliseconds.
+    target_duration: u32,
+    /// Longest segment duration so far. The target duration of the playlist never drops below
+    /// it, since players expect it to stay the same.
+    max_segment_duration: u32,
+    playlist_length: usize,
+    media_info: MediaInfo,
+    muxer: TsMuxer,
+    segments: VecDeque<Segment>,
+    next_sequence: u64,
+    /// Segment being written, and the timestamp of its first frame.
+    current: Vec<u8>,
+    current_start: Option<u32>,
+    last_timestamp: u32,
+    ended: bool,
+}
+
+impl HlsPackager {
+    pub fn new(config: &Config, name: &str) -> Self {
+        Self {
+            directory: if config.hls_mode == HlsMode::Disk {
+                Some(config.hls_directory.join(sanitize(name)))
+            } else {
+                None
+            },
+            target_duration: config.hls_segment_duration.saturating_mul(1000),
+            max_segment_duration: 0,
+            playlist_length: std::cmp::max(config.hls_playlist_length, 1),
+            media_info: MediaInfo::default(),
+            muxer: TsMuxer::new(),
+            segments: VecDeque::new(),
+            next_sequence: 0,
+            current: Vec::new(),
+            current_start: None,
+            last_timestamp: 0,
+            ended: false,
+        }
+    }
+
+    fn has_video(&self) -> bool {
+        self.media_info.avc_config.is_some()
+    }
+
+    fn is_segment_full(&self, timestamp: u32) -> bool {
+        self.current_start
+            .is_some_and(|start| timestamp.wrapping_sub(start) >= self.target_duration)
+    }
+
+    fn start_segment(&mut self, timestamp: u32) {
+        let has_audio = self.media_info.aac_config.is_some();
+        let has_video = self.has_video();
+        self.current.clear();
+        self.muxer
+            .write_tables(&mut self.current, has_audio, has_video);
+        self.current_start = Some(timestamp);
+    }
+
+    /// Finishes the current segment, which lasts until `end`, and updates the playlist.
+    fn finish_segment(&mut self, end: u32) -> Result<()> {
+        let start = match self.current_start.take() {
+            Some(start) => start,
+            None => return Ok(()),
+        };
+        let segment = Segment {
+            sequence: self.next_sequence,
+            duration: end.wrapping_sub(start),
+            data: Arc::from(std::mem::take(&mut self.current)),
+        };
+        self.next_sequence += 1;
+        self.max_segment_duration = std::cmp::max(self.max_segment_duration, segment.duration);
+        if let Some(ref directory) = self.directory {
+            fs::create_dir_all(directory).map_err(Error::Io)?;
+            fs::write(
+                directory.join(format!("{}.ts", segment.sequence)),
+                &segment.data,
+            )
+            .map_err(Error::Io)?;
+        }
+        self.segments.push_back(segment);
+        while self.segments.len() > self.playlist_length {
+            let segment = self.segments.pop_front();
+            if let (Some(directory), Some(segment)) = (&self.directory, segment) {
+                // Players may still be downloading it.
+                let _ = fs::remove_file(directory.join(format!("{}.ts", segment.sequence)));
+            }
+        }
+        self.write_playlist()
+    }
+
+    /// Writes the playlist file in disk mode, replacing the previous one atomically.
+    fn write_playlist(&self) -> Result<()> {
+        if let Some(ref directory) = self.directory {
+            let path = directory.join("index.m3u8");
+            let temporary_path = directory.join("index.m3u8.tmp");
+            fs::write(&temporary_path, self.playlist()).map_err(Error::Io)?;
+            fs::rename(&temporary_path, &path).map_err(Error::Io)?;
+        }
+        Ok(())
+    }
+
+    /// Adds an audio or video message. Segments start with a keyframe, or with any audio frame
+    /// for streams without video. Codecs other than H.264 and AAC are ignored.
+    pub fn push(&mut self, message_type_id: u8, timestamp: u32, payload: &[u8]) -> Result<()> {
+        match message_type_id {
+            RTMP_VIDEO_MESSAGE => self.push_video(timestamp, payload),
+            RTMP_AUDIO_MESSAGE => self.push_audio(timestamp, payload),
+            _ => Ok(()),
+        }
+    }
+
+    fn push_video(&mut self, timestamp: u32, payload: &[u8]) -> Result<()> {
+        let (header, data) = VideoTagHeader::parse(payload)?;
+        if !header.codec.is_avc() {
+            return Ok(());
+        }
+        if header.is_sequence_header() {
+            return self.media_info.update_video(&header, data);
+        }
+        if header.packet_type != Some(FLV_PACKET_TYPE_CODED_FRAMES)
+            && header.packet_type != Some(FLV_VIDEO_PACKET_TYPE_CODED_FRAMES_X)
+            || !self.has_video()
+        {
+            return Ok(());
+        }
+        if header.is_keyframe() && self.is_segment_full(timestamp) {
+            self.finish_segment(timestamp)?;
+        }
+        if self.current_start.is_none() {
+            if !header.is_keyframe() {
+                return Ok(());
+            }
+            self.start_segment(timestamp);
+        }
+        let mut access_unit = Vec::with_capacity(data.len() + 64);
+        if let Some(ref config) = self.media_info.avc_config {
+            avc_to_annex_b(data, config, &mut access_unit)?;
+        }
+        let dts = u64::from(timestamp) * 90;
+        let pts = std::cmp::max(i64::from(timestamp) + i64::from(header.composition_time), 0);
+        self.muxer.write_video(
+            &mut self.current,
+            pts as u64 * 90,
+            dts,
+            &access_unit,
+            header.is_keyframe(),
+        );
+        self.last_timestamp = timestamp;
+        Ok(())
+    }
+
+    fn push_audio(&mut self, timestamp: u32, payload: &[u8]) -> Result<()> {
+        let (header, data) = AudioTagHeader::parse(payload)?;
+        if !header.codec.is_aac() {
+            return Ok(());
+        }
+        if header.is_sequence_header() {
+            return self.media_info.update_audio(&header, data);
+        }
+        let config = match self.media_info.aac_config {
+            Some(ref config) if header.packet_type == Some(FLV_PACKET_TYPE_CODED_FRAMES) => config,
+            _ => return Ok(()),
+        };
+        let mut frame = adts_header(config, data.len())?.to_vec();
+        frame.extend_from_slice(data);
+        if !self.has_video() {
+            if self.is_segment_full(timestamp) {
+                self.finish_segment(timestamp)?;
+            }
+            if self.current_start.is_none() {
+                self.start_segment(timestamp);
+            }
+        } else if self.current_start.is_none() {
+            return Ok(());
+        }
+        self.muxer
+            .write_audio(&mut self.current, u64::from(timestamp) * 90, &frame);
+        self.last_timestamp = timestamp;
+        Ok(())
+    }
+
+    /// Media playlist listing the latest segments, whose URIs are relative to the playlist.
+    pub fn playlist(&self) -> String {
+        let target_duration = std::cmp::max(self.target_duration, self.max_segment_duration);
+        let media_sequence = self
+            .segments
+            .front()
+            .map_or(self.next_sequence, |segment| segment.sequence);
+        let mut playlist = format!(
+            "#EXTM3U\n#EXT-X-VERSION:3\n#EXT-X-TARGETDURATION:{}\n#EXT-X-MEDIA-SEQUENCE:{}\n",
+            target_duration.div_ceil(1000),
+            media_sequence
+        );
+        for segment in &self.segments {
+            playlist.push_str(&format!(
+                "#EXTINF:{:.3},\n{}.ts\n",
+                f64::from(segment.duration) / 1000.0,
+                segment.sequence
+            ));
+        }
+        if self.ended {
+            playlist.push_str("#EXT-X-ENDLIST\n");
+        }
+        playlist
+    }
+
+    pub fn segment(&self, sequence: u64) -> Option<Arc<[u8]>> {
+        self.segments
+            .iter()
+            .find(|segment| segment.sequence == sequence)
+            .map(|segment| Arc::clone(&segment.data))
+    }
+}
+
+impl Drop for HlsPackager {
+    /// Finishes the last segment and ends the playlist written to disk.
+    fn drop(&mut self) {
+        self.ended = true;
+        let result = if self.current_start.is_some() {
+            self.finish_segment(self.last_timestamp)
+        } else {
+            self.write_playlist()
+        };
+        if let Err(e) = result {
+            eprintln!("Failed to finish HLS playlist: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::media::tests::AVC_SEQUENCE_HEADER;
+    use crate::ts::TS_PACKET_SIZE;
+
+    fn frame(frame_type: u8) -> Vec<u8> {
+        vec![
+            frame_type, 0x1, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x2, 0x65, 0x88,
+        ]
+    }
+
+    #[test]
+    fn test_hls_packager() {
+        let config = Config {
+            hls_segment_duration: 2,
+            hls_playlist_length: 2,
+            ..Config::default()
+        };
+        let mut packager = HlsPackager::new(&config, "test");
+        packager
+            .push(RTMP_VIDEO_MESSAGE, 0, &AVC_SEQUENCE_HEADER)
+            .unwrap();
+        packager
+            .push(RTMP_AUDIO_MESSAGE, 0, &[0xAF, 0x0, 0x12, 0x10])
+            .unwrap();
+        // Frames before the first keyframe are dropped.
+        packager.push(RTMP_VIDEO_MESSAGE, 0, &frame(0x27)).unwrap();
+        for timestamp in (0..=7000).step_by(500) {
+            let frame_type = if timestamp % 1500 == 0 { 0x17 } else { 0x27 };
+            packager
+                .push(RTMP_VIDEO_MESSAGE, timestamp, &frame(frame_type))
+                .unwrap();
+            packager
+                .push(RTMP_AUDIO_MESSAGE, timestamp, &[0xAF, 0x1, 0x21])
+                .unwrap();
+        }
+        // Segments are cut on the first keyframe after 2 seconds: at 3000 and 6000.
+        assert_eq!(
+            packager.playlist(),
+            "#EXTM3U\n#EXT-X-VERSION:3\n#EXT-X-TARGETDURATION:3\n#EXT-X-MEDIA-SEQUENCE:0\n\
+             #EXTINF:3.000,\n0.ts\n#EXTINF:3.000,\n1.ts\n"
+        );
+        let segment = packager.segment(1).unwrap();
+        assert_eq!(segment.len() % TS_PACKET_SIZE, 0);
+        // PAT, PMT, then the keyframe with the random access indicator.
+        assert_eq!(segment[2 * TS_PACKET_SIZE + 5] & 0x40, 0x40);
+        assert!(packager.segment(2).is_none());
+        packager
+            .push(RTMP_VIDEO_MESSAGE, 9000, &frame(0x17))
+            .unwrap();
+        assert!(packager.segment(0).is_none());
+        assert!(packager.playlist().contains("#EXT-X-MEDIA-SEQUENCE:1\n"));
+        // The target duration stays at the longest segment after it leaves the playlist.
+        for timestamp in [14000, 16000, 18000] {
+            packager
+                .push(RTMP_VIDEO_MESSAGE, timestamp, &frame(0x17))
+                .unwrap();
+        }
+        assert_eq!(
+            packager.playlist(),
+            "#EXTM3U\n#EXT-X-VERSION:3\n#EXT-X-TARGETDURATION:5\n#EXT-X-MEDIA-SEQUENCE:4\n\
+             #EXTINF:2.000,\n4.ts\n#EXTINF:2.000,\n5.ts\n"
+        );
+    }
+}
@@ -0,0 +1,500 @@
+//! HTTP output of live streams. `GET /<app>/<stream>.flv` serves a published stream as an FLV
+//! byte stream (HTTP-FLV), as pulled by web players such as flv.js and by CDNs.
+//! `GET /<app>/<stream>/index.m3u8` serves its HLS playlist, which lists segments
+//! `/<app>/<stream>/<sequence>.ts`, and `GET /<app>/<stream>/ll.m3u8` its low-latency HLS
+//! playlist, which lists fragmented MP4 segments `<sequence>.m4s` and their parts
+//! `<sequence>.<part>.m4s`. `GET /<app>/<stream>/index.mpd` serves its DASH MPD, which lists
+//! segments `<track>-<number>.m4s` of the `video` and `audio` tracks.
+
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader, IoSlice, Read, Write};
+use std::net::TcpStream;
+use std::os::unix::io::AsRawFd;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use crate::cmaf::LlHlsStream;
+use crate::config::Config;
+use crate::constant::*;
+use crate::dash::DashPackager;
+use crate::error::{Error, Result};
+use crate::flv::{encode_header, FlvTagHeader, FLV_TAG_HEADER_SIZE};
+use crate::object::ConnectObject;
+use crate::server::{RtmpClient, RtmpMediaStream};
+use crate::stream::SharedMessage;
+use crate::utils::{percent_decode, write_all_vectored};
+
+const MAX_HEADER_SIZE: usize = 8192;
+
+/// Request line and headers of an HTTP request.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HttpRequest {
+    pub method: String,
+    /// Path of the request target, without the query.
+    pub path: String,
+    /// Query of the request target, without the `?`.
+    pub query: String,
+    /// Headers, with lowercase names.
+    pub headers: Vec<(String, String)>,
+}
+
+impl HttpRequest {
+    pub fn read<R: BufRead>(reader: &mut R) -> Result<Self> {
+        let mut lines = vec![];
+        let mut size = 0;
+        loop {
+            let mut line = String::new();
+            let limit = (MAX_HEADER_SIZE - size) as u64;
+            let n = reader.take(limit).read_line(&mut line).map_err(Error::Io)?;
+            if n == 0 {
+                return Err(Error::InvalidHttpRequest(if size == MAX_HEADER_SIZE {
+                    "Headers are too large"
+                } else {
+                    "Connection closed before the end of the headers"
+                }));
+            }
+            size += n;
+            let line = line.trim_end_matches(['\r', '\n']);
+            if line.is_empty() {
+                break;
+            }
+            lines.push(String::from(line));
+        }
+        let mut lines = lines.into_iter();
+        let request_line = lines.next().unwrap_or_default();
+        let mut parts = request_line.split(' ');
+        let (method, target) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+            (Some(method), Some(target), Some(version), None) if version.starts_with("HTTP/1.") => {
+                (method, target)
+            }
+            _ => return Err(Error::InvalidHttpRequest("Malformed request line")),
+        };
+        let headers = lines
+            .map(|line| {
+                let (name, value) = line
+                    .split_once(':')
+                    .ok_or(Error::InvalidHttpRequest("Malformed header"))?;
+                Ok((name.trim().to_ascii_lowercase(), String::from(value.trim())))
+            })
+            .collect::<Result<_>>()?;
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+        Ok(Self {
+            method: String::from(method),
+            path: String::from(path),
+            query: String::from(query),
+            headers,
+        })
+    }
+
+    pub fn query_parameter(&self, name: &str) -> Option<String> {
+        self.query
+            .split('&')
+            .filter_map(|parameter| parameter.split_once('='))
+            .find(|&(n, _)| n == name)
+            .and_then(|(_, value)| percent_decode(value))
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// Name of the stream requested by `/<app>/<stream>.flv`. As for RTMP, streams are looked up by
+/// name only, whatever the application.
+fn flv_stream_name(path: &str) -> Option<String> {
+    let (_, name) = path.strip_prefix('/')?.split_once('/')?;
+    let name = percent_decode(name.strip_suffix(".flv")?)?;
+    if name.is_empty() {
+        None
+    } else {
+        Some(name)
+    }
+}
+
+/// Stream name and file name requested by `/<app>/<stream>/<file>`.
+fn stream_file(path: &str) -> Option<(String, &str)> {
+    let (_, rest) = path.strip_prefix('/')?.split_once('/')?;
+    let (name, file) = rest.rsplit_once('/')?;
+    let name = percent_decode(name)?;
+    if name.is_empty() {
+        None
+    } else {
+        Some((name, file))
+    }
+}
+
+fn write_response<W: Write>(writer: &mut W, status: &str, headers: &[(&str, &str)]) -> Result<()> {
+    let mut response = format!("HTTP/1.1 {}\r\n", status);
+    // Web players are usually served from another origin.
+    response.push_str("Access-Control-Allow-Origin: *\r\n");
+    for (name, value) in headers {
+        response.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    response.push_str("\r\n");
+    writer.write_all(response.as_bytes()).map_err(Error::Io)
+}
+
+fn write_file<W: Write>(
+    writer: &mut W,
+    content_type: &str,
+    cache_control: &str,
+    body: &[u8],
+) -> Result<()> {
+    write_response(
+        writer,
+        "200 OK",
+        &[
+            ("Content-Type", content_type),
+            ("Content-Length", &body.len().to_string()),
+            ("Cache-Control", cache_control),
+            ("Connection", "close"),
+        ],
+    )?;
+    writer.write_all(body).map_err(Error::Io)
+}
+
+fn write_error<W: Write>(writer: &mut W, status: &str) -> Result<()> {
+    write_response(
+        writer,
+        status,
+        &[("Content-Length", "0"), ("Connection", "close")],
+    )
+}
+
+/// Data messages of publishers start with `@setDataFrame`, which is not part of script tags.
+fn strip_set_data_frame(payload: &[u8]) -> &[u8] {
+    const SET_DATA_FRAME: &[u8] = b"\x02\x00\x0D@setDataFrame";
+    payload.strip_prefix(SET_DATA_FRAME).unwrap_or(payload)
+}
+
+/// Writes an audio, video or data message as an FLV tag, in an HTTP chunk of its own.
+fn write_tag<W: Write>(writer: &mut W, message: &SharedMessage) -> io::Result<()> {
+    let message_type_id = message.header.message_type_id;
+    let payload = match message_type_id {
+        RTMP_AUDIO_MESSAGE | RTMP_VIDEO_MESSAGE => &message.payload[..],
+        RTMP_DATA_MESSAGE_AMF0 => strip_set_data_frame(&message.payload),
+        _ => return Ok(()),
+    };
+    let header = FlvTagHeader {
+        tag_type: message_type_id,
+        data_size: payload.len(),
+        timestamp: message.header.timestamp,
+    }
+    .encode();
+    let tag_size = ((FLV_TAG_HEADER_SIZE + payload.len()) as u32).to_be_bytes();
+    let chunk_size = format!("{:X}\r\n", FLV_TAG_HEADER_SIZE + payload.len() + 4);
+    write_all_vectored(
+        writer,
+        &mut [
+            IoSlice::new(chunk_size.as_bytes()),
+            IoSlice::new(&header),
+            IoSlice::new(payload),
+            IoSlice::new(&tag_size),
+            IoSlice::new(b"\r\n"),
+        ],
+    )
+}
+
+/// Serves a request of an HTTP connection.
+pub fn serve_http(
+    mut stream: TcpStream,
+    media_streams: Arc<Mutex<HashMap<String, RtmpMediaStream>>>,
+    config: &Config,
+) -> Result<()> {
+    let request = HttpRequest::read(&mut BufReader::new(stream.try_clone().map_err(Error::Io)?))?;
+    eprintln!("{} {}", request.method, request.path);
+    match request.method.as_str() {
+        "GET" => {}
+        "OPTIONS" => {
+            // CORS preflight request.
+            let allowed_headers = request
+                .header("access-control-request-headers")
+                .unwrap_or("*");
+            return write_response(
+                &mut stream,
+                "204 No Content",
+                &[
+                    ("Access-Control-Allow-Methods", "GET, OPTIONS"),
+                    ("Access-Control-Allow-Headers", allowed_headers),
+                    ("Access-Control-Max-Age", "86400"),
+                    ("Content-Length", "0"),
+                ],
+            );
+        }
+        _ => return write_error(&mut stream, "405 Method Not Allowed"),
+    }
+    if let Some(name) = flv_stream_name(&request.path) {
+        serve_flv(stream, name, media_streams, config)
+    } else if let Some((name, file)) = stream_file(&request.path) {
+        serve_stream_file(stream, &name, file, &request, &media_streams)
+    } else {
+        write_error(&mut stream, "404 Not Found")
+    }
+}
+
+/// Serves a file of the HLS, low-latency HLS or DASH output of a stream.
+fn serve_stream_file(
+    mut stream: TcpStream,
+    name: &str,
+    file: &str,
+    request: &HttpRequest,
+    media_streams: &Mutex<HashMap<String, RtmpMediaStream>>,
+) -> Result<()> {
+    if file == "index.mpd" || file.starts_with("video-") || file.starts_with("audio-") {
+        let dash = media_streams
+            .lock()
+            .unwrap()
+            .get(name)
+            .and_then(|media_stream| media_stream.dash().cloned());
+        return match dash {
+            Some(dash) => serve_dash(stream, &dash, file),
+            None => write_error(&mut stream, "404 Not Found"),
+        };
+    }
+    if file == "ll.m3u8" || file == "init.mp4" || file.ends_with(".m4s") {
+        let ll_hls = media_streams
+            .lock()
+            .unwrap()
+            .get(name)
+            .and_then(|media_stream| media_stream.ll_hls().cloned());
+        return match ll_hls {
+            Some(ll_hls) => serve_ll_hls(stream, &ll_hls, file, request),
+            None => write_error(&mut stream, "404 Not Found"),
+        };
+    }
+    let packager = media_streams
+        .lock()
+        .unwrap()
+        .get(name)
+        .and_then(|media_stream| media_stream.hls().cloned());
+    let packager = match packager {
+        Some(packager) => packager,
+        None => return write_error(&mut stream, "404 Not Found"),
+    };
+    let (content_type, cache_control, body) = if file == "index.m3u8" {
+        let playlist = packager.lock().unwrap().playlist();
+        (
+            "application/vnd.apple.mpegurl",
+            "no-cache",
+            Arc::from(playlist.into_bytes()),
+        )
+    } else {
+        let segment = file
+            .strip_suffix(".ts")
+            .and_then(|sequence| sequence.parse().ok())
+            .and_then(|sequence| packager.lock().unwrap().segment(sequence));
+        match segment {
+            // Segments never change.
+            Some(segment) => ("video/mp2t", "max-age=3600", segment),
+            None => return write_error(&mut stream, "404 Not Found"),
+        }
+    };
+    write_file(&mut stream, content_type, cache_control, &body)
+}
+
+/// Serves the MPD, or an init or media segment of a track, of DASH.
+fn serve_dash(mut stream: TcpStream, dash: &Mutex<DashPackager>, file: &str) -> Result<()> {
+    if file == "index.mpd" {
+        let manifest = dash.lock().unwrap().manifest(SystemTime::now());
+        return match manifest {
+            Some(manifest) => write_file(
+                &mut stream,
+                "application/dash+xml",
+                "no-cache",
+                manifest.as_bytes(),
+            ),
+            None => write_error(&mut stream, "404 Not Found"),
+        };
+    }
+    let body = match file.split_once('-') {
+        Some((track, "init.mp4")) => dash.lock().unwrap().init_segment(track),
+        Some((track, number)) => number
+            .strip_suffix(".m4s")
+            .and_then(|number| number.parse().ok())
+            .and_then(|number| dash.lock().unwrap().segment(track, number)),
+        None => None,
+    };
+    match body {
+        Some(body) => write_file(&mut stream, "video/mp4", "max-age=3600", &body),
+        None => write_error(&mut stream, "404 Not Found"),
+    }
+}
+
+/// Serves the playlist, init segment, segments or parts of low-latency HLS. Playlist requests
+/// with `_HLS_msn` and `_HLS_part`, and requests of the next part, wait for up to three target
+/// durations for it to be out.
+fn serve_ll_hls(
+    mut stream: TcpStream,
+    ll_hls: &LlHlsStream,
+    file: &str,
+    request: &HttpRequest,
+) -> Result<()> {
+    let timeout = Duration::from_millis(u64::from(ll_hls.lock().target_duration()) * 3);
+    if file == "ll.m3u8" {
+        let msn = request.query_parameter("_HLS_msn");
+        let part = request.query_parameter("_HLS_part");
+        let blocking = match (msn, part) {
+            (None, None) => None,
+            (Some(msn), part) => match (
+                msn.parse::<u64>(),
+                part.map(|part| part.parse()).transpose(),
+            ) {
+                (Ok(msn), Ok(part)) => Some((msn, part)),
+                _ => return write_error(&mut stream, "400 Bad Request"),
+            },
+            (None, Some(_)) => return write_error(&mut stream, "400 Bad Request"),
+        };
+        let playlist = match blocking {
+            Some((msn, part)) => {
+                // Requests too far ahead of the live edge are not waited for.
+                if msn > ll_hls.lock().next_part().0 + 2 {
+                    return write_error(&mut stream, "400 Bad Request");
+                }
+                let packager = ll_hls.wait(timeout, |packager| packager.has_part(msn, part));
+                if !packager.has_part(msn, part) && !packager.is_ended() {
+                    drop(packager);
+                    return write_error(&mut stream, "503 Service Unavailable");
+                }
+                packager.playlist()
+            }
+            None => ll_hls.lock().playlist(),
+        };
+        return write_file(
+            &mut stream,
+            "application/vnd.apple.mpegurl",
+            "no-cache",
+            playlist.as_bytes(),
+        );
+    }
+    let (cache_control, body) = if file == "init.mp4" {
+        // Codecs may change when the stream is published again.
+        ("no-cache", ll_hls.lock().init_segment())
+    } else {
+        let name = file.strip_suffix(".m4s").unwrap_or(file);
+        let body = match name.split_once('.') {
+            Some((sequence, part)) => match (sequence.parse(), part.parse()) {
+                (Ok(sequence), Ok(part)) => ll_hls
+                    .wait(timeout, |packager| packager.next_part() != (sequence, part))
+                    .part(sequence, part),
+                _ => None,
+            },
+            None => name
+                .parse()
+                .ok()
+                .and_then(|sequence| ll_hls.lock().segment(sequence)),
+        };
+        ("max-age=3600", body)
+    };
+    match body {
+        Some(body) => write_file(&mut stream, "video/mp4", cache_control, &body),
+        None => write_error(&mut stream, "404 Not Found"),
+    }
+}
+
+/// Serves a live stream over HTTP-FLV. The connection is handed over to the writer thread of a
+/// new subscriber, which sends tags until the stream ends or the player goes away.
+fn serve_flv(
+    mut stream: TcpStream,
+    name: String,
+    media_streams: Arc<Mutex<HashMap<String, RtmpMediaStream>>>,
+    config: &Config,
+) -> Result<()> {
+    // Tracks whose codec is not known yet are assumed to be present.
+    let flags = media_streams
+        .lock()
+        .unwrap()
+        .get(&name)
+        .filter(|media_stream| media_stream.is_published())
+        .map(|media_stream| {
+            let media_info = media_stream.media_info();
+            match (media_info.audio.is_some(), media_info.video_codec.is_some()) {
+                (false, false) => (true, true),
+                flags => flags,
+            }
+        });
+    let (has_audio, has_video) = match flags {
+        Some(flags) => flags,
+        None => return write_error(&mut stream, "404 Not Found"),
+    };
+    write_response(
+        &mut stream,
+        "200 OK",
+        &[
+            ("Content-Type", "video/x-flv"),
+            ("Transfer-Encoding", "chunked"),
+            ("Cache-Control", "no-cache"),
+            ("Connection", "close"),
+        ],
+    )?;
+    let header = encode_header(has_audio, has_video);
+    stream
+        .write_all(format!("{:X}\r\n", header.len()).as_bytes())
+        .and_then(|_| stream.write_all(&header))
+        .and_then(|_| stream.write_all(b"\r\n"))
+        .map_err(Error::Io)?;
+    // There is no codec negotiation, so players get every codec.
+    let connect_object = ConnectObject {
+        four_cc_list: Some(vec![String::from("*")]),
+        ..ConnectObject::default()
+    };
+    let from_fd = stream.as_raw_fd();
+    let policy = config.overflow_policy;
+    let client = RtmpClient::spawn(from_fd, config, policy, connect_object, move |message| {
+        write_tag(&mut stream, message).map_err(Error::Io)
+    });
+    // The stream may have ended in the meantime, in which case the client is dropped.
+    if let Some(media_stream) = media_streams.lock().unwrap().get_mut(&name) {
+        media_stream.subscribe(client);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stream::ChunkMessageHeader;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_http_request() {
+        let mut reader = Cursor::new(
+            "GET /live/a%20b.flv?token=1 HTTP/1.1\r\nHost: localhost\r\nOrigin:  x \r\n\r\n",
+        );
+        let request = HttpRequest::read(&mut reader).unwrap();
+        assert_eq!(request.method, "GET");
+        assert_eq!(request.path, "/live/a%20b.flv");
+        assert_eq!(request.query_parameter("token").as_deref(), Some("1"));
+        assert_eq!(request.query_parameter("_HLS_msn"), None);
+        assert_eq!(request.header("origin"), Some("x"));
+        assert_eq!(flv_stream_name(&request.path).as_deref(), Some("a b"));
+        assert_eq!(flv_stream_name("/live/.flv"), None);
+        assert_eq!(flv_stream_name("/test.flv"), None);
+        assert_eq!(
+            stream_file("/live/a%20b/index.m3u8"),
+            Some((String::from("a b"), "index.m3u8"))
+        );
+        assert_eq!(stream_file("/live/index.m3u8"), None);
+
+        let mut reader = Cursor::new("GET / HTTP/1.1\r\nHost: localhost\r\n");
+        assert!(HttpRequest::read(&mut reader).is_err());
+        let mut reader = Cursor::new(format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(8192)));
+        assert!(HttpRequest::read(&mut reader).is_err());
+    }
+
+    #[test]
+    fn test_write_tag() {
+        let mut payload = b"\x02\x00\x0D@setDataFrame".to_vec();
+        payload.extend_from_slice(&[0x2, 0x0, 0x0]);
+        let header = ChunkMessageHeader::new(0x10, payload.len(), RTMP_DATA_MESSAGE_AMF0, 1);
+        let mut buffer = vec![];
+        write_tag(&mut buffer, &SharedMessage::new(header, payload)).unwrap();
+        let mut expected = b"12\r\n".to_vec();
+        expected.extend_from_slice(&[18, 0, 0, 3, 0, 0, 0x10, 0, 0, 0, 0, 0x2, 0x0, 0x0]);
+        expected.extend_from_slice(&[0, 0, 0, 14]);
+        expected.extend_from_slice(b"\r\n");
+        assert_eq!(buffer, expected);
+    }
+}
@@ -0,0 +1,26 @@
+pub mod amf;
+pub mod amf3;
+pub mod amf_serde;
+pub mod client;
+pub mod cmaf;
+pub mod command;
+pub mod config;
+pub mod constant;
+pub mod dash;
+pub mod error;
+pub mod flv;
+pub mod gop;
+pub mod handshake;
+pub mod hls;
+pub mod http;
+pub mod media;
+pub mod mp4;
+pub mod object;
+pub mod queue;
+pub mod record;
+pub mod relay;
+pub mod server;
+pub mod stream;
+pub mod ts;
+pub mod utils;
+pub mod vod;
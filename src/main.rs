@@ -3,31 +3,43 @@ use std::net::TcpListener;
 use std::sync::{Arc, Mutex};
 use std::thread;
 
-mod amf;
-mod constant;
-mod error;
-mod server;
-mod stream;
-mod utils;
-
-use error::{Error, Result};
-use server::{RtmpMediaStream, RtmpServer};
+use rtmp::config::Config;
+use rtmp::error::{Error, Result};
+use rtmp::http::serve_http;
+use rtmp::server::{RtmpMediaStream, RtmpServer};
 
 fn main() -> Result<()> {
-    let port = std::env::var("PORT")
-        .unwrap_or_else(|_| String::from("7122"))
-        .parse::<u16>()
-        .expect("Invalid port number");
-    let listener = TcpListener::bind(format!("127.0.0.1:{}", port)).map_err(Error::Io)?;
-    println!("Running RTMP server on port {}", port);
+    let config = Arc::new(Config::from_env()?);
+    let listener = TcpListener::bind(format!("127.0.0.1:{}", config.port)).map_err(Error::Io)?;
+    println!("Running RTMP server on port {}", config.port);
 
     let media_streams = Arc::new(Mutex::new(HashMap::<String, RtmpMediaStream>::new()));
 
+    if config.http_port != 0 {
+        let http_listener =
+            TcpListener::bind(format!("127.0.0.1:{}", config.http_port)).map_err(Error::Io)?;
+        println!("Running HTTP server on port {}", config.http_port);
+        let media_streams = Arc::clone(&media_streams);
+        let config = Arc::clone(&config);
+        thread::spawn(move || {
+            for stream in http_listener.incoming().flatten() {
+                let m = Arc::clone(&media_streams);
+                let config = Arc::clone(&config);
+                thread::spawn(move || {
+                    if let Err(e) = serve_http(stream, m, &config) {
+                        eprintln!("Error: {}", e);
+                    }
+                });
+            }
+        });
+    }
+
     for stream in listener.incoming() {
         let m = Arc::clone(&media_streams);
+        let config = Arc::clone(&config);
         let stream = stream.map_err(Error::Io)?;
         thread::spawn(move || {
-            let mut server = RtmpServer::new(stream, m);
+            let mut server = RtmpServer::new(stream, m, config);
             if let Err(e) = server.serve() {
                 eprintln!("Error: {}", e);
             }
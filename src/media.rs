@@ -0,0 +1,664 @@
+//! Parsing of FLV audio and video tags and of the codec configuration they carry, including the
+//! extended tag headers of Enhanced RTMP.
+
+use crate::constant::*;
+use crate::error::{Error, Result};
+
+/// Codec of an audio or video tag, either a legacy FLV codec ID or an Enhanced RTMP FourCC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Codec {
+    Id(u8),
+    FourCc([u8; 4]),
+}
+
+impl Codec {
+    /// Whether this is the video codec H.264.
+    pub fn is_avc(&self) -> bool {
+        match *self {
+            Codec::Id(codec_id) => codec_id == FLV_VIDEO_CODEC_AVC,
+            Codec::FourCc(ref four_cc) => four_cc == b"avc1",
+        }
+    }
+
+    /// Whether this is the audio codec AAC.
+    pub fn is_aac(&self) -> bool {
+        match *self {
+            Codec::Id(sound_format) => sound_format == FLV_AUDIO_FORMAT_AAC,
+            Codec::FourCc(ref four_cc) => four_cc == b"mp4a",
+        }
+    }
+}
+
+/// Skips the modifier extensions of an extended tag header, returning the packet type following
+/// them along with the rest of the data.
+fn skip_mod_ex(mut packet_type: u8, mut data: &[u8]) -> Result<(u8, &[u8])> {
+    while packet_type == FLV_PACKET_TYPE_MOD_EX {
+        let (&size, rest) = data.split_first().ok_or(Error::MediaTruncated)?;
+        let (size, rest) = if size == 0xFF {
+            let size = rest.get(..2).ok_or(Error::MediaTruncated)?;
+            (
+                u16::from_be_bytes([size[0], size[1]]) as usize + 1,
+                &rest[2..],
+            )
+        } else {
+            (size as usize + 1, rest)
+        };
+        let (&last, rest) = rest
+            .get(size..)
+            .and_then(<[u8]>::split_first)
+            .ok_or(Error::MediaTruncated)?;
+        packet_type = last & 0xF;
+        data = rest;
+    }
+    Ok((packet_type, data))
+}
+
+fn read_four_cc(data: &[u8]) -> Result<([u8; 4], &[u8])> {
+    let four_cc = data.get(..4).ok_or(Error::MediaTruncated)?;
+    Ok(([four_cc[0], four_cc[1], four_cc[2], four_cc[3]], &data[4..]))
+}
+
+/// Reads a signed 24-bit composition time offset.
+fn read_composition_time(data: &[u8]) -> Result<(i32, &[u8])> {
+    let bytes = data.get(..3).ok_or(Error::MediaTruncated)?;
+    let composition_time = i32::from_be_bytes([bytes[0], bytes[1], bytes[2], 0]) >> 8;
+    Ok((composition_time, &data[3..]))
+}
+
+/// Header of an FLV VIDEODATA tag, or of an Enhanced RTMP ExVideoTagHeader.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct VideoTagHeader {
+    pub frame_type: u8,
+    pub codec: Codec,
+    /// AVCPacketType of legacy AVC and HEVC tags, or VideoPacketType of extended tags.
+    pub packet_type: Option<u8>,
+    /// Composition time offset in milliseconds of AVC and HEVC tags.
+    pub composition_time: i32,
+}
+
+impl VideoTagHeader {
+    /// Parses the header of a video message, returning it along with the data following it.
+    pub fn parse(payload: &[u8]) -> Result<(Self, &[u8])> {
+        let (&first, rest) = payload.split_first().ok_or(Error::MediaTruncated)?;
+        if first & FLV_VIDEO_EX_HEADER != 0 {
+            return Self::parse_ex(first, rest);
+        }
+        let (frame_type, codec_id) = (first >> 4, first & 0xF);
+        let mut header = Self {
+            frame_type,
+            codec: Codec::Id(codec_id),
+            packet_type: None,
+            composition_time: 0,
+        };
+        if codec_id != FLV_VIDEO_CODEC_AVC && codec_id != FLV_VIDEO_CODEC_HEVC {
+            return Ok((header, rest));
+        }
+        let (&packet_type, rest) = rest.split_first().ok_or(Error::MediaTruncated)?;
+        let (composition_time, rest) = read_composition_time(rest)?;
+        header.packet_type = Some(packet_type);
+        header.composition_time = composition_time;
+        Ok((header, rest))
+    }
+
+    fn parse_ex(first: u8, rest: &[u8]) -> Result<(Self, &[u8])> {
+        let (packet_type, rest) = skip_mod_ex(first & 0xF, rest)?;
+        if packet_type == FLV_VIDEO_PACKET_TYPE_MULTITRACK {
+            return Err(Error::InvalidMediaData("Multitrack video is not supported"));
+        }
+        let (four_cc, mut rest) = read_four_cc(rest)?;
+        let mut composition_time = 0;
+        // Only AVC and HEVC coded frames carry a composition time, the other codecs and
+        // CodedFramesX packets implying 0.
+        if packet_type == FLV_PACKET_TYPE_CODED_FRAMES
+            && (&four_cc == b"avc1" || &four_cc == b"hvc1")
+        {
+            let (offset, tail) = read_composition_time(rest)?;
+            composition_time = offset;
+            rest = tail;
+        }
+        let header = Self {
+            frame_type: (first >> 4) & 0x7,
+            codec: Codec::FourCc(four_cc),
+            packet_type: Some(packet_type),
+            composition_time,
+        };
+        Ok((header, rest))
+    }
+
+    pub fn is_keyframe(&self) -> bool {
+        self.frame_type == FLV_VIDEO_FRAME_TYPE_KEYFRAME
+    }
+
+    pub fn is_sequence_header(&self) -> bool {
+        match self.codec {
+            Codec::Id(_) => self.packet_type == Some(FLV_AVC_PACKET_TYPE_SEQUENCE_HEADER),
+            Codec::FourCc(_) => matches!(
+                self.packet_type,
+                Some(FLV_PACKET_TYPE_SEQUENCE_START)
+                    | Some(FLV_VIDEO_PACKET_TYPE_MPEG2TS_SEQUENCE_START)
+            ),
+        }
+    }
+}
+
+/// Header of an FLV AUDIODATA tag, or of an Enhanced RTMP ExAudioTagHeader.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AudioTagHeader {
+    pub codec: Codec,
+    /// 0 for 5.5 kHz, 1 for 11 kHz, 2 for 22 kHz and 3 for 44 kHz. Extended tags leave the
+    /// sound rate, size and type to the codec configuration.
+    pub sound_rate: u8,
+    /// 0 for 8-bit samples and 1 for 16-bit samples.
+    pub sound_size: u8,
+    /// 0 for mono and 1 for stereo.
+    pub sound_type: u8,
+    /// AACPacketType of legacy AAC tags, or AudioPacketType of extended tags.
+    pub packet_type: Option<u8>,
+}
+
+impl AudioTagHeader {
+    /// Parses the header of an audio message, returning it along with the data following it.
+    pub fn parse(payload: &[u8]) -> Result<(Self, &[u8])> {
+        let (&first, rest) = payload.split_first().ok_or(Error::MediaTruncated)?;
+        let sound_format = first >> 4;
+        if sound_format == FLV_AUDIO_FORMAT_EX_HEADER {
+            let (packet_type, rest) = skip_mod_ex(first & 0xF, rest)?;
+            if packet_type == FLV_AUDIO_PACKET_TYPE_MULTITRACK {
+                return Err(Error::InvalidMediaData("Multitrack audio is not supported"));
+            }
+            let (four_cc, rest) = read_four_cc(rest)?;
+            let header = Self {
+                codec: Codec::FourCc(four_cc),
+                sound_rate: 0,
+                sound_size: 0,
+                sound_type: 0,
+                packet_type: Some(packet_type),
+            };
+            return Ok((header, rest));
+        }
+        let mut header = Self {
+            codec: Codec::Id(sound_format),
+            sound_rate: (first >> 2) & 0x3,
+            sound_size: (first >> 1) & 0x1,
+            sound_type: first & 0x1,
+            packet_type: None,
+        };
+        if sound_format != FLV_AUDIO_FORMAT_AAC {
+            return Ok((header, rest));
+        }
+        let (&packet_type, rest) = rest.split_first().ok_or(Error::MediaTruncated)?;
+        header.packet_type = Some(packet_type);
+        Ok((header, rest))
+    }
+
+    pub fn is_sequence_header(&self) -> bool {
+        // AACPacketType and AudioPacketType both use 0 for sequence headers.
+        self.packet_type == Some(FLV_AAC_PACKET_TYPE_SEQUENCE_HEADER)
+    }
+}
+
+/// Reads a bit string MSB first, such as an H.264 RBSP or an AudioSpecificConfig.
+struct BitReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, position: 0 }
+    }
+
+    fn read_bit(&mut self) -> Result<u32> {
+        let byte = self
+            .data
+            .get(self.position / 8)
+            .ok_or(Error::MediaTruncated)?;
+        let bit = (byte >> (7 - self.position % 8)) & 0x1;
+        self.position += 1;
+        Ok(u32::from(bit))
+    }
+
+    fn read_bits(&mut self, nbits: u32) -> Result<u32> {
+        (0..nbits).try_fold(0, |value, _| Ok(value << 1 | self.read_bit()?))
+    }
+
+    fn read_flag(&mut self) -> Result<bool> {
+        Ok(self.read_bit()? == 1)
+    }
+
+    /// Reads an unsigned Exp-Golomb code.
+    fn read_ue(&mut self) -> Result<u32> {
+        let mut leading_zeros = 0;
+        while self.read_bit()? == 0 {
+            leading_zeros += 1;
+            if leading_zeros > 31 {
+                return Err(Error::InvalidMediaData("Exp-Golomb code is too long"));
+            }
+        }
+        Ok(((1u64 << leading_zeros) - 1 + u64::from(self.read_bits(leading_zeros)?)) as u32)
+    }
+
+    /// Reads a signed Exp-Golomb code.
+    fn read_se(&mut self) -> Result<i32> {
+        let value = self.read_ue()?;
+        let magnitude = (value as u64).div_ceil(2) as i32;
+        Ok(if value % 2 == 1 {
+            magnitude
+        } else {
+            -magnitude
+        })
+    }
+}
+
+/// Removes the emulation prevention bytes of a NAL unit payload.
+fn nal_unit_to_rbsp(nal_unit: &[u8]) -> Vec<u8> {
+    let mut rbsp = Vec::with_capacity(nal_unit.len());
+    let mut zeros = 0;
+    for &byte in nal_unit {
+        if zeros >= 2 && byte == 0x3 {
+            zeros = 0;
+            continue;
+        }
+        zeros = if byte == 0 { zeros + 1 } else { 0 };
+        rbsp.push(byte);
+    }
+    rbsp
+}
+
+// Largest width or height allowed by H.264 levels, sqrt(8 * MaxFS) macroblocks at level 6.2.
+const MAX_PICTURE_DIMENSION: u32 = 16880;
+
+/// Fields of an H.264 sequence parameter set.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SequenceParameterSet {
+    pub profile_idc: u8,
+    pub constraint_flags: u8,
+    pub level_idc: u8,
+    pub chroma_format_idc: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl SequenceParameterSet {
+    /// Parses an SPS NAL unit, including its NAL unit header.
+    pub fn parse(nal_unit: &[u8]) -> Result<Self> {
+        let rbsp = nal_unit_to_rbsp(nal_unit);
+        let mut reader = BitReader::new(rbsp.get(1..).ok_or(Error::MediaTruncated)?);
+        let profile_idc = reader.read_bits(8)? as u8;
+        let constraint_flags = reader.read_bits(8)? as u8;
+        let level_idc = reader.read_bits(8)? as u8;
+        let _seq_parameter_set_id = reader.read_ue()?;
+        let mut chroma_format_idc = 1;
+        let mut separate_colour_plane = false;
+        if matches!(
+            profile_idc,
+            100 | 110 | 122 | 244 | 44 | 83 | 86 | 118 | 128 | 138 | 139 | 134 | 135
+        ) {
+            chroma_format_idc = reader.read_ue()?;
+            if chroma_format_idc == 3 {
+                separate_colour_plane = reader.read_flag()?;
+            }
+            let _bit_depth_luma_minus8 = reader.read_ue()?;
+            let _bit_depth_chroma_minus8 = reader.read_ue()?;
+            let _qpprime_y_zero_transform_bypass = reader.read_flag()?;
+            if reader.read_flag()? {
+                let count = if chroma_format_idc == 3 { 12 } else { 8 };
+                for i in 0..count {
+                    if reader.read_flag()? {
+                        skip_scaling_list(&mut reader, if i < 6 { 16 } else { 64 })?;
+                    }
+                }
+            }
+        }
+        let _log2_max_frame_num_minus4 = reader.read_ue()?;
+        match reader.read_ue()? {
+            0 => {
+                let _log2_max_pic_order_cnt_lsb_minus4 = reader.read_ue()?;
+            }
+            1 => {
+                let _delta_pic_order_always_zero = reader.read_flag()?;
+                let _offset_for_non_ref_pic = reader.read_se()?;
+                let _offset_for_top_to_bottom_field = reader.read_se()?;
+                for _ in 0..reader.read_ue()? {
+                    let _offset_for_ref_frame = reader.read_se()?;
+                }
+            }
+            _ => {}
+        }
+        let _max_num_ref_frames = reader.read_ue()?;
+        let _gaps_in_frame_num_value_allowed = reader.read_flag()?;
+        let pic_width_in_mbs = reader.read_ue()? + 1;
+        let pic_height_in_map_units = reader.read_ue()? + 1;
+        let frame_mbs_only = reader.read_bit()?;
+        if frame_mbs_only == 0 {
+            let _mb_adaptive_frame_field = reader.read_flag()?;
+        }
+        let _direct_8x8_inference = reader.read_flag()?;
+        let (mut crop_left, mut crop_right, mut crop_top, mut crop_bottom) = (0, 0, 0, 0);
+        if reader.read_flag()? {
+            crop_left = reader.read_ue()?;
+            crop_right = reader.read_ue()?;
+            crop_top = reader.read_ue()?;
+            crop_bottom = reader.read_ue()?;
+        }
+        let (crop_unit_x, crop_unit_y) = if separate_colour_plane || chroma_format_idc == 0 {
+            (1, 2 - frame_mbs_only)
+        } else {
+            let sub_width = if chroma_format_idc == 3 { 1 } else { 2 };
+            let sub_height = if chroma_format_idc == 1 { 2 } else { 1 };
+            (sub_width, sub_height * (2 - frame_mbs_only))
+        };
+        let too_large = || Error::InvalidMediaData("SPS picture size is too large");
+        let frame_width = pic_width_in_mbs.checked_mul(16).ok_or_else(too_large)?;
+        let frame_height = pic_height_in_map_units
+            .checked_mul((2 - frame_mbs_only) * 16)
+            .ok_or_else(too_large)?;
+        let crop_width = crop_left
+            .checked_add(crop_right)
+            .and_then(|crop| crop.checked_mul(crop_unit_x))
+            .ok_or_else(too_large)?;
+        let crop_height = crop_top
+            .checked_add(crop_bottom)
+            .and_then(|crop| crop.checked_mul(crop_unit_y))
+            .ok_or_else(too_large)?;
+        let width = frame_width
+            .checked_sub(crop_width)
+            .ok_or(Error::InvalidMediaData(
+                "SPS cropping exceeds the picture width",
+            ))?;
+        let height = frame_height
+            .checked_sub(crop_height)
+            .ok_or(Error::InvalidMediaData(
+                "SPS cropping exceeds the picture height",
+            ))?;
+        if width > MAX_PICTURE_DIMENSION || height > MAX_PICTURE_DIMENSION {
+            return Err(too_large());
+        }
+        Ok(Self {
+            profile_idc,
+            constraint_flags,
+            level_idc,
+            chroma_format_idc,
+            width,
+            height,
+        })
+    }
+}
+
+fn skip_scaling_list(reader: &mut BitReader, size: usize) -> Result<()> {
+    let (mut last_scale, mut next_scale) = (8, 8);
+    for _ in 0..size {
+        if next_scale != 0 {
+            let delta_scale = reader.read_se()?;
+            if !(-128..=127).contains(&delta_scale) {
+                return Err(Error::InvalidMediaData(
+                    "SPS scaling list delta is out of range",
+                ));
+            }
+            next_scale = (last_scale + delta_scale + 256) % 256;
+        }
+        if next_scale != 0 {
+            last_scale = next_scale;
+        }
+    }
+    Ok(())
+}
+
+/// AVCDecoderConfigurationRecord carried by AVC sequence headers.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AvcDecoderConfigurationRecord {
+    pub profile_indication: u8,
+    pub profile_compatibility: u8,
+    pub level_indication: u8,
+    /// Size in bytes of the length prefix of each NAL unit in video data.
+    pub nal_unit_length_size: u8,
+    pub sequence_parameter_sets: Vec<Vec<u8>>,
+    pub picture_parameter_sets: Vec<Vec<u8>>,
+}
+
+impl AvcDecoderConfigurationRecord {
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        if data.len() < 6 {
+            return Err(Error::MediaTruncated);
+        }
+        if data[0] != 1 {
+            return Err(Error::InvalidMediaData(
+                "Unknown AVCDecoderConfigurationRecord version",
+            ));
+        }
+        let mut rest = &data[6..];
+        let sequence_parameter_sets = read_parameter_sets(&mut rest, data[5] & 0x1F)?;
+        let (&count, tail) = rest.split_first().ok_or(Error::MediaTruncated)?;
+        rest = tail;
+        let picture_parameter_sets = read_parameter_sets(&mut rest, count)?;
+        Ok(Self {
+            profile_indication: data[1],
+            profile_compatibility: data[2],
+            level_indication: data[3],
+            nal_unit_length_size: (data[4] & 0x3) + 1,
+            sequence_parameter_sets,
+            picture_parameter_sets,
+        })
+    }
+
+    /// Parses the first sequence parameter set.
+    pub fn sequence_parameter_set(&self) -> Result<SequenceParameterSet> {
+        let nal_unit = self
+            .sequence_parameter_sets
+            .first()
+            .ok_or(Error::InvalidMediaData(
+                "AVCDecoderConfigurationRecord has no SPS",
+            ))?;
+        SequenceParameterSet::parse(nal_unit)
+    }
+}
+
+/// Reads parameter sets each prefixed with its 16-bit length.
+fn read_parameter_sets(data: &mut &[u8], count: u8) -> Result<Vec<Vec<u8>>> {
+    (0..count)
+        .map(|_| {
+            if data.len() < 2 {
+                return Err(Error::MediaTruncated);
+            }
+            let length = u16::from_be_bytes([data[0], data[1]]) as usize;
+            let parameter_set = data.get(2..2 + length).ok_or(Error::MediaTruncated)?;
+            *data = &data[2 + length..];
+            Ok(parameter_set.to_vec())
+        })
+        .collect()
+}
+
+const AAC_SAMPLING_FREQUENCIES: [u32; 13] = [
+    96000, 88200, 64000, 48000, 44100, 32000, 24000, 22050, 16000, 12000, 11025, 8000, 7350,
+];
+
+/// AudioSpecificConfig carried by AAC sequence headers.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AudioSpecificConfig {
+    /// Audio object type, such as 2 for AAC LC.
+    pub object_type: u8,
+    pub sampling_frequency: u32,
+    pub channel_configuration: u8,
+}
+
+impl AudioSpecificConfig {
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        let mut reader = BitReader::new(data);
+        let mut object_type = reader.read_bits(5)?;
+        if object_type == 31 {
+            object_type = 32 + reader.read_bits(6)?;
+        }
+        let sampling_frequency = match reader.read_bits(4)? {
+            0xF => reader.read_bits(24)?,
+            index => {
+                *AAC_SAMPLING_FREQUENCIES
+                    .get(index as usize)
+                    .ok_or(Error::InvalidMediaData(
+                        "Reserved AAC sampling frequency index",
+                    ))?
+            }
+        };
+        let channel_configuration = reader.read_bits(4)? as u8;
+        Ok(Self {
+            object_type: object_type as u8,
+            sampling_frequency,
+            channel_configuration,
+        })
+    }
+
+    /// Index of the sampling frequency in the table of standard frequencies, if it is one.
+    pub fn sampling_frequency_index(&self) -> Option<u8> {
+        AAC_SAMPLING_FREQUENCIES
+            .iter()
+            .position(|&frequency| frequency == self.sampling_frequency)
+            .map(|index| index as u8)
+    }
+}
+
+/// Codecs of a published stream and their configuration, parsed from its messages.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct MediaInfo {
+    pub video_codec: Option<Codec>,
+    pub avc_config: Option<AvcDecoderConfigurationRecord>,
+    pub sequence_parameter_set: Option<SequenceParameterSet>,
+    pub audio: Option<AudioTagHeader>,
+    pub aac_config: Option<AudioSpecificConfig>,
+}
+
+impl MediaInfo {
+    /// Updates the video codec from the header of a video message and the data following it.
+    pub fn update_video(&mut self, header: &VideoTagHeader, data: &[u8]) -> Result<()> {
+        self.video_codec = Some(header.codec);
+        if !header.codec.is_avc() || !header.is_sequence_header() {
+            return Ok(());
+        }
+        let config = AvcDecoderConfigurationRecord::parse(data)?;
+        self.sequence_parameter_set = Some(config.sequence_parameter_set()?);
+        self.avc_config = Some(config);
+        Ok(())
+    }
+
+    /// Updates the audio codec from the header of an audio message and the data following it.
+    pub fn update_audio(&mut self, header: &AudioTagHeader, data: &[u8]) -> Result<()> {
+        self.audio = Some(*header);
+        if header.codec.is_aac() && header.is_sequence_header() {
+            self.aac_config = Some(AudioSpecificConfig::parse(data)?);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// AVC sequence header of a 1280x720 High profile stream, shared by the packager tests.
+    pub const AVC_SEQUENCE_HEADER: [u8; 46] = [
+        0x17, 0x00, 0x00, 0x00, 0x00, 0x01, 0x64, 0x00, 0x1F, 0xFF, 0xE1, 0x00, 0x1A, 0x67, 0x64,
+        0x00, 0x1F, 0xAC, 0xD9, 0x40, 0x50, 0x05, 0xBB, 0x01, 0x10, 0x00, 0x00, 0x03, 0x00, 0x10,
+        0x00, 0x00, 0x03, 0x03, 0xC0, 0xF1, 0x83, 0x19, 0x60, 0x01, 0x00, 0x04, 0x68, 0xEB, 0xE3,
+        0xCB,
+    ];
+
+    #[test]
+    fn test_video_tag_header() {
+        let (header, data) = VideoTagHeader::parse(&[0x27, 0x01, 0xFF, 0xFF, 0xC0, 0xAB]).unwrap();
+        assert_eq!(header.frame_type, 2);
+        assert_eq!(header.codec, Codec::Id(FLV_VIDEO_CODEC_AVC));
+        assert_eq!(header.packet_type, Some(1));
+        assert_eq!(header.composition_time, -64);
+        assert_eq!(data, [0xAB]);
+        let (header, data) = VideoTagHeader::parse(&[0x12, 0xAB]).unwrap();
+        assert!(header.is_keyframe());
+        assert_eq!(header.packet_type, None);
+        assert_eq!(data, [0xAB]);
+        assert!(VideoTagHeader::parse(&[0x17, 0x00]).is_err());
+    }
+
+    #[test]
+    fn test_ex_video_tag_header() {
+        // HEVC coded frames carry a composition time after the FourCC.
+        let payload = [0x91, b'h', b'v', b'c', b'1', 0x00, 0x00, 0x21, 0xAB];
+        let (header, data) = VideoTagHeader::parse(&payload).unwrap();
+        assert!(header.is_keyframe());
+        assert_eq!(header.codec, Codec::FourCc(*b"hvc1"));
+        assert_eq!(header.packet_type, Some(FLV_PACKET_TYPE_CODED_FRAMES));
+        assert_eq!(header.composition_time, 33);
+        assert_eq!(data, [0xAB]);
+        let payload = [0x90, b'a', b'v', b'0', b'1', 0x81];
+        let (header, data) = VideoTagHeader::parse(&payload).unwrap();
+        assert!(header.is_sequence_header());
+        assert_eq!(header.codec, Codec::FourCc(*b"av01"));
+        assert_eq!(data, [0x81]);
+        // Modifier extensions are skipped.
+        let payload = [0xA7, 0x1, 0x0, 0x0, 0x03, b'v', b'p', b'0', b'9', 0xAB];
+        let (header, data) = VideoTagHeader::parse(&payload).unwrap();
+        assert_eq!(header.frame_type, 2);
+        assert_eq!(header.codec, Codec::FourCc(*b"vp09"));
+        assert_eq!(
+            header.packet_type,
+            Some(FLV_VIDEO_PACKET_TYPE_CODED_FRAMES_X)
+        );
+        assert_eq!(data, [0xAB]);
+        assert!(VideoTagHeader::parse(&[0x96, 0x0, b'a', b'v', b'0', b'1']).is_err());
+    }
+
+    #[test]
+    fn test_ex_audio_tag_header() {
+        let payload = [0x90, b'O', b'p', b'u', b's', 0xAB];
+        let (header, data) = AudioTagHeader::parse(&payload).unwrap();
+        assert!(header.is_sequence_header());
+        assert_eq!(header.codec, Codec::FourCc(*b"Opus"));
+        assert_eq!(data, [0xAB]);
+        let (header, _) = AudioTagHeader::parse(&[0x91, b'f', b'L', b'a', b'C']).unwrap();
+        assert!(!header.is_sequence_header());
+    }
+
+    #[test]
+    fn test_avc_decoder_configuration_record() {
+        let (header, data) = VideoTagHeader::parse(&AVC_SEQUENCE_HEADER).unwrap();
+        assert!(header.is_sequence_header());
+        let config = AvcDecoderConfigurationRecord::parse(data).unwrap();
+        assert_eq!(config.profile_indication, 100);
+        assert_eq!(config.level_indication, 31);
+        assert_eq!(config.nal_unit_length_size, 4);
+        assert_eq!(config.sequence_parameter_sets.len(), 1);
+        assert_eq!(
+            config.picture_parameter_sets,
+            [vec![0x68, 0xEB, 0xE3, 0xCB]]
+        );
+        let sps = config.sequence_parameter_set().unwrap();
+        assert_eq!(sps.profile_idc, 100);
+        assert_eq!(sps.level_idc, 31);
+        assert_eq!(sps.chroma_format_idc, 1);
+        assert_eq!((sps.width, sps.height), (1280, 720));
+    }
+
+    #[test]
+    fn test_sequence_parameter_set_too_large() {
+        // Baseline profile SPS whose pic_width_in_mbs_minus1 is 2^28 - 1.
+        let nal_unit = [
+            0x67, 0x42, 0x00, 0x1E, 0xDC, 0x00, 0x00, 0x00, 0x10, 0x00, 0x00, 0x00, 0xE4,
+        ];
+        assert!(matches!(
+            SequenceParameterSet::parse(&nal_unit),
+            Err(Error::InvalidMediaData(_))
+        ));
+    }
+
+    #[test]
+    fn test_audio_specific_config() {
+        let (header, data) = AudioTagHeader::parse(&[0xAF, 0x00, 0x12, 0x10]).unwrap();
+        assert_eq!(header.codec, Codec::Id(FLV_AUDIO_FORMAT_AAC));
+        assert_eq!(
+            (header.sound_rate, header.sound_size, header.sound_type),
+            (3, 1, 1)
+        );
+        assert!(header.is_sequence_header());
+        let config = AudioSpecificConfig::parse(data).unwrap();
+        assert_eq!(config.object_type, 2);
+        assert_eq!(config.sampling_frequency, 44100);
+        assert_eq!(config.channel_configuration, 2);
+        assert!(AudioSpecificConfig::parse(&[0x17, 0x90]).is_err());
+    }
+}
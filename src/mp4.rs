@@ -0,0 +1,434 @@
+//! Fragmented MP4 (CMAF) boxes: init segments describing H.264 and AAC tracks, and movie
+//! fragments carrying their samples.
+
+const SAMPLE_FLAGS_SYNC: u32 = 0x02000000;
+/// Depends on other samples and is not a sync sample.
+const SAMPLE_FLAGS_NON_SYNC: u32 = 0x01010000;
+const TRUN_DATA_OFFSET: u32 = 0x1;
+const TRUN_SAMPLE_DURATION: u32 = 0x100;
+const TRUN_SAMPLE_SIZE: u32 = 0x200;
+const TRUN_SAMPLE_FLAGS: u32 = 0x400;
+const TRUN_SAMPLE_COMPOSITION_TIME_OFFSET: u32 = 0x800;
+const TFHD_DEFAULT_BASE_IS_MOOF: u32 = 0x20000;
+const UNITY_MATRIX: [u32; 9] = [0x10000, 0, 0, 0, 0x10000, 0, 0, 0, 0x40000000];
+
+/// Codec configuration of a track.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SampleEntry {
+    /// H.264, with the AVCDecoderConfigurationRecord of the stream.
+    Avc {
+        width: u16,
+        height: u16,
+        config: Vec<u8>,
+    },
+    /// AAC, with the AudioSpecificConfig of the stream.
+    Aac {
+        channel_count: u16,
+        sample_rate: u32,
+        config: Vec<u8>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Track {
+    pub id: u32,
+    /// Units per second of the timestamps and durations of the track.
+    pub timescale: u32,
+    pub sample_entry: SampleEntry,
+}
+
+/// A sample, in units of the timescale of its track. H.264 samples keep their NAL units
+/// prefixed with their length.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sample {
+    pub duration: u32,
+    pub composition_time_offset: i32,
+    pub is_sync: bool,
+    pub data: Vec<u8>,
+}
+
+/// The samples of a track in a fragment, starting at `base_media_decode_time`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TrackFragment<'a> {
+    pub track_id: u32,
+    pub base_media_decode_time: u64,
+    pub samples: &'a [Sample],
+}
+
+fn write_box<F: FnOnce(&mut Vec<u8>)>(output: &mut Vec<u8>, box_type: &[u8; 4], write: F) {
+    let start = output.len();
+    output.extend_from_slice(&[0x0; 4]);
+    output.extend_from_slice(box_type);
+    write(output);
+    let size = (output.len() - start) as u32;
+    output[start..start + 4].copy_from_slice(&size.to_be_bytes());
+}
+
+fn write_full_box<F: FnOnce(&mut Vec<u8>)>(
+    output: &mut Vec<u8>,
+    box_type: &[u8; 4],
+    version: u8,
+    flags: u32,
+    write: F,
+) {
+    write_box(output, box_type, |output| {
+        output.push(version);
+        output.extend_from_slice(&flags.to_be_bytes()[1..]);
+        write(output);
+    });
+}
+
+fn write_u16s(output: &mut Vec<u8>, values: &[u16]) {
+    for value in values {
+        output.extend_from_slice(&value.to_be_bytes());
+    }
+}
+
+fn write_u32s(output: &mut Vec<u8>, values: &[u32]) {
+    for value in values {
+        output.extend_from_slice(&value.to_be_bytes());
+    }
+}
+
+/// Writes an MPEG-4 descriptor, whose contents are shorter than 128 bytes.
+fn write_descriptor(output: &mut Vec<u8>, tag: u8, contents: &[u8]) {
+    output.extend_from_slice(&[tag, contents.len() as u8]);
+    output.extend_from_slice(contents);
+}
+
+fn write_esds(output: &mut Vec<u8>, config: &[u8]) {
+    let mut decoder_specific_info = vec![];
+    write_descriptor(&mut decoder_specific_info, 0x5, config);
+    // MPEG-4 audio, audio stream, unknown buffer size and bitrates.
+    let mut decoder_config = vec![0x40, 0x15];
+    decoder_config.extend_from_slice(&[0x0; 11]);
+    decoder_config.extend_from_slice(&decoder_specific_info);
+    let mut es = vec![0x0, 0x0, 0x0];
+    write_descriptor(&mut es, 0x4, &decoder_config);
+    // Predefined SL configuration for MP4 files.
+    write_descriptor(&mut es, 0x6, &[0x2]);
+    write_full_box(output, b"esds", 0, 0, |output| {
+        write_descriptor(output, 0x3, &es)
+    });
+}
+
+fn write_sample_entry(output: &mut Vec<u8>, sample_entry: &SampleEntry) {
+    match *sample_entry {
+        SampleEntry::Avc {
+            width,
+            height,
+            ref config,
+        } => write_box(output, b"avc1", |output| {
+            // Reserved, then data reference index 1.
+            output.extend_from_slice(&[0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x1]);
+            output.extend_from_slice(&[0x0; 16]);
+            write_u16s(output, &[width, height]);
+            // 72 dpi, one frame per sample.
+            write_u32s(output, &[0x480000, 0x480000, 0]);
+            write_u16s(output, &[1]);
+            output.extend_from_slice(&[0x0; 32]);
+            // Color with no alpha, no color table.
+            write_u16s(output, &[0x18, 0xFFFF]);
+            write_box(output, b"avcC", |output| output.extend_from_slice(config));
+        }),
+        SampleEntry::Aac {
+            channel_count,
+            sample_rate,
+            ref config,
+        } => write_box(output, b"mp4a", |output| {
+            output.extend_from_slice(&[0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x1]);
+            output.extend_from_slice(&[0x0; 8]);
+            write_u16s(output, &[channel_count, 16, 0, 0]);
+            // 16.16 fixed point, whose integer part only holds rates up to 65535 Hz.
+            write_u32s(output, &[std::cmp::min(sample_rate, 0xFFFF) << 16]);
+            write_esds(output, config);
+        }),
+    }
+}
+
+fn write_track(output: &mut Vec<u8>, track: &Track) {
+    let (handler_type, name, width, height, volume) = match track.sample_entry {
+        SampleEntry::Avc { width, height, .. } => (b"vide", "VideoHandler", width, height, 0),
+        SampleEntry::Aac { .. } => (b"soun", "SoundHandler", 0, 0, 0x100),
+    };
+    write_box(output, b"trak", |output| {
+        // Enabled and in the presentation.
+        write_full_box(output, b"tkhd", 0, 0x3, |output| {
+            write_u32s(output, &[0, 0, track.id, 0, 0, 0, 0]);
+            write_u16s(output, &[0, 0, volume, 0]);
+            write_u32s(output, &UNITY_MATRIX);
+            write_u32s(output, &[u32::from(width) << 16, u32::from(height) << 16]);
+        });
+        write_box(output, b"mdia", |output| {
+            write_full_box(output, b"mdhd", 0, 0, |output| {
+                write_u32s(output, &[0, 0, track.timescale, 0]);
+                // Undetermined language.
+                write_u16s(output, &[0x55C4, 0]);
+            });
+            write_full_box(output, b"hdlr", 0, 0, |output| {
+                write_u32s(output, &[0]);
+                output.extend_from_slice(handler_type);
+                output.extend_from_slice(&[0x0; 12]);
+                output.extend_from_slice(name.as_bytes());
+                output.push(0x0);
+            });
+            write_box(output, b"minf", |output| {
+                match track.sample_entry {
+                    SampleEntry::Avc { .. } => write_full_box(output, b"vmhd", 0, 0x1, |output| {
+                        write_u16s(output, &[0, 0, 0, 0])
+                    }),
+                    SampleEntry::Aac { .. } => {
+                        write_full_box(output, b"smhd", 0, 0, |output| write_u16s(output, &[0, 0]))
+                    }
+                }
+                write_box(output, b"dinf", |output| {
+                    write_full_box(output, b"dref", 0, 0, |output| {
+                        write_u32s(output, &[1]);
+                        // Media data in the same file.
+                        write_full_box(output, b"url ", 0, 0x1, |_| {});
+                    });
+                });
+                // Samples are all in fragments.
+                write_box(output, b"stbl", |output| {
+                    write_full_box(output, b"stsd", 0, 0, |output| {
+                        write_u32s(output, &[1]);
+                        write_sample_entry(output, &track.sample_entry);
+                    });
+                    write_full_box(output, b"stts", 0, 0, |output| write_u32s(output, &[0]));
+                    write_full_box(output, b"stsc", 0, 0, |output| write_u32s(output, &[0]));
+                    write_full_box(output, b"stsz", 0, 0, |output| write_u32s(output, &[0, 0]));
+                    write_full_box(output, b"stco", 0, 0, |output| write_u32s(output, &[0]));
+                });
+            });
+        });
+    });
+}
+
+/// Writes the `ftyp` and `moov` boxes which start a fragmented MP4 stream.
+pub fn write_init_segment(output: &mut Vec<u8>, tracks: &[Track]) {
+    write_box(output, b"ftyp", |output| {
+        output.extend_from_slice(b"iso6");
+        write_u32s(output, &[0]);
+        output.extend_from_slice(b"iso6cmfcmp41");
+    });
+    write_box(output, b"moov", |output| {
+        write_full_box(output, b"mvhd", 0, 0, |output| {
+            // Times, a timescale of 1 ms and an unknown duration.
+            write_u32s(output, &[0, 0, 1000, 0]);
+            // Normal rate and volume.
+            write_u32s(output, &[0x10000]);
+            write_u16s(output, &[0x100, 0]);
+            write_u32s(output, &[0, 0]);
+            write_u32s(output, &UNITY_MATRIX);
+            write_u32s(output, &[0; 6]);
+            let next_track_id = tracks.iter().map(|track| track.id).max().unwrap_or(0) + 1;
+            write_u32s(output, &[next_track_id]);
+        });
+        for track in tracks {
+            write_track(output, track);
+        }
+        write_box(output, b"mvex", |output| {
+            for track in tracks {
+                write_full_box(output, b"trex", 0, 0, |output| {
+                    write_u32s(output, &[track.id, 1, 0, 0, 0])
+                });
+            }
+        });
+    });
+}
+
+/// Writes a `moof` box and the `mdat` box holding the samples of its track fragments.
+pub fn write_fragment(output: &mut Vec<u8>, sequence_number: u32, fragments: &[TrackFragment]) {
+    let moof_start = output.len();
+    // Positions of the data offsets, which are only known once the `moof` box is complete.
+    let mut data_offset_positions = vec![];
+    write_box(output, b"moof", |output| {
+        write_full_box(output, b"mfhd", 0, 0, |output| {
+            write_u32s(output, &[sequence_number])
+        });
+        for fragment in fragments {
+            write_box(output, b"traf", |output| {
+                write_full_box(output, b"tfhd", 0, TFHD_DEFAULT_BASE_IS_MOOF, |output| {
+                    write_u32s(output, &[fragment.track_id])
+                });
+                write_full_box(output, b"tfdt", 1, 0, |output| {
+                    output.extend_from_slice(&fragment.base_media_decode_time.to_be_bytes())
+                });
+                let has_composition_time_offsets = fragment
+                    .samples
+                    .iter()
+                    .any(|sample| sample.composition_time_offset != 0);
+                let mut flags =
+                    TRUN_DATA_OFFSET | TRUN_SAMPLE_DURATION | TRUN_SAMPLE_SIZE | TRUN_SAMPLE_FLAGS;
+                if has_composition_time_offsets {
+                    flags |= TRUN_SAMPLE_COMPOSITION_TIME_OFFSET;
+                }
+                // Version 1 for signed composition time offsets.
+                write_full_box(output, b"trun", 1, flags, |output| {
+                    write_u32s(output, &[fragment.samples.len() as u32]);
+                    data_offset_positions.push(output.len());
+                    write_u32s(output, &[0]);
+                    for sample in fragment.samples {
+                        let sample_flags = if sample.is_sync {
+                            SAMPLE_FLAGS_SYNC
+                        } else {
+                            SAMPLE_FLAGS_NON_SYNC
+                        };
+                        write_u32s(
+                            output,
+                            &[sample.duration, sample.data.len() as u32, sample_flags],
+                        );
+                        if has_composition_time_offsets {
+                            output.extend_from_slice(&sample.composition_time_offset.to_be_bytes());
+                        }
+                    }
+                });
+            });
+        }
+    });
+    // Data offsets are relative to the start of the `moof` box.
+    let mut data_offset = output.len() - moof_start + 8;
+    for (fragment, position) in fragments.iter().zip(data_offset_positions) {
+        output[position..position + 4].copy_from_slice(&(data_offset as u32).to_be_bytes());
+        data_offset += fragment
+            .samples
+            .iter()
+            .map(|sample| sample.data.len())
+            .sum::<usize>();
+    }
+    write_box(output, b"mdat", |output| {
+        for sample in fragments.iter().flat_map(|fragment| fragment.samples) {
+            output.extend_from_slice(&sample.data);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryInto;
+
+    /// Types and sizes of the boxes of `data`.
+    fn boxes(data: &[u8]) -> Vec<(&[u8], usize)> {
+        let mut boxes = vec![];
+        let mut rest = data;
+        while !rest.is_empty() {
+            let size = u32::from_be_bytes(rest[..4].try_into().unwrap()) as usize;
+            boxes.push((&rest[4..8], size));
+            rest = &rest[size..];
+        }
+        boxes
+    }
+
+    #[test]
+    fn test_init_segment() {
+        let tracks = [
+            Track {
+                id: 1,
+                timescale: 90000,
+                sample_entry: SampleEntry::Avc {
+                    width: 1280,
+                    height: 720,
+                    config: vec![0x1, 0x64, 0x0, 0x1F],
+                },
+            },
+            Track {
+                id: 2,
+                timescale: 44100,
+                sample_entry: SampleEntry::Aac {
+                    channel_count: 2,
+                    sample_rate: 44100,
+                    config: vec![0x12, 0x10],
+                },
+            },
+        ];
+        let mut output = vec![];
+        write_init_segment(&mut output, &tracks);
+        let top_level = boxes(&output);
+        assert_eq!(top_level[0], (&b"ftyp"[..], 28));
+        assert_eq!(top_level[1].0, b"moov");
+        assert_eq!(top_level.len(), 2);
+        let children: Vec<_> = boxes(&output[28 + 8..])
+            .into_iter()
+            .map(|(box_type, _)| box_type)
+            .collect();
+        assert_eq!(children, [&b"mvhd"[..], b"trak", b"trak", b"mvex"]);
+        // ES descriptor of the AAC track.
+        let esds = output.windows(4).position(|w| w == b"esds").unwrap();
+        assert_eq!(
+            &output[esds + 8..esds + 8 + 27],
+            &[
+                0x3, 0x19, 0x0, 0x0, 0x0, 0x4, 0x11, 0x40, 0x15, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0,
+                0x0, 0x0, 0x0, 0x0, 0x5, 0x2, 0x12, 0x10, 0x6, 0x1, 0x2,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_fragment() {
+        let video = [
+            Sample {
+                duration: 3000,
+                composition_time_offset: 3000,
+                is_sync: true,
+                data: vec![0x0, 0x0, 0x0, 0x1, 0x65],
+            },
+            Sample {
+                duration: 3000,
+                composition_time_offset: 0,
+                is_sync: false,
+                data: vec![0x0, 0x0, 0x0, 0x1, 0x41],
+            },
+        ];
+        let audio = [Sample {
+            duration: 1024,
+            composition_time_offset: 0,
+            is_sync: true,
+            data: vec![0x21],
+        }];
+        let mut output = vec![];
+        write_fragment(
+            &mut output,
+            7,
+            &[
+                TrackFragment {
+                    track_id: 1,
+                    base_media_decode_time: 90000,
+                    samples: &video,
+                },
+                TrackFragment {
+                    track_id: 2,
+                    base_media_decode_time: 44100,
+                    samples: &audio,
+                },
+            ],
+        );
+        let top_level = boxes(&output);
+        assert_eq!(top_level[0].0, b"moof");
+        let moof_size = top_level[0].1;
+        assert_eq!(top_level[1], (&b"mdat"[..], 8 + 11));
+        assert_eq!(
+            &output[moof_size + 8..],
+            &[0x0, 0x0, 0x0, 0x1, 0x65, 0x0, 0x0, 0x0, 0x1, 0x41, 0x21]
+        );
+        // The video trun, with composition time offsets.
+        let trun = output.windows(4).position(|w| w == b"trun").unwrap();
+        assert_eq!(&output[trun + 4..trun + 8], &[0x1, 0x0, 0xF, 0x1]);
+        assert_eq!(
+            u32::from_be_bytes(output[trun + 12..trun + 16].try_into().unwrap()) as usize,
+            moof_size + 8
+        );
+        // The audio trun, whose data follows the video.
+        let trun = trun
+            + 4
+            + output[trun + 4..]
+                .windows(4)
+                .position(|w| w == b"trun")
+                .unwrap();
+        assert_eq!(&output[trun + 4..trun + 8], &[0x1, 0x0, 0x7, 0x1]);
+        assert_eq!(
+            u32::from_be_bytes(output[trun + 12..trun + 16].try_into().unwrap()) as usize,
+            moof_size + 8 + 10
+        );
+    }
+}
@@ -0,0 +1,139 @@
+//! Typed objects carried in command and data messages.
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::amf_serde::lenient;
+
+/// Command object of the `connect` command. Optional properties of an unexpected type are
+/// ignored.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct ConnectObject {
+    pub app: String,
+    #[serde(skip_serializing_if = "Option::is_none", deserialize_with = "lenient")]
+    pub flash_ver: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none", deserialize_with = "lenient")]
+    pub swf_url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none", deserialize_with = "lenient")]
+    pub tc_url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none", deserialize_with = "lenient")]
+    pub fpad: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none", deserialize_with = "lenient")]
+    pub capabilities: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none", deserialize_with = "lenient")]
+    pub audio_codecs: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none", deserialize_with = "lenient")]
+    pub video_codecs: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none", deserialize_with = "lenient")]
+    pub video_function: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none", deserialize_with = "lenient")]
+    pub page_url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none", deserialize_with = "lenient")]
+    pub object_encoding: Option<u8>,
+    /// Enhanced RTMP video codecs supported by the client.
+    #[serde(skip_serializing_if = "Option::is_none", deserialize_with = "lenient")]
+    pub four_cc_list: Option<Vec<String>>,
+    /// Enhanced RTMP video codecs mapped to what the client can do with them.
+    #[serde(skip_serializing_if = "Option::is_none", deserialize_with = "lenient")]
+    pub video_four_cc_info_map: Option<BTreeMap<String, f64>>,
+    /// Enhanced RTMP audio codecs mapped to what the client can do with them.
+    #[serde(skip_serializing_if = "Option::is_none", deserialize_with = "lenient")]
+    pub audio_four_cc_info_map: Option<BTreeMap<String, f64>>,
+    #[serde(skip_serializing_if = "Option::is_none", deserialize_with = "lenient")]
+    pub caps_ex: Option<f64>,
+}
+
+impl ConnectObject {
+    /// Whether the client announced an Enhanced RTMP codec, `*` standing for any codec.
+    pub fn supports_four_cc(&self, four_cc: &str) -> bool {
+        let matches = |name: &String| name == "*" || name == four_cc;
+        let in_info_map = |map: &Option<BTreeMap<String, f64>>| {
+            map.iter()
+                .flatten()
+                .any(|(name, &flags)| matches(name) && flags != 0.0)
+        };
+        self.four_cc_list.iter().flatten().any(matches)
+            || in_info_map(&self.video_four_cc_info_map)
+            || in_info_map(&self.audio_four_cc_info_map)
+    }
+}
+
+/// Server properties sent in the `_result` of `connect`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ServerProperties {
+    pub fms_ver: String,
+    pub capabilities: f64,
+    pub mode: f64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub four_cc_list: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub video_four_cc_info_map: Option<BTreeMap<String, f64>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub audio_four_cc_info_map: Option<BTreeMap<String, f64>>,
+}
+
+/// Information object of `_result`, `_error` and `onStatus` responses.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StatusInfo {
+    pub level: String,
+    pub code: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub object_encoding: Option<u8>,
+}
+
+impl StatusInfo {
+    pub fn new(code: &str, success: bool) -> Self {
+        Self {
+            level: String::from(if success { "status" } else { "error" }),
+            code: String::from(code),
+            description: None,
+            object_encoding: None,
+        }
+    }
+}
+
+/// Codec identifier in `onMetaData`, either a legacy FLV codec ID or a FourCC string.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum CodecId {
+    Number(f64),
+    FourCc(String),
+}
+
+/// Properties of `onMetaData`.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MetaData {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub duration: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub width: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub height: Option<f64>,
+    #[serde(rename = "videodatarate", skip_serializing_if = "Option::is_none")]
+    pub video_data_rate: Option<f64>,
+    #[serde(rename = "framerate", skip_serializing_if = "Option::is_none")]
+    pub frame_rate: Option<f64>,
+    #[serde(rename = "videocodecid", skip_serializing_if = "Option::is_none")]
+    pub video_codec_id: Option<CodecId>,
+    #[serde(rename = "audiodatarate", skip_serializing_if = "Option::is_none")]
+    pub audio_data_rate: Option<f64>,
+    #[serde(rename = "audiosamplerate", skip_serializing_if = "Option::is_none")]
+    pub audio_sample_rate: Option<f64>,
+    #[serde(rename = "audiosamplesize", skip_serializing_if = "Option::is_none")]
+    pub audio_sample_size: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stereo: Option<bool>,
+    #[serde(rename = "audiocodecid", skip_serializing_if = "Option::is_none")]
+    pub audio_codec_id: Option<CodecId>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub encoder: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub filesize: Option<f64>,
+}
@@ -0,0 +1,275 @@
+use std::collections::VecDeque;
+use std::str::FromStr;
+use std::sync::{Arc, Condvar, Mutex};
+
+use crate::constant::*;
+use crate::error::Error;
+use crate::media::{AudioTagHeader, VideoTagHeader};
+use crate::stream::SharedMessage;
+
+/// What to do when a subscriber falls so far behind that its send queue is full.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OverflowPolicy {
+    /// Drop the incoming message and every following video frame up to the next keyframe, so
+    /// that the player never receives frames referencing dropped ones. Sequence headers and
+    /// data messages are never dropped.
+    DropNonKeyframes,
+    /// Drop the oldest queued audio or video frame. Sequence headers and data messages are
+    /// never dropped.
+    DropOldest,
+    /// Disconnect the subscriber.
+    Disconnect,
+}
+
+impl FromStr for OverflowPolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "drop-non-keyframes" => Ok(OverflowPolicy::DropNonKeyframes),
+            "drop-oldest" => Ok(OverflowPolicy::DropOldest),
+            "disconnect" => Ok(OverflowPolicy::Disconnect),
+            _ => Err(Error::InvalidConfig(format!(
+                "unknown overflow policy {}",
+                s
+            ))),
+        }
+    }
+}
+
+pub fn is_keyframe(message: &SharedMessage) -> bool {
+    message.header.message_type_id == RTMP_VIDEO_MESSAGE
+        && message.payload.first().map(|b| (b >> 4) & 0x7) == Some(FLV_VIDEO_FRAME_TYPE_KEYFRAME)
+}
+
+/// Whether a message is an audio or video frame, which players can do without, rather than a
+/// sequence header or a data message.
+fn is_frame(message: &SharedMessage) -> bool {
+    let payload = &message.payload;
+    match message.header.message_type_id {
+        RTMP_VIDEO_MESSAGE => {
+            !VideoTagHeader::parse(payload).is_ok_and(|(header, _)| header.is_sequence_header())
+        }
+        RTMP_AUDIO_MESSAGE => {
+            !AudioTagHeader::parse(payload).is_ok_and(|(header, _)| header.is_sequence_header())
+        }
+        _ => false,
+    }
+}
+
+#[derive(Debug, Default)]
+struct SendQueueState {
+    messages: VecDeque<Arc<SharedMessage>>,
+    closed: bool,
+    /// Whether the queue closes once the queued messages have been popped.
+    finishing: bool,
+    skipping_to_keyframe: bool,
+    dropped_frames: u64,
+}
+
+/// Bounded queue of messages waiting to be sent to a subscriber by its writer thread.
+#[derive(Debug)]
+pub struct SendQueue {
+    capacity: usize,
+    policy: OverflowPolicy,
+    state: Mutex<SendQueueState>,
+    ready: Condvar,
+}
+
+impl SendQueue {
+    pub fn new(capacity: usize, policy: OverflowPolicy) -> Self {
+        Self {
+            capacity,
+            policy,
+            state: Mutex::new(SendQueueState::default()),
+            ready: Condvar::new(),
+        }
+    }
+
+    /// Queues a message without blocking, applying the overflow policy if the queue is full.
+    /// Returns `false` if the queue has been closed.
+    pub fn push(&self, message: Arc<SharedMessage>) -> bool {
+        let state = &mut *self.state.lock().unwrap();
+        if state.closed || state.finishing {
+            return false;
+        }
+        if state.skipping_to_keyframe
+            && message.header.message_type_id == RTMP_VIDEO_MESSAGE
+            && !is_keyframe(&message)
+            && is_frame(&message)
+        {
+            state.dropped_frames += 1;
+            return true;
+        }
+        if state.messages.len() >= self.capacity {
+            match self.policy {
+                // Messages other than frames are queued even beyond the capacity.
+                OverflowPolicy::DropNonKeyframes if !is_frame(&message) => {}
+                OverflowPolicy::DropNonKeyframes => {
+                    state.skipping_to_keyframe = true;
+                    state.dropped_frames += 1;
+                    return true;
+                }
+                OverflowPolicy::DropOldest => {
+                    if let Some(index) = state.messages.iter().position(|m| is_frame(m)) {
+                        state.messages.remove(index);
+                        state.dropped_frames += 1;
+                    }
+                }
+                OverflowPolicy::Disconnect => {
+                    state.closed = true;
+                    state.messages.clear();
+                    self.ready.notify_all();
+                    return false;
+                }
+            }
+        }
+        if is_keyframe(&message) && is_frame(&message) {
+            state.skipping_to_keyframe = false;
+        }
+        state.messages.push_back(message);
+        self.ready.notify_one();
+        true
+    }
+
+    /// Waits for the next message, or returns `None` once the queue has been closed or finished.
+    pub fn pop(&self) -> Option<Arc<SharedMessage>> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if state.closed {
+                return None;
+            }
+            if let Some(message) = state.messages.pop_front() {
+                return Some(message);
+            }
+            if state.finishing {
+                return None;
+            }
+            state = self.ready.wait(state).unwrap();
+        }
+    }
+
+    pub fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.ready.notify_all();
+    }
+
+    /// Stops queueing messages, and closes the queue once the queued ones have been popped.
+    pub fn finish(&self) {
+        self.state.lock().unwrap().finishing = true;
+        self.ready.notify_all();
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.state.lock().unwrap().closed
+    }
+
+    /// Number of messages dropped because the subscriber could not keep up.
+    pub fn dropped_frames(&self) -> u64 {
+        self.state.lock().unwrap().dropped_frames
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stream::ChunkMessageHeader;
+
+    fn message(message_type_id: u8, first_byte: u8) -> Arc<SharedMessage> {
+        Arc::new(SharedMessage::new(
+            ChunkMessageHeader::new(0, 1, message_type_id, 1),
+            vec![first_byte],
+        ))
+    }
+
+    fn drain(queue: &SendQueue) -> Vec<u8> {
+        let mut bytes = vec![];
+        while !queue.state.lock().unwrap().messages.is_empty() {
+            bytes.push(queue.pop().unwrap().payload[0]);
+        }
+        bytes
+    }
+
+    #[test]
+    fn test_drop_non_keyframes() {
+        let queue = SendQueue::new(2, OverflowPolicy::DropNonKeyframes);
+        assert!(queue.push(message(RTMP_VIDEO_MESSAGE, 0x17)));
+        assert!(queue.push(message(RTMP_VIDEO_MESSAGE, 0x27)));
+        assert!(queue.push(message(RTMP_VIDEO_MESSAGE, 0x27)));
+        assert_eq!(drain(&queue), [0x17, 0x27]);
+        // Frames up to the next keyframe are dropped even though there is room again.
+        assert!(queue.push(message(RTMP_VIDEO_MESSAGE, 0x27)));
+        assert!(queue.push(message(RTMP_AUDIO_MESSAGE, 0xAF)));
+        assert!(queue.push(message(RTMP_VIDEO_MESSAGE, 0x17)));
+        assert!(queue.push(message(RTMP_VIDEO_MESSAGE, 0x27)));
+        assert_eq!(drain(&queue), [0xAF, 0x17]);
+        assert_eq!(queue.dropped_frames(), 3);
+    }
+
+    #[test]
+    fn test_drop_non_keyframes_keeps_headers() {
+        let queue = SendQueue::new(1, OverflowPolicy::DropNonKeyframes);
+        assert!(queue.push(message(RTMP_VIDEO_MESSAGE, 0x17)));
+        assert!(queue.push(message(RTMP_AUDIO_MESSAGE, 0xAF)));
+        // Sequence headers and metadata are queued even though the queue is full.
+        let sequence_header = |message_type_id, payload: &[u8]| {
+            Arc::new(SharedMessage::new(
+                ChunkMessageHeader::new(0, payload.len(), message_type_id, 1),
+                payload.to_vec(),
+            ))
+        };
+        assert!(queue.push(sequence_header(
+            RTMP_VIDEO_MESSAGE,
+            &[0x17, 0x0, 0x0, 0x0, 0x0]
+        )));
+        assert!(queue.push(sequence_header(RTMP_AUDIO_MESSAGE, &[0xAF, 0x0])));
+        assert!(queue.push(message(RTMP_DATA_MESSAGE_AMF0, 0x2)));
+        assert!(queue.push(message(RTMP_VIDEO_MESSAGE, 0x27)));
+        assert_eq!(drain(&queue), [0x17, 0x17, 0xAF, 0x2]);
+        assert_eq!(queue.dropped_frames(), 2);
+    }
+
+    #[test]
+    fn test_drop_oldest() {
+        let queue = SendQueue::new(2, OverflowPolicy::DropOldest);
+        for i in 0..4 {
+            assert!(queue.push(message(RTMP_AUDIO_MESSAGE, i)));
+        }
+        assert_eq!(drain(&queue), [2, 3]);
+        assert_eq!(queue.dropped_frames(), 2);
+
+        // Metadata and sequence headers are kept, and frames after them are dropped instead.
+        let sequence_header = Arc::new(SharedMessage::new(
+            ChunkMessageHeader::new(0, 2, RTMP_AUDIO_MESSAGE, 1),
+            vec![0xAF, 0x0],
+        ));
+        assert!(queue.push(message(RTMP_DATA_MESSAGE_AMF0, 0x2)));
+        assert!(queue.push(sequence_header));
+        for i in 0..2 {
+            assert!(queue.push(message(RTMP_AUDIO_MESSAGE, i)));
+        }
+        assert_eq!(drain(&queue), [0x2, 0xAF, 1]);
+        assert_eq!(queue.dropped_frames(), 3);
+    }
+
+    #[test]
+    fn test_disconnect() {
+        let queue = SendQueue::new(1, OverflowPolicy::Disconnect);
+        assert!(queue.push(message(RTMP_AUDIO_MESSAGE, 0)));
+        assert!(!queue.push(message(RTMP_AUDIO_MESSAGE, 1)));
+        assert!(queue.is_closed());
+        assert!(queue.pop().is_none());
+    }
+
+    #[test]
+    fn test_finish() {
+        let queue = SendQueue::new(2, OverflowPolicy::Disconnect);
+        assert!(queue.push(message(RTMP_AUDIO_MESSAGE, 0)));
+        assert!(queue.push(message(RTMP_AUDIO_MESSAGE, 1)));
+        queue.finish();
+        assert!(!queue.push(message(RTMP_AUDIO_MESSAGE, 2)));
+        assert_eq!(queue.pop().unwrap().payload[0], 0);
+        assert_eq!(queue.pop().unwrap().payload[0], 1);
+        assert!(queue.pop().is_none());
+    }
+}
@@ -0,0 +1,284 @@
+//! Recording of published streams to FLV files.
+
+use std::fs::{File, OpenOptions};
+use std::io::BufWriter;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::amf::AmfObjectMap;
+use crate::config::Config;
+use crate::constant::*;
+use crate::error::{Error, Result};
+use crate::flv::FlvWriter;
+use crate::media::{AudioTagHeader, VideoTagHeader};
+
+/// Replaces characters which could escape the recording directory or are awkward in file names.
+pub fn sanitize(name: &str) -> String {
+    name.chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' | '.' => c,
+            _ => '_',
+        })
+        .collect()
+}
+
+/// Records a published stream, starting a new file whenever the current one exceeds the size or
+/// duration limits.
+#[derive(Debug)]
+pub struct Recorder {
+    directory: PathBuf,
+    template: String,
+    name: String,
+    max_size: u64,
+    /// Maximum duration of a file in milliseconds, or 0 for no limit.
+    max_duration: u32,
+    /// Whether the first file is appended to instead of overwritten.
+    append: bool,
+    writer: Option<FlvWriter<BufWriter<File>>>,
+    /// Number of files started so far.
+    index: u32,
+    /// File timestamp of the first tag of the current file, and the stream timestamp it maps to.
+    start: u32,
+    base_timestamp: Option<u32>,
+    metadata: Option<AmfObjectMap>,
+    video_sequence_header: Option<Vec<u8>>,
+    audio_sequence_header: Option<Vec<u8>>,
+    has_video: bool,
+}
+
+impl Recorder {
+    pub fn new(config: &Config, name: &str, append: bool) -> Self {
+        Self {
+            directory: config.record_directory.clone(),
+            template: config.record_template.clone(),
+            name: sanitize(name),
+            max_size: config.record_max_size,
+            max_duration: config.record_max_duration.saturating_mul(1000),
+            append,
+            writer: None,
+            index: 0,
+            start: 0,
+            base_timestamp: None,
+            metadata: None,
+            video_sequence_header: None,
+            audio_sequence_header: None,
+            has_video: false,
+        }
+    }
+
+    /// Path of the next file. `{name}` in the template is replaced by the stream name, `{time}`
+    /// by the current Unix time and `{index}` by the number of files started before. Templates
+    /// without `{time}` nor `{index}` get the index appended to the name of rotated files.
+    pub fn path(&self) -> PathBuf {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |time| time.as_secs());
+        let mut file_name = self
+            .template
+            .replace("{name}", &self.name)
+            .replace("{time}", &time.to_string())
+            .replace("{index}", &self.index.to_string());
+        if self.index > 0 && !self.template.contains("{time}") && !self.template.contains("{index}")
+        {
+            let suffix = format!("-{}", self.index);
+            match file_name.rfind('.') {
+                Some(extension) => file_name.insert_str(extension, &suffix),
+                None => file_name.push_str(&suffix),
+            }
+        }
+        self.directory.join(file_name)
+    }
+
+    fn open(&mut self) -> Result<()> {
+        let path = self.path();
+        eprintln!("Recording {} to {}", self.name, path.display());
+        let append = self.append && self.index == 0 && path.exists();
+        let mut writer = if append {
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(&path)
+                .map_err(Error::Io)?;
+            let mut reader = file.try_clone().map_err(Error::Io)?;
+            FlvWriter::append(BufWriter::new(file), &mut reader)?
+        } else {
+            let file = File::create(&path).map_err(Error::Io)?;
+            let has_audio = self.metadata.as_ref().is_none_or(|metadata| {
+                metadata.get("audiocodecid").is_some() || self.audio_sequence_header.is_some()
+            });
+            let has_video = self
+                .metadata
+                .as_ref()
+                .is_none_or(|metadata| metadata.get("videocodecid").is_some() || self.has_video);
+            FlvWriter::create(BufWriter::new(file), has_audio, has_video)?
+        };
+        // Appended tags follow the last one of the file.
+        let start = if append { writer.duration() + 1 } else { 0 };
+        if !append {
+            writer.write_metadata(0, self.metadata.clone().unwrap_or_default())?;
+        }
+        // Files started by rotation need the sequence headers to be decodable.
+        if let Some(ref data) = self.video_sequence_header {
+            writer.write_tag(FLV_TAG_TYPE_VIDEO, start, data)?;
+        }
+        if let Some(ref data) = self.audio_sequence_header {
+            writer.write_tag(FLV_TAG_TYPE_AUDIO, start, data)?;
+        }
+        self.writer = Some(writer);
+        self.index += 1;
+        self.start = start;
+        self.base_timestamp = None;
+        Ok(())
+    }
+
+    /// Records the properties of `onMetaData`, which are written at the beginning of each file.
+    pub fn write_metadata(&mut self, properties: AmfObjectMap) -> Result<()> {
+        if let Some(ref mut writer) = self.writer {
+            let timestamp = writer.duration();
+            writer.write_metadata(timestamp, properties.clone())?;
+        }
+        self.metadata = Some(properties);
+        Ok(())
+    }
+
+    /// Records an audio or video message. The first file is created on the first message, and
+    /// files are rotated on keyframes, or on any audio message for streams without video.
+    pub fn write_message(
+        &mut self,
+        message_type_id: u8,
+        timestamp: u32,
+        data: &[u8],
+    ) -> Result<()> {
+        let (is_sequence_header, is_rotation_point) = match message_type_id {
+            RTMP_VIDEO_MESSAGE => {
+                self.has_video = true;
+                match VideoTagHeader::parse(data) {
+                    Ok((header, _)) => (
+                        header.is_sequence_header(),
+                        header.is_keyframe() && !header.is_sequence_header(),
+                    ),
+                    Err(_) => (false, false),
+                }
+            }
+            RTMP_AUDIO_MESSAGE => {
+                let is_sequence_header = AudioTagHeader::parse(data)
+                    .is_ok_and(|(header, _)| header.is_sequence_header());
+                (is_sequence_header, !self.has_video && !is_sequence_header)
+            }
+            _ => return Ok(()),
+        };
+        if is_rotation_point && self.is_full(timestamp) {
+            self.finish()?;
+        }
+        if self.writer.is_none() {
+            self.open()?;
+        }
+        let base_timestamp = *self.base_timestamp.get_or_insert(timestamp);
+        let file_timestamp = self
+            .start
+            .wrapping_add(timestamp.wrapping_sub(base_timestamp));
+        if let Some(ref mut writer) = self.writer {
+            writer.write_tag(message_type_id, file_timestamp, data)?;
+        }
+        if is_sequence_header {
+            let sequence_header = Some(data.to_vec());
+            if message_type_id == RTMP_VIDEO_MESSAGE {
+                self.video_sequence_header = sequence_header;
+            } else {
+                self.audio_sequence_header = sequence_header;
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether the current file has reached the size or duration limit.
+    fn is_full(&self, timestamp: u32) -> bool {
+        let writer = match self.writer {
+            Some(ref writer) => writer,
+            None => return false,
+        };
+        let duration = self
+            .base_timestamp
+            .map_or(0, |base_timestamp| timestamp.wrapping_sub(base_timestamp));
+        (self.max_size > 0 && writer.size() >= self.max_size)
+            || (self.max_duration > 0 && duration >= self.max_duration)
+    }
+
+    /// Finishes the current file.
+    pub fn finish(&mut self) -> Result<()> {
+        match self.writer.take() {
+            Some(writer) => writer.finish().map(|_| ()),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for Recorder {
+    fn drop(&mut self) {
+        if let Err(e) = self.finish() {
+            eprintln!("Failed to finish recording of {}: {}", self.name, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flv::{FlvTagHeader, FLV_TAG_HEADER_SIZE};
+    use std::fs;
+
+    #[test]
+    fn test_recorder_rotation() {
+        let directory = std::env::temp_dir().join(format!("rtmp-record-{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let config = Config {
+            record_directory: directory.clone(),
+            record_max_duration: 2,
+            ..Config::default()
+        };
+        let mut recorder = Recorder::new(&config, "live/../test", false);
+        assert_eq!(recorder.path(), directory.join("live_.._test.flv"));
+        let sequence_header = [0x17, 0x0, 0x0, 0x0, 0x0];
+        recorder
+            .write_message(RTMP_VIDEO_MESSAGE, 500, &sequence_header)
+            .unwrap();
+        for timestamp in [500, 1500, 2500, 3500] {
+            recorder
+                .write_message(RTMP_VIDEO_MESSAGE, timestamp, &[0x17, 0x1, 0x0, 0x0, 0x0])
+                .unwrap();
+        }
+        recorder.finish().unwrap();
+        let first = fs::read(directory.join("live_.._test.flv")).unwrap();
+        let second = fs::read(directory.join("live_.._test-1.flv")).unwrap();
+        fs::remove_dir_all(&directory).unwrap();
+        // The second file starts with the sequence header and the keyframe at 2500.
+        let tags = |buffer: &[u8]| {
+            let mut tags = vec![];
+            let mut position = 13;
+            while position < buffer.len() {
+                let header = FlvTagHeader::read(&mut &buffer[position..]).unwrap();
+                tags.push((header.tag_type, header.timestamp));
+                position += FLV_TAG_HEADER_SIZE + header.data_size + 4;
+            }
+            tags
+        };
+        assert_eq!(
+            tags(&first),
+            [
+                (FLV_TAG_TYPE_SCRIPT, 0),
+                (FLV_TAG_TYPE_VIDEO, 0),
+                (FLV_TAG_TYPE_VIDEO, 0),
+                (FLV_TAG_TYPE_VIDEO, 1000),
+            ]
+        );
+        assert_eq!(
+            tags(&second),
+            [
+                (FLV_TAG_TYPE_SCRIPT, 0),
+                (FLV_TAG_TYPE_VIDEO, 0),
+                (FLV_TAG_TYPE_VIDEO, 0),
+                (FLV_TAG_TYPE_VIDEO, 1000),
+            ]
+        );
+    }
+}
@@ -0,0 +1,476 @@
+//! Push relay forwarding published streams to upstream RTMP servers.
+
+use std::str::FromStr;
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::client::RtmpConnection;
+use crate::constant::*;
+use crate::error::{Error, Result};
+use crate::media::{AudioTagHeader, VideoTagHeader};
+use crate::queue::is_keyframe;
+use crate::stream::SharedMessage;
+use crate::utils::percent_decode;
+
+// Delay before reconnecting to an upstream server, doubled after each failed attempt.
+const RECONNECT_DELAY_MIN: Duration = Duration::from_secs(1);
+const RECONNECT_DELAY_MAX: Duration = Duration::from_secs(30);
+// Time after which connecting, publishing or sending to an upstream server counts as failed.
+const UPSTREAM_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Upstream server and stream a published stream is pushed to.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PushTarget {
+    /// `host:port` of the server.
+    pub address: String,
+    pub app: String,
+    pub name: String,
+}
+
+impl FromStr for PushTarget {
+    type Err = Error;
+
+    /// Parses `rtmp://host[:port]/app/name`.
+    fn from_str(url: &str) -> Result<Self> {
+        let invalid = || Error::InvalidRtmpUrl(String::from(url));
+        let (host, path) = url
+            .strip_prefix("rtmp://")
+            .and_then(|rest| rest.split_once('/'))
+            .ok_or_else(invalid)?;
+        let (app, name) = path.split_once('/').ok_or_else(invalid)?;
+        if host.is_empty() || app.is_empty() || name.is_empty() {
+            return Err(invalid());
+        }
+        // The colons of an IPv6 address are enclosed in brackets.
+        let has_port = host.rfind(':').is_some_and(|i| !host[i..].contains(']'));
+        let address = if has_port {
+            String::from(host)
+        } else {
+            format!("{}:{}", host, RTMP_DEFAULT_PORT)
+        };
+        Ok(Self {
+            address,
+            app: String::from(app),
+            name: String::from(name),
+        })
+    }
+}
+
+/// Push targets configured for stream names, as space-separated `<stream>=<url>` entries. The
+/// stream `*` matches every stream, and `{name}` in a URL is replaced by the stream name.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PushTargets(Vec<(String, String)>);
+
+impl FromStr for PushTargets {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        s.split_whitespace()
+            .map(|entry| {
+                let (stream, url) = entry
+                    .split_once('=')
+                    .ok_or_else(|| Error::InvalidRtmpUrl(String::from(entry)))?;
+                url.replace("{name}", "name").parse::<PushTarget>()?;
+                Ok((String::from(stream), String::from(url)))
+            })
+            .collect::<Result<_>>()
+            .map(PushTargets)
+    }
+}
+
+impl PushTargets {
+    /// Targets the stream `name` is pushed to.
+    pub fn get(&self, name: &str) -> Vec<PushTarget> {
+        self.0
+            .iter()
+            .filter(|(stream, _)| stream == "*" || stream == name)
+            .filter_map(|(_, url)| url.replace("{name}", name).parse().ok())
+            .collect()
+    }
+}
+
+/// Targets given by `push` parameters in the query of a publishing name, such as
+/// `name?push=rtmp://host/app/key`. Invalid ones are logged and left out.
+pub fn query_push_targets(query: &str) -> Vec<PushTarget> {
+    query
+        .split('&')
+        .filter_map(|parameter| parameter.split_once('='))
+        .filter(|&(name, _)| name == "push")
+        .filter_map(|(_, value)| {
+            let target = percent_decode(value)
+                .ok_or_else(|| Error::InvalidRtmpUrl(String::from(value)))
+                .and_then(|url| url.parse());
+            target
+                .map_err(|e| eprintln!("Ignoring push target: {}", e))
+                .ok()
+        })
+        .collect()
+}
+
+/// Forwards the messages of a stream to a push target, from the writer thread of a subscriber
+/// of its own. Connecting happens on a thread of its own, so that the queue keeps draining.
+/// While the target cannot be reached, messages are dropped and connecting is retried with
+/// exponential backoff.
+#[derive(Debug)]
+pub struct PushRelay {
+    stream_name: String,
+    target: PushTarget,
+    connection: Option<RtmpConnection>,
+    /// Result of the connection attempt in progress, if any.
+    connecting: Option<Receiver<Result<RtmpConnection>>>,
+    /// Messages sent again after reconnecting so that the target can decode the stream.
+    metadata: Option<Arc<SharedMessage>>,
+    video_sequence_header: Option<Arc<SharedMessage>>,
+    audio_sequence_header: Option<Arc<SharedMessage>>,
+    /// Whether video is dropped until the next keyframe, after connecting.
+    waiting_for_keyframe: bool,
+    reconnect_delay: Duration,
+    next_attempt: Instant,
+}
+
+impl PushRelay {
+    pub fn new(stream_name: &str, target: PushTarget) -> Self {
+        Self {
+            stream_name: String::from(stream_name),
+            target,
+            connection: None,
+            connecting: None,
+            metadata: None,
+            video_sequence_header: None,
+            audio_sequence_header: None,
+            waiting_for_keyframe: true,
+            reconnect_delay: RECONNECT_DELAY_MIN,
+            next_attempt: Instant::now(),
+        }
+    }
+
+    pub fn push(&mut self, message: &Arc<SharedMessage>) {
+        if self.connection.is_none() {
+            self.poll_connect();
+        }
+        let is_sequence_header = self.update_sequence_headers(message);
+        let connection = match self.connection {
+            Some(ref mut connection) => connection,
+            None => return,
+        };
+        if message.header.message_type_id == RTMP_VIDEO_MESSAGE && !is_sequence_header {
+            if self.waiting_for_keyframe && !is_keyframe(message) {
+                return;
+            }
+            self.waiting_for_keyframe = false;
+        }
+        if let Err(e) = send(connection, message) {
+            eprintln!(
+                "Lost connection pushing {} to {}: {}",
+                self.stream_name, self.target.address, e
+            );
+            self.disconnect();
+            self.next_attempt = Instant::now() + self.reconnect_delay;
+        }
+    }
+
+    /// Keeps the metadata and sequence headers of the stream. Returns whether the message is a
+    /// sequence header.
+    fn update_sequence_headers(&mut self, message: &Arc<SharedMessage>) -> bool {
+        let payload = &message.payload;
+        let slot = match message.header.message_type_id {
+            RTMP_DATA_MESSAGE_AMF0 => {
+                self.metadata = Some(Arc::clone(message));
+                return false;
+            }
+            RTMP_VIDEO_MESSAGE
+                if VideoTagHeader::parse(payload)
+                    .is_ok_and(|(header, _)| header.is_sequence_header()) =>
+            {
+                &mut self.video_sequence_header
+            }
+            RTMP_AUDIO_MESSAGE
+                if AudioTagHeader::parse(payload)
+                    .is_ok_and(|(header, _)| header.is_sequence_header()) =>
+            {
+                &mut self.audio_sequence_header
+            }
+            _ => return false,
+        };
+        *slot = Some(Arc::clone(message));
+        true
+    }
+
+    /// Starts connecting once the backoff delay has passed, and takes the connection when the
+    /// attempt in progress succeeds.
+    fn poll_connect(&mut self) {
+        let result = match self.connecting.as_ref().map(Receiver::try_recv) {
+            None if Instant::now() >= self.next_attempt => {
+                self.connecting = Some(spawn_connect(self.target.clone()));
+                return;
+            }
+            None | Some(Err(TryRecvError::Empty)) => return,
+            Some(Ok(result)) => result,
+            Some(Err(TryRecvError::Disconnected)) => Err(Error::Io(std::io::Error::other(
+                "connecting thread panicked",
+            ))),
+        };
+        self.connecting = None;
+        match result.and_then(|connection| self.start(connection)) {
+            Ok(connection) => {
+                eprintln!("Pushing {} to {}", self.stream_name, self.target.address);
+                self.connection = Some(connection);
+                self.waiting_for_keyframe = true;
+                self.reconnect_delay = RECONNECT_DELAY_MIN;
+            }
+            Err(e) => {
+                eprintln!(
+                    "Failed to push {} to {}, retrying in {:?}: {}",
+                    self.stream_name, self.target.address, self.reconnect_delay, e
+                );
+                self.next_attempt = Instant::now() + self.reconnect_delay;
+                self.reconnect_delay = (self.reconnect_delay * 2).min(RECONNECT_DELAY_MAX);
+            }
+        }
+    }
+
+    /// Sends the metadata and sequence headers received so far on a new connection.
+    fn start(&self, mut connection: RtmpConnection) -> Result<RtmpConnection> {
+        let result = self
+            .metadata
+            .iter()
+            .chain(self.video_sequence_header.iter())
+            .chain(self.audio_sequence_header.iter())
+            .try_for_each(|message| send(&mut connection, message));
+        match result {
+            Ok(()) => Ok(connection),
+            Err(e) => {
+                let _ = connection.close();
+                Err(e)
+            }
+        }
+    }
+
+    fn disconnect(&mut self) {
+        if let Some(connection) = self.connection.take() {
+            let _ = connection.close();
+        }
+    }
+}
+
+impl Drop for PushRelay {
+    fn drop(&mut self) {
+        self.disconnect();
+    }
+}
+
+/// Connects to `target` from a thread of its own, returning the result through a channel.
+fn spawn_connect(target: PushTarget) -> Receiver<Result<RtmpConnection>> {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        // The relay may have gone away in the meantime.
+        if let Err(mpsc::SendError(Ok(connection))) = sender.send(connect(&target)) {
+            let _ = connection.close();
+        }
+    });
+    receiver
+}
+
+/// Publishes to `target`. The connection is then read from a thread of its own, which
+/// acknowledges received bytes and answers pings until the socket is shut down. Writes still
+/// time out, so that an upstream server which stops reading is dropped.
+fn connect(target: &PushTarget) -> Result<RtmpConnection> {
+    let mut connection =
+        RtmpConnection::connect_timeout(&target.address, &target.app, UPSTREAM_TIMEOUT)?;
+    connection.publish(&target.name)?;
+    connection.set_read_timeout(None)?;
+    let sender = connection.decouple();
+    thread::spawn(move || while connection.read().is_ok() {});
+    Ok(sender)
+}
+
+fn send(connection: &mut RtmpConnection, message: &SharedMessage) -> Result<()> {
+    connection.send_message(
+        message.header.message_type_id,
+        message.header.timestamp,
+        &message.payload,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::tests::{read_video, spawn_server};
+    use crate::config::Config;
+    use crate::queue::OverflowPolicy;
+    use std::io::{self, Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    fn target(address: &str, app: &str, name: &str) -> PushTarget {
+        PushTarget {
+            address: String::from(address),
+            app: String::from(app),
+            name: String::from(name),
+        }
+    }
+
+    #[test]
+    fn test_push_target() {
+        assert_eq!(
+            "rtmp://example.com/live/key".parse::<PushTarget>().unwrap(),
+            target("example.com:1935", "live", "key")
+        );
+        assert_eq!(
+            "rtmp://[::1]:1936/live/a/b".parse::<PushTarget>().unwrap(),
+            target("[::1]:1936", "live", "a/b")
+        );
+        assert_eq!(
+            "rtmp://[::1]/live/key".parse::<PushTarget>().unwrap(),
+            target("[::1]:1935", "live", "key")
+        );
+        assert!("http://example.com/live/key".parse::<PushTarget>().is_err());
+        assert!("rtmp://example.com/live".parse::<PushTarget>().is_err());
+        assert!("rtmp://example.com/live/".parse::<PushTarget>().is_err());
+    }
+
+    #[test]
+    fn test_push_targets() {
+        let targets: PushTargets = "*=rtmp://backup/live/{name} cam=rtmp://partner:1936/in/xyz"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            targets.get("cam"),
+            vec![
+                target("backup:1935", "live", "cam"),
+                target("partner:1936", "in", "xyz")
+            ]
+        );
+        assert_eq!(
+            targets.get("other"),
+            vec![target("backup:1935", "live", "other")]
+        );
+        assert!("cam".parse::<PushTargets>().is_err());
+        assert!("cam=rtmp://partner".parse::<PushTargets>().is_err());
+    }
+
+    #[test]
+    fn test_query_push_targets() {
+        assert_eq!(
+            query_push_targets(
+                "push=rtmp://a/live/x&token=1&push=rtmp%3A%2F%2Fb%2Flive%2Fy&push=z"
+            ),
+            vec![target("a:1935", "live", "x"), target("b:1935", "live", "y")]
+        );
+    }
+
+    /// Sends `frame` every 20 milliseconds from `timestamp` on, until `player` receives it from
+    /// the upstream server. Returns the publisher with the next timestamp.
+    fn push_until_received(
+        mut publisher: RtmpConnection,
+        player: &mut RtmpConnection,
+        mut timestamp: u32,
+        frame: &[u8],
+    ) -> (RtmpConnection, u32) {
+        let done = Arc::new(AtomicBool::new(false));
+        let sending = Arc::clone(&done);
+        let sent = frame.to_vec();
+        let sender = thread::spawn(move || {
+            while !sending.load(Ordering::Relaxed) {
+                publisher.send_video(timestamp, &sent).unwrap();
+                timestamp += 40;
+                thread::sleep(Duration::from_millis(20));
+            }
+            (publisher, timestamp)
+        });
+        while read_video(player).1 != frame {}
+        done.store(true, Ordering::Relaxed);
+        sender.join().unwrap()
+    }
+
+    #[test]
+    fn test_push_relay() {
+        let upstream = spawn_server(Config::default());
+        let address = spawn_server(Config {
+            push_from_query: true,
+            ..Config::default()
+        });
+        let mut player = RtmpConnection::connect(&upstream, "live").unwrap();
+        player.play("remote").unwrap();
+        let mut publisher = RtmpConnection::connect(&address, "live").unwrap();
+        publisher
+            .publish(&format!("local?push=rtmp://{}/live/remote", upstream))
+            .unwrap();
+        // Video is pushed from a keyframe on, once the relay has connected.
+        publisher.send_video(0, &[0x22, 0x0, 0x0, 0x84]).unwrap();
+        let keyframe = vec![0x12, 0x0, 0x0, 0x84];
+        let (mut publisher, timestamp) = push_until_received(publisher, &mut player, 40, &keyframe);
+        publisher.send_video(timestamp, &[0x22, 0x1]).unwrap();
+        while read_video(&mut player) != (timestamp, vec![0x22, 0x1]) {}
+
+        // The stream is published locally without the query.
+        let mut local_player = RtmpConnection::connect(&address, "live").unwrap();
+        local_player.play("local").unwrap();
+        assert_eq!(read_video(&mut local_player).1, keyframe);
+    }
+
+    /// Forwards connections to `upstream`, holding data sent upstream while `paused` is set.
+    fn spawn_proxy(upstream: String, paused: Arc<AtomicBool>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        thread::spawn(move || {
+            for mut client in listener.incoming().flatten() {
+                let mut server = TcpStream::connect(&upstream).unwrap();
+                let (mut from_server, mut to_client) =
+                    (server.try_clone().unwrap(), client.try_clone().unwrap());
+                thread::spawn(move || io::copy(&mut from_server, &mut to_client));
+                let paused = Arc::clone(&paused);
+                thread::spawn(move || {
+                    let mut buffer = [0; 4096];
+                    loop {
+                        while paused.load(Ordering::Relaxed) {
+                            thread::sleep(Duration::from_millis(10));
+                        }
+                        match client.read(&mut buffer) {
+                            Ok(0) | Err(_) => break,
+                            Ok(n) => server.write_all(&buffer[..n]).unwrap(),
+                        }
+                    }
+                });
+            }
+        });
+        address
+    }
+
+    #[test]
+    fn test_push_relay_overflow() {
+        let paused = Arc::new(AtomicBool::new(false));
+        let upstream = spawn_server(Config::default());
+        let proxy = spawn_proxy(upstream.clone(), Arc::clone(&paused));
+        // Players are disconnected when falling behind, but relays are not.
+        let address = spawn_server(Config {
+            push_from_query: true,
+            overflow_policy: OverflowPolicy::Disconnect,
+            send_queue_size: 1,
+            ..Config::default()
+        });
+        let mut player = RtmpConnection::connect(&upstream, "live").unwrap();
+        player.play("remote").unwrap();
+        player
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut publisher = RtmpConnection::connect(&address, "live").unwrap();
+        publisher
+            .publish(&format!("local?push=rtmp://{}/live/remote", proxy))
+            .unwrap();
+        let (mut publisher, mut timestamp) =
+            push_until_received(publisher, &mut player, 0, &[0x12, 0x0, 0x0, 0x84]);
+
+        // The relay falls behind while the upstream server does not read.
+        paused.store(true, Ordering::Relaxed);
+        let frame: Vec<_> = [0x22].iter().copied().chain(vec![0x7; 65536]).collect();
+        for _ in 0..400 {
+            publisher.send_video(timestamp, &frame).unwrap();
+            timestamp += 1;
+        }
+        paused.store(false, Ordering::Relaxed);
+        push_until_received(publisher, &mut player, timestamp, &[0x12, 0x0, 0x0, 0x85]);
+    }
+}
@@ -1,26 +1,194 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::io::Cursor;
 use std::net::TcpStream;
 use std::ops::{Deref, DerefMut};
+use std::os::unix::io::RawFd;
 use std::sync::{Arc, Mutex};
+use std::thread;
 
 use crate::amf::*;
+use crate::amf_serde::{from_amf_object, to_amf_object};
+use crate::cmaf::LlHlsStream;
+use crate::command::{encode_command_message, on_status, Command, CommandMessage};
+use crate::config::Config;
 use crate::constant::*;
+use crate::dash::DashPackager;
 use crate::error::{Error, Result};
-use crate::stream::{ChunkMessageHeader, Message, RtmpMessageStream};
+use crate::gop::GopCache;
+use crate::hls::{HlsMode, HlsPackager};
+use crate::media::{AudioTagHeader, Codec, MediaInfo, VideoTagHeader};
+use crate::object::{ConnectObject, MetaData, ServerProperties, StatusInfo};
+use crate::queue::{is_keyframe, OverflowPolicy, SendQueue};
+use crate::record::Recorder;
+use crate::relay::{query_push_targets, PushRelay, PushTarget};
+use crate::stream::{
+    chunk_stream_id, ChunkMessageHeader, Message, RtmpMessageStream, SharedMessage,
+};
 use crate::utils::*;
+use crate::vod::{vod_duration, vod_path, PlayItem, VodPlayer};
 
+/// A subscriber of a media stream, whose messages are sent by a writer thread of its own so
+/// that a slow subscriber cannot stall the publisher.
 #[derive(Debug)]
 pub struct RtmpClient {
-    stream: RtmpMessageStream,
+    queue: Arc<SendQueue>,
+    from_fd: RawFd,
     paused: bool,
+    /// Whether audio and video are sent, as toggled by `receiveAudio` and `receiveVideo`.
+    receive_audio: bool,
+    receive_video: bool,
+    /// Number of dropped frames last logged.
+    logged_dropped_frames: u64,
+    /// Command object of the player's `connect`, announcing the codecs it supports.
+    connect_object: ConnectObject,
+    /// Whether the messages queued when it is dropped are still sent, so that internal
+    /// subscribers such as recordings get the end of the stream.
+    finish_on_drop: bool,
+}
+
+/// FourCC of the Enhanced RTMP codec of an audio or video message, if it has an extended header.
+fn message_four_cc(message: &SharedMessage) -> Option<[u8; 4]> {
+    let codec = match message.header.message_type_id {
+        RTMP_VIDEO_MESSAGE => VideoTagHeader::parse(&message.payload).ok()?.0.codec,
+        RTMP_AUDIO_MESSAGE => AudioTagHeader::parse(&message.payload).ok()?.0.codec,
+        _ => return None,
+    };
+    match codec {
+        Codec::FourCc(four_cc) => Some(four_cc),
+        Codec::Id(_) => None,
+    }
 }
 
 impl RtmpClient {
-    fn new(stream: RtmpMessageStream) -> Self {
+    fn new(mut stream: RtmpMessageStream, config: &Config, connect_object: ConnectObject) -> Self {
+        let from_fd = stream.from_fd;
+        Self::spawn(
+            from_fd,
+            config,
+            config.overflow_policy,
+            connect_object,
+            move |message| {
+                let chunk_stream_id = chunk_stream_id(message.header.message_type_id);
+                stream.send_shared_message(chunk_stream_id, message)
+            },
+        )
+    }
+
+    /// Creates a subscriber whose messages are sent by `send` from its writer thread, until
+    /// sending fails or the queue overflows under `policy`.
+    pub fn spawn<F>(
+        from_fd: RawFd,
+        config: &Config,
+        policy: OverflowPolicy,
+        connect_object: ConnectObject,
+        mut send: F,
+    ) -> Self
+    where
+        F: FnMut(&Arc<SharedMessage>) -> Result<()> + Send + 'static,
+    {
+        let queue = Arc::new(SendQueue::new(config.send_queue_size, policy));
+        let writer_queue = Arc::clone(&queue);
+        thread::spawn(move || {
+            while let Some(message) = writer_queue.pop() {
+                if send(&message).is_err() {
+                    writer_queue.close();
+                }
+            }
+        });
         Self {
-            stream,
+            queue,
+            from_fd,
             paused: false,
+            receive_audio: true,
+            receive_video: true,
+            logged_dropped_frames: 0,
+            connect_object,
+            finish_on_drop: false,
+        }
+    }
+
+    /// Logs the frames dropped since the last call, if any.
+    fn log_dropped_frames(&mut self) {
+        let dropped_frames = self.queue.dropped_frames();
+        if dropped_frames > self.logged_dropped_frames {
+            eprintln!(
+                "Subscriber {} is falling behind, {} frames dropped",
+                self.from_fd,
+                dropped_frames - self.logged_dropped_frames
+            );
+            self.logged_dropped_frames = dropped_frames;
+        }
+    }
+
+    /// Whether the player wants messages of the given type.
+    fn receives(&self, message_type_id: u8) -> bool {
+        match message_type_id {
+            RTMP_AUDIO_MESSAGE => self.receive_audio,
+            RTMP_VIDEO_MESSAGE => self.receive_video,
+            _ => true,
+        }
+    }
+
+    /// Whether the player can play messages of the given Enhanced RTMP codec. Legacy codecs are
+    /// sent to every player.
+    fn accepts(&self, four_cc: Option<&[u8; 4]>) -> bool {
+        four_cc.is_none_or(|four_cc| {
+            std::str::from_utf8(four_cc)
+                .is_ok_and(|four_cc| self.connect_object.supports_four_cc(four_cc))
+        })
+    }
+}
+
+/// Subscriber of a stream internal to the server, which handles every codec. It drops frames
+/// rather than being disconnected when falling behind, whatever the configured policy.
+fn internal_client<F>(config: &Config, send: F) -> RtmpClient
+where
+    F: FnMut(&Arc<SharedMessage>) -> Result<()> + Send + 'static,
+{
+    let connect_object = ConnectObject {
+        four_cc_list: Some(vec![String::from("*")]),
+        ..ConnectObject::default()
+    };
+    let mut client = RtmpClient::spawn(
+        -1,
+        config,
+        OverflowPolicy::DropNonKeyframes,
+        connect_object,
+        send,
+    );
+    client.finish_on_drop = true;
+    client
+}
+
+/// Subscriber packaging a stream with `push`, whose errors are logged so that packaging goes on.
+fn packaging_client<F>(config: &Config, name: &str, mut push: F) -> RtmpClient
+where
+    F: FnMut(&SharedMessage) -> Result<()> + Send + 'static,
+{
+    let name = String::from(name);
+    internal_client(config, move |message| {
+        if let Err(e) = push(message) {
+            eprintln!("Failed to package {}: {}", name, e);
+        }
+        Ok(())
+    })
+}
+
+/// Ends a low-latency HLS stream when its subscriber goes away with the stream.
+struct LlHlsWriter(Arc<LlHlsStream>);
+
+impl Drop for LlHlsWriter {
+    fn drop(&mut self) {
+        self.0.finish();
+    }
+}
+
+impl Drop for RtmpClient {
+    fn drop(&mut self) {
+        if self.finish_on_drop {
+            self.queue.finish();
+        } else {
+            self.queue.close();
         }
     }
 }
@@ -29,7 +197,12 @@ impl RtmpClient {
 pub struct RtmpMediaStream {
     clients: Vec<RtmpClient>,
     metadata: Option<Message>,
+    gop_cache: GopCache,
+    media_info: MediaInfo,
     published: bool,
+    hls: Option<Arc<Mutex<HlsPackager>>>,
+    ll_hls: Option<Arc<LlHlsStream>>,
+    dash: Option<Arc<Mutex<DashPackager>>>,
 }
 
 impl Deref for RtmpMediaStream {
@@ -49,69 +222,212 @@ impl DerefMut for RtmpMediaStream {
 pub struct RtmpServer {
     message_stream: RtmpMessageStream,
     media_streams: Arc<Mutex<HashMap<String, RtmpMediaStream>>>,
+    config: Arc<Config>,
     stream_name: String,
+    /// Whether this connection publishes `stream_name`, rather than playing it.
+    publishing: bool,
+    object_encoding: u8,
+    connect_object: ConnectObject,
+    /// Player of the recorded stream being played, if any.
+    player: Option<VodPlayer>,
 }
 
 impl RtmpMediaStream {
-    fn broadcast(&mut self, timestamp: u32, type_id: u8, message: &Message) {
-        let offline: Vec<_> = self
-            .clients
-            .iter_mut()
-            .enumerate()
-            .map(|(i, client)| {
-                if client.paused {
-                    return None;
-                }
-                if client
-                    .stream
-                    .send_message(
-                        3,
-                        message.header.message_stream_id,
-                        timestamp,
-                        type_id,
-                        &message.message,
+    fn new(config: &Config) -> Self {
+        Self {
+            gop_cache: GopCache::new(config.gop_cache_size),
+            ..Self::default()
+        }
+    }
+
+    /// Codecs of the published stream, as parsed from its audio and video messages.
+    pub fn media_info(&self) -> &MediaInfo {
+        &self.media_info
+    }
+
+    pub fn is_published(&self) -> bool {
+        self.published
+    }
+
+    /// HLS packager of the published stream, unless HLS is disabled.
+    pub fn hls(&self) -> Option<&Arc<Mutex<HlsPackager>>> {
+        self.hls.as_ref()
+    }
+
+    /// Low-latency HLS stream of the published stream, unless it is disabled.
+    pub fn ll_hls(&self) -> Option<&Arc<LlHlsStream>> {
+        self.ll_hls.as_ref()
+    }
+
+    /// DASH packager of the published stream, unless DASH is disabled.
+    pub fn dash(&self) -> Option<&Arc<Mutex<DashPackager>>> {
+        self.dash.as_ref()
+    }
+
+    /// Packages the stream as HLS, low-latency HLS and DASH from subscribers of their own, so
+    /// that publishing is not held up by muxing or writing segments.
+    fn start_packaging(&mut self, config: &Config, name: &str) {
+        if config.dash {
+            let packager = Arc::new(Mutex::new(DashPackager::new(config)));
+            let writer_packager = Arc::clone(&packager);
+            self.clients
+                .push(packaging_client(config, name, move |message| {
+                    writer_packager.lock().unwrap().push(
+                        message.header.message_type_id,
+                        message.header.timestamp,
+                        &message.payload,
                     )
-                    .is_err()
-                {
-                    Some(i)
-                } else {
-                    None
+                }));
+            self.dash = Some(packager);
+        }
+        if config.hls_mode == HlsMode::Disabled {
+            return;
+        }
+        let packager = Arc::new(Mutex::new(HlsPackager::new(config, name)));
+        let writer_packager = Arc::clone(&packager);
+        self.clients
+            .push(packaging_client(config, name, move |message| {
+                writer_packager.lock().unwrap().push(
+                    message.header.message_type_id,
+                    message.header.timestamp,
+                    &message.payload,
+                )
+            }));
+        self.hls = Some(packager);
+        if config.ll_hls {
+            let ll_hls = Arc::new(LlHlsStream::new(config));
+            let writer = LlHlsWriter(Arc::clone(&ll_hls));
+            self.clients
+                .push(packaging_client(config, name, move |message| {
+                    writer.0.push(
+                        message.header.message_type_id,
+                        message.header.timestamp,
+                        &message.payload,
+                    )
+                }));
+            self.ll_hls = Some(ll_hls);
+        }
+    }
+
+    /// Records the stream from a subscriber of its own, so that publishing is not held up by
+    /// writing files. Recording stops when writing fails.
+    fn start_recording(&mut self, config: &Config, name: &str, append: bool) {
+        let mut recorder = Recorder::new(config, name, append);
+        let name = String::from(name);
+        let limits = config.amf_decode_limits();
+        self.clients.push(internal_client(config, move |message| {
+            let result = match message.header.message_type_id {
+                RTMP_DATA_MESSAGE_AMF0 => metadata_properties(&message.payload, limits)
+                    .and_then(|properties| recorder.write_metadata(properties)),
+                type_id => {
+                    recorder.write_message(type_id, message.header.timestamp, &message.payload)
                 }
+            };
+            result.map_err(|e| {
+                eprintln!("Failed to record {}: {}", name, e);
+                e
             })
-            .flatten()
-            .collect();
+        }));
+    }
 
+    /// Pushes the stream to upstream servers from subscribers of their own, so that an upstream
+    /// server going down affects neither the publisher nor other subscribers.
+    fn start_pushing(&mut self, config: &Config, name: &str, targets: Vec<PushTarget>) {
+        for target in targets {
+            let mut relay = PushRelay::new(name, target);
+            self.clients.push(internal_client(config, move |message| {
+                relay.push(message);
+                Ok(())
+            }));
+        }
+    }
+
+    /// Adds a subscriber. If the stream has already begun, it first receives the metadata, then
+    /// the sequence headers and the current group of pictures so that playback starts with a
+    /// keyframe.
+    pub fn subscribe(&mut self, client: RtmpClient) {
+        if let Some(ref metadata) = self.metadata {
+            let mut header = metadata.header.clone();
+            header.message_type_id = RTMP_DATA_MESSAGE_AMF0;
+            client.queue.push(Arc::new(SharedMessage::new(
+                header,
+                metadata.message.clone(),
+            )));
+        }
+        for message in self.gop_cache.messages() {
+            if client.accepts(message_four_cc(message).as_ref()) {
+                client.queue.push(Arc::clone(message));
+            }
+        }
+        self.clients.push(client);
+    }
+
+    fn broadcast(&mut self, timestamp: u32, type_id: u8, message: Message) {
+        let mut header = message.header.clone();
+        header.timestamp = timestamp;
+        header.message_type_id = type_id;
+        // The payload is shared by every subscriber.
+        let message = Arc::new(SharedMessage::new(header, message.message));
+        self.gop_cache.push(&message);
+        let four_cc = message_four_cc(&message);
+        let is_keyframe = is_keyframe(&message);
         // Remove offline clients
-        offline.iter().for_each(|i| {
-            self.clients.remove(*i);
+        self.clients.retain_mut(|client| {
+            // Frames are dropped up to a keyframe, after which the count is logged.
+            if is_keyframe {
+                client.log_dropped_frames();
+            }
+            if client.paused
+                || !client.receives(message.header.message_type_id)
+                || !client.accepts(four_cc.as_ref())
+                || client.queue.push(Arc::clone(&message))
+            {
+                return true;
+            }
+            eprintln!(
+                "Subscriber {} disconnected, {} frames dropped",
+                client.from_fd,
+                client.queue.dropped_frames()
+            );
+            false
         });
     }
 }
 
+/// Properties of an `@setDataFrame` message with `onMetaData`, which are empty unless they are
+/// an object or an ECMA array.
+fn metadata_properties(payload: &[u8], limits: AmfDecodeLimits) -> Result<AmfObjectMap> {
+    let mut reader = Cursor::new(payload);
+    decode_amf_string(&mut reader, true)?;
+    decode_amf_string(&mut reader, true)?;
+    Ok(match AmfDecoder::new(limits).decode(&mut reader)? {
+        AmfObject::EcmaArray(entries) => entries.into_iter().collect(),
+        AmfObject::Object(properties) => properties,
+        _ => AmfObjectMap::new(),
+    })
+}
+
+/// Info map of the Enhanced RTMP codecs the server can forward.
+fn four_cc_info_map(four_ccs: &[&str]) -> BTreeMap<String, f64> {
+    four_ccs
+        .iter()
+        .map(|&four_cc| (String::from(four_cc), f64::from(FOURCC_INFO_CAN_FORWARD)))
+        .collect()
+}
+
 impl RtmpServer {
-    #[allow(clippy::float_cmp)]
-    fn handle_connect(&mut self, mut reader: Cursor<Vec<u8>>) -> Result<()> {
-        let transaction_id = decode_amf_number(&mut reader, true)?;
-        assert_eq!(transaction_id, 1_f64);
-        let cmd_object = decode_amf_object(&mut reader, true)?;
+    fn handle_connect(&mut self, transaction_id: f64, cmd_object: ConnectObject) -> Result<()> {
         eprintln!("cmd_object = {:?}", cmd_object);
-        self.message_stream.send_message(
-            RTMP_PROTOCOL_CONTROL_CHUNK_STREAM_ID,
-            RTMP_PROTOCOL_CONTROL_MESSAGE_STREAM_ID,
-            0,
-            RTMP_ACKNOWLEDGEMENT,
-            &7122_u32.to_be_bytes(),
-        )?;
-        let mut buffer = Vec::from(1048576_u32.to_be_bytes());
-        // Set window acknowledgement size.
-        self.message_stream.send_message(
-            RTMP_PROTOCOL_CONTROL_CHUNK_STREAM_ID,
-            RTMP_PROTOCOL_CONTROL_MESSAGE_STREAM_ID,
-            0,
-            RTMP_WINDOW_ACK_SIZE,
-            &buffer,
-        )?;
-        buffer.push(2);
+        // AMF-0 is used unless the client asks for AMF-3, the only other encoding.
+        self.object_encoding = match cmd_object.object_encoding {
+            Some(RTMP_OBJECT_ENCODING_AMF3) => RTMP_OBJECT_ENCODING_AMF3,
+            _ => RTMP_OBJECT_ENCODING_AMF0,
+        };
+        self.connect_object = cmd_object;
+        self.message_stream
+            .send_window_ack_size(RTMP_DEFAULT_WINDOW_ACK_SIZE)?;
+        let mut buffer = Vec::from(RTMP_DEFAULT_WINDOW_ACK_SIZE.to_be_bytes());
+        buffer.push(RTMP_PEER_BANDWIDTH_DYNAMIC);
         // Set peer bandwidth.
         self.message_stream.send_message(
             RTMP_PROTOCOL_CONTROL_CHUNK_STREAM_ID,
@@ -130,125 +446,173 @@ impl RtmpServer {
         )?;
 
         // TODO: Fill properties and Information.
-        let properties: HashMap<String, AmfObject> = [
-            (
-                String::from("fmsVer"),
-                AmfObject::String(String::from("FMS/4,5,0,297")),
-            ),
-            (String::from("capabilities"), AmfObject::Number(255.0_f64)),
-            (String::from("mode"), AmfObject::Number(1.0)),
-        ]
-        .iter()
-        .cloned()
-        .collect();
-        let information: HashMap<String, AmfObject> = [
-            (
-                String::from("level"),
-                AmfObject::String(String::from("status")),
-            ),
-            (
-                String::from("code"),
-                AmfObject::String(String::from("NetConnection.Connect.Success")),
+        let properties = ServerProperties {
+            fms_ver: String::from("FMS/4,5,0,297"),
+            capabilities: 255.0,
+            mode: 1.0,
+            four_cc_list: Some(
+                ENHANCED_RTMP_VIDEO_FOURCCS
+                    .iter()
+                    .map(|&four_cc| String::from(four_cc))
+                    .collect(),
             ),
-            (String::from("objectEncoding"), AmfObject::Number(0.0)),
-        ]
-        .iter()
-        .cloned()
-        .collect();
-        let buffer = encode_amf_messages(&[
-            AmfObject::String(String::from("_result")),
-            AmfObject::Number(1_f64),
-            AmfObject::Object(properties),
-            AmfObject::Object(information),
-        ]);
-        self.message_stream.send_message(
-            3,
-            RTMP_NET_CONNECTION_STREAM_ID,
-            0,
-            RTMP_COMMAND_MESSAGE_AMF0,
-            &buffer,
-        )?;
-        Ok(())
+            video_four_cc_info_map: Some(four_cc_info_map(&ENHANCED_RTMP_VIDEO_FOURCCS)),
+            audio_four_cc_info_map: Some(four_cc_info_map(&ENHANCED_RTMP_AUDIO_FOURCCS)),
+        };
+        let information = StatusInfo {
+            object_encoding: Some(self.object_encoding),
+            ..StatusInfo::new("NetConnection.Connect.Success", true)
+        };
+        self.send_result(
+            transaction_id,
+            to_amf_object(&properties)?,
+            vec![to_amf_object(&information)?],
+        )
     }
 
-    fn handle_release_stream(&self, mut reader: Cursor<Vec<u8>>) -> Result<()> {
-        let _ = decode_amf_number(&mut reader, true)?;
-        let _ = decode_amf_null(&mut reader, true)?;
-        let _ = decode_amf_string(&mut reader, true)?;
-        Ok(())
+    fn send_command(&mut self, transaction_id: f64, command: Command) -> Result<()> {
+        let values = CommandMessage::new(transaction_id, command).serialize()?;
+        self.send_command_message(RTMP_NET_CONNECTION_STREAM_ID, &values)
     }
 
-    fn handle_create_stream(
+    /// Answers a command with `_result`. Commands with a transaction ID of 0 expect no response.
+    fn send_result(
         &mut self,
-        mut reader: Cursor<Vec<u8>>,
-        header: ChunkMessageHeader,
+        transaction_id: f64,
+        command_object: AmfObject,
+        arguments: Vec<AmfObject>,
     ) -> Result<()> {
-        let transaction_id = decode_amf_number(&mut reader, true)?;
-        let cmd_object = decode_amf_message(&mut reader)?;
-        match cmd_object {
-            AmfObject::Object(_) | AmfObject::Null => {
-                self.message_stream.send_message(
-                    3,
-                    RTMP_NET_CONNECTION_STREAM_ID,
-                    0,
-                    RTMP_COMMAND_MESSAGE_AMF0,
-                    &encode_amf_messages(&[
-                        AmfObject::String(String::from("_result")),
-                        AmfObject::Number(transaction_id),
-                        AmfObject::Null,
-                        AmfObject::Number(header.message_stream_id as f64),
-                    ]),
-                )?;
-                Ok(())
-            }
-            _ => Err(Error::UnexpectedAmfObjectType),
+        if transaction_id == 0_f64 {
+            return Ok(());
         }
+        self.send_command(
+            transaction_id,
+            Command::Call {
+                name: String::from("_result"),
+                command_object,
+                arguments,
+            },
+        )
     }
 
-    fn on_status(code: &str, success: bool) -> Vec<u8> {
-        let information: HashMap<String, AmfObject> = [
-            (
-                String::from("level"),
-                AmfObject::String(String::from(if success { "status" } else { "error" })),
-            ),
-            (String::from("code"), AmfObject::String(code.to_string())),
-        ]
-        .iter()
-        .cloned()
-        .collect();
-        encode_amf_messages(&[
-            AmfObject::String(String::from("onStatus")),
-            AmfObject::Number(0_f64),
+    /// Answers a command with `_error`, unless its transaction ID is 0.
+    fn send_error(&mut self, transaction_id: f64, code: &str, description: String) -> Result<()> {
+        if transaction_id == 0_f64 {
+            return Ok(());
+        }
+        let information = StatusInfo {
+            description: Some(description),
+            ..StatusInfo::new(code, false)
+        };
+        self.send_command(
+            transaction_id,
+            Command::Call {
+                name: String::from("_error"),
+                command_object: AmfObject::Null,
+                arguments: vec![to_amf_object(&information)?],
+            },
+        )
+    }
+
+    /// Answers a known command with invalid arguments with `_error`, or with an error `onStatus`
+    /// if its transaction ID is 0, as for stream commands such as `publish`.
+    fn send_invalid_command(&mut self, transaction_id: f64, error: Error) -> Result<()> {
+        eprintln!("Invalid command: {}", error);
+        if transaction_id != 0_f64 {
+            return self.send_error(
+                transaction_id,
+                "NetConnection.Call.Failed",
+                error.to_string(),
+            );
+        }
+        let information = StatusInfo {
+            description: Some(error.to_string()),
+            ..StatusInfo::new("NetStream.Failed", false)
+        };
+        self.send_command(
+            0_f64,
+            Command::Call {
+                name: String::from("onStatus"),
+                command_object: AmfObject::Null,
+                arguments: vec![to_amf_object(&information)?],
+            },
+        )
+    }
+
+    /// Sends a command message, encapsulated in an AMF-3 command message if AMF-3 has been
+    /// negotiated in `connect`.
+    fn send_command_message(&mut self, message_stream_id: u32, values: &[AmfObject]) -> Result<()> {
+        let (message_type_id, buffer) = encode_command_message(self.object_encoding, values)?;
+        self.message_stream.send_message(
+            RTMP_COMMAND_CHUNK_STREAM_ID,
+            message_stream_id,
+            0,
+            message_type_id,
+            &buffer,
+        )
+    }
+
+    fn handle_create_stream(
+        &mut self,
+        transaction_id: f64,
+        header: ChunkMessageHeader,
+    ) -> Result<()> {
+        self.send_result(
+            transaction_id,
             AmfObject::Null,
-            AmfObject::Object(information),
-        ])
+            vec![AmfObject::Number(header.message_stream_id as f64)],
+        )
     }
 
     fn handle_play(
         &mut self,
-        _header: ChunkMessageHeader,
-        mut reader: Cursor<Vec<u8>>,
+        stream_name: String,
+        start: Option<f64>,
+        duration: Option<f64>,
+        reset: Option<bool>,
     ) -> Result<()> {
-        let _transaction_id = decode_amf_number(&mut reader, true)?;
-        // assert_eq!(_transaction_id, 0_f64);
-        let _cmd_object = decode_amf_null(&mut reader, true)?;
-        let stream_name = decode_amf_string(&mut reader, true)?;
-        let start = decode_amf_message(&mut reader);
-        let duration = decode_amf_message(&mut reader);
-        let reset = decode_amf_message(&mut reader);
         eprintln!(
             "stream_name = {}, start = {:?}, duration = {:?}, reset = {:?}",
             stream_name, start, duration, reset
         );
+        // A start of -2 plays the live stream if it is published and the recorded one otherwise,
+        // -1 only the live stream, and other values the recorded stream from that many seconds.
+        let start = start.unwrap_or(-2.0);
+        let reset = reset.unwrap_or(true);
+        let item = if start == -1.0 {
+            None
+        } else {
+            let published = self
+                .media_streams
+                .lock()
+                .unwrap()
+                .get(&stream_name)
+                .is_some_and(|media_stream| media_stream.published);
+            vod_path(&self.config, &stream_name)
+                .filter(|_| start >= 0.0 || !published)
+                .map(|path| PlayItem {
+                    path,
+                    start: (start.max(0.0) * 1000.0) as u32,
+                    // A negative duration plays up to the end.
+                    duration: duration
+                        .filter(|&duration| duration >= 0.0)
+                        .map(|duration| (duration * 1000.0) as u32),
+                })
+        };
+        // Without reset, recorded streams are added to the playlist.
+        if let Some(ref item) = item {
+            if !reset
+                && self
+                    .player
+                    .as_ref()
+                    .is_some_and(|player| player.queue(item.clone()))
+            {
+                return Ok(());
+            }
+        }
         // Set chunk size.
-        self.message_stream.send_message(
-            RTMP_PROTOCOL_CONTROL_CHUNK_STREAM_ID,
-            RTMP_PROTOCOL_CONTROL_MESSAGE_STREAM_ID,
-            0,
-            RTMP_SET_CHUNK_SIZE,
-            &(0x7FFFFFFF_u32).to_be_bytes(),
-        )?;
-        self.message_stream.max_chunk_size_write = 0x7FFFFFFF;
+        self.message_stream
+            .set_chunk_size(RTMP_DEFAULT_CHUNK_SIZE)?;
 
         // Send user control message: Stream Begin.
         self.message_stream.send_message(
@@ -259,23 +623,19 @@ impl RtmpServer {
             &[0x0; 6],
         )?;
 
-        self.message_stream.send_message(
-            3,
-            RTMP_NET_CONNECTION_STREAM_ID,
-            0,
-            RTMP_COMMAND_MESSAGE_AMF0,
-            &Self::on_status("NetStream.Play.Reset", true),
-        )?;
-        self.message_stream.send_message(
-            3,
+        if reset {
+            self.send_command_message(
+                RTMP_NET_CONNECTION_STREAM_ID,
+                &on_status("NetStream.Play.Reset", true)?,
+            )?;
+        }
+        self.send_command_message(
             RTMP_NET_CONNECTION_STREAM_ID,
-            0,
-            RTMP_COMMAND_MESSAGE_AMF0,
-            &Self::on_status("NetStream.Play.Start", true),
+            &on_status("NetStream.Play.Start", true)?,
         )?;
         // XXX: Unknown message
         self.message_stream.send_message(
-            3,
+            RTMP_DATA_CHUNK_STREAM_ID,
             RTMP_NET_CONNECTION_STREAM_ID,
             0,
             RTMP_DATA_MESSAGE_AMF0,
@@ -283,138 +643,282 @@ impl RtmpServer {
                 AmfObject::String(String::from("|RtmpSampleAccess")),
                 AmfObject::Boolean(true),
                 AmfObject::Boolean(true),
-            ]),
+            ])?,
         )?;
+        if let Some(item) = item {
+            self.player = Some(VodPlayer::new(
+                self.message_stream.decouple(),
+                self.object_encoding,
+                item,
+            ));
+            return Ok(());
+        }
+        self.player = None;
         // self.message_stream
         //     .set_read_timeout(Duration::from_micros(1));
         let media_streams = &mut *self.media_streams.lock().unwrap();
-        let media_streams = media_streams
+        let config = &self.config;
+        let media_stream = media_streams
             .entry(stream_name.clone())
-            .or_insert_with(RtmpMediaStream::default);
-
-        // Stream has already begun, send metadata first.
-        if let Some(ref metadata) = media_streams.metadata {
-            self.message_stream.send_message(
-                3,
-                metadata.header.message_stream_id,
-                metadata.header.timestamp,
-                RTMP_DATA_MESSAGE_AMF0,
-                &metadata.message,
-            )?;
-        }
-        media_streams.push(RtmpClient::new(self.message_stream.decouple()));
+            .or_insert_with(|| RtmpMediaStream::new(config));
+        media_stream.subscribe(RtmpClient::new(
+            self.message_stream.decouple(),
+            &self.config,
+            self.connect_object.clone(),
+        ));
         self.stream_name = stream_name;
         Ok(())
     }
 
-    #[allow(clippy::float_cmp)]
-    fn handle_seek(&mut self, mut reader: Cursor<Vec<u8>>) -> Result<()> {
-        let transaction_id = decode_amf_number(&mut reader, true)?;
-        assert_eq!(transaction_id, 0_f64);
-        let _ = decode_amf_null(&mut reader, true)?;
-        let _ = decode_amf_number(&mut reader, true)?;
-        // Seek is not supported.
-        self.message_stream.send_message(
-            3,
+    fn handle_seek(&mut self, milliseconds: f64) -> Result<()> {
+        if let Some(ref player) = self.player {
+            // The player answers once it has found the keyframe to resume from.
+            player.seek(milliseconds.max(0.0) as u32);
+            return Ok(());
+        }
+        // Live streams cannot be seeked.
+        self.send_command_message(
             RTMP_NET_CONNECTION_STREAM_ID,
-            0,
-            RTMP_COMMAND_MESSAGE_AMF0,
-            &Self::on_status("NetStream.Seek.Notify", false),
+            &on_status("NetStream.Seek.Notify", false)?,
         )?;
         Ok(())
     }
 
-    #[allow(clippy::float_cmp)]
-    fn handle_pause(&mut self, mut reader: Cursor<Vec<u8>>) -> Result<()> {
-        let transaction_id = decode_amf_number(&mut reader, true)?;
-        assert_eq!(transaction_id, 0_f64);
-        let _ = decode_amf_null(&mut reader, true)?;
-        let pause = decode_amf_boolean(&mut reader, true)?;
-        let _pause_time = decode_amf_number(&mut reader, true)?;
-        let media_streams = &mut *self.media_streams.lock().unwrap();
-        if let Some(media_stream) = media_streams.get_mut(&self.stream_name) {
+    fn handle_pause(&mut self, pause: bool) -> Result<()> {
+        if let Some(ref player) = self.player {
+            player.pause(pause);
+        } else if let Some(media_stream) = self
+            .media_streams
+            .lock()
+            .unwrap()
+            .get_mut(&self.stream_name)
+        {
             media_stream.clients.iter_mut().for_each(|client| {
-                if client.stream.from_fd == self.message_stream.from_fd {
+                if client.from_fd == self.message_stream.from_fd {
                     client.paused = pause;
                 }
             });
         }
-        self.message_stream.send_message(
-            3,
+        self.send_command_message(
             RTMP_NET_CONNECTION_STREAM_ID,
-            0,
-            RTMP_COMMAND_MESSAGE_AMF0,
-            &Self::on_status("NetStream.Pause.Notify", true),
+            &on_status(
+                if pause {
+                    "NetStream.Pause.Notify"
+                } else {
+                    "NetStream.Unpause.Notify"
+                },
+                true,
+            )?,
         )?;
         Ok(())
     }
 
-    fn handle_publish(&mut self, mut reader: Cursor<Vec<u8>>) -> Result<()> {
-        let _transaction_id = decode_amf_number(&mut reader, true)?;
-        // assert_eq!(_transaction_id, 0_f64);
-        let _cmd_object = decode_amf_null(&mut reader, true)?;
-        let publishing_name = decode_amf_string(&mut reader, true)?;
-        let publishing_type = decode_amf_string(&mut reader, true)?;
+    fn handle_publish(
+        &mut self,
+        publishing_name: String,
+        publishing_type: Option<String>,
+    ) -> Result<()> {
         eprintln!(
-            "publishing_name = {}, publishing_type = {}",
+            "publishing_name = {}, publishing_type = {:?}",
             publishing_name, publishing_type
         );
-        let media_streams = &mut *self.media_streams.lock().unwrap();
-        let entry = media_streams
-            .entry(publishing_name.clone())
-            .or_insert_with(RtmpMediaStream::default);
-        let code = if entry.published {
-            "NetStream.Publish.Denied"
-        } else {
-            entry.published = true;
-            "NetStream.Publish.Start"
+        // Parameters may follow the stream name, as in `name?push=rtmp://host/app/name`.
+        let (publishing_name, query) = match publishing_name.split_once('?') {
+            Some((name, query)) => (String::from(name), String::from(query)),
+            None => (publishing_name, String::new()),
         };
-        self.message_stream.send_message(
-            3,
-            RTMP_NET_CONNECTION_STREAM_ID,
-            0,
-            RTMP_COMMAND_MESSAGE_AMF0,
-            &Self::on_status(code, true),
-        )?;
+        let append = match publishing_type.as_deref() {
+            Some("record") => Some(false),
+            Some("append") => Some(true),
+            _ => None,
+        };
+        let code = {
+            let media_streams = &mut *self.media_streams.lock().unwrap();
+            let config = &self.config;
+            let entry = media_streams
+                .entry(publishing_name.clone())
+                .or_insert_with(|| RtmpMediaStream::new(config));
+            if entry.published {
+                "NetStream.Publish.Denied"
+            } else {
+                entry.published = true;
+                entry.start_packaging(config, &publishing_name);
+                let mut targets = config.push.get(&publishing_name);
+                if config.push_from_query {
+                    targets.extend(query_push_targets(&query));
+                }
+                entry.start_pushing(config, &publishing_name, targets);
+                if let Some(append) = append {
+                    entry.start_recording(config, &publishing_name, append);
+                }
+                "NetStream.Publish.Start"
+            }
+        };
+        self.send_command_message(RTMP_NET_CONNECTION_STREAM_ID, &on_status(code, true)?)?;
+        if code == "NetStream.Publish.Start" {
+            self.publishing = true;
+        }
         self.stream_name = publishing_name;
         Ok(())
     }
 
-    fn handle_delete_stream(&mut self, _reader: Cursor<Vec<u8>>) -> Result<()> {
-        self.media_streams.lock().unwrap().remove(&self.stream_name);
+    /// Unpublishes the stream if this connection published it, and stops playing otherwise.
+    fn handle_delete_stream(&mut self) -> Result<()> {
+        if self.publishing {
+            self.publishing = false;
+            self.media_streams.lock().unwrap().remove(&self.stream_name);
+        } else {
+            self.handle_close_stream();
+        }
         Ok(())
     }
 
-    #[allow(clippy::float_cmp)]
-    fn handle_get_stream_length(&mut self, mut reader: Cursor<Vec<u8>>) -> Result<()> {
-        let transaction_id = decode_amf_number(&mut reader, true)?;
-        assert_eq!(transaction_id, 3_f64);
-        let _ = decode_amf_null(&mut reader, true)?;
-        let _stream_name = decode_amf_string(&mut reader, true)?;
-        Ok(())
+    /// Stops playing, whether from a file or a live stream.
+    fn handle_close_stream(&mut self) {
+        self.player = None;
+        let from_fd = self.message_stream.from_fd;
+        if let Some(media_stream) = self
+            .media_streams
+            .lock()
+            .unwrap()
+            .get_mut(&self.stream_name)
+        {
+            media_stream
+                .clients
+                .retain(|client| client.from_fd != from_fd);
+        }
+    }
+
+    /// Turns sending audio or video of the live stream being played on or off.
+    fn handle_receive(&mut self, message_type_id: u8, flag: bool) {
+        let from_fd = self.message_stream.from_fd;
+        if let Some(media_stream) = self
+            .media_streams
+            .lock()
+            .unwrap()
+            .get_mut(&self.stream_name)
+        {
+            for client in media_stream.clients.iter_mut() {
+                if client.from_fd == from_fd {
+                    match message_type_id {
+                        RTMP_AUDIO_MESSAGE => client.receive_audio = flag,
+                        RTMP_VIDEO_MESSAGE => client.receive_video = flag,
+                        _ => {}
+                    }
+                }
+            }
+        }
+    }
+
+    fn handle_get_stream_length(&mut self, transaction_id: f64, name: String) -> Result<()> {
+        // Live streams have no length.
+        let length = match vod_path(&self.config, &name) {
+            Some(path) => vod_duration(&path).unwrap_or_else(|e| {
+                eprintln!("Failed to read the duration of {}: {}", path.display(), e);
+                0_f64
+            }),
+            None => 0_f64,
+        };
+        self.send_result(
+            transaction_id,
+            AmfObject::Null,
+            vec![AmfObject::Number(length)],
+        )
+    }
+
+    /// Answers `FCPublish` and `FCUnpublish` with `onFCPublish` and `onFCUnpublish`.
+    fn handle_fc_publish(
+        &mut self,
+        transaction_id: f64,
+        response: &str,
+        code: &str,
+        stream_name: String,
+    ) -> Result<()> {
+        self.send_result(transaction_id, AmfObject::Null, vec![AmfObject::Undefined])?;
+        let information = StatusInfo {
+            description: Some(stream_name),
+            ..StatusInfo::new(code, true)
+        };
+        self.send_command(
+            0_f64,
+            Command::Call {
+                name: String::from(response),
+                command_object: AmfObject::Null,
+                arguments: vec![to_amf_object(&information)?],
+            },
+        )
     }
 
     fn handle_command_message(&mut self, message: Message) -> Result<bool> {
-        let mut reader = Cursor::new(message.message);
-        if let AmfObject::String(cmd) = decode_amf_message(&mut reader)? {
-            eprintln!("cmd = {}", cmd);
-            match cmd.as_str() {
-                "connect" => self.handle_connect(reader)?,
-                "deleteStream" => self.handle_delete_stream(reader)?,
-                "releaseStream" => self.handle_release_stream(reader)?,
-                "createStream" => self.handle_create_stream(reader, message.header)?,
-                "play" => self.handle_play(message.header, reader)?,
-                "seek" => self.handle_seek(reader)?,
-                "pause" => self.handle_pause(reader)?,
-                "getStreamLength" => self.handle_get_stream_length(reader)?,
-                "publish" => self.handle_publish(reader)?,
-                "FCPublish" | "FCUnpublish" => {}
-                _ => return Err(Error::UnknownCommandMessage(cmd)),
+        let values = AmfDecoder::new(self.config.amf_decode_limits())
+            .decode_all(&mut Cursor::new(message.message))?;
+        // The transaction ID is taken beforehand so that malformed commands can be answered.
+        let transaction_id = match values.get(1) {
+            Some(&AmfObject::Number(transaction_id)) => transaction_id,
+            _ => 0_f64,
+        };
+        let CommandMessage {
+            transaction_id,
+            command,
+        } = match CommandMessage::parse(values) {
+            Ok(message) => message,
+            Err(Error::NonStringCommand) => return Err(Error::NonStringCommand),
+            Err(e) => {
+                self.send_invalid_command(transaction_id, e)?;
+                return Ok(false);
             }
-            Ok(cmd == "deleteStream")
-        } else {
-            Err(Error::NonStringCommand)
+        };
+        eprintln!("cmd = {}", command.name());
+        match command {
+            Command::Connect(cmd_object) => self.handle_connect(transaction_id, cmd_object)?,
+            Command::DeleteStream { .. } => {
+                self.handle_delete_stream()?;
+                return Ok(true);
+            }
+            Command::CreateStream => self.handle_create_stream(transaction_id, message.header)?,
+            Command::Play {
+                name,
+                start,
+                duration,
+                reset,
+            } => self.handle_play(name, start, duration, reset)?,
+            Command::Seek { milliseconds } => self.handle_seek(milliseconds)?,
+            Command::Pause { pause, .. } => self.handle_pause(pause)?,
+            Command::GetStreamLength { name } => {
+                self.handle_get_stream_length(transaction_id, name)?
+            }
+            Command::Publish {
+                name,
+                publishing_type,
+            } => self.handle_publish(name, publishing_type)?,
+            Command::ReleaseStream { .. } => {
+                self.send_result(transaction_id, AmfObject::Null, vec![AmfObject::Undefined])?
+            }
+            Command::FCPublish { name } => self.handle_fc_publish(
+                transaction_id,
+                "onFCPublish",
+                "NetStream.Publish.Start",
+                name,
+            )?,
+            Command::FCUnpublish { name } => self.handle_fc_publish(
+                transaction_id,
+                "onFCUnpublish",
+                "NetStream.Unpublish.Success",
+                name,
+            )?,
+            Command::CloseStream => self.handle_close_stream(),
+            Command::ReceiveAudio(flag) => self.handle_receive(RTMP_AUDIO_MESSAGE, flag),
+            Command::ReceiveVideo(flag) => self.handle_receive(RTMP_VIDEO_MESSAGE, flag),
+            // Responses from the client need no answer.
+            Command::Call { ref name, .. }
+                if matches!(name.as_str(), "_result" | "_error" | "onStatus") => {}
+            command => self.send_error(
+                transaction_id,
+                "NetConnection.Call.Failed",
+                format!("Method not found ({})", command.name()),
+            )?,
         }
+        Ok(false)
     }
 
     fn handle_data_message(&mut self, message: Message) -> Result<()> {
@@ -425,10 +929,14 @@ impl RtmpServer {
         if decode_amf_string(&mut reader, true)? != "onMetaData" {
             return Err(Error::UnknownDataMessage);
         }
-        let properties = decode_amf_ecma_array(&mut reader, true)?;
-        eprintln!("{:?}", properties);
+        let object = AmfDecoder::new(self.config.amf_decode_limits()).decode(&mut reader)?;
+        // Metadata is relayed as is, even if its properties are not of the expected types.
+        match from_amf_object::<MetaData>(object) {
+            Ok(metadata) => eprintln!("{:?}", metadata),
+            Err(e) => eprintln!("Unexpected metadata: {}", e),
+        }
 
-        self.broadcast(0, RTMP_DATA_MESSAGE_AMF0, &message)?;
+        self.broadcast(0, RTMP_DATA_MESSAGE_AMF0, message.clone())?;
         let media_streams = &mut *self.media_streams.lock().unwrap();
         let media_stream = media_streams
             .get_mut(&self.stream_name)
@@ -437,19 +945,30 @@ impl RtmpServer {
         Ok(())
     }
 
-    fn handle_set_chunk_size(&mut self, message: Message) {
-        assert_eq!(message.header.message_length, 4);
-        let mut buffer = [0x0; 4];
-        buffer.copy_from_slice(&message.message);
-        self.message_stream.max_chunk_size_read = u32::from_be_bytes(buffer) as usize;
+    fn handle_set_chunk_size(&mut self, message: Message) -> Result<()> {
+        let chunk_size = read_u32(&mut Cursor::new(message.message)).map_err(Error::Io)?;
+        // The first bit is reserved, and a chunk size of 0 would never make progress.
+        let chunk_size = chunk_size & 0x7FFFFFFF;
+        if chunk_size == 0 {
+            return Err(Error::InvalidChunkSize(chunk_size));
+        }
+        self.message_stream.max_chunk_size_read = chunk_size as usize;
+        Ok(())
     }
 
-    fn handle_window_ack_size(&mut self, message: Message) {
-        assert_eq!(message.header.message_length, 4);
-        let mut buffer = [0x0; 4];
-        buffer.copy_from_slice(&message.message);
-        let window_ack_size = u32::from_be_bytes(buffer);
+    fn handle_window_ack_size(&mut self, message: Message) -> Result<()> {
+        let window_ack_size = read_u32(&mut Cursor::new(message.message)).map_err(Error::Io)?;
         eprintln!("window ack size = {}", window_ack_size);
+        self.message_stream.window_ack_size_read = window_ack_size;
+        Ok(())
+    }
+
+    fn handle_set_peer_bandwidth(&mut self, message: Message) -> Result<()> {
+        let mut cursor = Cursor::new(message.message);
+        let window_size = read_u32(&mut cursor).map_err(Error::Io)?;
+        let limit_type = read_u8(&mut cursor).map_err(Error::Io)?;
+        self.message_stream
+            .handle_set_peer_bandwidth(window_size, limit_type)
     }
 
     fn handle_user_control_message(&mut self, message: Message) -> Result<()> {
@@ -471,7 +990,7 @@ impl RtmpServer {
         Ok(())
     }
 
-    fn broadcast(&mut self, timestamp: u32, type_id: u8, message: &Message) -> Result<()> {
+    fn broadcast(&mut self, timestamp: u32, type_id: u8, message: Message) -> Result<()> {
         let media_streams = &mut *self.media_streams.lock().unwrap();
         let s = media_streams
             .get_mut(&self.stream_name)
@@ -481,13 +1000,35 @@ impl RtmpServer {
     }
 
     fn handle_video_message(&mut self, message: Message) -> Result<()> {
-        let (_frame_type, _codec_id) = ((message.message[0] >> 4) & 0xf, message.message[0] & 0xf);
-        self.broadcast(message.header.timestamp, RTMP_VIDEO_MESSAGE, &message)?;
+        // Messages whose header cannot be parsed, such as multitrack ones, are relayed anyway.
+        if let Ok((header, data)) = VideoTagHeader::parse(&message.message) {
+            self.update_media_info(|media_info| media_info.update_video(&header, data))?;
+        }
+        self.broadcast(message.header.timestamp, RTMP_VIDEO_MESSAGE, message)?;
         Ok(())
     }
 
     fn handle_audio_message(&mut self, message: Message) -> Result<()> {
-        self.broadcast(message.header.timestamp, RTMP_AUDIO_MESSAGE, &message)?;
+        if let Ok((header, data)) = AudioTagHeader::parse(&message.message) {
+            self.update_media_info(|media_info| media_info.update_audio(&header, data))?;
+        }
+        self.broadcast(message.header.timestamp, RTMP_AUDIO_MESSAGE, message)?;
+        Ok(())
+    }
+
+    /// Updates the codecs of the published stream. Sequence headers which cannot be parsed are
+    /// still relayed, since players may understand them.
+    fn update_media_info<F>(&mut self, update: F) -> Result<()>
+    where
+        F: FnOnce(&mut MediaInfo) -> Result<()>,
+    {
+        let media_streams = &mut *self.media_streams.lock().unwrap();
+        let media_stream = media_streams
+            .get_mut(&self.stream_name)
+            .ok_or(Error::MissingMediaStream)?;
+        if let Err(e) = update(&mut media_stream.media_info) {
+            eprintln!("Failed to parse codec configuration: {}", e);
+        }
         Ok(())
     }
 
@@ -499,6 +1040,32 @@ impl RtmpServer {
         Ok(())
     }
 
+    /// Converts an AMF-3 command or data message into the equivalent AMF-0 one by dropping the
+    /// format selector and replacing AMF-3 values with their AMF-0 counterparts.
+    fn convert_amf3_message(message: Message, limits: AmfDecodeLimits) -> Result<Message> {
+        let Message {
+            mut header,
+            message,
+        } = message;
+        let start = if message.first() == Some(&0x0) { 1 } else { 0 };
+        let values: Vec<_> = AmfDecoder::new(limits)
+            .decode_all(&mut Cursor::new(&message[start..]))?
+            .into_iter()
+            .map(|value| match value {
+                AmfObject::AvmPlus(value) => AmfObject::from(value),
+                value => value,
+            })
+            .collect();
+        let message = encode_amf_messages(&values)?;
+        header.message_type_id = if header.message_type_id == RTMP_COMMAND_MESSAGE_AMF3 {
+            RTMP_COMMAND_MESSAGE_AMF0
+        } else {
+            RTMP_DATA_MESSAGE_AMF0
+        };
+        header.message_length = message.len();
+        Ok(Message { header, message })
+    }
+
     fn handle_message(&mut self, message: Message) -> Result<bool> {
         match message.header.message_type_id {
             RTMP_COMMAND_MESSAGE_AMF0 => {
@@ -511,17 +1078,33 @@ impl RtmpServer {
                 // AMF-0 encoded data message.
                 self.handle_data_message(message)?;
             }
-            RTMP_COMMAND_MESSAGE_AMF3 | RTMP_DATA_MESSAGE_AMF3 => {
-                return Err(Error::Amf3NotSupported);
+            RTMP_COMMAND_MESSAGE_AMF3 => {
+                // AMF-3 encoded control message.
+                if self.handle_command_message(Self::convert_amf3_message(
+                    message,
+                    self.config.amf_decode_limits(),
+                )?)? {
+                    return Ok(true);
+                }
+            }
+            RTMP_DATA_MESSAGE_AMF3 => {
+                // AMF-3 encoded data message.
+                self.handle_data_message(Self::convert_amf3_message(
+                    message,
+                    self.config.amf_decode_limits(),
+                )?)?;
             }
             RTMP_SET_CHUNK_SIZE => {
-                self.handle_set_chunk_size(message);
+                self.handle_set_chunk_size(message)?;
             }
             RTMP_ABORT_MESSAGE => {
                 self.handle_abort_message(message)?;
             }
             RTMP_WINDOW_ACK_SIZE => {
-                self.handle_window_ack_size(message);
+                self.handle_window_ack_size(message)?;
+            }
+            RTMP_SET_PEER_BANDWIDTH => {
+                self.handle_set_peer_bandwidth(message)?;
             }
             RTMP_USER_CONTROL_MESSAGE => {
                 self.handle_user_control_message(message)?;
@@ -534,7 +1117,7 @@ impl RtmpServer {
             }
             RTMP_ACKNOWLEDGEMENT => {
                 let ack = read_u32(&mut Cursor::new(message.message)).map_err(Error::Io)?;
-                eprintln!("ack = {}", ack);
+                self.message_stream.handle_acknowledgement(ack);
             }
             _ => {
                 return Err(Error::UnknownMessageTypeId(message.header.message_type_id));
@@ -569,11 +1152,29 @@ impl RtmpServer {
     pub fn new(
         stream: TcpStream,
         media_streams: Arc<Mutex<HashMap<String, RtmpMediaStream>>>,
+        config: Arc<Config>,
     ) -> RtmpServer {
         RtmpServer {
             message_stream: RtmpMessageStream::new(stream),
             media_streams,
+            config,
             stream_name: String::new(),
+            publishing: false,
+            object_encoding: RTMP_OBJECT_ENCODING_AMF0,
+            connect_object: ConnectObject::default(),
+            player: None,
+        }
+    }
+}
+
+impl Drop for RtmpServer {
+    /// Unpublishes the stream of a publisher which goes away without `deleteStream`, which also
+    /// finishes its recording and packaging.
+    fn drop(&mut self) {
+        if self.publishing {
+            if let Ok(mut media_streams) = self.media_streams.lock() {
+                media_streams.remove(&self.stream_name);
+            }
         }
     }
 }
@@ -1,10 +1,18 @@
-use std::collections::HashMap;
-use std::io::{self, Read, Write};
-use std::net::TcpStream;
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, IoSlice, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::ops::Range;
 use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
 
+use crate::constant::*;
 use crate::error::{Error, Result};
-use crate::utils::{aggregate, read_buffer, read_buffer_sized, read_numeric, read_u32};
+use crate::handshake::{self, HANDSHAKE_SIZE};
+use crate::utils::{aggregate, read_buffer, read_buffer_sized, write_all_vectored};
+
+// Time a shared message waits for the peer to acknowledge bytes when its peer bandwidth is used up.
+const PEER_BANDWIDTH_TIMEOUT: Duration = Duration::from_secs(10);
 
 pub trait TryClone: Sized {
     fn try_clone(&self) -> io::Result<Self>;
@@ -17,14 +25,290 @@ impl TryClone for TcpStream {
     }
 }
 
+/// Outbound state of a connection. It is shared by all streams decoupled from the connection,
+/// since they write to the same socket and chunk header compression depends on what the peer
+/// received last.
+#[derive(Debug)]
+struct WriteState {
+    max_chunk_size: usize,
+    prev_message_header: HashMap<u16, ChunkMessageHeader>,
+    // Byte counters wrap around at 2^32 like the sequence number of Acknowledgement.
+    bytes_written: u32,
+    bytes_acknowledged: u32,
+    /// Window size last sent to the peer with Window Acknowledgement Size.
+    window_ack_size: u32,
+    peer_bandwidth_limit_type: Option<u8>,
+    /// Messages waiting to be sent on each chunk stream.
+    queues: HashMap<u16, VecDeque<OutboundMessage>>,
+    sequence: u64,
+    /// Whether a stream is currently writing queued chunks to the socket.
+    flushing: bool,
+}
+
+#[derive(Debug)]
+struct OutboundMessage {
+    header: ChunkMessageHeader,
+    payload: Arc<[u8]>,
+    /// The message being sent if it is shared with other connections.
+    shared: Option<Arc<SharedMessage>>,
+    /// Chunks following the first one serialized by the shared message, along with the chunk
+    /// size they were serialized with.
+    continuation: Option<(usize, Arc<[u8]>)>,
+    /// Number of payload bytes already sent, or `None` if no chunk has been sent yet.
+    sent: Option<usize>,
+    priority: u8,
+    sequence: u64,
+}
+
+/// A chunk ready to be written, made of its header followed by a range of a shared buffer.
+#[derive(Debug)]
+struct Chunk {
+    header: Vec<u8>,
+    body: Arc<[u8]>,
+    range: Range<usize>,
+}
+
+impl Chunk {
+    fn len(&self) -> usize {
+        self.header.len() + self.range.len()
+    }
+}
+
+/// Serialized chunks following the first one, keyed by chunk size and chunk stream ID.
+type Continuations = HashMap<(usize, u16), Arc<[u8]>>;
+
+/// A message sent to many peers, such as a media message broadcast to every subscriber. Its
+/// payload is reference-counted, and the chunks following the first one are serialized once for
+/// each chunk size and chunk stream the message is sent with.
+#[derive(Debug)]
+pub struct SharedMessage {
+    pub header: ChunkMessageHeader,
+    pub payload: Arc<[u8]>,
+    continuations: Mutex<Continuations>,
+}
+
+impl SharedMessage {
+    pub fn new(header: ChunkMessageHeader, payload: Vec<u8>) -> Self {
+        Self {
+            header,
+            payload: Arc::from(payload),
+            continuations: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Serializes every chunk but the first, each preceded by a type 3 basic header. Chunks
+    /// following a header with an extended timestamp also repeat it, so they cannot be shared.
+    fn continuation(&self, chunk_size: usize, chunk_stream_id: u16) -> Arc<[u8]> {
+        let mut continuations = self.continuations.lock().unwrap();
+        let continuation = continuations
+            .entry((chunk_size, chunk_stream_id))
+            .or_insert_with(|| {
+                let mut buffer = Vec::new();
+                for chunk in self.payload.chunks(chunk_size).skip(1) {
+                    encode_chunk_basic_header(chunk_stream_id, 3, &mut buffer);
+                    buffer.extend_from_slice(chunk);
+                }
+                Arc::from(buffer)
+            });
+        Arc::clone(continuation)
+    }
+}
+
+/// Scheduling priority of a message type, lower being more urgent. Control and command messages
+/// go first, and audio is preferred over video since it is small and jitter is most noticeable.
+fn message_priority(message_type_id: u8) -> u8 {
+    match message_type_id {
+        RTMP_SET_CHUNK_SIZE..=RTMP_SET_PEER_BANDWIDTH => 0,
+        RTMP_COMMAND_MESSAGE_AMF0 | RTMP_COMMAND_MESSAGE_AMF3 => 1,
+        RTMP_AUDIO_MESSAGE => 2,
+        RTMP_VIDEO_MESSAGE => 4,
+        _ => 3,
+    }
+}
+
+/// Chunk stream used for messages of the given type, so that each kind of message has its own
+/// header compression state and can be interleaved with the others.
+pub fn chunk_stream_id(message_type_id: u8) -> u16 {
+    match message_type_id {
+        RTMP_SET_CHUNK_SIZE..=RTMP_SET_PEER_BANDWIDTH => RTMP_PROTOCOL_CONTROL_CHUNK_STREAM_ID,
+        RTMP_COMMAND_MESSAGE_AMF0 | RTMP_COMMAND_MESSAGE_AMF3 => RTMP_COMMAND_CHUNK_STREAM_ID,
+        RTMP_AUDIO_MESSAGE => RTMP_AUDIO_CHUNK_STREAM_ID,
+        RTMP_VIDEO_MESSAGE => RTMP_VIDEO_CHUNK_STREAM_ID,
+        _ => RTMP_DATA_CHUNK_STREAM_ID,
+    }
+}
+
+impl Default for WriteState {
+    fn default() -> Self {
+        Self {
+            max_chunk_size: 128,
+            prev_message_header: HashMap::new(),
+            bytes_written: 0,
+            bytes_acknowledged: 0,
+            window_ack_size: 0,
+            peer_bandwidth_limit_type: None,
+            queues: HashMap::new(),
+            sequence: 0,
+            flushing: false,
+        }
+    }
+}
+
+impl WriteState {
+    /// Whether the peer limited its bandwidth with Set Peer Bandwidth and the bytes it has not
+    /// acknowledged reach the window. Acknowledgements ahead of the bytes written, as from
+    /// peers counting the handshake, do not count as unacknowledged bytes.
+    fn peer_bandwidth_exceeded(&self) -> bool {
+        let unacknowledged = self.bytes_written.wrapping_sub(self.bytes_acknowledged);
+        self.peer_bandwidth_limit_type.is_some()
+            && self.window_ack_size != 0
+            && unacknowledged < 1 << 31
+            && unacknowledged >= self.window_ack_size
+    }
+
+    fn queue(
+        &mut self,
+        chunk_stream_id: u16,
+        header: ChunkMessageHeader,
+        payload: Arc<[u8]>,
+        shared: Option<Arc<SharedMessage>>,
+    ) {
+        self.sequence += 1;
+        self.queues
+            .entry(chunk_stream_id)
+            .or_default()
+            .push_back(OutboundMessage {
+                priority: message_priority(header.message_type_id),
+                header,
+                payload,
+                shared,
+                continuation: None,
+                sent: None,
+                sequence: self.sequence,
+            });
+    }
+
+    /// Encodes the next chunk to send. Chunks of messages on different chunk streams are
+    /// interleaved by priority, while messages on the same chunk stream are sent in order.
+    fn next_chunk(&mut self) -> Option<Chunk> {
+        let chunk_stream_id = self
+            .queues
+            .iter()
+            .filter_map(|(&id, queue)| queue.front().map(|m| (m.priority, m.sequence, id)))
+            .min()?
+            .2;
+        let queue = self.queues.get_mut(&chunk_stream_id)?;
+        let message = queue.front_mut()?;
+        let mut header = Vec::new();
+        let offset = match message.sent {
+            Some(offset) => {
+                // The shared chunks are only valid as long as the chunk size is unchanged.
+                if let Some((chunk_size, ref continuation)) = message.continuation {
+                    if chunk_size == self.max_chunk_size {
+                        let stride = basic_header_size(chunk_stream_id) + chunk_size;
+                        let start = (offset / chunk_size - 1) * stride;
+                        let end = std::cmp::min(start + stride, continuation.len());
+                        let chunk = Chunk {
+                            header,
+                            body: Arc::clone(continuation),
+                            range: start..end,
+                        };
+                        let end = std::cmp::min(offset + chunk_size, message.payload.len());
+                        return Some(self.complete_chunk(chunk_stream_id, end, chunk));
+                    }
+                }
+                message.continuation = None;
+                encode_chunk_basic_header(chunk_stream_id, 3, &mut header);
+                encode_chunk_message_header(&message.header, 3, &mut header);
+                offset
+            }
+            None => {
+                let message_header = &mut message.header;
+                // Choose the most compact header given the last one sent on the chunk stream.
+                // Timestamps are compared as serial numbers, so a delta may wrap around 2^32
+                // but not go backwards. A type 0 header is followed by deltas equal to its
+                // timestamp.
+                message_header.timestamp_delta = message_header.timestamp;
+                let chunk_type = match self.prev_message_header.get(&chunk_stream_id) {
+                    Some(prev)
+                        if prev.message_stream_id == message_header.message_stream_id
+                            && message_header.timestamp.wrapping_sub(prev.timestamp)
+                                < 0x80000000 =>
+                    {
+                        message_header.timestamp_delta =
+                            message_header.timestamp.wrapping_sub(prev.timestamp);
+                        if prev.message_length != message_header.message_length
+                            || prev.message_type_id != message_header.message_type_id
+                        {
+                            1
+                        } else if prev.timestamp_delta != message_header.timestamp_delta {
+                            2
+                        } else {
+                            3
+                        }
+                    }
+                    _ => 0,
+                };
+                encode_chunk_basic_header(chunk_stream_id, chunk_type, &mut header);
+                encode_chunk_message_header(message_header, chunk_type, &mut header);
+                self.prev_message_header
+                    .insert(chunk_stream_id, message_header.clone());
+                let chunk_size = self.max_chunk_size;
+                if message_header.timestamp_delta < 0xFFFFFF && message.payload.len() > chunk_size {
+                    message.continuation = message.shared.as_ref().map(|shared| {
+                        (chunk_size, shared.continuation(chunk_size, chunk_stream_id))
+                    });
+                }
+                0
+            }
+        };
+        let end = std::cmp::min(offset + self.max_chunk_size, message.payload.len());
+        let chunk = Chunk {
+            header,
+            body: Arc::clone(&message.payload),
+            range: offset..end,
+        };
+        Some(self.complete_chunk(chunk_stream_id, end, chunk))
+    }
+
+    /// Records that the payload of the message at the front of the chunk stream has been sent
+    /// up to `end` by the given chunk.
+    fn complete_chunk(&mut self, chunk_stream_id: u16, end: usize, chunk: Chunk) -> Chunk {
+        let queue = self.queues.get_mut(&chunk_stream_id).unwrap();
+        let message = queue.front_mut().unwrap();
+        message.sent = Some(end);
+        if end == message.payload.len() {
+            let message = queue.pop_front().unwrap();
+            if queue.is_empty() {
+                self.queues.remove(&chunk_stream_id);
+            }
+            // The new chunk size applies to every chunk sent after Set Chunk Size.
+            if message.header.message_type_id == RTMP_SET_CHUNK_SIZE {
+                self.max_chunk_size =
+                    (aggregate::<u32>(&message.payload, false) & 0x7FFFFFFF) as usize;
+            }
+        }
+        self.bytes_written = self.bytes_written.wrapping_add(chunk.len() as u32);
+        chunk
+    }
+}
+
 #[derive(Debug)]
 pub struct RtmpMessageStreamImpl<S: TryClone + Read + Write + AsRawFd> {
     pub channels: HashMap<u16, Message>,
-    prev_message_header: HashMap<u16, (ChunkMessageHeader, u8)>,
+    // The last message header read on each chunk stream, its chunk type and whether it carried
+    // an extended timestamp.
+    prev_message_header: HashMap<u16, (ChunkMessageHeader, u8, bool)>,
     stream: S,
     pub from_fd: RawFd,
     pub max_chunk_size_read: usize,
-    pub max_chunk_size_write: usize,
+    bytes_read: u32,
+    bytes_read_acknowledged: u32,
+    /// Window size set by the peer with Window Acknowledgement Size, or 0 if not set.
+    pub window_ack_size_read: u32,
+    write_state: Arc<Mutex<WriteState>>,
+    /// Notified when the peer acknowledges bytes or changes its bandwidth.
+    acknowledged: Arc<Condvar>,
 }
 
 pub type RtmpMessageStream = RtmpMessageStreamImpl<TcpStream>;
@@ -45,7 +329,24 @@ pub struct ChunkMessageHeader {
     timestamp_delta: u32,
 }
 
-#[derive(Debug)]
+impl ChunkMessageHeader {
+    pub fn new(
+        timestamp: u32,
+        message_length: usize,
+        message_type_id: u8,
+        message_stream_id: u32,
+    ) -> Self {
+        Self {
+            timestamp,
+            message_length,
+            message_type_id,
+            message_stream_id,
+            timestamp_delta: 0,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
 pub struct Message {
     pub header: ChunkMessageHeader,
     pub message: Vec<u8>,
@@ -69,16 +370,30 @@ impl<S: TryClone + Read + Write + AsRawFd> RtmpMessageStreamImpl<S> {
             stream,
             from_fd,
             max_chunk_size_read: 128,
-            max_chunk_size_write: 128,
+            bytes_read: 0,
+            bytes_read_acknowledged: 0,
+            window_ack_size_read: 0,
+            write_state: Arc::new(Mutex::new(WriteState::default())),
+            acknowledged: Arc::new(Condvar::new()),
         }
     }
 
+    fn read_chunk_bytes(&mut self, nbytes: usize) -> io::Result<Vec<u8>> {
+        let buffer = read_buffer(&mut self.stream, nbytes)?;
+        self.bytes_read = self.bytes_read.wrapping_add(nbytes as u32);
+        Ok(buffer)
+    }
+
     fn read_chunk_basic_header(&mut self) -> io::Result<ChunkBasicHeader> {
-        let header = read_numeric::<u8, _>(&mut self.stream, 1)?;
+        let header = self.read_chunk_bytes(1)?[0];
         let (chunk_type, chunk_stream_id) = (header >> 6, header & 0b111111);
         let chunk_stream_id = match chunk_stream_id {
-            0x0 => 64 + read_numeric::<u16, _>(&mut self.stream, 1)?,
-            0x1 => 64 + read_numeric::<u16, _>(&mut self.stream, 2)?,
+            0x0 => 64 + aggregate::<u16>(&self.read_chunk_bytes(1)?, false),
+            0x1 => aggregate::<u16>(&self.read_chunk_bytes(2)?, true)
+                .checked_add(64)
+                .ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidData, "Chunk stream ID is too large")
+                })?,
             _ => chunk_stream_id as u16,
         };
         Ok(ChunkBasicHeader {
@@ -90,28 +405,32 @@ impl<S: TryClone + Read + Write + AsRawFd> RtmpMessageStreamImpl<S> {
     fn read_chunk_message_header(
         &mut self,
         basic_header: &ChunkBasicHeader,
-    ) -> Result<ChunkMessageHeader> {
-        let (mut message_header, prev_chunk_type) =
-            if let Some(h) = self.prev_message_header.get(&basic_header.chunk_stream_id) {
-                h.clone()
-            } else {
-                (ChunkMessageHeader::default(), 0)
+    ) -> Result<(ChunkMessageHeader, bool)> {
+        let (mut message_header, prev_chunk_type, prev_extended) =
+            match self.prev_message_header.get(&basic_header.chunk_stream_id) {
+                Some(h) => h.clone(),
+                None if basic_header.chunk_type == 0 => (ChunkMessageHeader::default(), 0, false),
+                // Only type 0 headers carry an absolute timestamp.
+                None => return Err(Error::InvalidTimestamp),
             };
 
         if basic_header.chunk_type == 3 {
+            // Type 3 chunks repeat the extended timestamp of the header they continue.
+            if prev_extended {
+                self.read_chunk_bytes(4).map_err(Error::Io)?;
+            }
             if prev_chunk_type == 0 {
                 message_header.timestamp_delta = message_header.timestamp;
             }
-            message_header.timestamp =
-                (message_header.timestamp + message_header.timestamp_delta) % 0xFFFFFF;
-            return Ok(message_header);
+            message_header.timestamp = message_header
+                .timestamp
+                .wrapping_add(message_header.timestamp_delta);
+            return Ok((message_header, prev_extended));
         }
         const CHUNK_MESSAGE_HEADER_SIZE: [usize; 4] = [11, 7, 3, 0];
-        let buffer = read_buffer(
-            &mut self.stream,
-            CHUNK_MESSAGE_HEADER_SIZE[basic_header.chunk_type as usize],
-        )
-        .map_err(Error::Io)?;
+        let buffer = self
+            .read_chunk_bytes(CHUNK_MESSAGE_HEADER_SIZE[basic_header.chunk_type as usize])
+            .map_err(Error::Io)?;
         if basic_header.chunk_type < 2 {
             message_header.message_length = aggregate::<usize>(&buffer[3..6], false);
             message_header.message_type_id = buffer[6];
@@ -120,27 +439,28 @@ impl<S: TryClone + Read + Write + AsRawFd> RtmpMessageStreamImpl<S> {
             message_header.message_stream_id = aggregate::<u32>(&buffer[7..11], true);
         }
         let timestamp_or_delta = aggregate::<u32>(&buffer[0..3], false);
-        let timestamp_or_delta = match timestamp_or_delta {
-            0..=0xFFFFFE => timestamp_or_delta,
-            0xFFFFFF => read_u32(&mut self.stream).map_err(Error::Io)?,
-            _ => {
-                return Err(Error::InvalidTimestamp);
-            }
+        let extended = timestamp_or_delta == 0xFFFFFF;
+        let timestamp_or_delta = if extended {
+            aggregate::<u32>(&self.read_chunk_bytes(4).map_err(Error::Io)?, false)
+        } else {
+            timestamp_or_delta
         };
         if basic_header.chunk_type == 0 {
             message_header.timestamp = timestamp_or_delta;
             message_header.timestamp_delta = 0;
         } else {
+            // Timestamps are 32-bit serial numbers which wrap around.
             message_header.timestamp_delta = timestamp_or_delta;
-            message_header.timestamp =
-                (message_header.timestamp + message_header.timestamp_delta) % 0xFFFFFF;
+            message_header.timestamp = message_header
+                .timestamp
+                .wrapping_add(message_header.timestamp_delta);
         }
-        Ok(message_header)
+        Ok((message_header, extended))
     }
 
     pub fn read_message(&mut self) -> Result<Option<Message>> {
         let basic_header = self.read_chunk_basic_header().map_err(Error::Io)?;
-        let message_header = self.read_chunk_message_header(&basic_header)?;
+        let (message_header, extended) = self.read_chunk_message_header(&basic_header)?;
         let is_first_chunk = !self.channels.contains_key(&basic_header.chunk_stream_id);
         let msg = self
             .channels
@@ -150,8 +470,9 @@ impl<S: TryClone + Read + Write + AsRawFd> RtmpMessageStreamImpl<S> {
             self.max_chunk_size_read,
             msg.header.message_length - msg.message.len(),
         );
-        msg.message
-            .extend_from_slice(&read_buffer(&mut self.stream, buffer_size).map_err(Error::Io)?);
+        let chunk = read_buffer(&mut self.stream, buffer_size).map_err(Error::Io)?;
+        self.bytes_read = self.bytes_read.wrapping_add(buffer_size as u32);
+        msg.message.extend_from_slice(&chunk);
         let result = if msg.message.len() == msg.header.message_length {
             self.channels.remove(&basic_header.chunk_stream_id)
         } else {
@@ -161,12 +482,94 @@ impl<S: TryClone + Read + Write + AsRawFd> RtmpMessageStreamImpl<S> {
         if is_first_chunk {
             self.prev_message_header.insert(
                 basic_header.chunk_stream_id,
-                (message_header, basic_header.chunk_type),
+                (message_header, basic_header.chunk_type, extended),
             );
         }
+        self.acknowledge()?;
         Ok(result)
     }
 
+    /// Sends Acknowledgement once the bytes received since the last one reach the window size
+    /// set by the peer.
+    fn acknowledge(&mut self) -> Result<()> {
+        if self.window_ack_size_read == 0
+            || self.bytes_read.wrapping_sub(self.bytes_read_acknowledged)
+                < self.window_ack_size_read
+        {
+            return Ok(());
+        }
+        self.bytes_read_acknowledged = self.bytes_read;
+        self.send_message(
+            RTMP_PROTOCOL_CONTROL_CHUNK_STREAM_ID,
+            RTMP_PROTOCOL_CONTROL_MESSAGE_STREAM_ID,
+            0,
+            RTMP_ACKNOWLEDGEMENT,
+            &self.bytes_read.to_be_bytes(),
+        )
+    }
+
+    /// Records an Acknowledgement received from the peer.
+    pub fn handle_acknowledgement(&mut self, sequence_number: u32) {
+        self.write_state.lock().unwrap().bytes_acknowledged = sequence_number;
+        self.acknowledged.notify_all();
+    }
+
+    /// Sets the window size after which the peer should acknowledge received bytes.
+    pub fn send_window_ack_size(&mut self, window_ack_size: u32) -> Result<()> {
+        self.write_state.lock().unwrap().window_ack_size = window_ack_size;
+        self.send_message(
+            RTMP_PROTOCOL_CONTROL_CHUNK_STREAM_ID,
+            RTMP_PROTOCOL_CONTROL_MESSAGE_STREAM_ID,
+            0,
+            RTMP_WINDOW_ACK_SIZE,
+            &window_ack_size.to_be_bytes(),
+        )
+    }
+
+    /// Applies Set Peer Bandwidth from the peer to the outbound window, and announces the new
+    /// window size with Window Acknowledgement Size if it changes.
+    pub fn handle_set_peer_bandwidth(&mut self, window_size: u32, limit_type: u8) -> Result<()> {
+        let window_size = {
+            let state = &mut *self.write_state.lock().unwrap();
+            let window_size = match limit_type {
+                RTMP_PEER_BANDWIDTH_HARD => window_size,
+                RTMP_PEER_BANDWIDTH_SOFT if state.window_ack_size != 0 => {
+                    std::cmp::min(state.window_ack_size, window_size)
+                }
+                RTMP_PEER_BANDWIDTH_SOFT => window_size,
+                // A dynamic limit is treated as hard if the previous limit was hard, and
+                // ignored otherwise.
+                RTMP_PEER_BANDWIDTH_DYNAMIC
+                    if state.peer_bandwidth_limit_type == Some(RTMP_PEER_BANDWIDTH_HARD) =>
+                {
+                    window_size
+                }
+                _ => return Ok(()),
+            };
+            if limit_type != RTMP_PEER_BANDWIDTH_DYNAMIC {
+                state.peer_bandwidth_limit_type = Some(limit_type);
+            }
+            if window_size == state.window_ack_size {
+                return Ok(());
+            }
+            window_size
+        };
+        self.acknowledged.notify_all();
+        self.send_window_ack_size(window_size)
+    }
+
+    /// Sets the maximum chunk size of outgoing messages and announces it to the peer. The new
+    /// size takes effect once Set Chunk Size has been sent.
+    pub fn set_chunk_size(&mut self, chunk_size: u32) -> Result<()> {
+        self.send_message(
+            RTMP_PROTOCOL_CONTROL_CHUNK_STREAM_ID,
+            RTMP_PROTOCOL_CONTROL_MESSAGE_STREAM_ID,
+            0,
+            RTMP_SET_CHUNK_SIZE,
+            &chunk_size.to_be_bytes(),
+        )
+    }
+
     pub fn handle_handshake(&mut self) -> Result<()> {
         let c0 = read_buffer_sized::<_, 1>(&mut self.stream).map_err(Error::Io)?;
         if c0[0] != 0x3 {
@@ -174,8 +577,24 @@ impl<S: TryClone + Read + Write + AsRawFd> RtmpMessageStreamImpl<S> {
         }
         let s0 = [0x3; 1];
         self.stream.write_all(&s0).map_err(Error::Io)?;
-        const HANDSHAKE_SIZE: usize = 1536;
         let c1 = read_buffer_sized::<_, HANDSHAKE_SIZE>(&mut self.stream).map_err(Error::Io)?;
+        // Clients sending a non-zero version in C1 expect the digest-based handshake, unless
+        // C1 turns out to carry no valid digest.
+        let digest = if c1[4..8] == [0x0; 4] {
+            None
+        } else {
+            handshake::find_digest(&c1, &handshake::client_key(false))
+        };
+        if let Some((schema, c1_digest)) = digest {
+            let s1 = handshake::generate_s1(schema);
+            self.stream.write_all(&s1).map_err(Error::Io)?;
+            let s2 = handshake::generate_s2(&c1_digest);
+            self.stream.write_all(&s2).map_err(Error::Io)?;
+            // Many encoders do not sign C2 properly, so it is read but not verified.
+            let _c2 =
+                read_buffer_sized::<_, HANDSHAKE_SIZE>(&mut self.stream).map_err(Error::Io)?;
+            return Ok(());
+        }
         // Send a buffer consisting of random bytes.
         let s1: Vec<_> = (0..HANDSHAKE_SIZE)
             .map(|i| if i < 8 { 0 } else { rand::random::<u8>() })
@@ -191,95 +610,121 @@ impl<S: TryClone + Read + Write + AsRawFd> RtmpMessageStreamImpl<S> {
         }
     }
 
-    fn send_chunk_basic_header(&mut self, header: ChunkBasicHeader) -> Result<()> {
-        if header.chunk_stream_id < 64 {
-            let byte = (header.chunk_stream_id as u8) | (header.chunk_type << 6);
-            self.stream.write_all(&[byte])
-        } else if header.chunk_stream_id < 320 {
-            self.stream.write_all(&[
-                header.chunk_type << 6 | 1,
-                (header.chunk_stream_id - 64) as u8,
-            ])
-        } else {
-            self.stream.write_all(&[
-                header.chunk_type << 6,
-                ((header.chunk_stream_id - 64) >> 8) as u8,
-                ((header.chunk_stream_id - 64) & 255) as u8,
-            ])
+    /// Performs the client side of the handshake, offering the digest-based handshake and
+    /// falling back to the simple one if S1 carries no digest.
+    pub fn handle_client_handshake(&mut self) -> Result<()> {
+        let mut c0c1 = vec![0x3];
+        c0c1.extend_from_slice(&handshake::generate_c1());
+        self.stream.write_all(&c0c1).map_err(Error::Io)?;
+        let s0 = read_buffer_sized::<_, 1>(&mut self.stream).map_err(Error::Io)?;
+        if s0[0] != 0x3 {
+            return Err(Error::HandshakeCorrupted);
         }
-        .map_err(Error::Io)?;
-        Ok(())
+        let s1 = read_buffer_sized::<_, HANDSHAKE_SIZE>(&mut self.stream).map_err(Error::Io)?;
+        // Like C2 on the server side, S2 is read but not verified.
+        let _s2 = read_buffer_sized::<_, HANDSHAKE_SIZE>(&mut self.stream).map_err(Error::Io)?;
+        let c2 = match handshake::find_digest(&s1, &handshake::server_key(false)) {
+            Some((_, s1_digest)) => handshake::generate_c2(&s1_digest),
+            None => Vec::from(&s1[..]),
+        };
+        self.stream.write_all(&c2).map_err(Error::Io)
     }
 
-    fn send_chunk_message_header(
+    /// Queues a message and sends every queued chunk unless another stream sharing the
+    /// connection is already doing so.
+    pub fn send_message(
         &mut self,
-        header: ChunkMessageHeader,
-        chunk_type: u8,
+        chunk_stream_id: u16,
+        message_stream_id: u32,
+        timestamp: u32,
+        message_type_id: u8,
+        message: &[u8],
     ) -> Result<()> {
-        if chunk_type == 3 {
-            return Ok(());
-        }
-        // The maximum size of header is 11 bytes.
-        let mut buffer = Vec::with_capacity(11);
-        let timestamp_or_delta = if chunk_type == 0 {
-            header.timestamp
-        } else {
-            header.timestamp_delta
-        };
-        let timestamp_or_delta_non_extended = if timestamp_or_delta >= 0xFFFFFF {
-            0xFFFFFF
-        } else {
-            timestamp_or_delta
-        };
-        buffer.extend_from_slice(&timestamp_or_delta_non_extended.to_be_bytes()[1..]);
-        if chunk_type < 2 {
-            buffer.extend_from_slice(
-                &header.message_length.to_be_bytes()[std::mem::size_of::<usize>() - 3..],
-            );
-            buffer.push(header.message_type_id);
-        }
-        if chunk_type == 0 {
-            buffer.extend_from_slice(&header.message_stream_id.to_le_bytes());
-        }
-        if chunk_type < 3 && timestamp_or_delta >= 0xFFFFFF {
-            buffer.extend_from_slice(&timestamp_or_delta.to_be_bytes());
-        }
-        self.stream.write_all(&buffer).map_err(Error::Io)?;
-        Ok(())
+        self.queue_message(
+            chunk_stream_id,
+            message_stream_id,
+            timestamp,
+            message_type_id,
+            message,
+        );
+        self.flush()
     }
 
-    pub fn send_message(
+    pub fn queue_message(
         &mut self,
         chunk_stream_id: u16,
         message_stream_id: u32,
         timestamp: u32,
         message_type_id: u8,
         message: &[u8],
+    ) {
+        let header =
+            ChunkMessageHeader::new(timestamp, message.len(), message_type_id, message_stream_id);
+        self.write_state
+            .lock()
+            .unwrap()
+            .queue(chunk_stream_id, header, Arc::from(message), None);
+    }
+
+    /// Queues a message shared with other connections and sends every queued chunk. The payload
+    /// is not copied, and chunks serialized for another connection are reused when possible.
+    ///
+    /// While the peer bandwidth is used up, waits for the peer to acknowledge bytes, and fails
+    /// if it does not in time. This must not be called from the thread reading the connection.
+    pub fn send_shared_message(
+        &mut self,
+        chunk_stream_id: u16,
+        message: &Arc<SharedMessage>,
     ) -> Result<()> {
-        let mut ptr = 0;
-        while ptr < message.len() {
-            let size = std::cmp::min(self.max_chunk_size_write, message.len() - ptr);
-            let chunk_type = if ptr == 0 { 0 } else { 3 };
-            self.send_chunk_basic_header(ChunkBasicHeader {
-                chunk_stream_id,
-                chunk_type,
-            })?;
-            self.send_chunk_message_header(
-                ChunkMessageHeader {
-                    timestamp,
-                    message_length: message.len(),
-                    message_type_id,
-                    message_stream_id,
-                    timestamp_delta: 0,
-                },
-                chunk_type,
-            )?;
-            self.stream
-                .write_all(&message[ptr..ptr + size])
-                .map_err(Error::Io)?;
-            ptr += size;
+        let deadline = Instant::now() + PEER_BANDWIDTH_TIMEOUT;
+        let mut state = self.write_state.lock().unwrap();
+        while state.peer_bandwidth_exceeded() {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            if timeout.is_zero() {
+                return Err(Error::Io(io::ErrorKind::TimedOut.into()));
+            }
+            state = self.acknowledged.wait_timeout(state, timeout).unwrap().0;
+        }
+        state.queue(
+            chunk_stream_id,
+            message.header.clone(),
+            Arc::clone(&message.payload),
+            Some(Arc::clone(message)),
+        );
+        drop(state);
+        self.flush()
+    }
+
+    /// Writes queued chunks until the queues are empty. Chunks are written without holding the
+    /// lock so that other streams can queue urgent messages in the meantime.
+    pub fn flush(&mut self) -> Result<()> {
+        {
+            let state = &mut *self.write_state.lock().unwrap();
+            if state.flushing {
+                return Ok(());
+            }
+            state.flushing = true;
+        }
+        loop {
+            let chunk = {
+                let state = &mut *self.write_state.lock().unwrap();
+                match state.next_chunk() {
+                    Some(chunk) => chunk,
+                    None => {
+                        state.flushing = false;
+                        return Ok(());
+                    }
+                }
+            };
+            let mut buffers = [
+                IoSlice::new(&chunk.header),
+                IoSlice::new(&chunk.body[chunk.range]),
+            ];
+            if let Err(e) = write_all_vectored(&mut self.stream, &mut buffers) {
+                self.write_state.lock().unwrap().flushing = false;
+                return Err(Error::Io(e));
+            }
         }
-        Ok(())
     }
 
     pub fn decouple(&self) -> Self {
@@ -289,11 +734,81 @@ impl<S: TryClone + Read + Write + AsRawFd> RtmpMessageStreamImpl<S> {
             stream: self.stream.try_clone().expect("Failed to clone"),
             from_fd: self.from_fd,
             max_chunk_size_read: self.max_chunk_size_read,
-            max_chunk_size_write: self.max_chunk_size_write,
+            bytes_read: 0,
+            bytes_read_acknowledged: 0,
+            window_ack_size_read: 0,
+            write_state: Arc::clone(&self.write_state),
+            acknowledged: Arc::clone(&self.acknowledged),
         }
     }
 }
 
+impl RtmpMessageStream {
+    /// Shuts down both directions of the connection, including for decoupled streams.
+    pub fn shutdown(&self) -> io::Result<()> {
+        self.stream.shutdown(Shutdown::Both)
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.stream.set_read_timeout(timeout)
+    }
+
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.stream.set_write_timeout(timeout)
+    }
+}
+
+fn basic_header_size(chunk_stream_id: u16) -> usize {
+    match chunk_stream_id {
+        0..=63 => 1,
+        64..=319 => 2,
+        _ => 3,
+    }
+}
+
+fn encode_chunk_basic_header(chunk_stream_id: u16, chunk_type: u8, buffer: &mut Vec<u8>) {
+    if chunk_stream_id < 64 {
+        buffer.push((chunk_stream_id as u8) | (chunk_type << 6));
+    } else if chunk_stream_id < 320 {
+        buffer.extend_from_slice(&[chunk_type << 6, (chunk_stream_id - 64) as u8]);
+    } else {
+        // The ID is stored in little endian in the 3-byte form.
+        buffer.push(chunk_type << 6 | 1);
+        buffer.extend_from_slice(&(chunk_stream_id - 64).to_le_bytes());
+    }
+}
+
+/// Writes the message header of a chunk. Type 1, 2 and 3 headers use `timestamp_delta`, which
+/// is also repeated as extended timestamp in type 3 chunks if it does not fit in 24 bits.
+fn encode_chunk_message_header(header: &ChunkMessageHeader, chunk_type: u8, buffer: &mut Vec<u8>) {
+    let timestamp_or_delta = if chunk_type == 0 {
+        header.timestamp
+    } else {
+        header.timestamp_delta
+    };
+    let is_extended = timestamp_or_delta >= 0xFFFFFF;
+    if chunk_type < 3 {
+        let timestamp_or_delta_non_extended = if is_extended {
+            0xFFFFFF
+        } else {
+            timestamp_or_delta
+        };
+        buffer.extend_from_slice(&timestamp_or_delta_non_extended.to_be_bytes()[1..]);
+    }
+    if chunk_type < 2 {
+        buffer.extend_from_slice(
+            &header.message_length.to_be_bytes()[std::mem::size_of::<usize>() - 3..],
+        );
+        buffer.push(header.message_type_id);
+    }
+    if chunk_type == 0 {
+        buffer.extend_from_slice(&header.message_stream_id.to_le_bytes());
+    }
+    if is_extended {
+        buffer.extend_from_slice(&timestamp_or_delta.to_be_bytes());
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -365,20 +880,124 @@ mod tests {
         assert_eq!(basic_header.chunk_stream_id, 64);
     }
 
+    #[test]
+    fn test_basic_header_round_trip() {
+        let mut buffer = Vec::new();
+        for &chunk_stream_id in &[2, 63, 64, 319, 320, 65535] {
+            encode_chunk_basic_header(chunk_stream_id, 1, &mut buffer);
+        }
+        let mock = MockTcpStream {
+            cursor: io::Cursor::new(buffer),
+            buffer: Vec::new(),
+        };
+        let mut stream = MockRtmpMessageStream::new(mock);
+        for &chunk_stream_id in &[2, 63, 64, 319, 320, 65535] {
+            let basic_header = stream.read_chunk_basic_header().unwrap();
+            assert_eq!(basic_header.chunk_type, 1);
+            assert_eq!(basic_header.chunk_stream_id, chunk_stream_id);
+        }
+    }
+
+    #[test]
+    fn test_interleaving() {
+        let mut stream = MockRtmpMessageStream::new(MockTcpStream::default());
+        stream.queue_message(RTMP_VIDEO_CHUNK_STREAM_ID, 1, 0, 9, &[0x9; 300]);
+        let first = stream.write_state.lock().unwrap().next_chunk().unwrap();
+        assert_eq!(first.len(), 12 + 128);
+        // Audio and control messages queued in the middle of a video message go first.
+        stream.queue_message(RTMP_AUDIO_CHUNK_STREAM_ID, 1, 0, 8, &[0x8; 10]);
+        stream.set_chunk_size(256).unwrap();
+        let buffer = &stream.stream.buffer;
+        assert_eq!(buffer.len(), 16 + 22 + 173);
+        assert_eq!(buffer[0], RTMP_PROTOCOL_CONTROL_CHUNK_STREAM_ID as u8);
+        assert_eq!(buffer[16], RTMP_AUDIO_CHUNK_STREAM_ID as u8);
+        // The rest of the video message is sent as a single chunk of the new size.
+        assert_eq!(buffer[38], 0xC0 | RTMP_VIDEO_CHUNK_STREAM_ID as u8);
+        assert_eq!(buffer[39..], [0x9; 172]);
+    }
+
+    #[test]
+    fn test_shared_message() {
+        let payload: Vec<u8> = (0..300).map(|i| i as u8).collect();
+        let header = ChunkMessageHeader::new(40, payload.len(), RTMP_VIDEO_MESSAGE, 1);
+        let message = Arc::new(SharedMessage::new(header, payload.clone()));
+        let mut expected = MockRtmpMessageStream::new(MockTcpStream::default());
+        expected
+            .send_message(
+                RTMP_VIDEO_CHUNK_STREAM_ID,
+                1,
+                40,
+                RTMP_VIDEO_MESSAGE,
+                &payload,
+            )
+            .unwrap();
+        for _ in 0..2 {
+            let mut stream = MockRtmpMessageStream::new(MockTcpStream::default());
+            stream
+                .send_shared_message(RTMP_VIDEO_CHUNK_STREAM_ID, &message)
+                .unwrap();
+            assert_eq!(stream.stream.buffer, expected.stream.buffer);
+            stream.stream.consume_buffer();
+            let received = loop {
+                if let Some(message) = stream.read_message().unwrap() {
+                    break message;
+                }
+            };
+            assert_eq!(received.message, payload);
+        }
+        // Both streams share the chunks serialized for the first one.
+        assert_eq!(message.continuations.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_shared_message_chunk_size_change() {
+        let payload = vec![0x9; 300];
+        let header = ChunkMessageHeader::new(0, payload.len(), RTMP_VIDEO_MESSAGE, 1);
+        let message = Arc::new(SharedMessage::new(header, payload));
+        let mut stream = MockRtmpMessageStream::new(MockTcpStream::default());
+        stream.write_state.lock().unwrap().queue(
+            RTMP_VIDEO_CHUNK_STREAM_ID,
+            message.header.clone(),
+            Arc::clone(&message.payload),
+            Some(Arc::clone(&message)),
+        );
+        let first = stream.write_state.lock().unwrap().next_chunk().unwrap();
+        assert_eq!(first.len(), 12 + 128);
+        // The shared chunks no longer apply once the chunk size changes.
+        stream.set_chunk_size(256).unwrap();
+        let buffer = &stream.stream.buffer;
+        assert_eq!(buffer.len(), 16 + 173);
+        assert_eq!(buffer[16], 0xC0 | RTMP_VIDEO_CHUNK_STREAM_ID as u8);
+        assert_eq!(buffer[17..], [0x9; 172]);
+    }
+
+    #[test]
+    fn test_shared_message_extended_timestamp() {
+        let payload = vec![0x8; 200];
+        let header = ChunkMessageHeader::new(0x1000000, payload.len(), RTMP_AUDIO_MESSAGE, 1);
+        let message = Arc::new(SharedMessage::new(header, payload.clone()));
+        let mut stream = MockRtmpMessageStream::new(MockTcpStream::default());
+        stream
+            .send_shared_message(RTMP_AUDIO_CHUNK_STREAM_ID, &message)
+            .unwrap();
+        // Chunks repeating an extended timestamp are serialized for the connection alone.
+        assert!(message.continuations.lock().unwrap().is_empty());
+        stream.stream.consume_buffer();
+        assert!(stream.read_message().unwrap().is_none());
+        let received = stream.read_message().unwrap().unwrap();
+        assert_eq!(received.header.timestamp, 0x1000000);
+        assert_eq!(received.message, payload);
+    }
+
     fn send_message_header(
         stream: &mut MockRtmpMessageStream,
         header: ChunkMessageHeader,
         chunk_type: u8,
     ) {
-        stream
-            .send_chunk_basic_header(ChunkBasicHeader {
-                chunk_stream_id: 3,
-                chunk_type,
-            })
-            .unwrap();
-        stream
-            .send_chunk_message_header(header, chunk_type)
-            .unwrap();
+        let mut buffer = Vec::new();
+        encode_chunk_basic_header(3, chunk_type, &mut buffer);
+        encode_chunk_message_header(&header, chunk_type, &mut buffer);
+        stream.stream.write_all(&buffer).unwrap();
     }
 
     #[test]
@@ -550,4 +1169,278 @@ mod tests {
         assert_eq!(msg.header.message_length, 129);
         assert_eq!(msg.message.len(), 129);
     }
+
+    #[test]
+    fn test_timestamp_extended_boundary() {
+        let mut stream = MockRtmpMessageStream::new(MockTcpStream::default());
+        for &timestamp in &[0xFFFFFE, 0xFFFFFF] {
+            send_message_header(
+                &mut stream,
+                ChunkMessageHeader {
+                    timestamp,
+                    message_length: 0,
+                    message_type_id: 0,
+                    message_stream_id: 0,
+                    timestamp_delta: 0,
+                },
+                0,
+            );
+        }
+        assert_eq!(stream.stream.buffer.len(), 12 + 16);
+        stream.stream.consume_buffer();
+        let msg = stream.read_message().unwrap().unwrap();
+        assert_eq!(msg.header.timestamp, 0xFFFFFE);
+        let msg = stream.read_message().unwrap().unwrap();
+        assert_eq!(msg.header.timestamp, 0xFFFFFF);
+    }
+
+    #[test]
+    fn test_timestamp_wraparound() {
+        let mut stream = MockRtmpMessageStream::new(MockTcpStream::default());
+        send_message_header(
+            &mut stream,
+            ChunkMessageHeader {
+                timestamp: 0xFFFFFFF0,
+                message_length: 0,
+                message_type_id: 0,
+                message_stream_id: 0,
+                timestamp_delta: 0,
+            },
+            0,
+        );
+        send_message_header(
+            &mut stream,
+            ChunkMessageHeader {
+                timestamp: 0,
+                message_length: 0,
+                message_type_id: 0,
+                message_stream_id: 0,
+                timestamp_delta: 0x20,
+            },
+            1,
+        );
+        stream.stream.consume_buffer();
+        let msg = stream.read_message().unwrap().unwrap();
+        assert_eq!(msg.header.timestamp, 0xFFFFFFF0);
+        let msg = stream.read_message().unwrap().unwrap();
+        assert_eq!(msg.header.timestamp, 0x10);
+    }
+
+    #[test]
+    fn test_timestamp_wraparound_send() {
+        let mut stream = MockRtmpMessageStream::new(MockTcpStream::default());
+        stream.send_message(4, 1, 0xFFFFFFF0, 8, &[0x0; 4]).unwrap();
+        let size = stream.stream.buffer.len();
+        stream.send_message(4, 1, 0x10, 8, &[0x0; 4]).unwrap();
+        // The delta wraps around instead of falling back to a type 0 header.
+        assert_eq!(stream.stream.buffer[size] >> 6, 2);
+        stream.stream.consume_buffer();
+        let msg = stream.read_message().unwrap().unwrap();
+        assert_eq!(msg.header.timestamp, 0xFFFFFFF0);
+        let msg = stream.read_message().unwrap().unwrap();
+        assert_eq!(msg.header.timestamp, 0x10);
+    }
+
+    #[test]
+    fn test_extended_timestamp_type3() {
+        let mut stream = MockRtmpMessageStream::new(MockTcpStream::default());
+        let payload: Vec<_> = (0..200).map(|i| i as u8).collect();
+        stream.send_message(3, 1, 0x1000000, 9, &payload).unwrap();
+        // Same length, type and delta, so the second message has a type 3 header.
+        stream.send_message(3, 1, 0x2000000, 9, &payload).unwrap();
+        stream.stream.consume_buffer();
+        for &timestamp in &[0x1000000, 0x2000000] {
+            assert!(stream.read_message().unwrap().is_none());
+            let msg = stream.read_message().unwrap().unwrap();
+            assert_eq!(msg.header.timestamp, timestamp);
+            assert_eq!(msg.message, payload);
+        }
+        assert!(stream.read_message().is_err());
+    }
+
+    #[test]
+    fn test_timestamp_delta_without_timestamp() {
+        let mock = MockTcpStream {
+            cursor: io::Cursor::new(vec![0x43, 0x0, 0x0, 0x1, 0x0, 0x0, 0x0, 0x8]),
+            buffer: Vec::new(),
+        };
+        let mut stream = MockRtmpMessageStream::new(mock);
+        let e = stream.read_message().unwrap_err();
+        assert!(matches!(e, Error::InvalidTimestamp));
+        assert!(!e.to_string().is_empty());
+    }
+
+    #[test]
+    fn test_header_compression() {
+        let mut stream = MockRtmpMessageStream::new(MockTcpStream::default());
+        let mut sizes = vec![];
+        for &(timestamp, length) in &[(1000, 20), (1020, 20), (1040, 20), (1050, 30)] {
+            stream
+                .send_message(4, 1, timestamp, 8, &vec![0x0; length])
+                .unwrap();
+            sizes.push(stream.stream.buffer.len());
+        }
+        let buffer = &stream.stream.buffer;
+        assert_eq!(sizes, [32, 56, 77, 115]);
+        // Type 0, 2, 3 and 1 headers.
+        assert_eq!(buffer[0], 0x04);
+        assert_eq!(buffer[sizes[0]], 0x84);
+        assert_eq!(buffer[sizes[1]], 0xC4);
+        assert_eq!(buffer[sizes[2]], 0x44);
+
+        let mut decoupled = stream.decouple();
+        decoupled.send_message(4, 1, 1060, 8, &[0x0; 30]).unwrap();
+        assert_eq!(decoupled.stream.buffer[0], 0xC4);
+
+        stream.stream.consume_buffer();
+        for &timestamp in &[1000, 1020, 1040, 1050] {
+            let msg = stream.read_message().unwrap().unwrap();
+            assert_eq!(msg.header.timestamp, timestamp);
+            assert_eq!(msg.header.message_stream_id, 1);
+        }
+    }
+
+    #[test]
+    fn test_extended_timestamp_continuation() {
+        let mut stream = MockRtmpMessageStream::new(MockTcpStream::default());
+        stream
+            .send_message(3, 1, 0x1000000, 9, &[0x0; 200])
+            .unwrap();
+        let buffer = &stream.stream.buffer;
+        assert_eq!(buffer.len(), 12 + 4 + 128 + 1 + 4 + 72);
+        assert_eq!(buffer[1..4], [0xFF; 3]);
+        assert_eq!(buffer[12..16], [0x1, 0x0, 0x0, 0x0]);
+        assert_eq!(buffer[144], 0xC3);
+        assert_eq!(buffer[145..149], [0x1, 0x0, 0x0, 0x0]);
+    }
+
+    #[test]
+    fn test_acknowledgement() {
+        let mut stream = MockRtmpMessageStream::new(MockTcpStream::default());
+        stream.send_message(3, 0, 0, 20, &[0x0; 200]).unwrap();
+        stream.stream.consume_buffer();
+        stream.window_ack_size_read = 150;
+        // The first chunk is 12 + 128 bytes long.
+        assert!(stream.read_message().unwrap().is_none());
+        assert!(stream.stream.buffer.is_empty());
+        assert!(stream.read_message().unwrap().is_some());
+        let total = 12 + 200 + 1;
+        let mut expected = vec![0x2, 0x0, 0x0, 0x0, 0x0, 0x0, 0x4, 0x3, 0x0, 0x0, 0x0, 0x0];
+        expected.extend_from_slice(&(total as u32).to_be_bytes());
+        assert_eq!(stream.stream.buffer, expected);
+    }
+
+    #[test]
+    fn test_set_peer_bandwidth() {
+        let mut stream = MockRtmpMessageStream::new(MockTcpStream::default());
+        stream.send_window_ack_size(1000).unwrap();
+        let sent = |stream: &mut MockRtmpMessageStream| {
+            let buffer: Vec<_> = stream.stream.buffer.drain(..).collect();
+            buffer[buffer.len() - 4..].to_vec()
+        };
+        assert_eq!(sent(&mut stream), 1000_u32.to_be_bytes());
+        // Soft limits only ever lower the window.
+        stream
+            .handle_set_peer_bandwidth(2000, RTMP_PEER_BANDWIDTH_SOFT)
+            .unwrap();
+        assert!(stream.stream.buffer.is_empty());
+        stream
+            .handle_set_peer_bandwidth(500, RTMP_PEER_BANDWIDTH_SOFT)
+            .unwrap();
+        assert_eq!(sent(&mut stream), 500_u32.to_be_bytes());
+        // Dynamic limits are ignored unless the previous limit was hard.
+        stream
+            .handle_set_peer_bandwidth(700, RTMP_PEER_BANDWIDTH_DYNAMIC)
+            .unwrap();
+        assert!(stream.stream.buffer.is_empty());
+        stream
+            .handle_set_peer_bandwidth(2000, RTMP_PEER_BANDWIDTH_HARD)
+            .unwrap();
+        assert_eq!(sent(&mut stream), 2000_u32.to_be_bytes());
+        stream
+            .handle_set_peer_bandwidth(700, RTMP_PEER_BANDWIDTH_DYNAMIC)
+            .unwrap();
+        assert_eq!(sent(&mut stream), 700_u32.to_be_bytes());
+    }
+
+    #[test]
+    fn test_peer_bandwidth_exceeded() {
+        let mut stream = MockRtmpMessageStream::new(MockTcpStream::default());
+        stream
+            .handle_set_peer_bandwidth(100, RTMP_PEER_BANDWIDTH_HARD)
+            .unwrap();
+        let header = ChunkMessageHeader::new(0, 200, RTMP_VIDEO_MESSAGE, 1);
+        let message = Arc::new(SharedMessage::new(header, vec![0x0; 200]));
+        stream.send_shared_message(6, &message).unwrap();
+        let bytes_written = stream.write_state.lock().unwrap().bytes_written;
+        assert!(bytes_written > 100);
+
+        // Shared messages wait until the peer acknowledges the bytes sent.
+        let mut sender = stream.decouple();
+        let (tx, rx) = std::sync::mpsc::channel();
+        std::thread::spawn(move || tx.send(sender.send_shared_message(6, &message).is_ok()));
+        std::thread::sleep(Duration::from_millis(50));
+        assert!(rx.try_recv().is_err());
+        stream.handle_acknowledgement(bytes_written);
+        assert!(rx.recv().unwrap());
+
+        // Acknowledgements ahead of the bytes written do not block.
+        stream.handle_acknowledgement(bytes_written.wrapping_mul(3));
+        assert!(!stream.write_state.lock().unwrap().peer_bandwidth_exceeded());
+    }
+
+    #[test]
+    fn test_complex_handshake() {
+        let c1 = handshake::generate_digested_packet(
+            handshake::DigestSchema::Schema0,
+            [0x80, 0x0, 0x7, 0x2],
+            &handshake::client_key(false),
+        );
+        let (_, c1_digest) = handshake::find_digest(&c1, &handshake::client_key(false)).unwrap();
+        let mut input = vec![0x3];
+        input.extend_from_slice(&c1);
+        input.extend_from_slice(&[0x0; HANDSHAKE_SIZE]);
+        let mock = MockTcpStream {
+            cursor: io::Cursor::new(input),
+            buffer: Vec::new(),
+        };
+        let mut stream = MockRtmpMessageStream::new(mock);
+        stream.handle_handshake().unwrap();
+
+        let output = &stream.stream.buffer;
+        assert_eq!(output.len(), 1 + 2 * HANDSHAKE_SIZE);
+        assert_eq!(output[0], 0x3);
+        let (s1, s2) = output[1..].split_at(HANDSHAKE_SIZE);
+        let (schema, _) = handshake::find_digest(s1, &handshake::server_key(false)).unwrap();
+        assert_eq!(schema, handshake::DigestSchema::Schema0);
+        assert_eq!(
+            s2[HANDSHAKE_SIZE - handshake::DIGEST_SIZE..],
+            handshake::response_signature(s2, &c1_digest, &handshake::server_key(true))
+        );
+    }
+
+    #[test]
+    fn test_client_handshake() {
+        let s1 = handshake::generate_s1(handshake::DigestSchema::Schema1);
+        let (_, s1_digest) = handshake::find_digest(&s1, &handshake::server_key(false)).unwrap();
+        let mut input = vec![0x3];
+        input.extend_from_slice(&s1);
+        input.extend_from_slice(&[0x0; HANDSHAKE_SIZE]);
+        let mock = MockTcpStream {
+            cursor: io::Cursor::new(input),
+            buffer: Vec::new(),
+        };
+        let mut stream = MockRtmpMessageStream::new(mock);
+        stream.handle_client_handshake().unwrap();
+
+        let output = &stream.stream.buffer;
+        assert_eq!(output.len(), 1 + 2 * HANDSHAKE_SIZE);
+        assert_eq!(output[0], 0x3);
+        let (c1, c2) = output[1..].split_at(HANDSHAKE_SIZE);
+        assert!(handshake::find_digest(c1, &handshake::client_key(false)).is_some());
+        assert_eq!(
+            c2[HANDSHAKE_SIZE - handshake::DIGEST_SIZE..],
+            handshake::response_signature(c2, &s1_digest, &handshake::client_key(true))
+        );
+    }
 }
@@ -0,0 +1,428 @@
+//! MPEG-TS muxing of H.264 and AAC, as used by HLS segments.
+
+use std::collections::HashMap;
+
+use crate::error::{Error, Result};
+use crate::media::{AudioSpecificConfig, AvcDecoderConfigurationRecord};
+
+pub const TS_PACKET_SIZE: usize = 188;
+const TS_SYNC_BYTE: u8 = 0x47;
+const PAT_PID: u16 = 0x0;
+const PMT_PID: u16 = 0x1000;
+const VIDEO_PID: u16 = 0x100;
+const AUDIO_PID: u16 = 0x101;
+const STREAM_TYPE_H264: u8 = 0x1B;
+const STREAM_TYPE_AAC_ADTS: u8 = 0x0F;
+const PES_STREAM_ID_VIDEO: u8 = 0xE0;
+const PES_STREAM_ID_AUDIO: u8 = 0xC0;
+const NAL_UNIT_TYPE_IDR: u8 = 5;
+const NAL_UNIT_TYPE_SPS: u8 = 7;
+const NAL_UNIT_TYPE_AUD: u8 = 9;
+const ANNEX_B_START_CODE: [u8; 4] = [0x0, 0x0, 0x0, 0x1];
+
+/// CRC-32 of MPEG-2 sections.
+fn crc32(data: &[u8]) -> u32 {
+    data.iter().fold(0xFFFFFFFF, |crc, &byte| {
+        (0..8).fold(crc ^ u32::from(byte) << 24, |crc, _| {
+            if crc & 0x80000000 != 0 {
+                crc << 1 ^ 0x04C11DB7
+            } else {
+                crc << 1
+            }
+        })
+    })
+}
+
+/// Converts AVC video data, made of NAL units prefixed with their length, to an Annex B byte
+/// stream starting with an access unit delimiter. The parameter sets of `config` are inserted
+/// before IDR pictures which are not preceded by their own.
+pub fn avc_to_annex_b(
+    data: &[u8],
+    config: &AvcDecoderConfigurationRecord,
+    output: &mut Vec<u8>,
+) -> Result<()> {
+    let length_size = usize::from(config.nal_unit_length_size);
+    output.extend_from_slice(&ANNEX_B_START_CODE);
+    output.extend_from_slice(&[NAL_UNIT_TYPE_AUD, 0xF0]);
+    let mut has_parameter_sets = false;
+    let mut rest = data;
+    while !rest.is_empty() {
+        let length = rest
+            .get(..length_size)
+            .ok_or(Error::MediaTruncated)?
+            .iter()
+            .fold(0, |length, &byte| length << 8 | usize::from(byte));
+        let nal_unit = rest
+            .get(length_size..length_size + length)
+            .ok_or(Error::MediaTruncated)?;
+        rest = &rest[length_size + length..];
+        let nal_unit_type = match nal_unit.first() {
+            Some(&header) => header & 0x1F,
+            None => continue,
+        };
+        match nal_unit_type {
+            NAL_UNIT_TYPE_AUD => continue,
+            NAL_UNIT_TYPE_SPS => has_parameter_sets = true,
+            NAL_UNIT_TYPE_IDR if !has_parameter_sets => {
+                for parameter_set in config
+                    .sequence_parameter_sets
+                    .iter()
+                    .chain(config.picture_parameter_sets.iter())
+                {
+                    output.extend_from_slice(&ANNEX_B_START_CODE);
+                    output.extend_from_slice(parameter_set);
+                }
+                has_parameter_sets = true;
+            }
+            _ => {}
+        }
+        output.extend_from_slice(&ANNEX_B_START_CODE);
+        output.extend_from_slice(nal_unit);
+    }
+    Ok(())
+}
+
+/// ADTS header of a raw AAC frame of `size` bytes.
+pub fn adts_header(config: &AudioSpecificConfig, size: usize) -> Result<[u8; 7]> {
+    let sampling_frequency_index =
+        config
+            .sampling_frequency_index()
+            .ok_or(Error::InvalidMediaData(
+                "ADTS cannot carry an explicit sampling frequency",
+            ))?;
+    // ADTS only knows the first four object types, as its profile.
+    let profile = config.object_type.clamp(1, 4) - 1;
+    let channel_configuration = config.channel_configuration;
+    let frame_length = size + 7;
+    if frame_length > 0x1FFF {
+        return Err(Error::InvalidMediaData("AAC frame is too large for ADTS"));
+    }
+    Ok([
+        0xFF,
+        // MPEG-4, without CRC.
+        0xF1,
+        profile << 6 | sampling_frequency_index << 2 | channel_configuration >> 2,
+        (channel_configuration & 0x3) << 6 | (frame_length >> 11) as u8,
+        (frame_length >> 3) as u8,
+        ((frame_length & 0x7) as u8) << 5 | 0x1F,
+        0xFC,
+    ])
+}
+
+/// Encodes a 33-bit PTS or DTS with its 4-bit prefix.
+fn encode_timestamp(prefix: u8, timestamp: u64) -> [u8; 5] {
+    [
+        prefix << 4 | ((timestamp >> 29) & 0xE) as u8 | 0x1,
+        (timestamp >> 22) as u8,
+        ((timestamp >> 14) & 0xFE) as u8 | 0x1,
+        (timestamp >> 7) as u8,
+        ((timestamp << 1) & 0xFE) as u8 | 0x1,
+    ]
+}
+
+/// Muxes H.264 and AAC elementary streams into a single program transport stream. Timestamps
+/// are in units of 90 kHz.
+#[derive(Debug, Default)]
+pub struct TsMuxer {
+    continuity_counters: HashMap<u16, u8>,
+    /// PID of the elementary stream carrying the PCR.
+    pcr_pid: u16,
+}
+
+impl TsMuxer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn next_continuity_counter(&mut self, pid: u16) -> u8 {
+        let counter = self.continuity_counters.entry(pid).or_insert(0xF);
+        *counter = (*counter + 1) & 0xF;
+        *counter
+    }
+
+    /// Writes a PSI section in a packet of its own.
+    fn write_section(&mut self, output: &mut Vec<u8>, pid: u16, table_id: u8, body: &[u8]) {
+        let continuity_counter = self.next_continuity_counter(pid);
+        let start = output.len();
+        output.extend_from_slice(&[
+            TS_SYNC_BYTE,
+            0x40 | (pid >> 8) as u8,
+            pid as u8,
+            0x10 | continuity_counter,
+            // Pointer field.
+            0x0,
+        ]);
+        let section_start = output.len();
+        // The section length counts the bytes after it, up to the CRC.
+        let section_length = 5 + body.len() + 4;
+        output.extend_from_slice(&[
+            table_id,
+            0xB0 | (section_length >> 8) as u8,
+            section_length as u8,
+            // Transport stream ID or program number, always 1.
+            0x0,
+            0x1,
+            // Version 0, current.
+            0xC1,
+            0x0,
+            0x0,
+        ]);
+        output.extend_from_slice(body);
+        let crc = crc32(&output[section_start..]);
+        output.extend_from_slice(&crc.to_be_bytes());
+        output.resize(start + TS_PACKET_SIZE, 0xFF);
+    }
+
+    /// Writes the PAT and PMT, which start each segment so that it can be played on its own.
+    pub fn write_tables(&mut self, output: &mut Vec<u8>, has_audio: bool, has_video: bool) {
+        let pmt_pid = [0xE0 | (PMT_PID >> 8) as u8, PMT_PID as u8];
+        self.write_section(output, PAT_PID, 0x0, &[0x0, 0x1, pmt_pid[0], pmt_pid[1]]);
+        self.pcr_pid = if has_video { VIDEO_PID } else { AUDIO_PID };
+        let mut body = vec![
+            0xE0 | (self.pcr_pid >> 8) as u8,
+            self.pcr_pid as u8,
+            // No program descriptors.
+            0xF0,
+            0x0,
+        ];
+        let streams = [
+            (has_video, STREAM_TYPE_H264, VIDEO_PID),
+            (has_audio, STREAM_TYPE_AAC_ADTS, AUDIO_PID),
+        ];
+        for &(_, stream_type, pid) in streams.iter().filter(|stream| stream.0) {
+            body.extend_from_slice(&[stream_type, 0xE0 | (pid >> 8) as u8, pid as u8, 0xF0, 0x0]);
+        }
+        self.write_section(output, PMT_PID, 0x2, &body);
+    }
+
+    /// Writes an access unit of H.264 in Annex B format.
+    pub fn write_video(
+        &mut self,
+        output: &mut Vec<u8>,
+        pts: u64,
+        dts: u64,
+        data: &[u8],
+        is_keyframe: bool,
+    ) {
+        self.write_pes(
+            output,
+            VIDEO_PID,
+            PES_STREAM_ID_VIDEO,
+            pts,
+            Some(dts),
+            data,
+            is_keyframe,
+        );
+    }
+
+    /// Writes AAC frames with their ADTS headers.
+    pub fn write_audio(&mut self, output: &mut Vec<u8>, pts: u64, data: &[u8]) {
+        let is_random_access = self.pcr_pid == AUDIO_PID;
+        self.write_pes(
+            output,
+            AUDIO_PID,
+            PES_STREAM_ID_AUDIO,
+            pts,
+            None,
+            data,
+            is_random_access,
+        );
+    }
+
+    /// Writes a PES packet split into transport packets, with the PCR in the first one if the
+    /// stream carries it.
+    #[allow(clippy::too_many_arguments)]
+    fn write_pes(
+        &mut self,
+        output: &mut Vec<u8>,
+        pid: u16,
+        stream_id: u8,
+        pts: u64,
+        dts: Option<u64>,
+        data: &[u8],
+        is_random_access: bool,
+    ) {
+        let pts = pts & 0x1FFFFFFFF;
+        let dts = dts.map(|dts| dts & 0x1FFFFFFFF).filter(|&dts| dts != pts);
+        let header_data_length = if dts.is_some() { 10 } else { 5 };
+        let mut pes = vec![0x0, 0x0, 0x1, stream_id];
+        let pes_packet_length = 3 + header_data_length + data.len();
+        // Video PES packets may be longer than the field allows, which 0 stands for.
+        let pes_packet_length = if pes_packet_length > 0xFFFF || stream_id == PES_STREAM_ID_VIDEO {
+            0
+        } else {
+            pes_packet_length as u16
+        };
+        pes.extend_from_slice(&pes_packet_length.to_be_bytes());
+        pes.push(0x80);
+        match dts {
+            Some(dts) => {
+                pes.extend_from_slice(&[0xC0, header_data_length as u8]);
+                pes.extend_from_slice(&encode_timestamp(0x3, pts));
+                pes.extend_from_slice(&encode_timestamp(0x1, dts));
+            }
+            None => {
+                pes.extend_from_slice(&[0x80, header_data_length as u8]);
+                pes.extend_from_slice(&encode_timestamp(0x2, pts));
+            }
+        }
+        let pcr = if pid == self.pcr_pid {
+            Some(dts.unwrap_or(pts))
+        } else {
+            None
+        };
+
+        let mut payloads = [&pes[..], data];
+        let mut remaining = pes.len() + data.len();
+        let mut is_first = true;
+        while remaining > 0 {
+            // Adaptation field, without its length.
+            let mut adaptation_field = None;
+            if is_first && (is_random_access || pcr.is_some()) {
+                let mut field = vec![if is_random_access { 0x40 } else { 0x0 }];
+                if let Some(pcr) = pcr {
+                    field[0] |= 0x10;
+                    field.extend_from_slice(&[
+                        (pcr >> 25) as u8,
+                        (pcr >> 17) as u8,
+                        (pcr >> 9) as u8,
+                        (pcr >> 1) as u8,
+                        ((pcr & 0x1) as u8) << 7 | 0x7E,
+                        0x0,
+                    ]);
+                }
+                adaptation_field = Some(field);
+            }
+            let available = |field: &Option<Vec<u8>>| {
+                TS_PACKET_SIZE - 4 - field.as_ref().map_or(0, |field| field.len() + 1)
+            };
+            // The last packet is filled up with stuffing bytes in the adaptation field.
+            if remaining < available(&adaptation_field) {
+                let stuffing = available(&adaptation_field) - remaining;
+                match adaptation_field {
+                    Some(ref mut field) => field.resize(field.len() + stuffing, 0xFF),
+                    None if stuffing == 1 => adaptation_field = Some(vec![]),
+                    None => {
+                        let mut field = vec![0x0];
+                        field.resize(stuffing - 1, 0xFF);
+                        adaptation_field = Some(field);
+                    }
+                }
+            }
+            let continuity_counter = self.next_continuity_counter(pid);
+            output.extend_from_slice(&[
+                TS_SYNC_BYTE,
+                if is_first { 0x40 } else { 0x0 } | (pid >> 8) as u8,
+                pid as u8,
+                if adaptation_field.is_some() {
+                    0x30
+                } else {
+                    0x10
+                } | continuity_counter,
+            ]);
+            let mut size = available(&adaptation_field);
+            if let Some(field) = adaptation_field {
+                output.push(field.len() as u8);
+                output.extend_from_slice(&field);
+            }
+            remaining -= size;
+            for payload in payloads.iter_mut() {
+                let n = std::cmp::min(size, payload.len());
+                output.extend_from_slice(&payload[..n]);
+                *payload = &payload[n..];
+                size -= n;
+            }
+            is_first = false;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tables() {
+        let mut output = vec![];
+        TsMuxer::new().write_tables(&mut output, true, true);
+        assert_eq!(output.len(), 2 * TS_PACKET_SIZE);
+        // The same PAT as FFmpeg's.
+        assert_eq!(
+            output[..21],
+            [
+                0x47, 0x40, 0x00, 0x10, 0x00, 0x00, 0xB0, 0x0D, 0x00, 0x01, 0xC1, 0x00, 0x00, 0x00,
+                0x01, 0xF0, 0x00, 0x2A, 0xB1, 0x04, 0xB2
+            ]
+        );
+        assert!(output[21..TS_PACKET_SIZE].iter().all(|&b| b == 0xFF));
+        let pmt = &output[TS_PACKET_SIZE..];
+        assert_eq!(pmt[..4], [0x47, 0x50, 0x00, 0x10]);
+        // PCR on the video stream, followed by the H.264 and AAC streams.
+        assert_eq!(
+            pmt[13..27],
+            [0xE1, 0x00, 0xF0, 0x00, 0x1B, 0xE1, 0x00, 0xF0, 0x00, 0x0F, 0xE1, 0x01, 0xF0, 0x00]
+        );
+    }
+
+    #[test]
+    fn test_pes() {
+        let mut muxer = TsMuxer::new();
+        let mut output = vec![];
+        muxer.write_tables(&mut output, true, true);
+        output.clear();
+        let data: Vec<u8> = (0..300).map(|i| i as u8).collect();
+        muxer.write_video(&mut output, 9000 + 3600, 9000, &data, true);
+        assert_eq!(output.len(), 2 * TS_PACKET_SIZE);
+        let (first, second) = output.split_at(TS_PACKET_SIZE);
+        assert_eq!(first[..4], [0x47, 0x41, 0x00, 0x30]);
+        // Random access indicator and PCR.
+        assert_eq!(first[4..6], [7, 0x50]);
+        assert_eq!(first[6..12], [0x0, 0x0, 0x11, 0x94, 0x7E, 0x0]);
+        assert_eq!(
+            first[12..26],
+            [0x0, 0x0, 0x1, 0xE0, 0x0, 0x0, 0x80, 0xC0, 10, 0x31, 0x0, 0x1, 0x62, 0x71]
+        );
+        assert_eq!(second[..4], [0x47, 0x01, 0x00, 0x31]);
+        // The stuffing of the last packet leaves room for the end of the data exactly.
+        let payload_start = 5 + usize::from(second[4]);
+        let mut payload = first[31..].to_vec();
+        payload.extend_from_slice(&second[payload_start..]);
+        assert_eq!(payload, data);
+    }
+
+    #[test]
+    fn test_adts_header() {
+        let config = AudioSpecificConfig {
+            object_type: 2,
+            sampling_frequency: 44100,
+            channel_configuration: 2,
+        };
+        assert_eq!(
+            adts_header(&config, 100).unwrap(),
+            [0xFF, 0xF1, 0x50, 0x80, 0x0D, 0x7F, 0xFC]
+        );
+    }
+
+    #[test]
+    fn test_avc_to_annex_b() {
+        let config = AvcDecoderConfigurationRecord {
+            profile_indication: 0x64,
+            profile_compatibility: 0x0,
+            level_indication: 0x1F,
+            nal_unit_length_size: 4,
+            sequence_parameter_sets: vec![vec![0x67, 0x1]],
+            picture_parameter_sets: vec![vec![0x68, 0x2]],
+        };
+        let data = [0, 0, 0, 2, 0x09, 0xF0, 0, 0, 0, 3, 0x65, 0x88, 0x84];
+        let mut output = vec![];
+        avc_to_annex_b(&data, &config, &mut output).unwrap();
+        assert_eq!(
+            output,
+            [
+                0, 0, 0, 1, 0x09, 0xF0, 0, 0, 0, 1, 0x67, 0x1, 0, 0, 0, 1, 0x68, 0x2, 0, 0, 0, 1,
+                0x65, 0x88, 0x84
+            ]
+        );
+        assert!(avc_to_annex_b(&data[..12], &config, &mut output).is_err());
+    }
+}
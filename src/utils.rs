@@ -1,4 +1,4 @@
-use std::io::{self, Read};
+use std::io::{self, IoSlice, Read, Write};
 use std::ops;
 
 pub fn read_u8<R: Read>(reader: &mut R) -> io::Result<u8> {
@@ -40,6 +40,28 @@ pub fn read_buffer_sized<R: Read, const N: usize>(reader: &mut R) -> io::Result<
     Ok(buffer)
 }
 
+/// Writes every buffer with as few vectored writes as possible.
+pub fn write_all_vectored<W: Write>(
+    writer: &mut W,
+    mut buffers: &mut [IoSlice<'_>],
+) -> io::Result<()> {
+    IoSlice::advance_slices(&mut buffers, 0);
+    while !buffers.is_empty() {
+        match writer.write_vectored(buffers) {
+            Ok(0) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::WriteZero,
+                    "failed to write whole buffer",
+                ))
+            }
+            Ok(n) => IoSlice::advance_slices(&mut buffers, n),
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
 pub fn aggregate<T>(buffer: &[u8], is_little_endian: bool) -> T
 where
     T: From<u8> + ops::Shl<u8, Output = T> + ops::BitOr<Output = T>,
@@ -62,6 +84,21 @@ where
     }
 }
 
+/// Decodes a percent-encoded URL component, or returns `None` if it is malformed.
+pub fn percent_decode(s: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut iter = s.bytes();
+    while let Some(b) = iter.next() {
+        if b == b'%' {
+            let hex = [iter.next()?, iter.next()?];
+            bytes.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+        } else {
+            bytes.push(b);
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
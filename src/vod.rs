@@ -0,0 +1,443 @@
+//! Playback of FLV files on demand.
+
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::amf::{encode_amf_messages, AmfObject, AmfObjectMap};
+use crate::amf_serde::to_amf_object;
+use crate::command::{encode_command_message, on_status};
+use crate::config::Config;
+use crate::constant::*;
+use crate::error::{Error, Result};
+use crate::flv::{FlvReader, FlvTag, Keyframe};
+use crate::object::StatusInfo;
+use crate::record::sanitize;
+use crate::stream::{chunk_stream_id, RtmpMessageStream};
+
+/// File played for a stream name, if it exists. The name may have an `flv:` prefix or an `.flv`
+/// extension, and maps to the same file as a recording made with the default template.
+pub fn vod_path(config: &Config, name: &str) -> Option<PathBuf> {
+    let name = name.strip_prefix("flv:").unwrap_or(name);
+    let name = name.strip_suffix(".flv").unwrap_or(name);
+    let path = config.vod_directory.join(format!("{}.flv", sanitize(name)));
+    if path.is_file() {
+        Some(path)
+    } else {
+        None
+    }
+}
+
+/// A file to play from `start` for `duration` milliseconds, or up to its end.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PlayItem {
+    pub path: PathBuf,
+    pub start: u32,
+    pub duration: Option<u32>,
+}
+
+#[derive(Debug, Default)]
+struct PlayerState {
+    /// Items to play after the current one.
+    playlist: VecDeque<PlayItem>,
+    paused: bool,
+    seek: Option<u32>,
+    stopped: bool,
+    finished: bool,
+}
+
+/// State shared by a player and its playback thread, which is woken up whenever it changes.
+#[derive(Debug, Default)]
+struct PlayerControl {
+    state: Mutex<PlayerState>,
+    changed: Condvar,
+}
+
+impl PlayerControl {
+    fn update<T, F: FnOnce(&mut PlayerState) -> T>(&self, update: F) -> T {
+        let result = update(&mut self.state.lock().unwrap());
+        self.changed.notify_all();
+        result
+    }
+}
+
+/// Plays a playlist of FLV files to a subscriber from a thread of its own, sending each tag
+/// when it is due according to its timestamp.
+#[derive(Debug)]
+pub struct VodPlayer {
+    control: Arc<PlayerControl>,
+}
+
+impl VodPlayer {
+    pub fn new(stream: RtmpMessageStream, object_encoding: u8, item: PlayItem) -> Self {
+        let control = Arc::new(PlayerControl::default());
+        control.update(|state| state.playlist.push_back(item));
+        let mut playback = Playback {
+            stream,
+            object_encoding,
+            control: Arc::clone(&control),
+            next_tag: None,
+            clock: None,
+        };
+        thread::spawn(move || {
+            if let Err(e) = playback.run() {
+                eprintln!("Playback failed: {}", e);
+            }
+            playback.control.update(|state| state.finished = true);
+        });
+        Self { control }
+    }
+
+    /// Plays `item` after the current playlist. Returns `false` if playback is already over.
+    pub fn queue(&self, item: PlayItem) -> bool {
+        self.control.update(|state| {
+            if state.finished {
+                return false;
+            }
+            state.playlist.push_back(item);
+            true
+        })
+    }
+
+    pub fn pause(&self, pause: bool) {
+        self.control.update(|state| state.paused = pause);
+    }
+
+    /// Resumes playback of the current file from the keyframe at or before `timestamp`.
+    pub fn seek(&self, timestamp: u32) {
+        self.control.update(|state| state.seek = Some(timestamp));
+    }
+}
+
+impl Drop for VodPlayer {
+    fn drop(&mut self) {
+        self.control.update(|state| state.stopped = true);
+    }
+}
+
+/// A file being played.
+struct PlayFile {
+    reader: FlvReader<BufReader<File>>,
+    metadata: Option<AmfObjectMap>,
+    /// Metadata and sequence headers, which are sent again before playing from a keyframe.
+    headers: Vec<FlvTag>,
+    /// Position of the first tag which is not a header.
+    first_frame: u64,
+    /// Keyframes, looked up on the first seek.
+    keyframes: Option<Vec<Keyframe>>,
+    /// Timestamp after which playback of the file stops.
+    end: Option<u32>,
+}
+
+impl PlayFile {
+    fn open(item: &PlayItem) -> Result<Self> {
+        let file = File::open(&item.path).map_err(Error::Io)?;
+        let mut reader = FlvReader::open(BufReader::new(file))?;
+        let (metadata, headers, first_frame) = reader.read_headers()?;
+        Ok(Self {
+            reader,
+            metadata,
+            headers,
+            first_frame,
+            keyframes: None,
+            end: item
+                .duration
+                .map(|duration| item.start.saturating_add(duration)),
+        })
+    }
+
+    /// Positions the reader at the keyframe at or before `timestamp`, or at the first one.
+    fn seek(&mut self, timestamp: u32) -> Result<()> {
+        if timestamp == 0 {
+            return self.reader.seek(self.first_frame);
+        }
+        if self.keyframes.is_none() {
+            self.keyframes = Some(self.reader.keyframes(self.metadata.as_ref())?);
+        }
+        let keyframes = self.keyframes.as_deref().unwrap_or_default();
+        let position = keyframes
+            .iter()
+            .rev()
+            .find(|keyframe| keyframe.timestamp <= timestamp)
+            .or_else(|| keyframes.first())
+            .map_or(self.first_frame, |keyframe| {
+                std::cmp::max(keyframe.position, self.first_frame)
+            });
+        self.reader.seek(position)
+    }
+}
+
+/// Playback thread of a `VodPlayer`.
+struct Playback {
+    stream: RtmpMessageStream,
+    object_encoding: u8,
+    control: Arc<PlayerControl>,
+    next_tag: Option<FlvTag>,
+    /// When the tag with the given timestamp was sent, from which the following tags are due.
+    clock: Option<(Instant, u32)>,
+}
+
+impl Playback {
+    fn run(&mut self) -> Result<()> {
+        let mut is_first = true;
+        loop {
+            let item = {
+                let state = &mut *self.control.state.lock().unwrap();
+                if state.stopped {
+                    return Ok(());
+                }
+                match state.playlist.pop_front() {
+                    Some(item) => item,
+                    None => {
+                        // Later items need a new player.
+                        state.finished = true;
+                        break;
+                    }
+                }
+            };
+            // `play` already answered for the first item.
+            if !std::mem::take(&mut is_first) {
+                self.send_status("NetStream.Play.Start", true)?;
+            }
+            let mut file = match PlayFile::open(&item) {
+                Ok(file) => file,
+                Err(e) => {
+                    eprintln!("Failed to open {}: {}", item.path.display(), e);
+                    self.send_status("NetStream.Play.StreamNotFound", false)?;
+                    continue;
+                }
+            };
+            self.start(&mut file, item.start)?;
+            if !self.play(&mut file)? {
+                return Ok(());
+            }
+        }
+        self.send_status("NetStream.Play.Stop", true)?;
+        let information = to_amf_object(&StatusInfo::new("NetStream.Play.Complete", true))?;
+        self.stream.send_message(
+            RTMP_DATA_CHUNK_STREAM_ID,
+            RTMP_NET_CONNECTION_STREAM_ID,
+            0,
+            RTMP_DATA_MESSAGE_AMF0,
+            &encode_amf_messages(&[AmfObject::String(String::from("onPlayStatus")), information])?,
+        )?;
+        let mut buffer = Vec::from(RTMP_USER_CONTROL_STREAM_EOF.to_be_bytes());
+        buffer.extend_from_slice(&RTMP_NET_CONNECTION_STREAM_ID.to_be_bytes());
+        self.stream.send_message(
+            RTMP_PROTOCOL_CONTROL_CHUNK_STREAM_ID,
+            RTMP_PROTOCOL_CONTROL_MESSAGE_STREAM_ID,
+            0,
+            RTMP_USER_CONTROL_MESSAGE,
+            &buffer,
+        )
+    }
+
+    /// Plays from the keyframe at or before `timestamp`, starting with the headers of the file
+    /// since players flush their buffers when seeking.
+    fn start(&mut self, file: &mut PlayFile, timestamp: u32) -> Result<()> {
+        file.seek(timestamp)?;
+        self.next_tag = file.reader.read_tag()?;
+        self.clock = None;
+        let timestamp = self
+            .next_tag
+            .as_ref()
+            .map_or(timestamp, |tag| tag.header.timestamp);
+        for tag in &file.headers {
+            send_tag(&mut self.stream, tag, timestamp)?;
+        }
+        Ok(())
+    }
+
+    /// Sends the tags of a file as they become due. Returns `false` if playback was stopped
+    /// before the end of the file.
+    fn play(&mut self, file: &mut PlayFile) -> Result<bool> {
+        let control = Arc::clone(&self.control);
+        loop {
+            if self.next_tag.is_none() {
+                self.next_tag = file.reader.read_tag()?;
+            }
+            let timestamp = match self.next_tag {
+                Some(ref tag) => tag.header.timestamp,
+                None => return Ok(true),
+            };
+            if file.end.is_some_and(|end| timestamp > end) {
+                return Ok(true);
+            }
+            let mut state = control.state.lock().unwrap();
+            if state.stopped {
+                return Ok(false);
+            }
+            if let Some(timestamp) = state.seek.take() {
+                drop(state);
+                // Players expect the statuses before the media of the new position.
+                self.send_status("NetStream.Seek.Notify", true)?;
+                self.send_status("NetStream.Play.Start", true)?;
+                self.start(file, timestamp)?;
+                continue;
+            }
+            if state.paused {
+                // The clock starts over when playback resumes.
+                self.clock = None;
+                drop(control.changed.wait(state).unwrap());
+                continue;
+            }
+            let (sent_at, sent_timestamp) = *self.clock.get_or_insert((Instant::now(), timestamp));
+            let due = sent_at
+                + Duration::from_millis(u64::from(timestamp.saturating_sub(sent_timestamp)));
+            let now = Instant::now();
+            if due > now {
+                drop(control.changed.wait_timeout(state, due - now).unwrap());
+                continue;
+            }
+            drop(state);
+            if let Some(tag) = self.next_tag.take() {
+                send_tag(&mut self.stream, &tag, timestamp)?;
+            }
+        }
+    }
+
+    fn send_status(&mut self, code: &str, success: bool) -> Result<()> {
+        let (message_type_id, buffer) =
+            encode_command_message(self.object_encoding, &on_status(code, success)?)?;
+        self.stream.send_message(
+            RTMP_COMMAND_CHUNK_STREAM_ID,
+            RTMP_NET_CONNECTION_STREAM_ID,
+            0,
+            message_type_id,
+            &buffer,
+        )
+    }
+}
+
+/// Sends an audio, video or script tag as the message of the same type.
+fn send_tag(stream: &mut RtmpMessageStream, tag: &FlvTag, timestamp: u32) -> Result<()> {
+    let message_type_id = tag.header.tag_type;
+    match message_type_id {
+        FLV_TAG_TYPE_AUDIO | FLV_TAG_TYPE_VIDEO | FLV_TAG_TYPE_SCRIPT => stream.send_message(
+            chunk_stream_id(message_type_id),
+            RTMP_NET_CONNECTION_STREAM_ID,
+            timestamp,
+            message_type_id,
+            &tag.data,
+        ),
+        _ => Ok(()),
+    }
+}
+
+/// Duration of a file in seconds, from its `onMetaData` or its last tag.
+pub fn vod_duration(path: &Path) -> Result<f64> {
+    let file = File::open(path).map_err(Error::Io)?;
+    let mut reader = FlvReader::open(BufReader::new(file))?;
+    let (metadata, _, _) = reader.read_headers()?;
+    if let Some(AmfObject::Number(duration)) = metadata.as_ref().and_then(|m| m.get("duration")) {
+        return Ok(*duration);
+    }
+    let mut duration = 0;
+    while let Some(tag) = reader.read_tag()? {
+        duration = std::cmp::max(duration, tag.header.timestamp);
+    }
+    Ok(f64::from(duration) / 1000.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flv::FlvWriter;
+    use std::fs;
+    use std::io::BufWriter;
+    use std::net::{TcpListener, TcpStream};
+
+    #[test]
+    fn test_vod_player() {
+        let directory = std::env::temp_dir().join(format!("rtmp-vod-{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let path = directory.join("test.flv");
+        let file = BufWriter::new(File::create(&path).unwrap());
+        let mut flv = FlvWriter::create(file, false, true).unwrap();
+        flv.write_metadata(0, AmfObjectMap::new()).unwrap();
+        flv.write_tag(FLV_TAG_TYPE_VIDEO, 0, &[0x17, 0x0, 0x0, 0x0, 0x0])
+            .unwrap();
+        for timestamp in [0, 40, 80, 120] {
+            let frame_type = if timestamp % 80 == 0 { 0x17 } else { 0x27 };
+            flv.write_tag(
+                FLV_TAG_TYPE_VIDEO,
+                timestamp,
+                &[frame_type, 0x1, 0x0, 0x0, 0x0],
+            )
+            .unwrap();
+        }
+        flv.finish().unwrap();
+        let config = Config {
+            vod_directory: directory.clone(),
+            ..Config::default()
+        };
+        assert_eq!(vod_path(&config, "flv:test"), Some(path.clone()));
+        assert_eq!(vod_path(&config, "missing"), None);
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let play = |start: u32| {
+            let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+            let client = RtmpMessageStream::new(stream);
+            let server = RtmpMessageStream::new(listener.accept().unwrap().0);
+            let item = PlayItem {
+                path: path.clone(),
+                start,
+                duration: None,
+            };
+            let player = VodPlayer::new(server, RTMP_OBJECT_ENCODING_AMF0, item);
+            (client, player)
+        };
+        // Messages sent until the end of playback, as type and timestamp.
+        let read_messages = |client: &mut RtmpMessageStream| {
+            let mut messages = vec![];
+            while messages.last().map(|&(message_type_id, _)| message_type_id)
+                != Some(RTMP_USER_CONTROL_MESSAGE)
+            {
+                if let Some(message) = client.read_message().unwrap() {
+                    messages.push((message.header.message_type_id, message.header.timestamp));
+                }
+            }
+            messages
+        };
+        let started_at = Instant::now();
+        let (mut client, _player) = play(100);
+        let messages = read_messages(&mut client);
+        // Playback starts with the headers and the keyframe before the start, and is paced.
+        assert!(started_at.elapsed() >= Duration::from_millis(40));
+        assert_eq!(
+            messages,
+            [
+                (RTMP_DATA_MESSAGE_AMF0, 80),
+                (RTMP_VIDEO_MESSAGE, 80),
+                (RTMP_VIDEO_MESSAGE, 80),
+                (RTMP_VIDEO_MESSAGE, 120),
+                (RTMP_COMMAND_MESSAGE_AMF0, 0),
+                (RTMP_DATA_MESSAGE_AMF0, 0),
+                (RTMP_USER_CONTROL_MESSAGE, 0),
+            ]
+        );
+
+        // After seeking, Seek.Notify and Play.Start come before the headers.
+        let (mut client, player) = play(0);
+        player.seek(0);
+        let messages = read_messages(&mut client);
+        fs::remove_dir_all(&directory).unwrap();
+        let seek = messages
+            .iter()
+            .position(|&(message_type_id, _)| message_type_id == RTMP_COMMAND_MESSAGE_AMF0)
+            .unwrap();
+        assert_eq!(
+            messages[seek..seek + 4],
+            [
+                (RTMP_COMMAND_MESSAGE_AMF0, 0),
+                (RTMP_COMMAND_MESSAGE_AMF0, 0),
+                (RTMP_DATA_MESSAGE_AMF0, 0),
+                (RTMP_VIDEO_MESSAGE, 0),
+            ]
+        );
+    }
+}